@@ -0,0 +1,469 @@
+//! Measures how fast packets can be encoded and decoded, so changes to the protocol can be compared with numbers.
+//! Also sends movement over a loopback connection, to compare streams with datagrams.
+//!
+//! Run with `cargo bench --bench packets`.
+use bincode::{decode_from_slice, encode_to_vec};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use miniscop::networking::{
+    dequantize_position, encode_frame, movement_batches, origin_tag, quantize_position,
+    receive_packet, receive_packet_datagram, send_packet, send_packet_datagram, DisconnectReason,
+    Packet, MAX_QUANTIZATION_ERROR, PACKET_CONFIG, PROTOCOL_VERSION,
+};
+use quinn::rustls::RootCertStore;
+use quinn::{ClientConfig, Connection, Endpoint, ServerConfig};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// How many players the mixed stream benchmark pretends are moving at once.
+const PLAYER_COUNT: u64 = 50;
+/// How many ticks of movement the mixed stream benchmark contains.
+const TICKS: u64 = 64;
+/// About how many bytes fit in one datagram on most connections.
+const TYPICAL_DATAGRAM_SIZE: usize = 1200;
+/// How long to wait for a datagram over loopback before deciding it was lost.
+const DATAGRAM_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn movement(id: u64, tick: u64) -> Packet {
+    let t = tick as f32 / 64.0;
+    Packet::PlayerMovement {
+        id: Some(id),
+        x: id as f32 + t.cos(),
+        y: 0.5,
+        z: id as f32 + t.sin(),
+        animation_frame: (tick % 25) as u8,
+        teleported: false,
+        zone: 0,
+    }
+}
+
+/// One of every packet variant, with realistic values.
+fn every_variant() -> Vec<(&'static str, Packet)> {
+    vec![
+        (
+            "Hello",
+            Packet::Hello {
+                version: PROTOCOL_VERSION,
+                session_token: u64::MAX / 3,
+                player_token: u64::MAX / 5,
+            },
+        ),
+        ("ClientConnect", Packet::ClientConnect { id: 3 }),
+        (
+            "ClientDisconnect",
+            Packet::ClientDisconnect(Some(1_234_567), Some(DisconnectReason::Timeout)),
+        ),
+        ("PlayerMovement", movement(1_234_567, 17)),
+        (
+            "LevelConfig",
+            Packet::LevelConfig {
+                move_speed: 4.0,
+                spawn: [0.0, 0.5, 0.0],
+                lockstep: false,
+            },
+        ),
+        (
+            "SetSpawn",
+            Packet::SetSpawn {
+                x: 1.0,
+                y: 0.5,
+                z: 0.0,
+            },
+        ),
+        (
+            "Interact",
+            Packet::Interact {
+                id: Some(1_234_567),
+            },
+        ),
+        ("GiftState", Packet::GiftState { opened: true }),
+        ("ReloadLevel", Packet::ReloadLevel),
+        (
+            "Chat",
+            Packet::Chat {
+                id: Some(1_234_567),
+                message: "Has anyone found the way into the school?".to_string(),
+                global: false,
+            },
+        ),
+        (
+            "PlayerRoster",
+            Packet::PlayerRoster(
+                (0..PLAYER_COUNT)
+                    .map(|id| (id, id as f32, 0.5, id as f32, 0, 0))
+                    .collect(),
+            ),
+        ),
+        ("Heartbeat", Packet::Heartbeat),
+        ("Ping", Packet::Ping { nonce: 1_234 }),
+        ("Pong", Packet::Pong { nonce: 1_234 }),
+        ("PlayerOutOfRange", Packet::PlayerOutOfRange(1_234_567)),
+        (
+            "QuantizedMovement",
+            Packet::QuantizedMovement {
+                origin: 17,
+                x: 40,
+                y: 0,
+                z: -25,
+                animation_frame: 17,
+            },
+        ),
+        ("ServerShutdown", Packet::ServerShutdown),
+        (
+            "VersionMismatch",
+            Packet::VersionMismatch {
+                server_version: PROTOCOL_VERSION,
+            },
+        ),
+        (
+            "MovementBatch",
+            Packet::MovementBatch(
+                (0..PLAYER_COUNT)
+                    .map(|id| (id, id as f32, 0.5, id as f32, 0, 0))
+                    .collect(),
+            ),
+        ),
+        (
+            "SetName",
+            Packet::SetName {
+                id: Some(1_234_567),
+                name: "Paul".to_string(),
+            },
+        ),
+        (
+            "Emote",
+            Packet::Emote {
+                id: Some(1_234_567),
+                emote: 2,
+            },
+        ),
+        (
+            "RoomList",
+            Packet::RoomList {
+                rooms: vec![(0, PLAYER_COUNT as u32)],
+                capacity: 100,
+            },
+        ),
+        ("JoinRoom", Packet::JoinRoom { zone: 0 }),
+        (
+            "Input",
+            Packet::Input {
+                id: Some(1_234_567),
+                tick: 300,
+                input: 5,
+            },
+        ),
+    ]
+}
+
+/// What a client receives while 50 players walk around: mostly movement, with the occasional join or leave.
+fn mixed_stream() -> Vec<Packet> {
+    let mut packets = Vec::new();
+    for tick in 0..TICKS {
+        for id in 0..PLAYER_COUNT {
+            packets.push(movement(id, tick));
+        }
+        if tick % 16 == 0 {
+            packets.push(Packet::ClientDisconnect(
+                Some(tick),
+                Some(DisconnectReason::Graceful),
+            ));
+        }
+    }
+    packets
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for (name, packet) in every_variant() {
+        group.bench_function(name, |b| {
+            b.iter(|| encode_to_vec(black_box(&packet), PACKET_CONFIG).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for (name, packet) in every_variant() {
+        let bytes = encode_to_vec(&packet, PACKET_CONFIG).unwrap();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let (packet, _): (Packet, usize) =
+                    decode_from_slice(black_box(&bytes), PACKET_CONFIG).unwrap();
+                packet
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Compares encoding a packet on its own with encoding it as a length-prefixed frame.
+fn framing(c: &mut Criterion) {
+    let packet = movement(1_234_567, 17);
+    let unframed = encode_to_vec(&packet, PACKET_CONFIG).unwrap().len();
+    let framed = encode_frame(&packet).unwrap().len();
+    println!("PlayerMovement is {unframed} bytes, or {framed} bytes framed.");
+
+    let mut group = c.benchmark_group("framing");
+    group.bench_function("unframed", |b| {
+        b.iter(|| encode_to_vec(black_box(&packet), PACKET_CONFIG).unwrap())
+    });
+    group.bench_function("framed", |b| {
+        b.iter(|| encode_frame(black_box(&packet)).unwrap())
+    });
+    group.finish();
+}
+
+/// Compares encoding one tick of movement as separate packets with encoding it as Packet::MovementBatch,
+/// and prints how many datagrams each client receives per tick either way.
+fn batched_movement(c: &mut Criterion) {
+    let tick: Vec<Packet> = (0..PLAYER_COUNT).map(|id| movement(id, 0)).collect();
+    let movements: Vec<(u64, f32, f32, f32, u8, u16)> = tick
+        .iter()
+        .map(|packet| match *packet {
+            Packet::PlayerMovement {
+                id: Some(id),
+                x,
+                y,
+                z,
+                animation_frame,
+                zone,
+                ..
+            } => (id, x, y, z, animation_frame, zone),
+            _ => unreachable!(),
+        })
+        .collect();
+    let separate: usize = tick
+        .iter()
+        .map(|packet| encode_to_vec(packet, PACKET_CONFIG).unwrap().len())
+        .sum();
+    let batches = movement_batches(movements.clone(), TYPICAL_DATAGRAM_SIZE);
+    let batched: Vec<Vec<u8>> = batches
+        .iter()
+        .map(|packet| encode_to_vec(packet, PACKET_CONFIG).unwrap())
+        .collect();
+    println!(
+        "{PLAYER_COUNT} movements are {separate} bytes in {PLAYER_COUNT} datagrams separately, or {} bytes in {} datagrams batched.",
+        batched.iter().map(Vec::len).sum::<usize>(),
+        batched.len()
+    );
+
+    let mut group = c.benchmark_group("batched_movement");
+    group.throughput(Throughput::Elements(PLAYER_COUNT));
+    group.bench_function("encode_separate", |b| {
+        b.iter(|| {
+            black_box(&tick)
+                .iter()
+                .map(|packet| encode_to_vec(packet, PACKET_CONFIG).unwrap())
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("encode_batched", |b| {
+        b.iter(|| {
+            movement_batches(black_box(movements.clone()), TYPICAL_DATAGRAM_SIZE)
+                .iter()
+                .map(|packet| encode_to_vec(packet, PACKET_CONFIG).unwrap())
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("decode_batched", |b| {
+        b.iter(|| {
+            black_box(&batched)
+                .iter()
+                .map(|bytes| {
+                    let (packet, _): (Packet, usize) =
+                        decode_from_slice(bytes, PACKET_CONFIG).unwrap();
+                    packet
+                })
+                .collect::<Vec<_>>()
+        })
+    });
+    group.finish();
+}
+
+/// Compares the size of absolute and quantized movement, and checks that quantizing stays within its documented error.
+fn quantization(c: &mut Criterion) {
+    let origin = [12.3, 0.5, -45.6];
+    let mut worst_error: f32 = 0.0;
+    for step in 0..1000 {
+        let t = step as f32 / 1000.0;
+        let position = [origin[0] + t * 0.5, origin[1], origin[2] - t * 0.37];
+        let offset = quantize_position(position, origin).unwrap();
+        let round_trip = dequantize_position(offset, origin);
+        for (round_trip, position) in round_trip.into_iter().zip(position) {
+            worst_error = worst_error.max((round_trip - position).abs());
+        }
+    }
+    assert!(
+        worst_error <= MAX_QUANTIZATION_ERROR + f32::EPSILON * 64.0,
+        "Quantizing was off by {worst_error}m, more than {MAX_QUANTIZATION_ERROR}m."
+    );
+
+    let absolute = encode_to_vec(movement(1_234_567, 17), PACKET_CONFIG)
+        .unwrap()
+        .len();
+    let quantized = Packet::QuantizedMovement {
+        origin: origin_tag(origin),
+        x: 40,
+        y: 0,
+        z: -25,
+        animation_frame: 17,
+    };
+    let quantized = encode_to_vec(quantized, PACKET_CONFIG).unwrap().len();
+    println!(
+        "Absolute movement is {absolute} bytes, and quantized movement is {quantized} bytes. Quantizing was off by at most {worst_error}m."
+    );
+
+    let position = [origin[0] + 0.3, origin[1], origin[2] - 0.2];
+    let mut group = c.benchmark_group("quantization");
+    group.bench_function("quantize", |b| {
+        b.iter(|| quantize_position(black_box(position), black_box(origin)))
+    });
+    group.bench_function("dequantize", |b| {
+        b.iter(|| dequantize_position(black_box([38, 0, -26]), black_box(origin)))
+    });
+    group.finish();
+}
+
+/// Encodes and then decodes every packet of a stream representative of 50 players moving.
+fn mixed(c: &mut Criterion) {
+    let stream = mixed_stream();
+    let bytes: usize = stream
+        .iter()
+        .map(|packet| encode_to_vec(packet, PACKET_CONFIG).unwrap().len())
+        .sum();
+
+    let mut group = c.benchmark_group("mixed_stream");
+    group.throughput(Throughput::Bytes(bytes as u64));
+    group.bench_function("round_trip", |b| {
+        b.iter_batched(
+            || stream.clone(),
+            |stream| {
+                for packet in stream {
+                    let bytes = encode_to_vec(packet, PACKET_CONFIG).unwrap();
+                    let (packet, _): (Packet, usize) =
+                        decode_from_slice(&bytes, PACKET_CONFIG).unwrap();
+                    black_box(packet);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+/// A server and a client connected over IPv4 loopback, using the server tests' self-signed certificate.
+/// The endpoints are returned too, since the connections close once they're dropped.
+async fn connect_loopback() -> (Connection, Connection, [Endpoint; 2]) {
+    let testing = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/bin/server/testing");
+    let certificate = CertificateDer::from_pem_file(testing.join("certificate.pem")).unwrap();
+    let key = PrivateKeyDer::from_pem_file(testing.join("key.pem")).unwrap();
+    let server_config = ServerConfig::with_single_cert(vec![certificate.clone()], key).unwrap();
+    let server_endpoint = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate).unwrap();
+    let mut client_endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client_endpoint
+        .set_default_client_config(ClientConfig::with_root_certificates(Arc::new(roots)).unwrap());
+
+    let address = server_endpoint.local_addr().unwrap();
+    let (server, client) = tokio::join!(
+        async { server_endpoint.accept().await.unwrap().await.unwrap() },
+        async {
+            client_endpoint
+                .connect(address, "localhost")
+                .unwrap()
+                .await
+                .unwrap()
+        },
+    );
+    (client, server, [client_endpoint, server_endpoint])
+}
+
+/// Sends movements the way they were sent before datagrams, opening a stream and spawning a task to write each one,
+/// and waits for all of them to arrive. Returns how many tasks were spawned.
+async fn send_on_streams(
+    sender: &Connection,
+    receiver: &Connection,
+    movements: &[Packet],
+) -> usize {
+    let mut spawned = 0;
+    let send = async {
+        for packet in movements {
+            let send = sender.open_uni().await.unwrap();
+            let packet = packet.clone();
+            tokio::spawn(async move { send_packet(send, packet).await.unwrap() });
+            spawned += 1;
+        }
+    };
+    let receive = async {
+        for _ in movements {
+            receive_packet(receiver.accept_uni().await.unwrap())
+                .await
+                .unwrap();
+        }
+    };
+    tokio::join!(send, receive);
+    spawned
+}
+
+/// Sends movements as datagrams, which never wait, so nothing has to be spawned. Waits for all of them to arrive.
+async fn send_as_datagrams(sender: &Connection, receiver: &Connection, movements: &[Packet]) {
+    for packet in movements {
+        send_packet_datagram(sender, packet).unwrap();
+    }
+    for _ in movements {
+        tokio::time::timeout(DATAGRAM_TIMEOUT, receive_packet_datagram(receiver))
+            .await
+            .expect("A movement datagram was lost over loopback.")
+            .unwrap();
+    }
+}
+
+/// Compares sending one tick of movement on a stream per movement with sending it as datagrams,
+/// and prints how many tasks each way spawns.
+fn movement_transport(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (client, server, _endpoints) = runtime.block_on(connect_loopback());
+    let tick: Vec<Packet> = (0..PLAYER_COUNT).map(|id| movement(id, 0)).collect();
+
+    let spawned = runtime.block_on(send_on_streams(&client, &server, &tick));
+    // The server spawned another for every player it passed each movement on to.
+    // send_packet_datagram never waits, so neither side spawns anything for datagrams.
+    println!(
+        "{PLAYER_COUNT} movements on their own streams spawned {spawned} tasks, {} per movement, and spawned {} more on the server \
+        passing them on to {} other players. As datagrams, they spawned none.",
+        spawned as f64 / PLAYER_COUNT as f64,
+        spawned as u64 * (PLAYER_COUNT - 1),
+        PLAYER_COUNT - 1,
+    );
+
+    let mut group = c.benchmark_group("movement_transport");
+    group.throughput(Throughput::Elements(PLAYER_COUNT));
+    group.bench_function("streams", |b| {
+        b.iter(|| runtime.block_on(send_on_streams(&client, &server, black_box(&tick))))
+    });
+    group.bench_function("datagrams", |b| {
+        b.iter(|| runtime.block_on(send_as_datagrams(&client, &server, black_box(&tick))))
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    encode,
+    decode,
+    framing,
+    batched_movement,
+    quantization,
+    mixed,
+    movement_transport
+);
+criterion_main!(benches);
@@ -1,6 +1,15 @@
+use crate::plugins::config::{default_config_path, ConfigPlugin};
+use crate::plugins::continue_prompt::ContinuePromptPlugin;
+use crate::plugins::controls::ControlsPlugin;
 use crate::plugins::garalina::GaralinaPlugin;
 use crate::plugins::mainmenu::MainMenuPlugin;
-use crate::plugins::overworld::OverworldPlugin;
+use crate::plugins::music::MusicPlugin;
+use crate::plugins::options::OptionsPlugin;
+use crate::plugins::overworld::{
+    InsecureMode, NetworkMode, OverworldPlugin, PlayerName, ServerAddress,
+};
+use crate::plugins::power_saving::PowerSavingPlugin;
+use crate::plugins::settings::SettingsPlugin;
 use bevy::dev_tools::fps_overlay::{FpsOverlayConfig, FpsOverlayPlugin};
 use bevy::prelude::{
     default, App, AppExtStates, AssetServer, Color, Font, ImagePlugin, PluginGroup, Res, ResMut,
@@ -10,47 +19,133 @@ use bevy::text::FontSmoothing;
 use bevy::window::{CursorOptions, PresentMode};
 use bevy::DefaultPlugins;
 use bevy_sprite3d::Sprite3dPlugin;
+use clap::Parser;
+use std::path::PathBuf;
 use std::time::Duration;
 
 mod plugins;
 
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// An optional file path to the settings file to load and save.
+    /// By default, it's settings.toml in the Miniscop folder of your config directory.
+    #[clap(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// An optional server to connect to, as HOST:PORT, instead of the one in the settings.
+    /// It's also saved as the server to use next time.
+    #[clap(short, long, value_name = "HOST:PORT", value_parser = parse_server_address)]
+    server: Option<ServerAddress>,
+    /// An optional name to show other players, instead of the one in the settings.
+    /// It's also saved as the name to use next time.
+    #[clap(short, long)]
+    name: Option<String>,
+    /// Start in the overworld, skipping the intro and the main menu.
+    #[clap(long)]
+    skip_intro: bool,
+    /// Connect without checking the server's certificate, so a local server with a self-signed one can be tested.
+    ///
+    /// Never use this with a public server. Anyone between you and it could pretend to be it and read everything you send.
+    #[clap(long)]
+    insecure: bool,
+    /// Play alone without connecting to a server. This can also be switched in the main menu.
+    #[clap(long)]
+    offline: bool,
+}
+
+/// Splits HOST:PORT at the last colon, so an IPv6 host has to be in brackets, like [::1]:4433.
+fn parse_server_address(address: &str) -> Result<ServerAddress, String> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or("expected HOST:PORT, like 127.0.0.1:4433")?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err("the host is empty".to_string());
+    }
+    if host.contains(':') && !address.starts_with('[') {
+        return Err("an IPv6 host has to be in brackets, like [::1]:4433".to_string());
+    }
+    let port = port
+        .parse()
+        .map_err(|e| format!("{port:?} isn't a valid port: {e}"))?;
+    Ok(ServerAddress {
+        host: host.to_string(),
+        port,
+    })
+}
+
 fn main() {
-    App::new()
-        .add_plugins((
-            DefaultPlugins
-                .set(WindowPlugin {
-                    primary_window: Some(Window {
-                        cursor_options: CursorOptions {
-                            visible: false,
-                            ..default()
-                        },
-                        present_mode: PresentMode::AutoVsync,
-                        // mode: WindowMode::Fullscreen(
-                        //     MonitorSelection::Primary,
-                        //     VideoModeSelection::Current,
-                        // ),
-                        title: "Miniscop: Investigate Together!".to_string(),
-                        name: Some("Miniscop".to_string()),
-                        prevent_default_event_handling: false, // Setting it to false means you should not bind inputs to F5, F12, Ctrl+R, and Tab
-                        fit_canvas_to_parent: true,
+    let args = Args::parse();
+
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins
+            .set(WindowPlugin {
+                primary_window: Some(Window {
+                    cursor_options: CursorOptions {
+                        visible: false,
                         ..default()
-                    }),
-                    ..default()
-                })
-                .set(ImagePlugin::default_nearest()),
-            Sprite3dPlugin,
-            FpsOverlayPlugin {
-                config: FpsOverlayConfig {
-                    text_color: Color::BLACK,
-                    refresh_interval: Duration::from_secs(1),
+                    },
+                    present_mode: PresentMode::AutoVsync,
+                    // mode: WindowMode::Fullscreen(
+                    //     MonitorSelection::Primary,
+                    //     VideoModeSelection::Current,
+                    // ),
+                    title: "Miniscop: Investigate Together!".to_string(),
+                    name: Some("Miniscop".to_string()),
+                    prevent_default_event_handling: false, // Setting it to false means you should not bind inputs to F5, F12, Ctrl+R, and Tab
+                    fit_canvas_to_parent: true,
                     ..default()
-                },
+                }),
+                ..default()
+            })
+            .set(ImagePlugin::default_nearest()),
+        Sprite3dPlugin,
+        FpsOverlayPlugin {
+            config: FpsOverlayConfig {
+                text_color: Color::BLACK,
+                refresh_interval: Duration::from_secs(1),
+                ..default()
             },
-        ))
-        .insert_state(AppState::Overworld)
-        .add_plugins((GaralinaPlugin, MainMenuPlugin, OverworldPlugin))
-        .add_systems(Startup, setup)
-        .run();
+        },
+    ))
+    .insert_state(if args.skip_intro {
+        AppState::Overworld
+    } else {
+        AppState::default()
+    })
+    .add_plugins((
+        ConfigPlugin {
+            path: args.config.or_else(default_config_path),
+        },
+        SettingsPlugin,
+        MusicPlugin,
+        ContinuePromptPlugin,
+        PowerSavingPlugin,
+        GaralinaPlugin,
+        MainMenuPlugin,
+        OptionsPlugin,
+        ControlsPlugin,
+        OverworldPlugin,
+    ))
+    .add_systems(Startup, setup);
+    // These replace what ConfigPlugin loaded, so they have to come after it.
+    if let Some(server_address) = args.server {
+        app.insert_resource(server_address);
+    }
+    if let Some(name) = args.name {
+        app.insert_resource(PlayerName(name));
+    }
+    if args.insecure {
+        app.insert_resource(InsecureMode(true));
+    }
+    if args.offline {
+        app.insert_resource(NetworkMode::Offline);
+    }
+    app.run();
 }
 
 #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, States)]
@@ -59,6 +154,10 @@ pub enum AppState {
     #[default]
     Garalina,
     MainMenu,
+    /// Opened from the main menu, and goes back to it.
+    Options,
+    /// Opened from the options screen, and goes back to it.
+    Controls,
     Overworld,
 }
 
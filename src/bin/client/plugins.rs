@@ -1,3 +1,10 @@
+pub mod config;
+pub mod continue_prompt;
+pub mod controls;
 pub mod garalina;
 pub mod mainmenu;
+pub mod music;
+pub mod options;
 pub mod overworld;
+pub mod power_saving;
+pub mod settings;
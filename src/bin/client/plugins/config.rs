@@ -0,0 +1,426 @@
+use crate::plugins::overworld::{PlayerName, PlayerToken, ServerAddress};
+use crate::plugins::settings::{
+    AudioSettings, GraphicsSettings, InputAction, InputBindings, Resolution,
+};
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Loads the volume, window resolution, server address, player name and token, and key bindings from a TOML file at startup,
+/// and saves them whenever they change.
+///
+/// This has to be added before the plugins that own those resources, so their defaults don't replace what was loaded.
+/// Without a path, like on the web, nothing is loaded or saved.
+pub struct ConfigPlugin {
+    pub path: Option<PathBuf>,
+}
+impl Plugin for ConfigPlugin {
+    fn build(&self, app: &mut App) {
+        let settings = match &self.path {
+            Some(path) => Settings::load(path),
+            None => {
+                info!("There is nowhere to keep settings, so they won't be saved.");
+                Settings::default()
+            }
+        };
+        settings.insert_resources(app);
+        app.insert_resource(ConfigPath(self.path.clone()))
+            .add_systems(
+                Update,
+                save_settings.run_if(
+                    resource_changed::<AudioSettings>
+                        .or(resource_changed::<GraphicsSettings>)
+                        .or(resource_changed::<ServerAddress>)
+                        .or(resource_changed::<PlayerName>)
+                        .or(resource_changed::<PlayerToken>)
+                        .or(resource_changed::<InputBindings>),
+                ),
+            );
+    }
+}
+
+/// Where settings are kept on this platform, unless the --config argument says otherwise.
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("Miniscop").join("settings.toml"))
+}
+
+// Resources
+#[derive(Resource)]
+struct ConfigPath(Option<PathBuf>);
+
+/// Everything kept in the config file.
+///
+/// Each field is read on its own, so one that is missing or invalid only resets that field to its default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub audio: AudioConfig,
+    pub graphics: GraphicsConfig,
+    pub server: ServerConfig,
+    pub player: PlayerConfig,
+    /// The keys for each action, by InputAction::config_key.
+    pub bindings: BTreeMap<String, Vec<KeyCode>>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AudioConfig {
+    pub master: f32,
+    pub music: f32,
+    pub sfx: f32,
+    /// In seconds.
+    pub crossfade: f32,
+}
+/// Only the resolution is kept. The other graphics settings start at their defaults every launch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GraphicsConfig {
+    pub resolution: Resolution,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerConfig {
+    pub name: String,
+    /// PlayerToken in hexadecimal, since TOML integers can't hold every u64.
+    pub token: String,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self::from_resources(
+            &AudioSettings::default(),
+            &GraphicsSettings::default(),
+            &ServerAddress::default(),
+            &PlayerName::default(),
+            &PlayerToken::default(),
+            &InputBindings::default(),
+        )
+    }
+}
+impl Settings {
+    fn from_resources(
+        audio: &AudioSettings,
+        graphics: &GraphicsSettings,
+        server_address: &ServerAddress,
+        player_name: &PlayerName,
+        player_token: &PlayerToken,
+        bindings: &InputBindings,
+    ) -> Self {
+        Self {
+            audio: AudioConfig {
+                master: audio.master,
+                music: audio.music,
+                sfx: audio.sfx,
+                crossfade: audio.crossfade,
+            },
+            graphics: GraphicsConfig {
+                resolution: graphics.resolution,
+            },
+            server: ServerConfig {
+                host: server_address.host.clone(),
+                port: server_address.port,
+            },
+            player: PlayerConfig {
+                name: player_name.0.clone(),
+                token: format!("{:016x}", player_token.0),
+            },
+            bindings: InputAction::ALL
+                .into_iter()
+                .map(|action| {
+                    (
+                        action.config_key().to_string(),
+                        bindings.keys(action).to_vec(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Reads the config file, keeping the default of every field that is missing or invalid.
+    /// A missing file is normal the first time the game runs, and is created by the first save.
+    fn load(path: &Path) -> Self {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No settings at {}, so using the defaults.", path.display());
+                return Self::default();
+            }
+            Err(e) => {
+                warn!("Could not read settings at {}: {e}", path.display());
+                return Self::default();
+            }
+        };
+        let table: toml::Table = match text.parse() {
+            Ok(table) => table,
+            Err(e) => {
+                warn!(
+                    "Settings at {} aren't valid TOML, so using the defaults: {e}",
+                    path.display()
+                );
+                return Self::default();
+            }
+        };
+
+        let mut settings = Self::default();
+        let volume = |name: &str, default: f32| {
+            let volume = read_field(&table, &["audio", name], default);
+            if (0.0..=1.0).contains(&volume) {
+                volume
+            } else {
+                warn!("audio.{name} has to be from 0.0 to 1.0, so it was reset to {default}.");
+                default
+            }
+        };
+        settings.audio = AudioConfig {
+            master: volume("master", settings.audio.master),
+            music: volume("music", settings.audio.music),
+            sfx: volume("sfx", settings.audio.sfx),
+            crossfade: {
+                let default = settings.audio.crossfade;
+                let crossfade = read_field(&table, &["audio", "crossfade"], default);
+                if crossfade.is_finite() && crossfade >= 0.0 {
+                    crossfade
+                } else {
+                    warn!("audio.crossfade can't be negative, so it was reset to {default}.");
+                    default
+                }
+            },
+        };
+        settings.graphics = GraphicsConfig {
+            resolution: read_field(
+                &table,
+                &["graphics", "resolution"],
+                settings.graphics.resolution,
+            ),
+        };
+        settings.server = ServerConfig {
+            host: read_field(&table, &["server", "host"], settings.server.host),
+            port: read_field(&table, &["server", "port"], settings.server.port),
+        };
+        settings.player = PlayerConfig {
+            name: read_field(&table, &["player", "name"], settings.player.name),
+            token: {
+                let default = settings.player.token;
+                let token: String = read_field(&table, &["player", "token"], default.clone());
+                if u64::from_str_radix(&token, 16).is_ok() {
+                    token
+                } else {
+                    warn!("player.token isn't a hexadecimal number, so a new one was made. Servers won't remember where you left off.");
+                    default
+                }
+            },
+        };
+        for action in InputAction::ALL {
+            let key = action.config_key();
+            let default = settings.bindings[key].clone();
+            settings.bindings.insert(
+                key.to_string(),
+                read_field(&table, &["bindings", key], default),
+            );
+        }
+        info!("Loaded settings from {}.", path.display());
+        settings
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Inserts the resources these settings are for.
+    fn insert_resources(self, app: &mut App) {
+        let mut keys = HashMap::new();
+        for action in InputAction::ALL {
+            if let Some(action_keys) = self.bindings.get(action.config_key()) {
+                keys.insert(action, action_keys.clone());
+            }
+        }
+        let bindings = InputBindings::from_keys(keys).unwrap_or_else(|key| {
+            warn!("{key:?} is bound to more than one action, so every binding was reset.");
+            InputBindings::default()
+        });
+        app.insert_resource(AudioSettings {
+            master: self.audio.master,
+            music: self.audio.music,
+            sfx: self.audio.sfx,
+            crossfade: self.audio.crossfade,
+        })
+        .insert_resource(GraphicsSettings {
+            resolution: self.graphics.resolution,
+            ..default()
+        })
+        .insert_resource(ServerAddress {
+            host: self.server.host,
+            port: self.server.port,
+        })
+        .insert_resource(PlayerName(self.player.name))
+        // load already replaced an invalid token.
+        .insert_resource(PlayerToken(
+            u64::from_str_radix(&self.player.token, 16).unwrap_or_default(),
+        ))
+        .insert_resource(bindings);
+    }
+}
+
+/// Reads one field from a config file's table, or returns the default if it's missing or the wrong type.
+fn read_field<T: DeserializeOwned>(table: &toml::Table, path: &[&str], default: T) -> T {
+    let name = path.join(".");
+    let Some((last, sections)) = path.split_last() else {
+        return default;
+    };
+    let mut table = table;
+    for section in sections {
+        match table.get(*section) {
+            Some(toml::Value::Table(section)) => table = section,
+            _ => {
+                info!("{name} isn't in the settings, so using the default.");
+                return default;
+            }
+        }
+    }
+    let Some(value) = table.get(*last) else {
+        info!("{name} isn't in the settings, so using the default.");
+        return default;
+    };
+    match value.clone().try_into() {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("{name} in the settings is invalid, so using the default: {e}");
+            default
+        }
+    }
+}
+
+// Systems
+fn save_settings(
+    config_path: Res<ConfigPath>,
+    audio_settings: Res<AudioSettings>,
+    graphics_settings: Res<GraphicsSettings>,
+    server_address: Res<ServerAddress>,
+    player_name: Res<PlayerName>,
+    player_token: Res<PlayerToken>,
+    bindings: Res<InputBindings>,
+) {
+    let Some(path) = &config_path.0 else {
+        return;
+    };
+    let settings = Settings::from_resources(
+        &audio_settings,
+        &graphics_settings,
+        &server_address,
+        &player_name,
+        &player_token,
+        &bindings,
+    );
+    if let Err(e) = settings.save(path) {
+        error!("Could not save settings to {}: {e:#}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path in the temp directory that no other test uses. The file is removed when this is dropped.
+    struct TempConfig(PathBuf);
+    impl TempConfig {
+        fn new(name: &str) -> Self {
+            Self(
+                std::env::temp_dir()
+                    .join(format!("miniscop-config-test-{}", std::process::id()))
+                    .join(format!("{name}.toml")),
+            )
+        }
+
+        fn write(name: &str, text: &str) -> Self {
+            let config = Self::new(name);
+            std::fs::create_dir_all(config.0.parent().unwrap()).unwrap();
+            std::fs::write(&config.0, text).unwrap();
+            config
+        }
+    }
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Settings::default makes a new random token every time, so it's left out when comparing with the defaults.
+    fn assert_defaults_except_token(settings: &Settings) {
+        let defaults = Settings::default();
+        assert_eq!(settings.audio, defaults.audio);
+        assert_eq!(settings.graphics, defaults.graphics);
+        assert_eq!(settings.server, defaults.server);
+        assert_eq!(settings.player.name, defaults.player.name);
+        assert_eq!(settings.bindings, defaults.bindings);
+        assert!(u64::from_str_radix(&settings.player.token, 16).is_ok());
+    }
+
+    #[test]
+    fn saved_settings_load_back() {
+        let config = TempConfig::new("saved");
+        let mut settings = Settings::default();
+        settings.audio.music = 0.25;
+        settings.audio.crossfade = 3.0;
+        settings.graphics.resolution = Resolution::FullHd;
+        settings.server.host = "example.com".to_string();
+        settings.server.port = 4000;
+        settings.player.name = "Quitter".to_string();
+        settings
+            .bindings
+            .insert("jump".to_string(), vec![KeyCode::KeyJ]);
+
+        settings.save(&config.0).unwrap();
+        assert_eq!(Settings::load(&config.0), settings);
+    }
+
+    #[test]
+    fn a_missing_file_loads_the_defaults() {
+        let config = TempConfig::new("missing");
+        assert!(!config.0.exists());
+        assert_defaults_except_token(&Settings::load(&config.0));
+    }
+
+    #[test]
+    fn a_file_that_isnt_toml_loads_the_defaults() {
+        let config = TempConfig::write("not-toml", "[audio\nmaster = ");
+        assert_defaults_except_token(&Settings::load(&config.0));
+    }
+
+    #[test]
+    fn invalid_fields_only_reset_themselves() {
+        let config = TempConfig::write(
+            "invalid-fields",
+            r#"
+            [audio]
+            master = 2.0
+            music = 0.5
+            crossfade = -1.0
+
+            [graphics]
+            resolution = "huge"
+
+            [server]
+            host = "example.com"
+            port = "not a port"
+
+            [player]
+            token = "not hexadecimal"
+            "#,
+        );
+        let defaults = Settings::default();
+        let settings = Settings::load(&config.0);
+        assert_eq!(settings.audio.master, defaults.audio.master);
+        assert_eq!(settings.audio.music, 0.5);
+        assert_eq!(settings.audio.crossfade, defaults.audio.crossfade);
+        assert_eq!(settings.graphics, defaults.graphics);
+        assert_eq!(settings.server.host, "example.com");
+        assert_eq!(settings.server.port, defaults.server.port);
+        assert!(u64::from_str_radix(&settings.player.token, 16).is_ok());
+        assert_eq!(settings.bindings, defaults.bindings);
+    }
+}
@@ -0,0 +1,71 @@
+use bevy::prelude::*;
+use std::time::Duration;
+
+/// Lets any screen wait for a key press without handling input itself.
+///
+/// Spawn an entity with a ContinuePrompt, and add an observer for Continue to it to decide what confirming does.
+pub struct ContinuePromptPlugin;
+impl Plugin for ContinuePromptPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, confirm_prompts);
+    }
+}
+
+// Components
+/// Triggers Continue on its entity once, when the player presses the prompt's key.
+#[derive(Component)]
+pub struct ContinuePrompt {
+    /// None means any key works.
+    key: Option<KeyCode>,
+    /// Key presses are ignored until this finishes.
+    delay: Timer,
+}
+impl ContinuePrompt {
+    pub fn key(key: KeyCode) -> Self {
+        Self {
+            key: Some(key),
+            delay: Timer::default(),
+        }
+    }
+
+    pub fn any_key() -> Self {
+        Self {
+            key: None,
+            delay: Timer::default(),
+        }
+    }
+
+    /// Ignores the prompt's key for a while, so a press meant for the previous screen can't confirm this one too.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Timer::new(delay, TimerMode::Once);
+        self
+    }
+}
+
+// Events
+/// Triggered on a ContinuePrompt's entity when it is confirmed.
+#[derive(Event)]
+pub struct Continue;
+
+// Systems
+fn confirm_prompts(
+    mut commands: Commands,
+    time: Res<Time>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut prompts: Query<(Entity, &mut ContinuePrompt)>,
+) {
+    for (entity, mut prompt) in prompts.iter_mut() {
+        if !prompt.delay.tick(time.delta()).finished() {
+            continue;
+        }
+        let confirmed = match prompt.key {
+            Some(key) => keyboard.just_pressed(key),
+            None => keyboard.get_just_pressed().next().is_some(),
+        };
+        if confirmed {
+            // Removing the prompt first means it can't be confirmed twice.
+            commands.entity(entity).remove::<ContinuePrompt>();
+            commands.trigger_targets(Continue, entity);
+        }
+    }
+}
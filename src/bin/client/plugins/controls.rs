@@ -0,0 +1,172 @@
+use crate::plugins::mainmenu::menu_song;
+use crate::plugins::settings::{InputAction, InputBindings};
+use crate::AppState;
+use bevy::prelude::*;
+use bevy::text::FontSmoothing;
+
+/// A screen for rebinding the overworld's controls, opened from the options screen.
+///
+/// Picking an action with Z waits for the next key press, which becomes that action's only key.
+pub struct ControlsPlugin;
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(AppState::Controls), setup_controls)
+            .add_systems(
+                Update,
+                (navigate_controls, update_control_lines)
+                    .chain()
+                    .run_if(in_state(AppState::Controls)),
+            );
+    }
+}
+
+// Constants
+const SELECT_KEY: KeyCode = KeyCode::KeyZ;
+const CANCEL_KEY: KeyCode = KeyCode::Escape;
+const UNSELECTED_COLOR: Color = Color::srgb(0.5, 0.5, 0.5);
+
+// Components
+/// One line of the controls screen. There is one for each action, followed by Back.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlsItem {
+    Action(InputAction),
+    Back,
+}
+impl ControlsItem {
+    fn all() -> impl Iterator<Item = ControlsItem> {
+        InputAction::ALL
+            .into_iter()
+            .map(ControlsItem::Action)
+            .chain([ControlsItem::Back])
+    }
+}
+/// The column of controls, which remembers which one is selected and whether it's waiting for a key.
+#[derive(Component, Default)]
+struct ControlsMenu {
+    selected: usize,
+    /// The action that the next key press will be bound to.
+    rebinding: Option<InputAction>,
+    /// Shown under the controls, like when a key is already taken.
+    message: String,
+}
+/// The line under the controls that shows ControlsMenu's message.
+#[derive(Component)]
+struct ControlsMessage;
+
+// Systems
+fn setup_controls(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn((StateScoped(AppState::Controls), Camera2d));
+    commands.spawn((StateScoped(AppState::Controls), menu_song(&asset_server)));
+    let font = TextFont {
+        font: asset_server.load("global/fonts/PetscopWide.ttf"),
+        font_size: 40.0,
+        font_smoothing: FontSmoothing::None,
+        ..default()
+    };
+    commands
+        .spawn((
+            StateScoped(AppState::Controls),
+            ControlsMenu::default(),
+            Node {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                row_gap: Val::Px(15.0),
+                ..default()
+            },
+        ))
+        .with_children(|menu| {
+            for item in ControlsItem::all() {
+                // update_control_lines fills in the text on the first frame.
+                menu.spawn((item, Text::default(), TextColor::WHITE, font.clone()));
+            }
+            menu.spawn((
+                ControlsMessage,
+                Text::default(),
+                TextColor::WHITE,
+                font.clone(),
+            ));
+        });
+}
+
+/// Up and down move the selection, and Z picks the selected option.
+/// While waiting for a key, the next key pressed is bound instead, and Escape cancels.
+fn navigate_controls(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut menu: Single<&mut ControlsMenu>,
+    mut bindings: ResMut<InputBindings>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    if let Some(action) = menu.rebinding {
+        let Some(key) = keyboard.get_just_pressed().next().copied() else {
+            return;
+        };
+        menu.rebinding = None;
+        if key == CANCEL_KEY {
+            menu.message.clear();
+            return;
+        }
+        menu.message = match bindings.bind(action, key) {
+            Ok(()) => {
+                info!("{} bound to {key:?}", action.name());
+                String::new()
+            }
+            Err(other) => format!("{key:?} is already bound to {}", other.name()),
+        };
+        return;
+    }
+
+    let count = ControlsItem::all().count();
+    if keyboard.just_pressed(KeyCode::ArrowDown) {
+        menu.selected = (menu.selected + 1) % count;
+    }
+    if keyboard.just_pressed(KeyCode::ArrowUp) {
+        menu.selected = (menu.selected + count - 1) % count;
+    }
+    if keyboard.just_pressed(SELECT_KEY) {
+        match ControlsItem::all().nth(menu.selected) {
+            Some(ControlsItem::Action(action)) => {
+                menu.rebinding = Some(action);
+                menu.message = format!("Press a key for {}, or Escape to cancel", action.name());
+            }
+            Some(ControlsItem::Back) => next_state.set(AppState::Options),
+            None => {}
+        }
+    }
+}
+
+/// Shows each action's keys and the menu's message, and highlights the selected line.
+fn update_control_lines(
+    menu: Single<&ControlsMenu>,
+    bindings: Res<InputBindings>,
+    mut lines: Query<(&ControlsItem, &mut Text, &mut TextColor)>,
+    mut message: Single<&mut Text, (With<ControlsMessage>, Without<ControlsItem>)>,
+) {
+    let selected = ControlsItem::all().nth(menu.selected);
+    for (item, mut text, mut color) in lines.iter_mut() {
+        let label = match item {
+            ControlsItem::Action(action) if menu.rebinding == Some(*action) => {
+                format!("{}: ...", action.name())
+            }
+            ControlsItem::Action(action) => {
+                let keys: Vec<String> = bindings
+                    .keys(*action)
+                    .iter()
+                    .map(|key| format!("{key:?}"))
+                    .collect();
+                format!("{}: {}", action.name(), keys.join(", "))
+            }
+            ControlsItem::Back => "Back".to_string(),
+        };
+        // Only touching the text when it changes avoids laying it out again every frame.
+        text.set_if_neq(Text(label));
+        color.set_if_neq(if Some(*item) == selected {
+            TextColor::WHITE
+        } else {
+            TextColor(UNSELECTED_COLOR)
+        });
+    }
+    message.set_if_neq(Text(menu.message.clone()));
+}
@@ -1,4 +1,7 @@
+use crate::plugins::continue_prompt::{Continue, ContinuePrompt};
+use crate::plugins::music::Song;
 use crate::AppState;
+use bevy::audio::PlaybackMode;
 use bevy::prelude::*;
 use bevy::window::WindowResized;
 
@@ -40,7 +43,11 @@ fn setup_garalina(
     commands.spawn((StateScoped(AppState::Garalina), Camera2d));
     commands.spawn((
         StateScoped(AppState::Garalina),
-        AudioPlayer::new(asset_server.load("garalina/garalina.ogg")),
+        Song {
+            source: asset_server.load("garalina/garalina.ogg"),
+            volume: 1.0,
+            mode: PlaybackMode::Once,
+        },
     ));
     commands.spawn((
         StateScoped(AppState::Garalina),
@@ -53,7 +60,7 @@ fn setup_garalina(
         Sprite {
             image: asset_server.load("garalina/logo_1.png"),
             custom_size: Some(Vec2::new(DEFAULT_WIDTH, DEFAULT_HEIGHT)),
-            image_mode: SpriteImageMode::Scale(ScalingMode::FitCenter).into(),
+            image_mode: SpriteImageMode::Scale(ScalingMode::FitCenter),
             ..default()
         },
         Transform::from_xyz(0.0, 0.0, 1.0),
@@ -65,6 +72,10 @@ fn setup_garalina(
         MeshMaterial2d(materials.add(Color::srgba(1.0, 1.0, 1.0, 0.0))),
         Transform::from_xyz(0.0, 0.0, 2.0),
     ));
+    // Any key skips the logo
+    commands
+        .spawn((StateScoped(AppState::Garalina), ContinuePrompt::any_key()))
+        .observe(skip_garalina);
     commands.insert_resource(MusicTimer(Timer::from_seconds(
         LOGO_DURATION,
         TimerMode::Once,
@@ -98,6 +109,9 @@ fn update_garalina(
         }
     }
 }
+fn skip_garalina(_: Trigger<Continue>, mut next_state: ResMut<NextState<AppState>>) {
+    next_state.set(AppState::MainMenu);
+}
 fn check_for_window_resize(
     mut resize_reader: EventReader<WindowResized>,
     mut logo_sprite: Single<&mut Sprite>,
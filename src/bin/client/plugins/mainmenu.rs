@@ -1,5 +1,12 @@
+use crate::plugins::continue_prompt::{Continue, ContinuePrompt};
+use crate::plugins::music::Song;
+use crate::plugins::overworld::{NetworkMode, ServerAddress};
+use crate::plugins::settings::AudioSettings;
 use crate::AppState;
 use bevy::asset::RenderAssetUsages;
+use bevy::audio::PlaybackMode;
+use bevy::input::keyboard::KeyboardInput;
+use bevy::input::ButtonState;
 use bevy::math::ops::{cos, sin};
 use bevy::prelude::*;
 use bevy::render::camera::RenderTarget;
@@ -7,6 +14,7 @@ use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat, T
 use bevy::text::FontSmoothing;
 use bevy::ui::PositionType;
 use std::f32::consts::PI;
+use std::time::Duration;
 
 pub struct MainMenuPlugin;
 impl Plugin for MainMenuPlugin {
@@ -14,21 +22,71 @@ impl Plugin for MainMenuPlugin {
         app.add_systems(OnEnter(AppState::MainMenu), setup_main_menu)
             .add_systems(
                 Update,
-                update_title_screen.run_if(in_state(AppState::MainMenu)),
-            );
+                (
+                    update_title_screen,
+                    swing_gift,
+                    edit_server_address,
+                    (toggle_network_mode, open_options)
+                        .run_if(not(resource_exists::<EditingServerAddress>)),
+                    update_server_field,
+                )
+                    .chain()
+                    .run_if(in_state(AppState::MainMenu)),
+            )
+            .add_systems(OnExit(AppState::MainMenu), stop_editing_server_address);
     }
 }
 
 // Constants
 const GIFT_ASPECT_RATIO: f32 = 88.0 / 83.0;
 const LOGO_ASPECT_RATIO: f32 = 528.0 / 145.0;
+const BEGIN_KEY: KeyCode = KeyCode::KeyZ;
+/// Z can't begin until the menu has been open this long, so mashing keys to skip the Garalina logo doesn't skip the menu too.
+const BEGIN_DELAY: Duration = Duration::from_millis(500);
+/// There's no dedicated menu sound yet, so beginning borrows a footstep.
+const BEGIN_SOUND: &str = "overworld/sounds/walking_1.ogg";
+const MENU_SONG: &str = "mainmenu/petscop.ogg";
+const OPTIONS_KEY: KeyCode = KeyCode::KeyX;
+const EDIT_SERVER_KEY: KeyCode = KeyCode::Enter;
+const CANCEL_EDIT_KEY: KeyCode = KeyCode::Escape;
+const NETWORK_MODE_KEY: KeyCode = KeyCode::KeyC;
+/// How far the gift swings each way, in radians. This is 10 degrees.
+const GIFT_SWING_AMPLITUDE: f32 = PI / 18.0;
+/// How many times per second the gift swings back and forth.
+const GIFT_SWING_FREQUENCY: f32 = 1.0;
+/// Hostnames can't be longer than this.
+const MAX_HOST_LENGTH: usize = 253;
 
 #[derive(Component)]
 struct Title;
+/// The gift above the title, which swings side to side.
 #[derive(Component)]
-struct Gift;
+struct SwingingGift;
 #[derive(Component)]
 struct FlashingText;
+/// The prompt that enters the overworld. It stops listening for Z while the server address is being typed.
+#[derive(Component)]
+struct BeginPrompt;
+/// The line showing which server the game will connect to, or that it won't connect at all.
+#[derive(Component)]
+struct ServerField;
+/// The line saying how to switch between playing online and offline.
+#[derive(Component)]
+struct NetworkModeField;
+
+// Resources
+/// The server address being typed. This only exists while the server field is being edited.
+#[derive(Resource)]
+struct EditingServerAddress(String);
+
+/// The main menu's song. The options and controls screens play it too, so it carries on between them.
+pub fn menu_song(asset_server: &AssetServer) -> Song {
+    Song {
+        source: asset_server.load(MENU_SONG),
+        volume: 1.0,
+        mode: PlaybackMode::Loop,
+    }
+}
 
 // Systems
 fn setup_main_menu(
@@ -37,10 +95,7 @@ fn setup_main_menu(
     mut images: ResMut<Assets<Image>>,
 ) {
     // Music
-    // commands.spawn((
-    //     StateScoped(AppState::MainMenu),
-    //     AudioPlayer::new(asset_server.load("mainmenu/petscop.ogg")),
-    // ));
+    commands.spawn((StateScoped(AppState::MainMenu), menu_song(&asset_server)));
     // Main Camera
     commands.spawn((
         StateScoped(AppState::MainMenu),
@@ -100,6 +155,14 @@ fn setup_main_menu(
         // https://github.com/bevyengine/bevy/issues/5183
         // RenderLayers::layer(1),
     ));
+    // Press Z to Begin
+    commands
+        .spawn((
+            StateScoped(AppState::MainMenu),
+            BeginPrompt,
+            ContinuePrompt::key(BEGIN_KEY).with_delay(BEGIN_DELAY),
+        ))
+        .observe(begin);
     // Font
     let petscop_font = asset_server.load::<Font>("global/fonts/PetscopWide.ttf");
     // UI
@@ -140,7 +203,7 @@ fn setup_main_menu(
                             ..default()
                         },
                         Transform::default(),
-                        Gift,
+                        SwingingGift,
                         children![ImageNode::new(camera_as_image_handle)]
                     ),
                     // Press Z to Begin
@@ -155,6 +218,41 @@ fn setup_main_menu(
                         },
                         FlashingText,
                     ),
+                    // Server
+                    (
+                        Text::default(),
+                        TextColor::WHITE,
+                        TextFont {
+                            font: petscop_font.clone(),
+                            font_size: 30.0,
+                            font_smoothing: FontSmoothing::None,
+                            ..default()
+                        },
+                        ServerField,
+                    ),
+                    // Online or Offline
+                    (
+                        Text::default(),
+                        TextColor::WHITE,
+                        TextFont {
+                            font: petscop_font.clone(),
+                            font_size: 30.0,
+                            font_smoothing: FontSmoothing::None,
+                            ..default()
+                        },
+                        NetworkModeField,
+                    ),
+                    // Options
+                    (
+                        Text::new("Press X for Options"),
+                        TextColor::WHITE,
+                        TextFont {
+                            font: petscop_font.clone(),
+                            font_size: 30.0,
+                            font_smoothing: FontSmoothing::None,
+                            ..default()
+                        },
+                    ),
                     // Copyright
                     (
                         Text::new("© 1997 Garalina"),
@@ -178,9 +276,128 @@ fn setup_main_menu(
     ));
 }
 
+fn begin(
+    _: Trigger<Continue>,
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    audio_settings: Res<AudioSettings>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    // Not scoped to the main menu, so it keeps playing after the menu is despawned.
+    commands.spawn((
+        AudioPlayer::new(asset_server.load(BEGIN_SOUND)),
+        PlaybackSettings::DESPAWN.with_volume(audio_settings.sfx_volume()),
+    ));
+    next_state.set(AppState::Overworld);
+}
+
+fn open_options(keyboard: Res<ButtonInput<KeyCode>>, mut next_state: ResMut<NextState<AppState>>) {
+    if keyboard.just_pressed(OPTIONS_KEY) {
+        next_state.set(AppState::Options);
+    }
+}
+
+/// Pressing C switches between playing online and playing alone, without connecting to a server at all.
+fn toggle_network_mode(keyboard: Res<ButtonInput<KeyCode>>, mut network_mode: ResMut<NetworkMode>) {
+    if keyboard.just_pressed(NETWORK_MODE_KEY) {
+        *network_mode = match *network_mode {
+            NetworkMode::Online => NetworkMode::Offline,
+            NetworkMode::Offline => NetworkMode::Online,
+        };
+        info!("Network mode set to {:?}", *network_mode);
+    }
+}
+
+/// Pressing Enter starts typing a new server address, and pressing it again saves it. Escape keeps the old one.
+fn edit_server_address(
+    mut commands: Commands,
+    mut keyboard_input: EventReader<KeyboardInput>,
+    mut server_address: ResMut<ServerAddress>,
+    mut editing: Option<ResMut<EditingServerAddress>>,
+    begin_prompt: Single<Entity, With<BeginPrompt>>,
+) {
+    for input in keyboard_input.read() {
+        if input.state != ButtonState::Pressed {
+            continue;
+        }
+        let Some(editing) = editing.as_mut() else {
+            if input.key_code == EDIT_SERVER_KEY {
+                commands.insert_resource(EditingServerAddress(server_address.host.clone()));
+                commands.entity(*begin_prompt).remove::<ContinuePrompt>();
+                // The resource is only inserted once commands are applied, so ignore the rest of this frame's typing.
+                return;
+            }
+            continue;
+        };
+
+        match input.key_code {
+            EDIT_SERVER_KEY => {
+                server_address.host = editing.0.trim().to_string();
+                info!("Server set to {}", server_address.host);
+                stop_editing(&mut commands, *begin_prompt);
+                return;
+            }
+            CANCEL_EDIT_KEY => {
+                stop_editing(&mut commands, *begin_prompt);
+                return;
+            }
+            KeyCode::Backspace => {
+                editing.0.pop();
+            }
+            _ => {
+                if let Some(text) = &input.text {
+                    for character in text.chars().filter(|character| !character.is_control()) {
+                        if editing.0.len() < MAX_HOST_LENGTH {
+                            editing.0.push(character);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn stop_editing(commands: &mut Commands, begin_prompt: Entity) {
+    commands.remove_resource::<EditingServerAddress>();
+    commands
+        .entity(begin_prompt)
+        .insert(ContinuePrompt::key(BEGIN_KEY));
+}
+
+/// Editing can't continue once the main menu is gone.
+fn stop_editing_server_address(mut commands: Commands) {
+    commands.remove_resource::<EditingServerAddress>();
+}
+
+fn update_server_field(
+    server_address: Res<ServerAddress>,
+    network_mode: Res<NetworkMode>,
+    editing: Option<Res<EditingServerAddress>>,
+    mut server_field: Single<&mut Text, With<ServerField>>,
+    mut network_mode_field: Single<&mut Text, (With<NetworkModeField>, Without<ServerField>)>,
+) {
+    network_mode_field.set_if_neq(Text(
+        match *network_mode {
+            NetworkMode::Online => "Press C to Play Offline",
+            NetworkMode::Offline => "Press C to Play Online",
+        }
+        .to_string(),
+    ));
+
+    let text = match editing {
+        Some(editing) => format!("Server: {}_", editing.0),
+        None if *network_mode == NetworkMode::Offline => {
+            "Server: None (Playing Offline)".to_string()
+        }
+        None if server_address.host.is_empty() => "Server: Default (Enter to change)".to_string(),
+        None => format!("Server: {} (Enter to change)", server_address.host),
+    };
+    // Only touching the text when it changes avoids laying it out again every frame.
+    server_field.set_if_neq(Text(text));
+}
+
 fn update_title_screen(
     mut title_transform: Single<&mut Transform, With<Title>>,
-    mut gift_transform: Single<&mut Transform, (With<Gift>, Without<Title>)>,
     mut flashing_text: Single<&mut Visibility, With<FlashingText>>,
     time: Res<Time>,
 ) {
@@ -193,10 +410,6 @@ fn update_title_screen(
     let theta_z = sin(2.0 * PI * seconds) * sin(PI / 6.0 * seconds) * PI / 18.0;
     title_transform.rotation = Quat::from_euler(EulerRot::XYZEx, 0.0, theta_y, theta_z);
 
-    // 10 degrees max in each direction
-    let theta_z = cos(2.0 * PI * seconds) * PI / 18.0;
-    gift_transform.rotation = Quat::from_rotation_z(theta_z);
-
     // Flash text every second
     if (seconds as i32) % 2 == 0 {
         **flashing_text = Visibility::Visible;
@@ -204,3 +417,36 @@ fn update_title_screen(
         **flashing_text = Visibility::Hidden;
     }
 }
+
+fn swing_gift(mut gift_transform: Single<&mut Transform, With<SwingingGift>>, time: Res<Time>) {
+    // The wrapped time keeps its precision on a long-open menu.
+    // It wraps after a whole number of swings, so the gift doesn't jump when it does.
+    let seconds = time.elapsed_secs_wrapped();
+    let theta_z = cos(2.0 * PI * GIFT_SWING_FREQUENCY * seconds) * GIFT_SWING_AMPLITUDE;
+    gift_transform.rotation = Quat::from_rotation_z(theta_z);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_switches_between_online_and_offline() {
+        let mut app = App::new();
+        app.init_resource::<ButtonInput<KeyCode>>()
+            .init_resource::<NetworkMode>()
+            .add_systems(Update, toggle_network_mode);
+        let mut press = |key: KeyCode| {
+            let mut keyboard = app.world_mut().resource_mut::<ButtonInput<KeyCode>>();
+            keyboard.clear();
+            keyboard.release(key);
+            keyboard.press(key);
+            app.update();
+            *app.world().resource::<NetworkMode>()
+        };
+        assert_eq!(press(NETWORK_MODE_KEY), NetworkMode::Offline);
+        assert_eq!(press(NETWORK_MODE_KEY), NetworkMode::Online);
+        // Tab used to switch the mode, and doesn't anymore.
+        assert_eq!(press(KeyCode::Tab), NetworkMode::Online);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::plugins::settings::AudioSettings;
+use bevy::audio::{PlaybackMode, Volume};
+use bevy::prelude::*;
+
+/// Plays the song each state asks for, and crossfades between songs when the state changes.
+///
+/// States spawn a Song scoped to themselves instead of playing music directly. The song fades in when the Song
+/// is spawned and fades out when it's despawned, over AudioSettings::crossfade.
+pub struct MusicPlugin;
+impl Plugin for MusicPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MusicManager>()
+            .add_systems(Update, (stop_songs, start_songs, fade_tracks).chain());
+    }
+}
+
+// Components
+/// Asks for a song to play for as long as this entity exists.
+#[derive(Component)]
+pub struct Song {
+    pub source: Handle<AudioSource>,
+    /// How loud the song is when every setting is at full, since some songs are quieter than others.
+    pub volume: f32,
+    pub mode: PlaybackMode,
+}
+/// A song being played for a Song, which may be fading in or out.
+#[derive(Component)]
+struct Track {
+    volume: f32,
+    /// How far faded in the track is, from 0.0 to 1.0. Its volume is multiplied by this.
+    gain: f32,
+    /// Whether it's fading toward full or toward silence. Tracks that fade to silence despawn.
+    fading_in: bool,
+}
+
+// Resources
+/// Keeps track of which track is playing for which Song.
+///
+/// Tracks are children of a root entity that no state owns, so each one can finish fading out
+/// after the state that asked for it has despawned its Song.
+#[derive(Resource)]
+struct MusicManager {
+    root: Entity,
+    /// The Song the current track is for, and the track.
+    current: Option<(Entity, Entity)>,
+}
+impl FromWorld for MusicManager {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            root: world.spawn(Name::new("Music")).id(),
+            current: None,
+        }
+    }
+}
+
+// Systems
+/// Fades out the current track once the Song it's for is despawned.
+fn stop_songs(
+    mut manager: ResMut<MusicManager>,
+    mut removed_songs: RemovedComponents<Song>,
+    mut tracks: Query<&mut Track>,
+) {
+    for song in removed_songs.read() {
+        if let Some((current_song, track)) = manager.current
+            && current_song == song
+        {
+            if let Ok(mut track) = tracks.get_mut(track) {
+                track.fading_in = false;
+            }
+            manager.current = None;
+        }
+    }
+}
+
+/// Fades out every track when a new Song is spawned, and fades in a track for it.
+///
+/// If the song is already playing, or still fading out, it carries on from where it is instead of starting over.
+fn start_songs(
+    mut commands: Commands,
+    mut manager: ResMut<MusicManager>,
+    songs: Query<(Entity, &Song), Added<Song>>,
+    mut tracks: Query<(Entity, &AudioPlayer, &mut Track)>,
+) {
+    for (song_entity, song) in songs.iter() {
+        let mut existing = None;
+        for (entity, player, mut track) in tracks.iter_mut() {
+            track.fading_in = player.0 == song.source;
+            if track.fading_in {
+                track.volume = song.volume;
+                existing = Some(entity);
+            }
+        }
+        let track = existing.unwrap_or_else(|| {
+            commands
+                .spawn((
+                    ChildOf(manager.root),
+                    Track {
+                        volume: song.volume,
+                        gain: 0.0,
+                        fading_in: true,
+                    },
+                    AudioPlayer::new(song.source.clone()),
+                    PlaybackSettings {
+                        mode: song.mode,
+                        volume: Volume::SILENT,
+                        ..default()
+                    },
+                ))
+                .id()
+        });
+        manager.current = Some((song_entity, track));
+    }
+}
+
+/// Ramps each track's volume toward full or silence, and keeps it matching AudioSettings.
+///
+/// Tracks only start fading once their song has loaded and started, so a slow load doesn't skip the fade in.
+/// This uses real time, so pausing doesn't hold up a fade.
+fn fade_tracks(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    audio_settings: Res<AudioSettings>,
+    mut tracks: Query<(Entity, &mut Track, &mut AudioSink)>,
+) {
+    let step = if audio_settings.crossfade > 0.0 {
+        time.delta_secs() / audio_settings.crossfade
+    } else {
+        1.0
+    };
+    for (entity, mut track, mut sink) in tracks.iter_mut() {
+        let target = if track.fading_in { 1.0 } else { 0.0 };
+        if track.gain == target && !track.is_changed() && !audio_settings.is_changed() {
+            continue;
+        }
+        track.gain = if track.fading_in {
+            (track.gain + step).min(1.0)
+        } else {
+            (track.gain - step).max(0.0)
+        };
+        sink.set_volume(audio_settings.music_volume(track.volume * track.gain));
+        if !track.fading_in && track.gain == 0.0 {
+            commands.entity(entity).despawn();
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use crate::plugins::mainmenu::menu_song;
+use crate::plugins::settings::{AudioSettings, GraphicsSettings};
+use crate::AppState;
+use bevy::prelude::*;
+use bevy::text::FontSmoothing;
+
+/// A screen for changing the volume and switching to fullscreen, opened from the main menu.
+///
+/// It also leads to the controls screen.
+pub struct OptionsPlugin;
+impl Plugin for OptionsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(AppState::Options), setup_options)
+            .add_systems(
+                Update,
+                (navigate_options, update_option_lines)
+                    .chain()
+                    .run_if(in_state(AppState::Options)),
+            );
+    }
+}
+
+// Constants
+const SELECT_KEY: KeyCode = KeyCode::KeyZ;
+/// How much one press of left or right changes a volume.
+const VOLUME_STEP: f32 = 0.1;
+const UNSELECTED_COLOR: Color = Color::srgb(0.5, 0.5, 0.5);
+
+// Components
+/// One line of the options screen. The lines are listed in the order they appear.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+enum OptionsItem {
+    MasterVolume,
+    MusicVolume,
+    SfxVolume,
+    Fullscreen,
+    Controls,
+    Back,
+}
+impl OptionsItem {
+    const ALL: [OptionsItem; 6] = [
+        OptionsItem::MasterVolume,
+        OptionsItem::MusicVolume,
+        OptionsItem::SfxVolume,
+        OptionsItem::Fullscreen,
+        OptionsItem::Controls,
+        OptionsItem::Back,
+    ];
+
+    fn label(self, audio: &AudioSettings, graphics: &GraphicsSettings) -> String {
+        let slider = |value: f32| format!("< {:>3}% >", (value * 100.0).round() as u32);
+        match self {
+            OptionsItem::MasterVolume => format!("Master Volume {}", slider(audio.master)),
+            OptionsItem::MusicVolume => format!("Music Volume {}", slider(audio.music)),
+            OptionsItem::SfxVolume => format!("SFX Volume {}", slider(audio.sfx)),
+            OptionsItem::Fullscreen => {
+                format!(
+                    "Fullscreen: {}",
+                    if graphics.fullscreen { "On" } else { "Off" }
+                )
+            }
+            OptionsItem::Controls => "Controls".to_string(),
+            OptionsItem::Back => "Back".to_string(),
+        }
+    }
+}
+/// The column of options, which remembers which one is selected.
+#[derive(Component, Default)]
+struct OptionsMenu {
+    selected: usize,
+}
+
+// Systems
+fn setup_options(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn((StateScoped(AppState::Options), Camera2d));
+    commands.spawn((StateScoped(AppState::Options), menu_song(&asset_server)));
+    let font = TextFont {
+        font: asset_server.load("global/fonts/PetscopWide.ttf"),
+        font_size: 50.0,
+        font_smoothing: FontSmoothing::None,
+        ..default()
+    };
+    commands
+        .spawn((
+            StateScoped(AppState::Options),
+            OptionsMenu::default(),
+            Node {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                row_gap: Val::Px(20.0),
+                ..default()
+            },
+        ))
+        .with_children(|menu| {
+            for item in OptionsItem::ALL {
+                // update_option_lines fills in the text on the first frame.
+                menu.spawn((item, Text::default(), TextColor::WHITE, font.clone()));
+            }
+        });
+}
+
+/// Up and down move the selection, left and right change the selected volume, and Z picks the selected option.
+fn navigate_options(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut menu: Single<&mut OptionsMenu>,
+    mut audio_settings: ResMut<AudioSettings>,
+    mut graphics_settings: ResMut<GraphicsSettings>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    let count = OptionsItem::ALL.len();
+    if keyboard.just_pressed(KeyCode::ArrowDown) {
+        menu.selected = (menu.selected + 1) % count;
+    }
+    if keyboard.just_pressed(KeyCode::ArrowUp) {
+        menu.selected = (menu.selected + count - 1) % count;
+    }
+
+    let mut step = 0.0;
+    if keyboard.just_pressed(KeyCode::ArrowRight) {
+        step += VOLUME_STEP;
+    }
+    if keyboard.just_pressed(KeyCode::ArrowLeft) {
+        step -= VOLUME_STEP;
+    }
+
+    let item = OptionsItem::ALL[menu.selected];
+    // Borrowing a volume mutably marks AudioSettings as changed, so only do it when it actually changes.
+    if step != 0.0 {
+        let volume = match item {
+            OptionsItem::MasterVolume => Some(&mut audio_settings.master),
+            OptionsItem::MusicVolume => Some(&mut audio_settings.music),
+            OptionsItem::SfxVolume => Some(&mut audio_settings.sfx),
+            OptionsItem::Fullscreen | OptionsItem::Controls | OptionsItem::Back => None,
+        };
+        if let Some(volume) = volume {
+            // Rounding keeps repeated steps from drifting away from whole percentages.
+            *volume = (((*volume + step) / VOLUME_STEP).round() * VOLUME_STEP).clamp(0.0, 1.0);
+        }
+    }
+
+    if keyboard.just_pressed(SELECT_KEY) {
+        match item {
+            OptionsItem::Fullscreen => {
+                graphics_settings.fullscreen = !graphics_settings.fullscreen;
+                info!("Fullscreen set to {}", graphics_settings.fullscreen);
+            }
+            OptionsItem::Controls => next_state.set(AppState::Controls),
+            OptionsItem::Back => next_state.set(AppState::MainMenu),
+            OptionsItem::MasterVolume | OptionsItem::MusicVolume | OptionsItem::SfxVolume => {}
+        }
+    }
+}
+
+/// Shows each option's current value, and highlights the selected one.
+fn update_option_lines(
+    menu: Single<&OptionsMenu>,
+    audio_settings: Res<AudioSettings>,
+    graphics_settings: Res<GraphicsSettings>,
+    mut lines: Query<(&OptionsItem, &mut Text, &mut TextColor)>,
+) {
+    let selected = OptionsItem::ALL[menu.selected];
+    for (item, mut text, mut color) in lines.iter_mut() {
+        // Only touching the text when it changes avoids laying it out again every frame.
+        text.set_if_neq(Text(item.label(&audio_settings, &graphics_settings)));
+        color.set_if_neq(if *item == selected {
+            TextColor::WHITE
+        } else {
+            TextColor(UNSELECTED_COLOR)
+        });
+    }
+}
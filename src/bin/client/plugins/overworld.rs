@@ -1,27 +1,53 @@
 mod animation;
+mod chat;
+mod emotes;
+#[cfg(feature = "debug")]
+mod free_look;
+mod gift;
+mod lobby;
+mod lockstep;
+mod minimap;
 mod multiplayer;
+mod name_tags;
+mod pause;
 mod physics;
+mod profiling;
 
+use crate::plugins::music::Song;
+use crate::plugins::settings::{CameraMode, GraphicsSettings};
 use crate::AppState;
 use avian3d::prelude::{
-    Collider, ColliderConstructor, ColliderConstructorHierarchy, Dominance, LockedAxes,
-    PhysicsDebugPlugin, RigidBody,
+    Collider, ColliderConstructor, ColliderConstructorHierarchy, CollidingEntities, Dominance,
+    LockedAxes, PhysicsDebugPlugin, PhysicsGizmos, RigidBody, RigidBodyDisabled, Sensor,
 };
 use avian3d::PhysicsPlugins;
-use bevy::audio::{PlaybackMode, Volume};
+use bevy::asset::{LoadState, UntypedAssetId};
+use bevy::audio::PlaybackMode;
+use bevy::input::mouse::{AccumulatedMouseMotion, AccumulatedMouseScroll, MouseScrollUnit};
+use bevy::math::Vec3Swizzles;
 use bevy::prelude::{
-    default, in_state, App, AppExtStates, AssetServer, Assets, AudioPlayer, AudioSource,
-    Camera, Camera3d, ClearColorConfig, Color, Commands, Component, Condition,
-    FixedLast, FixedUpdate, GltfAssetLabel, Handle, Image, IntoScheduleConfigs, NextState,
-    OnEnter, PlaybackSettings, Plugin, Res, ResMut, Resource, Scene, SceneRoot, Single, StateScoped,
-    StateSet, SubStates, TextureAtlas, TextureAtlasLayout, Timer, TimerMode, Transform, UVec2, Update,
-    Vec3, With, Without,
+    default, in_state, not, resource_changed, resource_equals, resource_exists, AlignItems, App,
+    AppExtStates, AssetServer, Assets, AudioSource, ButtonInput, Camera, Camera2d, Camera3d,
+    ClearColorConfig, Color, Commands, Component, Condition, DetectChangesMut, Entity, EulerRot,
+    Event, EventReader, EventWriter, FixedLast, FixedUpdate, GizmoConfigStore, GltfAssetLabel,
+    Handle, Image, IntoScheduleConfigs, JustifyContent, KeyCode, Local, MouseButton, NextState,
+    Node, OnEnter, OnExit, Plugin, Quat, Query, Real, Res, ResMut, Resource, RunFixedMainLoop,
+    RunFixedMainLoopSystem, Scene, SceneRoot, Single, SpatialListener, StateScoped, StateSet,
+    SubStates, SystemSet, Text, TextColor, TextFont, TextureAtlas, TextureAtlasLayout, Time, Timer,
+    TimerMode, Transform, UVec2, Update, Val, Vec2, Vec3, Window, With, Without,
 };
+use bevy::text::FontSmoothing;
+use bevy::window::{CursorGrabMode, PrimaryWindow};
 use bevy_sprite3d::{Sprite3dBuilder, Sprite3dParams};
 use bevy_tnua::prelude::{TnuaController, TnuaControllerPlugin};
 use bevy_tnua::TnuaUserControlsSystemSet;
 use bevy_tnua_avian3d::{TnuaAvian3dPlugin, TnuaAvian3dSensorShape};
 use multiplayer::MultiplayerState;
+pub use multiplayer::{InsecureMode, NetworkMode, PlayerName, PlayerToken, ServerAddress};
+use pause::PauseState;
+use std::f32::consts::{PI, TAU};
+use std::time::Duration;
+use tracing::{error, info};
 
 pub struct OverworldPlugin;
 impl Plugin for OverworldPlugin {
@@ -31,18 +57,101 @@ impl Plugin for OverworldPlugin {
             PhysicsDebugPlugin::default(),
             TnuaControllerPlugin::new(FixedUpdate),
             TnuaAvian3dPlugin::new(FixedUpdate),
+            profiling::ProfilingPlugin,
+            pause::PausePlugin,
+            minimap::MinimapPlugin,
+            name_tags::NameTagPlugin,
+            emotes::EmotePlugin,
+            lobby::LobbyPlugin,
         ))
         .add_sub_state::<OverworldState>()
         .init_state::<MultiplayerState>()
         .add_event::<multiplayer::OtherPlayerMoved>()
         .add_event::<multiplayer::OtherPlayerDisconnected>()
+        .add_event::<multiplayer::ServerShutDown>()
+        .add_event::<multiplayer::IncompatibleServer>()
+        .add_event::<gift::GiftOpened>()
+        .add_event::<gift::GiftStateChanged>()
+        .add_event::<multiplayer::LevelReloaded>()
+        .add_event::<chat::ChatReceived>()
+        .add_event::<LevelTransition>()
+        .init_resource::<multiplayer::DisconnectGracePeriod>()
+        .init_resource::<multiplayer::SessionToken>()
+        .init_resource::<multiplayer::ServerAddress>()
+        .init_resource::<multiplayer::PlayerName>()
+        .init_resource::<multiplayer::PlayerToken>()
+        .init_resource::<multiplayer::InsecureMode>()
+        .init_resource::<multiplayer::NetworkMode>()
+        .init_resource::<multiplayer::PlayerNames>()
+        .init_resource::<multiplayer::ReconnectPolicy>()
+        .init_resource::<multiplayer::InterpolationDelay>()
+        .init_resource::<multiplayer::HeartbeatTimer>()
+        .init_resource::<multiplayer::PendingPings>()
+        .init_resource::<multiplayer::NetworkStats>()
+        .init_resource::<multiplayer::MovementOrigin>()
+        .init_resource::<multiplayer::ReconnectAttempts>()
+        .init_resource::<physics::MovementTuning>()
+        .init_resource::<physics::JumpBuffer>()
+        .init_resource::<SpawnPoint>()
+        .init_resource::<CurrentLevel>()
+        .init_resource::<CameraFraming>()
+        .init_resource::<CameraFollow>()
+        .init_resource::<CameraOrbit>()
+        .init_resource::<multiplayer::SendThrottle>()
+        .init_resource::<lockstep::Lockstep>()
+        .add_systems(OnEnter(AppState::MainMenu), preload_overworld_assets)
         .add_systems(
             OnEnter(AppState::Overworld),
-            (setup_overworld, multiplayer::setup_client_runtime),
+            (
+                setup_overworld,
+                multiplayer::setup_client_runtime
+                    .run_if(resource_equals(multiplayer::NetworkMode::Online)),
+            ),
+        )
+        .add_systems(OnExit(AppState::Overworld), multiplayer::disconnect_on_exit)
+        .add_systems(OnExit(OverworldState::InGame), release_cursor)
+        .add_systems(OnEnter(OverworldState::LoadingScreen), spawn_loading_screen)
+        .add_systems(
+            Update,
+            (
+                update_loading_progress,
+                leave_if_loading_failed,
+                finish_loading,
+            )
+                .chain()
+                .run_if(in_state(OverworldState::LoadingScreen)),
         )
+        .add_systems(OnEnter(OverworldState::InGame), gift::spawn_gift)
         .add_systems(
             Update,
-            finish_loading.run_if(in_state(OverworldState::LoadingScreen)),
+            (
+                enter_level_exits,
+                on_level_transition,
+                multiplayer::despawn_other_players.run_if(
+                    resource_changed::<CurrentLevel>.and(in_state(MultiplayerState::Online)),
+                ),
+            )
+                .chain()
+                .run_if(in_state(OverworldState::InGame)),
+        )
+        .add_systems(
+            OnEnter(MultiplayerState::Online),
+            (
+                multiplayer::spawn_congestion_indicator,
+                multiplayer::spawn_ping_display,
+                chat::spawn_chat,
+                multiplayer::reset_reconnect_attempts,
+                multiplayer::reset_movement_origin,
+                multiplayer::send_name,
+            ),
+        )
+        .add_systems(
+            OnExit(MultiplayerState::Online),
+            (
+                chat::close_chat,
+                multiplayer::forget_player_names,
+                lockstep::leave_lockstep,
+            ),
         )
         .add_systems(
             FixedUpdate,
@@ -51,207 +160,1014 @@ impl Plugin for OverworldPlugin {
                     in_state(MultiplayerState::Connecting).or(in_state(MultiplayerState::Online)),
                 ),
                 (
+                    multiplayer::on_level_reloaded,
                     multiplayer::on_other_player_moved,
                     multiplayer::on_other_player_disconnected,
+                    multiplayer::fade_disconnected_players,
+                    multiplayer::fade_in_spawned_players,
                 )
                     .chain()
                     .run_if(in_state(MultiplayerState::Online)),
-                physics::apply_controls.in_set(TnuaUserControlsSystemSet),
-                animation::animate_sprites,
+                (gift::on_gift_opened, gift::on_gift_state_changed).chain(),
+                multiplayer::wait_for_level_config
+                    .run_if(resource_exists::<multiplayer::LevelConfigTimeout>),
+                move_player_to_spawn_point.run_if(resource_changed::<SpawnPoint>),
+                physics::apply_gravity.run_if(resource_changed::<physics::MovementTuning>),
+                (
+                    physics::apply_controls
+                        .in_set(TnuaUserControlsSystemSet)
+                        .run_if(not(lockstep::lockstep_mode)),
+                    lockstep::apply_lockstep_controls.run_if(lockstep::lockstep_mode),
+                    animation::animate_sprites,
+                    animation::animate_idle,
+                )
+                    .chain()
+                    .run_if(in_state(PauseState::Running)),
             )
                 .chain()
                 .run_if(in_state(OverworldState::InGame)),
         )
+        .add_systems(
+            RunFixedMainLoop,
+            physics::buffer_jump
+                .in_set(RunFixedMainLoopSystem::BeforeFixedMainLoop)
+                .run_if(in_state(PauseState::Running).and(not(resource_exists::<chat::ChatInput>))),
+        )
         .add_systems(
             FixedLast,
-            multiplayer::send_current_position.run_if(in_state(MultiplayerState::Online)),
+            multiplayer::send_current_position
+                .run_if(in_state(MultiplayerState::Online).and(not(lockstep::lockstep_mode))),
+        )
+        .add_systems(
+            Update,
+            apply_physics_debug.run_if(resource_changed::<GraphicsSettings>),
+        )
+        .add_systems(
+            Update,
+            (
+                zoom_camera.run_if(not(resource_exists::<chat::ChatInput>)),
+                orbit_camera,
+                follow_player_with_camera,
+            )
+                .chain()
+                .in_set(CameraFollowSet)
+                .run_if(in_state(OverworldState::InGame)),
         )
         .add_systems(
             Update,
-            follow_player_with_camera.run_if(in_state(OverworldState::InGame)),
+            animation::face_camera
+                .after(CameraFollowSet)
+                .after(multiplayer::interpolate_networked_entities)
+                .run_if(in_state(OverworldState::InGame)),
         )
         .add_systems(
             Update,
-            multiplayer::stop_client_runtime_on_window_close
+            (
+                multiplayer::stop_client_runtime_on_window_close,
+                multiplayer::update_congestion_indicator,
+                multiplayer::send_heartbeat,
+                multiplayer::send_ping,
+                multiplayer::update_ping_display
+                    .run_if(resource_changed::<multiplayer::NetworkStats>),
+                multiplayer::interpolate_networked_entities,
+                // Key presses can be missed in FixedUpdate, so this runs every frame.
+                gift::open_gift_on_interact.run_if(
+                    in_state(PauseState::Running).and(not(resource_exists::<chat::ChatInput>)),
+                ),
+                (
+                    chat::type_chat_message,
+                    chat::update_chat_input_line,
+                    chat::on_chat_received,
+                    chat::trim_chat_log,
+                )
+                    .chain(),
+            )
                 .run_if(in_state(MultiplayerState::Online)),
+        )
+        .add_systems(
+            Update,
+            (
+                multiplayer::on_server_shut_down,
+                multiplayer::on_incompatible_server,
+            )
+                .run_if(in_state(AppState::Overworld)),
+        )
+        .add_systems(
+            Update,
+            multiplayer::detect_connect_failure.run_if(
+                not(in_state(MultiplayerState::Online))
+                    .and(resource_exists::<multiplayer::ServerConnection>),
+            ),
+        )
+        .add_systems(
+            Update,
+            multiplayer::retry_connection.run_if(
+                in_state(MultiplayerState::Offline)
+                    .and(resource_exists::<multiplayer::ServerConnection>),
+            ),
         );
+
+        #[cfg(feature = "debug")]
+        app.add_plugins(free_look::FreeLookPlugin);
     }
 }
 
 // Constants
-/// Note: Based on current guardian sprite
-const SPRITE_PIXELS_PER_METER: f32 = 33.0;
-const STARTING_TRANSLATION: Vec3 = Vec3::new(0.0, 0.5, 0.0);
+/// Every frame in the sprite atlases is a square this many pixels wide.
+const SPRITE_FRAME_PIXELS: u32 = 64;
+const SPRITE_ATLAS_COLUMNS: u32 = 5;
+const SPRITE_ATLAS_ROWS: u32 = 5;
+/// Sprite atlas indices past this don't exist, and would crash bevy_sprite3d.
+const SPRITE_ATLAS_FRAMES: usize = (SPRITE_ATLAS_COLUMNS * SPRITE_ATLAS_ROWS) as usize;
+// Animation frames are sent as a u8, so every frame has to fit in one.
+const _: () = assert!(SPRITE_ATLAS_FRAMES <= u8::MAX as usize + 1);
+/// The rows of the walk cycle where a foot hits the ground, which is when footsteps play.
+const SPRITE_FOOTSTEP_ROWS: [u32; 2] = [2, 4];
+// Row 0 is standing, so footsteps have to be on walking rows.
+const _: () = {
+    let mut i = 0;
+    while i < SPRITE_FOOTSTEP_ROWS.len() {
+        assert!(SPRITE_FOOTSTEP_ROWS[i] > 0 && SPRITE_FOOTSTEP_ROWS[i] < SPRITE_ATLAS_ROWS);
+        i += 1;
+    }
+};
+/// How wide the guardian's body is inside its 64 pixel frame.
+/// Everything else about a player's size is derived from this, so the sprite and its collider always agree.
+const SPRITE_BODY_PIXELS: f32 = 33.0;
+/// Where the guardian's body starts and ends inside its frame while standing, in pixels from the top.
+/// That's the top edge of its head and the bottom edge of its feet. Walking frames bob a few pixels higher.
+const SPRITE_BODY_TOP_PIXEL: f32 = 18.0;
+const SPRITE_BODY_BOTTOM_PIXEL: f32 = 63.0;
+/// Makes every player sprite larger or smaller. Colliders and float height scale with it.
+const SPRITE_SCALE: f32 = 1.0;
+/// At a scale of 1, the guardian's body is 1 meter wide, which makes a whole frame about 1.94 meters wide.
+const SPRITE_PIXELS_PER_METER: f32 = SPRITE_BODY_PIXELS / SPRITE_SCALE;
+/// How far above and below a player's position the top of their head and the bottom of their feet are.
+/// Sprites are centered on their position, so these are measured from the middle of the frame.
+const PLAYER_HEAD_HEIGHT: f32 =
+    (SPRITE_FRAME_PIXELS as f32 / 2.0 - SPRITE_BODY_TOP_PIXEL) / SPRITE_PIXELS_PER_METER;
+const PLAYER_FEET_DEPTH: f32 =
+    (SPRITE_BODY_BOTTOM_PIXEL - SPRITE_FRAME_PIXELS as f32 / 2.0) / SPRITE_PIXELS_PER_METER;
+/// How far the bottom of a player's collider stays above their feet, so it never drags along the ground
+/// while the player floats. Bumps lower than this are floated over rather than walked into.
+const PLAYER_COLLIDER_GROUND_GAP: f32 = 0.25 * SPRITE_SCALE;
+/// The width, height, and depth of a player's collider. It covers the body in their sprite,
+/// from the top of their head down to PLAYER_COLLIDER_GROUND_GAP above their feet.
+const PLAYER_COLLIDER_SIZE: Vec3 = Vec3::new(
+    SPRITE_BODY_PIXELS / SPRITE_PIXELS_PER_METER,
+    PLAYER_HEAD_HEIGHT + PLAYER_FEET_DEPTH - PLAYER_COLLIDER_GROUND_GAP,
+    SPRITE_BODY_PIXELS / SPRITE_PIXELS_PER_METER,
+);
+/// How far the middle of a player's collider is above their position. The body sits low in its frame,
+/// so this is negative.
+const PLAYER_COLLIDER_OFFSET: f32 = PLAYER_HEAD_HEIGHT - PLAYER_COLLIDER_SIZE.y / 2.0;
+const ZOOM_IN_KEYS: [KeyCode; 2] = [KeyCode::Equal, KeyCode::NumpadAdd];
+const ZOOM_OUT_KEYS: [KeyCode; 2] = [KeyCode::Minus, KeyCode::NumpadSubtract];
+/// How much one press of a zoom key changes CameraFollow's zoom.
+const ZOOM_PER_KEY_PRESS: f32 = 0.1;
+/// Holding this turns the orbit camera.
+const ORBIT_BUTTON: MouseButton = MouseButton::Right;
+/// Radians the orbit camera turns per pixel of mouse movement.
+const ORBIT_SENSITIVITY: f32 = 0.005;
+/// How far the orbit camera can look down, in radians. Any flatter and the level hides the player,
+/// and any steeper and the camera flips over the top of them.
+const MIN_ORBIT_PITCH: f32 = 0.1;
+const MAX_ORBIT_PITCH: f32 = 1.4;
+/// How much one notch of the mouse wheel changes CameraFollow's zoom.
+const ZOOM_PER_SCROLL_LINE: f32 = 0.1;
+/// Touchpads scroll in pixels instead of lines, so this many pixels counts as one notch.
+const PIXELS_PER_SCROLL_LINE: f32 = 100.0;
+/// The level the overworld loads.
+const GIFT_PLANE: Level = Level {
+    scene: "overworld/3d/Gift_Plane.glb",
+    // The Gift Plane is small, so decomposing it doesn't take long, and the convex pieces are cheap to collide with.
+    collider_strategy: ColliderStrategy::ConvexDecomposition,
+    song: "overworld/sounds/gift_plane.ogg",
+    // The Gift Plane's song is loud, so it plays at half volume.
+    song_volume: 0.5,
+    spawn: Vec3::new(0.0, 0.5, 0.0),
+    // The Gift Plane is the only level so far, so there's nowhere to go.
+    exits: &[],
+};
+/// Every footstep picks one of these at random.
+const WALKING_SOUNDS: [&str; 2] = [
+    "overworld/sounds/walking_1.ogg",
+    "overworld/sounds/walking_2.ogg",
+];
+
+/// Everything that differs between levels. The rest of the overworld, like the players and their sprites,
+/// is the same in every level.
+struct Level {
+    scene: &'static str,
+    collider_strategy: ColliderStrategy,
+    /// The song that loops while the level is loaded.
+    song: &'static str,
+    /// How loud the song is before the music volume setting, from 0.0 to 1.0.
+    song_volume: f32,
+    /// Where players spawn, until the server says otherwise.
+    spawn: Vec3,
+    /// Where the player can walk to get to other levels.
+    exits: &'static [LevelExit],
+}
+
+/// Every level in the overworld. LevelId::config has what each one loads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+enum LevelId {
+    #[default]
+    GiftPlane,
+}
+impl LevelId {
+    /// Every level, in the order the lobby lists their rooms.
+    const ALL: [LevelId; 1] = [LevelId::GiftPlane];
+
+    fn config(self) -> &'static Level {
+        match self {
+            LevelId::GiftPlane => &GIFT_PLANE,
+        }
+    }
+
+    /// The ID sent to the server with the player's movement, so other clients only show players in the same level.
+    fn zone(self) -> u16 {
+        match self {
+            LevelId::GiftPlane => 0,
+        }
+    }
+
+    /// What the level is called in the lobby.
+    fn name(self) -> &'static str {
+        match self {
+            LevelId::GiftPlane => "Gift Plane",
+        }
+    }
+}
+
+/// A box in a level that takes the player to another level when they walk into it.
+struct LevelExit {
+    center: Vec3,
+    /// The width, height, and depth of the box.
+    size: Vec3,
+    to: LevelId,
+    /// Where the player appears in the other level.
+    spawn: Vec3,
+}
+
+/// How colliders are built from a level's "Hitbox Mesh".
+#[derive(Debug, Clone, Copy)]
+enum ColliderStrategy {
+    /// Exactly matches the mesh and builds instantly, but every collision has to check its triangles.
+    #[expect(dead_code, reason = "The Gift Plane is the only level so far.")]
+    Trimesh,
+    /// Splits the mesh into convex pieces, which collide faster and more reliably but take a while to build.
+    ConvexDecomposition,
+}
+impl ColliderStrategy {
+    fn constructor(self) -> ColliderConstructor {
+        match self {
+            ColliderStrategy::Trimesh => ColliderConstructor::TrimeshFromMesh,
+            ColliderStrategy::ConvexDecomposition => {
+                ColliderConstructor::ConvexDecompositionFromMesh
+            }
+        }
+    }
+}
+
+/// Moves an atlas index that doesn't exist back to the last frame, so it can't crash bevy_sprite3d.
+fn clamp_animation_frame(index: usize) -> usize {
+    index.min(SPRITE_ATLAS_FRAMES - 1)
+}
+
+/// A player's collider, moved down from the middle of their sprite to line up with the body in it.
+fn player_collider() -> Collider {
+    Collider::compound(vec![(
+        Vec3::new(0.0, PLAYER_COLLIDER_OFFSET, 0.0),
+        Quat::IDENTITY,
+        Collider::cuboid(
+            PLAYER_COLLIDER_SIZE.x,
+            PLAYER_COLLIDER_SIZE.y,
+            PLAYER_COLLIDER_SIZE.z,
+        ),
+    )])
+}
 
 // Sub-States
 #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, SubStates)]
 #[source(AppState = AppState::Overworld)]
 #[states(scoped_entities)]
-enum OverworldState {
+pub enum OverworldState {
+    /// Picking a room to join. Offline, this is skipped straight away.
     #[default]
+    Lobby,
     LoadingScreen,
     InGame,
 }
 
+// System Sets
+/// Systems that move the camera to follow the player. The debug free-look camera stops these from running.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+struct CameraFollowSet;
+
 // Resources
+/// Where the player spawns.
+/// The server can override this with Packet::LevelConfig, and then with Packet::SetSpawn.
+#[derive(Resource)]
+struct SpawnPoint {
+    translation: Vec3,
+    /// Whether the server gave this client its own spot with Packet::SetSpawn.
+    /// Once it has, the level's shared spawn point from Packet::LevelConfig is ignored.
+    assigned: bool,
+}
+impl Default for SpawnPoint {
+    fn default() -> Self {
+        Self {
+            translation: LevelId::default().config().spawn,
+            assigned: false,
+        }
+    }
+}
+
+/// How the camera frames the player, so levels with tall structures can pull it up or tilt it.
+///
+/// The fixed camera frames the level's origin at these settings and only slides sideways,
+/// while the behind-the-player camera frames the player.
 #[derive(Resource)]
+struct CameraFraming {
+    /// How high above what it looks at the camera sits, in meters.
+    height: f32,
+    /// How far the camera looks down, in radians.
+    pitch: f32,
+    /// Moves what the camera looks at away from the player, or the origin for the fixed camera.
+    look_offset: Vec3,
+}
+impl Default for CameraFraming {
+    fn default() -> Self {
+        // The camera used to sit at (0, 5, 10), looking at the origin.
+        Self {
+            height: 5.0,
+            pitch: 5.0_f32.atan2(10.0),
+            look_offset: Vec3::ZERO,
+        }
+    }
+}
+impl CameraFraming {
+    /// Where the camera sits relative to what it looks at, when looking forward.
+    fn offset(&self) -> Vec3 {
+        Vec3::new(0.0, self.height, self.height / self.pitch.tan())
+    }
+
+    /// The camera's transform when it looks forward at a point on the ground, from `zoom` times as far away.
+    fn fixed_transform_at(&self, focus: Vec3, zoom: f32) -> Transform {
+        Transform::from_translation(focus + self.look_offset + self.offset() * zoom)
+            .with_rotation(Quat::from_rotation_x(-self.pitch))
+    }
+
+    /// The point on the ground that the fixed camera looks at from a position.
+    ///
+    /// Zooming moves the camera straight towards or away from this point, so it doesn't depend on the zoom.
+    fn fixed_focus(&self, camera_translation: Vec3) -> Vec3 {
+        let offset = self.offset();
+        let distance = (camera_translation.y - self.look_offset.y) / offset.y;
+        let focus = camera_translation - self.look_offset - offset * distance;
+        Vec3::new(focus.x, 0.0, focus.z)
+    }
+}
+
+/// How the fixed camera follows the player around the level. CameraFraming decides where it sits relative to them.
+#[derive(Resource)]
+struct CameraFollow {
+    /// How far the player can walk from the point the camera looks at, along X or Z, before the camera moves.
+    /// This keeps the Petscop-style framing, where the camera stays put for small movements.
+    deadzone: f32,
+    /// How quickly the camera catches up once it moves. Higher is snappier.
+    smoothing: f32,
+    /// How many times further away than CameraFraming's offset the camera sits.
+    /// This lives for the whole session, so it's kept when re-entering the overworld.
+    zoom: f32,
+    /// Closer than this, the camera could end up inside the level.
+    min_zoom: f32,
+    /// Further than this, the level is too small to see.
+    max_zoom: f32,
+}
+/// Where the orbit camera is around the player. It sits as far from them as CameraFraming and the zoom say.
+///
+/// This lives for the whole session, like the zoom, so switching camera modes comes back to the same angle.
+#[derive(Resource)]
+struct CameraOrbit {
+    /// The angle around the player, in radians. 0.0 is behind them looking forward, like the fixed camera.
+    yaw: f32,
+    /// How far the camera looks down at the player, between MIN_ORBIT_PITCH and MAX_ORBIT_PITCH.
+    pitch: f32,
+}
+impl Default for CameraOrbit {
+    fn default() -> Self {
+        Self {
+            yaw: 0.0,
+            pitch: CameraFraming::default()
+                .pitch
+                .clamp(MIN_ORBIT_PITCH, MAX_ORBIT_PITCH),
+        }
+    }
+}
+impl CameraOrbit {
+    /// Turns movement relative to the camera, where -Z is away from it, into movement relative to the level.
+    fn relative_to_camera(&self, direction: Vec3) -> Vec3 {
+        Quat::from_rotation_y(self.yaw) * direction
+    }
+}
+
+impl Default for CameraFollow {
+    fn default() -> Self {
+        Self {
+            // The camera used to only slide sideways, once the player was 2 meters away.
+            deadzone: 2.0,
+            smoothing: 5.0,
+            zoom: 1.0,
+            min_zoom: 0.5,
+            max_zoom: 2.0,
+        }
+    }
+}
+
+/// The level the player is in, or is loading into.
+#[derive(Resource, Default)]
+struct CurrentLevel(LevelId);
+
+#[derive(Resource, Clone)]
 struct OverworldAssetCollection {
     level: Handle<Scene>,
+    level_id: LevelId,
+    level_config: &'static Level,
     sprites: OverworldSprites,
     sound_effects: OverworldSoundEffects,
     songs: OverworldSongs,
 }
+#[derive(Clone)]
 struct OverworldSprites {
     guardian_image: Handle<Image>,
     other_player_image: Handle<Image>,
+    gift_image: Handle<Image>,
     sprite_layout: Handle<TextureAtlasLayout>,
 }
+#[derive(Clone)]
 struct OverworldSoundEffects {
-    walking_1: Handle<AudioSource>,
-    walking_2: Handle<AudioSource>,
+    walking: Vec<Handle<AudioSource>>,
 }
+#[derive(Clone)]
 struct OverworldSongs {
-    gift_plane: Handle<AudioSource>,
+    level: Handle<AudioSource>,
 }
 
 impl OverworldAssetCollection {
-    fn all_assets_are_loaded(&self, asset_server: &Res<AssetServer>) -> bool {
-        asset_server
-            .get_load_state(self.level.id())
-            .is_some_and(|state| state.is_loaded())
-            && asset_server
-                .get_load_state(self.sprites.guardian_image.id())
-                .is_some_and(|state| state.is_loaded())
-            && asset_server
-                .get_load_state(self.sprites.other_player_image.id())
-                .is_some_and(|state| state.is_loaded())
-            && asset_server
-                .get_load_state(self.sound_effects.walking_1.id())
-                .is_some_and(|state| state.is_loaded())
-            && asset_server
-                .get_load_state(self.sound_effects.walking_2.id())
-                .is_some_and(|state| state.is_loaded())
-            && asset_server
-                .get_load_state(self.songs.gift_plane.id())
-                .is_some_and(|state| state.is_loaded())
+    /// Starts loading a level, and every asset the overworld needs in any level.
+    fn load(
+        level_id: LevelId,
+        asset_server: &AssetServer,
+        texture_atlas_layouts: &mut Assets<TextureAtlasLayout>,
+    ) -> Self {
+        let level = level_id.config();
+        Self {
+            level: asset_server.load(GltfAssetLabel::Scene(0).from_asset(level.scene)),
+            level_id,
+            level_config: level,
+            sprites: OverworldSprites {
+                guardian_image: asset_server.load("overworld/2d/guardian.png"),
+                other_player_image: asset_server.load("overworld/2d/other_player.png"),
+                gift_image: asset_server.load("mainmenu/gift.png"),
+                sprite_layout: texture_atlas_layouts.add(TextureAtlasLayout::from_grid(
+                    UVec2::splat(SPRITE_FRAME_PIXELS),
+                    SPRITE_ATLAS_COLUMNS,
+                    SPRITE_ATLAS_ROWS,
+                    None,
+                    None,
+                )),
+            },
+            sound_effects: OverworldSoundEffects {
+                walking: WALKING_SOUNDS
+                    .iter()
+                    .map(|path| asset_server.load(*path))
+                    .collect(),
+            },
+            songs: OverworldSongs {
+                level: asset_server.load(level.song),
+            },
+        }
+    }
+
+    /// Starts loading another level's assets in place of the current level's.
+    /// The sprites and sound effects are the same in every level, so they're kept.
+    fn switch_level(&mut self, level_id: LevelId, asset_server: &AssetServer) {
+        let level = level_id.config();
+        self.level = asset_server.load(GltfAssetLabel::Scene(0).from_asset(level.scene));
+        self.songs.level = asset_server.load(level.song);
+        self.level_id = level_id;
+        self.level_config = level;
+    }
+
+    /// Every asset loaded from a file. The sprite layout is made in code, so it's always ready.
+    fn file_asset_ids(&self) -> Vec<UntypedAssetId> {
+        let mut ids = vec![
+            self.level.id().untyped(),
+            self.sprites.guardian_image.id().untyped(),
+            self.sprites.other_player_image.id().untyped(),
+            self.sprites.gift_image.id().untyped(),
+            self.songs.level.id().untyped(),
+        ];
+        ids.extend(
+            self.sound_effects
+                .walking
+                .iter()
+                .map(|walking| walking.id().untyped()),
+        );
+        ids
+    }
+
+    /// How many assets have loaded, out of how many there are.
+    fn load_progress(&self, asset_server: &AssetServer) -> (usize, usize) {
+        let ids = self.file_asset_ids();
+        let loaded = ids
+            .iter()
+            .filter(|id| {
+                asset_server
+                    .get_load_state(**id)
+                    .is_some_and(|state| state.is_loaded())
+            })
+            .count();
+        (loaded, ids.len())
+    }
+
+    /// The path of every asset that failed to load. The asset server logs why each one failed.
+    fn failed_assets(&self, asset_server: &AssetServer) -> Vec<String> {
+        self.file_asset_ids()
+            .into_iter()
+            .filter(|id| matches!(asset_server.get_load_state(*id), Some(LoadState::Failed(_))))
+            .map(|id| match asset_server.get_path(id) {
+                Some(path) => path.to_string(),
+                None => format!("{id:?}"),
+            })
+            .collect()
+    }
+
+    fn all_assets_are_loaded(&self, asset_server: &AssetServer) -> bool {
+        let (loaded, total) = self.load_progress(asset_server);
+        loaded == total
     }
 }
 
+/// Overworld assets that started loading early, before the overworld was entered.
+/// Holding the handles here keeps the assets from being unloaded in the meantime.
+#[derive(Resource)]
+struct PreloadHandles(OverworldAssetCollection);
+
 // Components
 #[derive(Component)]
 struct Player;
+/// Marks that the player moved instantly, so the next movement packet tells other clients not to reject it.
+#[derive(Component)]
+struct Teleported;
+/// The text on the loading screen, which counts the assets that have loaded.
+#[derive(Component)]
+struct LoadingText;
+/// A sensor spawned from a LevelExit, which fires LevelTransition when the player walks into it.
+#[derive(Component)]
+struct LevelExitZone {
+    to: LevelId,
+    spawn: Vec3,
+}
+
+// Events
+/// The player walked into a level exit, so the next level should be loaded with the player at its spawn.
+#[derive(Event)]
+struct LevelTransition {
+    to: LevelId,
+    spawn: Vec3,
+}
 
 // Systems
+/// This system starts loading the overworld's assets while the main menu is open,
+/// so the loading screen has less to wait for once the player begins.
+fn preload_overworld_assets(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+) {
+    commands.insert_resource(PreloadHandles(OverworldAssetCollection::load(
+        LevelId::default(),
+        &asset_server,
+        &mut texture_atlas_layouts,
+    )));
+}
+
 fn setup_overworld(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+    preload_handles: Option<Res<PreloadHandles>>,
 ) {
-    // Start loading assets
-    commands.insert_resource(OverworldAssetCollection {
-        level: asset_server
-            .load(GltfAssetLabel::Scene(0).from_asset("overworld/3d/Gift_Plane.glb")),
-        sprites: OverworldSprites {
-            guardian_image: asset_server.load("overworld/2d/guardian.png"),
-            other_player_image: asset_server.load("overworld/2d/other_player.png"),
-            sprite_layout: texture_atlas_layouts.add(TextureAtlasLayout::from_grid(
-                UVec2::splat(64),
-                5,
-                5,
-                None,
-                None,
-            )),
-        },
-        sound_effects: OverworldSoundEffects {
-            walking_1: asset_server.load("overworld/sounds/walking_1.ogg"),
-            walking_2: asset_server.load("overworld/sounds/walking_2.ogg"),
-        },
-        songs: OverworldSongs {
-            gift_plane: asset_server.load("overworld/sounds/gift_plane.ogg"),
+    // Every visit to the overworld starts in the first level, which is also the one that was preloaded.
+    commands.insert_resource(CurrentLevel::default());
+    // Start loading assets, unless the main menu already started.
+    // Preloaded assets might not have finished loading, but finish_loading waits for them either way.
+    let assets = match preload_handles {
+        Some(preload_handles) => {
+            commands.remove_resource::<PreloadHandles>();
+            preload_handles.0.clone()
+        }
+        None => OverworldAssetCollection::load(
+            LevelId::default(),
+            &asset_server,
+            &mut texture_atlas_layouts,
+        ),
+    };
+    commands.insert_resource(assets);
+}
+
+/// Shows that the overworld is loading, so a slow load doesn't look like the game froze.
+/// Everything here despawns once the overworld is in game.
+fn spawn_loading_screen(mut commands: Commands, asset_server: Res<AssetServer>) {
+    // The overworld camera is still there when loading the next level, so this draws over it.
+    commands.spawn((
+        StateScoped(OverworldState::LoadingScreen),
+        Camera2d,
+        Camera {
+            order: 1,
+            ..default()
         },
-    });
+    ));
+    commands
+        .spawn((
+            StateScoped(OverworldState::LoadingScreen),
+            Node {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+        ))
+        .with_child((
+            LoadingText,
+            Text::new("Loading..."),
+            TextColor::WHITE,
+            TextFont {
+                font: asset_server.load("global/fonts/PetscopWide.ttf"),
+                font_size: 50.0,
+                font_smoothing: FontSmoothing::None,
+                ..default()
+            },
+        ));
+}
+
+fn update_loading_progress(
+    asset_server: Res<AssetServer>,
+    assets: Res<OverworldAssetCollection>,
+    mut text: Single<&mut Text, With<LoadingText>>,
+) {
+    let (loaded, total) = assets.load_progress(&asset_server);
+    // Only touching the text when it changes avoids laying it out again every frame.
+    text.set_if_neq(Text(format!("Loading... {loaded}/{total}")));
+}
+
+/// Goes back to the main menu if any asset failed to load, since the overworld can't be built without it.
+fn leave_if_loading_failed(
+    asset_server: Res<AssetServer>,
+    assets: Res<OverworldAssetCollection>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    let failed = assets.failed_assets(&asset_server);
+    if failed.is_empty() {
+        return;
+    }
+    for path in &failed {
+        error!("Could not load {path}.");
+    }
+    error!(
+        "The overworld can't load without those {} assets, so going back to the main menu.",
+        failed.len()
+    );
+    next_state.set(AppState::MainMenu);
 }
 
+#[expect(
+    clippy::too_many_arguments,
+    reason = "Spawning the player and camera needs the assets, spawn point and every camera setting."
+)]
 fn finish_loading(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     assets: Res<OverworldAssetCollection>,
+    spawn_point: Res<SpawnPoint>,
+    graphics_settings: Res<GraphicsSettings>,
+    camera_framing: Res<CameraFraming>,
+    camera_follow: Res<CameraFollow>,
     mut sprite3d_params: Sprite3dParams,
     mut next_state: ResMut<NextState<OverworldState>>,
+    existing_player: Option<Single<Entity, With<Player>>>,
+    real_time: Res<Time<Real>>,
+    // When the loading screen started, so the wait can be logged.
+    mut loading_started: Local<Option<Duration>>,
 ) {
+    let started = *loading_started.get_or_insert(real_time.elapsed());
     if assets.all_assets_are_loaded(&asset_server) {
         // Spawn level
+        info!(
+            "Building the level's colliders with {:?}.",
+            assets.level_config.collider_strategy
+        );
         commands.spawn((
-            StateScoped(AppState::Overworld),
+            StateScoped(OverworldState::InGame),
             SceneRoot(assets.level.clone()),
             Transform::default(),
             RigidBody::Static,
             ColliderConstructorHierarchy::new(None).with_constructor_for_name(
                 "Hitbox Mesh",
-                ColliderConstructor::ConvexDecompositionFromMesh,
+                assets.level_config.collider_strategy.constructor(),
             ),
         ));
-        // Spawn player
-        commands.spawn((
-            StateScoped(AppState::Overworld),
-            Player,
-            Sprite3dBuilder {
-                image: assets.sprites.guardian_image.clone(),
-                pixels_per_metre: SPRITE_PIXELS_PER_METER,
-                double_sided: false,
-                unlit: true,
-                ..default()
-            }
-            .bundle_with_atlas(
-                &mut sprite3d_params,
-                TextureAtlas {
-                    layout: assets.sprites.sprite_layout.clone(),
-                    index: 0,
+        for exit in assets.level_config.exits {
+            commands.spawn((
+                StateScoped(OverworldState::InGame),
+                LevelExitZone {
+                    to: exit.to,
+                    spawn: exit.spawn,
                 },
-            ),
-            Transform::from_translation(STARTING_TRANSLATION),
-            animation::AnimationTimer(Timer::from_seconds(0.15, TimerMode::Repeating)),
-            animation::AnimationDirection(Vec3::ZERO),
-            RigidBody::Dynamic,
-            Collider::cuboid(1.0, 1.0, 1.0),
-            TnuaController::default(),
-            TnuaAvian3dSensorShape(Collider::cuboid(1.0, 0.0, 1.0)),
-            LockedAxes::ROTATION_LOCKED,
-            Dominance(1),
-        ));
+                Transform::from_translation(exit.center),
+                Sensor,
+                Collider::cuboid(exit.size.x, exit.size.y, exit.size.z),
+                CollidingEntities::default(),
+            ));
+        }
 
         // Spawn music
         commands.spawn((
-            StateScoped(AppState::Overworld),
-            AudioPlayer::new(assets.songs.gift_plane.clone()),
-            PlaybackSettings {
+            StateScoped(OverworldState::InGame),
+            Song {
+                source: assets.songs.level.clone(),
+                volume: assets.level_config.song_volume,
                 mode: PlaybackMode::Loop,
-                volume: Volume::Linear(0.5),
-                ..default()
             },
         ));
 
-        // Spawn camera
-        commands.spawn((
-            StateScoped(AppState::Overworld),
-            Camera3d::default(),
-            Camera {
-                clear_color: ClearColorConfig::Custom(Color::WHITE),
-                ..default()
-            },
-            Transform::from_xyz(0.0, 5.0, 10.0).looking_at(Vec3::ZERO, Vec3::Y),
-        ));
+        // The player and camera stay from one level to the next, so they're only spawned the first time.
+        if let Some(player) = existing_player {
+            commands.entity(*player).remove::<RigidBodyDisabled>();
+        } else {
+            commands.spawn((
+                StateScoped(AppState::Overworld),
+                Player,
+                Sprite3dBuilder {
+                    image: assets.sprites.guardian_image.clone(),
+                    pixels_per_metre: SPRITE_PIXELS_PER_METER,
+                    double_sided: graphics_settings.double_sided_sprites,
+                    unlit: !graphics_settings.lit_sprites,
+                    ..default()
+                }
+                .bundle_with_atlas(
+                    &mut sprite3d_params,
+                    TextureAtlas {
+                        layout: assets.sprites.sprite_layout.clone(),
+                        index: 0,
+                    },
+                ),
+                Transform::from_translation(spawn_point.translation),
+                animation::AnimationTimer(Timer::from_seconds(0.15, TimerMode::Repeating)),
+                animation::AnimationDirection(Vec3::ZERO),
+                animation::IdleAnimation::default(),
+                animation::Billboard,
+                RigidBody::Dynamic,
+                player_collider(),
+                TnuaController::default(),
+                TnuaAvian3dSensorShape(Collider::cuboid(
+                    PLAYER_COLLIDER_SIZE.x,
+                    0.0,
+                    PLAYER_COLLIDER_SIZE.z,
+                )),
+                LockedAxes::ROTATION_LOCKED,
+                Dominance(1),
+            ));
+            commands.spawn((
+                StateScoped(AppState::Overworld),
+                Camera3d::default(),
+                Camera {
+                    clear_color: ClearColorConfig::Custom(Color::WHITE),
+                    ..default()
+                },
+                camera_framing.fixed_transform_at(Vec3::ZERO, camera_follow.zoom),
+                // Other players' footsteps are heard from here.
+                SpatialListener::default(),
+            ));
+        }
 
+        info!(
+            "Loaded {:?} in {:?}.",
+            assets.level_id,
+            real_time.elapsed() - started
+        );
+        *loading_started = None;
         next_state.set(OverworldState::InGame);
     }
 }
 
+/// Fires LevelTransition when the player is inside a level exit.
+fn enter_level_exits(
+    player: Single<Entity, With<Player>>,
+    exits: Query<(&LevelExitZone, &CollidingEntities)>,
+    mut level_transition: EventWriter<LevelTransition>,
+) {
+    for (exit, colliding) in exits.iter() {
+        if colliding.contains(&*player) {
+            level_transition.write(LevelTransition {
+                to: exit.to,
+                spawn: exit.spawn,
+            });
+        }
+    }
+}
+
+/// Starts loading the next level, and moves the player to its spawn.
+///
+/// Going back to the loading screen despawns everything scoped to OverworldState::InGame, which is the old level.
+/// The player stays, without physics so it doesn't fall while there's no level under it,
+/// and the connection to the server stays open the whole time.
+fn on_level_transition(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut level_transition: EventReader<LevelTransition>,
+    mut current_level: ResMut<CurrentLevel>,
+    mut assets: ResMut<OverworldAssetCollection>,
+    player: Single<(Entity, &mut Transform), With<Player>>,
+    mut next_state: ResMut<NextState<OverworldState>>,
+) {
+    let Some(transition) = level_transition.read().last() else {
+        return;
+    };
+    info!("Going from {:?} to {:?}.", current_level.0, transition.to);
+    current_level.0 = transition.to;
+    assets.switch_level(transition.to, &asset_server);
+    let (entity, mut transform) = player.into_inner();
+    transform.translation = transition.spawn;
+    commands
+        .entity(entity)
+        .insert((Teleported, RigidBodyDisabled));
+    next_state.set(OverworldState::LoadingScreen);
+}
+
+/// Shows or hides the collider gizmos drawn by PhysicsDebugPlugin, to match GraphicsSettings.
+fn apply_physics_debug(
+    graphics_settings: Res<GraphicsSettings>,
+    mut config_store: ResMut<GizmoConfigStore>,
+) {
+    config_store.config_mut::<PhysicsGizmos>().0.enabled = graphics_settings.physics_debug;
+}
+
+/// Moves the player to the spawn point whenever it changes, such as when the server sends a new one.
+fn move_player_to_spawn_point(
+    mut commands: Commands,
+    spawn_point: Res<SpawnPoint>,
+    player: Single<(Entity, &mut Transform), With<Player>>,
+) {
+    let (entity, mut transform) = player.into_inner();
+    transform.translation = spawn_point.translation;
+    commands.entity(entity).insert(Teleported);
+}
+
+/// The mouse wheel and the plus and minus keys move the camera closer or further away.
+fn zoom_camera(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mouse_scroll: Res<AccumulatedMouseScroll>,
+    mut camera_follow: ResMut<CameraFollow>,
+) {
+    let mut zoom_in = match mouse_scroll.unit {
+        MouseScrollUnit::Line => mouse_scroll.delta.y,
+        MouseScrollUnit::Pixel => mouse_scroll.delta.y / PIXELS_PER_SCROLL_LINE,
+    } * ZOOM_PER_SCROLL_LINE;
+    if keyboard.any_just_pressed(ZOOM_IN_KEYS) {
+        zoom_in += ZOOM_PER_KEY_PRESS;
+    }
+    if keyboard.any_just_pressed(ZOOM_OUT_KEYS) {
+        zoom_in -= ZOOM_PER_KEY_PRESS;
+    }
+    // Only touching the resource when zooming keeps it from looking changed every frame.
+    if zoom_in != 0.0 {
+        camera_follow.zoom =
+            (camera_follow.zoom - zoom_in).clamp(camera_follow.min_zoom, camera_follow.max_zoom);
+    }
+}
+
+/// Holding ORBIT_BUTTON in the orbit camera mode turns the camera around the player with the mouse.
+///
+/// The cursor is locked while turning, so it doesn't wander off the window or fight the mouse movement,
+/// and it's released as soon as the button is.
+fn orbit_camera(
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    mouse_motion: Res<AccumulatedMouseMotion>,
+    graphics_settings: Res<GraphicsSettings>,
+    mut orbit: ResMut<CameraOrbit>,
+    mut window: Single<&mut Window, With<PrimaryWindow>>,
+) {
+    let orbiting =
+        graphics_settings.camera_mode == CameraMode::Orbit && mouse_buttons.pressed(ORBIT_BUTTON);
+    let grab_mode = if orbiting {
+        CursorGrabMode::Locked
+    } else {
+        CursorGrabMode::None
+    };
+    // Only touching the window when the grab changes keeps it from looking changed every frame.
+    if window.cursor_options.grab_mode != grab_mode {
+        window.cursor_options.grab_mode = grab_mode;
+    }
+    if !orbiting || mouse_motion.delta == Vec2::ZERO {
+        return;
+    }
+    orbit.yaw -= mouse_motion.delta.x * ORBIT_SENSITIVITY;
+    orbit.pitch = (orbit.pitch + mouse_motion.delta.y * ORBIT_SENSITIVITY)
+        .clamp(MIN_ORBIT_PITCH, MAX_ORBIT_PITCH);
+}
+
+/// Lets go of the cursor if the overworld ends while the orbit camera is turning.
+fn release_cursor(mut window: Single<&mut Window, With<PrimaryWindow>>) {
+    window.cursor_options.grab_mode = CursorGrabMode::None;
+}
+
+/// The fixed camera slides along the ground to keep the player in view, without turning.
+/// The behind-the-player camera instead swings around to face the same way as the player, and looks at them.
+/// The orbit camera looks at the player from wherever CameraOrbit says.
+#[expect(
+    clippy::too_many_arguments,
+    reason = "Each camera mode reads its own settings."
+)]
 fn follow_player_with_camera(
-    player_transform: Single<&Transform, With<Player>>,
+    time: Res<Time>,
+    graphics_settings: Res<GraphicsSettings>,
+    camera_framing: Res<CameraFraming>,
+    camera_follow: Res<CameraFollow>,
+    orbit: Res<CameraOrbit>,
+    player: Single<(&Transform, &animation::AnimationDirection), With<Player>>,
     mut camera_transform: Single<&mut Transform, (With<Camera3d>, Without<Player>)>,
+    // The angle the behind-the-player camera has turned to, around the player. 0.0 is behind a player facing forward.
+    mut yaw: Local<f32>,
 ) {
-    camera_transform.translation.x = camera_transform.translation.x.clamp(
-        player_transform.translation.x - 2.0,
-        player_transform.translation.x + 2.0,
-    );
+    let (player_transform, direction) = player.into_inner();
+    match graphics_settings.camera_mode {
+        CameraMode::Fixed => {
+            // Coming back from the behind-the-player camera starts behind the player next time.
+            *yaw = 0.0;
+            // Work out what the camera is looking at, then move that just enough to keep the player in the deadzone.
+            let focus = camera_framing
+                .fixed_focus(camera_transform.translation)
+                .xz();
+            let player_position = player_transform.translation.xz();
+            let deadzone = Vec2::splat(camera_follow.deadzone);
+            let focus = focus.clamp(player_position - deadzone, player_position + deadzone);
+
+            let target = camera_framing
+                .fixed_transform_at(Vec3::new(focus.x, 0.0, focus.y), camera_follow.zoom);
+            let t = 1.0 - (-camera_follow.smoothing * time.delta_secs()).exp();
+            camera_transform.translation = camera_transform.translation.lerp(target.translation, t);
+            camera_transform.rotation = target.rotation;
+        }
+        CameraMode::BehindPlayer => {
+            let facing = direction.xz();
+            if facing.length() > 0.001 {
+                // Rotating forward (-Z) by this yaw points it the way the player is facing.
+                let target_yaw = (-facing.x).atan2(-facing.y);
+                // Turn the short way around
+                let difference = (target_yaw - *yaw + PI).rem_euclid(TAU) - PI;
+                let t = 1.0 - (-graphics_settings.camera_turn_speed * time.delta_secs()).exp();
+                *yaw += difference * t;
+            }
+            let look_target = player_transform.translation + camera_framing.look_offset;
+            camera_transform.translation = look_target
+                + Quat::from_rotation_y(*yaw) * camera_framing.offset() * camera_follow.zoom;
+            camera_transform.look_at(look_target, Vec3::Y);
+        }
+        CameraMode::Orbit => {
+            let look_target = player_transform.translation + camera_framing.look_offset;
+            let distance = camera_framing.offset().length() * camera_follow.zoom;
+            camera_transform.translation = look_target
+                + Quat::from_euler(EulerRot::YXZ, orbit.yaw, -orbit.pitch, 0.0)
+                    * Vec3::new(0.0, 0.0, distance);
+            camera_transform.look_at(look_target, Vec3::Y);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atlas_indices_in_range_are_left_alone() {
+        for index in [0, 1, SPRITE_ATLAS_FRAMES - 1] {
+            assert_eq!(clamp_animation_frame(index), index);
+        }
+    }
+
+    #[test]
+    fn out_of_range_atlas_indices_are_clamped_instead_of_panicking() {
+        for index in [SPRITE_ATLAS_FRAMES, 256, 1000, usize::MAX] {
+            let frame = clamp_animation_frame(index);
+            assert_eq!(frame, SPRITE_ATLAS_FRAMES - 1);
+            // send_current_position sends this as a u8, which it has to fit without wrapping.
+            assert_eq!(u8::try_from(frame).map(usize::from), Ok(frame));
+        }
+    }
 }
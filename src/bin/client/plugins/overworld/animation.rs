@@ -1,28 +1,119 @@
-use crate::plugins::overworld::OverworldAssetCollection;
+use crate::plugins::overworld::profiling::ANIMATE_SPRITES_TIME;
+use crate::plugins::overworld::{
+    OverworldAssetCollection, SPRITE_ATLAS_COLUMNS, SPRITE_FOOTSTEP_ROWS,
+};
+use crate::plugins::settings::AudioSettings;
 use crate::AppState;
-use bevy::audio::{AudioPlayer, PlaybackMode, PlaybackSettings};
+use bevy::audio::{AudioPlayer, AudioSource, PlaybackMode, PlaybackSettings, SpatialScale};
+use bevy::diagnostic::Diagnostics;
 use bevy::math::{Vec3, Vec3Swizzles};
-use bevy::prelude::{Commands, Component, Deref, DerefMut, Query, Res, StateScoped};
-use bevy::time::{Time, Timer};
+use bevy::platform::time::Instant;
+use bevy::prelude::{
+    Camera3d, Commands, Component, Deref, DerefMut, Handle, Local, Quat, Query, Res, Single,
+    StateScoped, Transform, With, Without,
+};
+use bevy::time::{Time, Timer, TimerMode};
 use bevy::utils::default;
 use bevy_sprite3d::Sprite3d;
+use rand::Rng;
+use std::time::Duration;
+use tracing::warn;
+
+// Constants
+/// Each column of the sprite atlas is a direction, and each row is a frame of the walking animation.
+/// Row 0 is standing still, and rows 1 to 4 are the walk cycle.
+const ATLAS_COLUMNS: usize = SPRITE_ATLAS_COLUMNS as usize;
+/// The highest atlas index used by the walk cycle. Past this, the animation wraps back to row 1.
+const LAST_WALKING_INDEX: usize = 23;
+/// Which atlas column faces each direction, indexed by [forward, neither, backward][left, neither, right].
+/// The middle is standing still, which keeps whichever column the sprite was already facing.
+///
+/// The current atlases have no diagonal columns, so to be faithful to Petscop, diagonals face left or right.
+/// An atlas with eight directions only needs its diagonal columns changed here.
+const FACING_COLUMNS: [[Option<usize>; 3]; 3] = [
+    // Forward
+    [Some(2), Some(3), Some(1)],
+    // Neither
+    [Some(2), None, Some(1)],
+    // Backward
+    [Some(2), Some(0), Some(1)],
+];
+// Every column has to exist in the atlas.
+const _: () = {
+    let mut row = 0;
+    while row < FACING_COLUMNS.len() {
+        let mut column = 0;
+        while column < FACING_COLUMNS[row].len() {
+            if let Some(atlas_column) = FACING_COLUMNS[row][column] {
+                assert!(atlas_column < ATLAS_COLUMNS);
+            }
+            column += 1;
+        }
+        row += 1;
+    }
+};
+/// Footsteps play at a random speed within this much of 1.0, which also shifts their pitch.
+const FOOTSTEP_PITCH_VARIATION: f32 = 0.08;
+/// Footsteps past this many playing at once are skipped, so busy scenes don't spawn a burst of audio entities.
+pub const MAX_FOOTSTEP_SOUNDS: usize = 8;
+/// Spatial footsteps scale their distance from the camera by this. Sounds fall off with the square of distance,
+/// and the camera is several meters from the player, so distances in meters would make everyone near silent.
+const FOOTSTEP_SPATIAL_SCALE: f32 = 0.25;
+/// The standing frame facing the camera.
+const FACING_CAMERA_FRAME: usize = 0;
+/// The spare frame at the end of the standing row, which faces the camera with a different expression.
+const IDLE_FRAME: usize = 4;
+/// How often a standing sprite with IdleAnimation switches to IDLE_FRAME.
+const IDLE_INTERVAL: Duration = Duration::from_secs(3);
+/// How long IDLE_FRAME stays up each time.
+const IDLE_FRAME_DURATION: Duration = Duration::from_millis(200);
 
 // Components
 #[derive(Component, Deref, DerefMut)]
 pub struct AnimationTimer(pub Timer);
 #[derive(Component, Deref, DerefMut)]
 pub struct AnimationDirection(pub Vec3);
+/// Makes a sprite that is standing still and facing the camera change its expression now and then,
+/// so it doesn't look frozen. Sprites without this just hold their standing frame.
+#[derive(Component)]
+pub struct IdleAnimation(Timer);
+impl Default for IdleAnimation {
+    fn default() -> Self {
+        Self(Timer::new(IDLE_INTERVAL, TimerMode::Repeating))
+    }
+}
+/// Turns a sprite around the Y axis to face the same way as the camera every frame, so it's never seen edge-on.
+/// Level props without this keep whatever rotation they were spawned with.
+///
+/// This only rotates the mesh. The atlas frame still comes from AnimationDirection, so turning the camera never
+/// changes which way a sprite looks like it's facing.
+#[derive(Component)]
+pub struct Billboard;
+/// Marks a footstep sound that is still playing. It despawns when it finishes.
+#[derive(Component)]
+pub struct FootstepSound;
 
 // Systems
 // Mod (%) by the column count to find which column the atlas is in.
 // Floor divide by the row count to find which row the atlas is in. Multiply by row count to return to that row.
+#[expect(
+    clippy::too_many_arguments,
+    reason = "Footsteps need their sounds, volume and a count of those playing, on top of the animation itself."
+)]
 pub fn animate_sprites(
     mut commands: Commands,
     fixed_time: Res<Time>,
     mut query: Query<(&mut AnimationTimer, &AnimationDirection, &mut Sprite3d)>,
     assets: Res<OverworldAssetCollection>,
+    audio_settings: Res<AudioSettings>,
+    mut last_walking_sound: Local<Option<usize>>,
+    footstep_sounds: Query<(), With<FootstepSound>>,
+    mut warned_about_footsteps: Local<bool>,
+    mut diagnostics: Diagnostics,
 ) {
+    let started = Instant::now();
     let delta = fixed_time.delta();
+    let mut playing_footsteps = footstep_sounds.iter().count();
     for (mut timer, direction, mut sprite_3d) in query.iter_mut() {
         let direction = direction.0;
 
@@ -32,65 +123,292 @@ pub fn animate_sprites(
             // Stopped moving, so stop animation in current direction
             timer.pause();
             timer.reset();
-            atlas.index = atlas.index % 5;
+            atlas.index = standing_frame(atlas.index);
         } else {
-            // Get the current animation frame without direction taken into account.
-            // Then update the animation to the current direction.
-            // To be faithful to Petscop, left and right overrides forward and backward.
-            let current_frame = (atlas.index as f32 / 5.0).floor() as usize * 5;
-            if direction.x < -0.001 {
-                // Left
-                atlas.index = current_frame + 2;
-            } else if direction.x > 0.001 {
-                // Right
-                atlas.index = current_frame + 1;
-            } else if direction.z < -0.001 {
-                // Forward
-                atlas.index = current_frame + 3;
-            } else if direction.z > 0.001 {
-                // Backward
-                atlas.index = current_frame;
-            }
+            atlas.index = turn_frame(atlas.index, direction);
 
             // If the player just started moving, immediately switch to the first frame, but don't play a sound.
             if timer.paused() {
                 timer.unpause();
-                // Increment and wrap
-                atlas.index += 5;
-                if atlas.index > 23 {
-                    atlas.index = atlas.index % 5 + 5;
-                }
+                atlas.index = next_frame(atlas.index);
             }
 
             timer.tick(delta);
             if timer.just_finished() {
-                // Increment and wrap
-                atlas.index += 5;
-                if atlas.index > 23 {
-                    atlas.index = atlas.index % 5 + 5;
-                }
+                atlas.index = next_frame(atlas.index);
                 // Play walking sound
-                let current_frame = (atlas.index as f32 / 5.0).floor() as usize;
-                if current_frame == 2 {
-                    commands.spawn((
-                        StateScoped(AppState::Overworld),
-                        AudioPlayer::new(assets.sound_effects.walking_1.clone()),
-                        PlaybackSettings {
-                            mode: PlaybackMode::Despawn,
-                            ..default()
-                        },
-                    ));
-                } else if current_frame == 4 {
+                if is_footstep_frame(atlas.index) {
+                    if playing_footsteps >= MAX_FOOTSTEP_SOUNDS {
+                        if !*warned_about_footsteps {
+                            warn!(
+                                "Too many footsteps are playing at once, so some will be skipped."
+                            );
+                            *warned_about_footsteps = true;
+                        }
+                        continue;
+                    }
+
+                    playing_footsteps += 1;
                     commands.spawn((
                         StateScoped(AppState::Overworld),
-                        AudioPlayer::new(assets.sound_effects.walking_2.clone()),
-                        PlaybackSettings {
-                            mode: PlaybackMode::Despawn,
-                            ..default()
-                        },
+                        footstep_sound(
+                            &assets.sound_effects.walking,
+                            &audio_settings,
+                            &mut last_walking_sound,
+                            false,
+                        ),
                     ));
                 }
             }
         }
     }
+    diagnostics.add_measurement(&ANIMATE_SPRITES_TIME, || {
+        started.elapsed().as_secs_f64() * 1000.0
+    });
+}
+
+/// Picks a walking sound at random, and returns a footstep that plays it once.
+///
+/// Picking the same sound as last time rolls again once, so repeats are rarer but still happen.
+/// Never repeating would just alternate between the two walking sounds.
+///
+/// A spatial footstep plays from wherever it's spawned, and gets quieter the further it is from the camera.
+pub fn footstep_sound(
+    walking_sounds: &[Handle<AudioSource>],
+    audio_settings: &AudioSettings,
+    last_walking_sound: &mut Option<usize>,
+    spatial: bool,
+) -> (FootstepSound, AudioPlayer, PlaybackSettings) {
+    let mut rng = rand::rng();
+    let mut sound = rng.random_range(0..walking_sounds.len());
+    if *last_walking_sound == Some(sound) {
+        sound = rng.random_range(0..walking_sounds.len());
+    }
+    *last_walking_sound = Some(sound);
+
+    (
+        FootstepSound,
+        AudioPlayer::new(walking_sounds[sound].clone()),
+        PlaybackSettings {
+            mode: PlaybackMode::Despawn,
+            volume: audio_settings.sfx_volume(),
+            speed: 1.0 + rng.random_range(-FOOTSTEP_PITCH_VARIATION..=FOOTSTEP_PITCH_VARIATION),
+            spatial,
+            spatial_scale: spatial.then(|| SpatialScale::new(FOOTSTEP_SPATIAL_SCALE)),
+            ..default()
+        },
+    )
+}
+
+/// Returns true if an atlas index is one of the frames where a foot hits the ground.
+fn is_footstep_frame(index: usize) -> bool {
+    SPRITE_FOOTSTEP_ROWS.contains(&((index / ATLAS_COLUMNS) as u32))
+}
+
+/// Returns true if going from one atlas index to another reaches a footstep frame from a different row.
+///
+/// Other players' frames come from the network, so they can skip a row or repeat one.
+pub fn reached_footstep(previous: usize, index: usize) -> bool {
+    is_footstep_frame(index) && previous / ATLAS_COLUMNS != index / ATLAS_COLUMNS
+}
+
+/// This system runs after animate_sprites, and shows IDLE_FRAME at the end of every IDLE_INTERVAL of standing still.
+///
+/// IDLE_FRAME is in the standing row, so walking turns it into the right directional frame without a pop.
+pub fn animate_idle(
+    fixed_time: Res<Time>,
+    mut query: Query<(&mut IdleAnimation, &AnimationDirection, &mut Sprite3d)>,
+) {
+    for (mut idle, direction, mut sprite_3d) in query.iter_mut() {
+        let atlas = sprite_3d.texture_atlas.as_mut().unwrap();
+        if direction.xz().length() >= 0.001 {
+            // Walking starts the wait over.
+            idle.0.reset();
+            continue;
+        }
+        if atlas.index != FACING_CAMERA_FRAME && atlas.index != IDLE_FRAME {
+            continue;
+        }
+        idle.0.tick(fixed_time.delta());
+        atlas.index = if idle.0.remaining() < IDLE_FRAME_DURATION {
+            IDLE_FRAME
+        } else {
+            FACING_CAMERA_FRAME
+        };
+    }
+}
+
+/// Sprites face opposite the camera's forward direction, rather than toward the camera itself,
+/// so sprites side by side stay parallel and don't turn as they cross the screen.
+pub fn face_camera(
+    camera: Single<&Transform, With<Camera3d>>,
+    mut sprites: Query<&mut Transform, (With<Billboard>, Without<Camera3d>)>,
+) {
+    let forward = camera.forward().xz();
+    if forward.length() < 0.001 {
+        // Looking straight down, which has no direction to turn to.
+        return;
+    }
+    // Sprite3d meshes face +Z, so this points +Z back at the camera.
+    let rotation = Quat::from_rotation_y((-forward.x).atan2(-forward.y));
+    for mut transform in sprites.iter_mut() {
+        // Only touching transforms that need turning keeps physics from seeing every player move each frame.
+        if !transform.rotation.abs_diff_eq(rotation, 0.0001) {
+            transform.rotation = rotation;
+        }
+    }
+}
+
+/// Returns the standing frame facing the same direction as an atlas index.
+pub fn standing_frame(index: usize) -> usize {
+    index % ATLAS_COLUMNS
+}
+
+/// Returns the same frame of the animation as an atlas index, facing a new direction.
+///
+/// Diagonal directions use FACING_COLUMNS' diagonal columns.
+/// If the direction has no horizontal movement, the index is returned unchanged.
+pub fn turn_frame(index: usize, direction: Vec3) -> usize {
+    let sign = |value: f32| {
+        if value < -0.001 {
+            0
+        } else if value > 0.001 {
+            2
+        } else {
+            1
+        }
+    };
+    match FACING_COLUMNS[sign(direction.z)][sign(direction.x)] {
+        Some(column) => index / ATLAS_COLUMNS * ATLAS_COLUMNS + column,
+        None => index,
+    }
+}
+
+/// Returns the next frame of the walk cycle after an atlas index, in the same direction.
+///
+/// After the last walking frame, this wraps back to the first walking frame rather than the standing frame.
+pub fn next_frame(index: usize) -> usize {
+    let index = index + ATLAS_COLUMNS;
+    if index > LAST_WALKING_INDEX {
+        index % ATLAS_COLUMNS + ATLAS_COLUMNS
+    } else {
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RIGHT: Vec3 = Vec3::X;
+    const LEFT: Vec3 = Vec3::NEG_X;
+    const FORWARD: Vec3 = Vec3::NEG_Z;
+    const BACKWARD: Vec3 = Vec3::Z;
+
+    #[test]
+    fn next_frame_walks_down_the_column() {
+        for column in 0..ATLAS_COLUMNS {
+            assert_eq!(next_frame(column), column + ATLAS_COLUMNS);
+            assert_eq!(
+                next_frame(column + ATLAS_COLUMNS),
+                column + 2 * ATLAS_COLUMNS
+            );
+        }
+    }
+
+    #[test]
+    fn next_frame_wraps_to_the_first_walking_row() {
+        // Row 4 is the last walking row, and 23 is its fourth column.
+        assert_eq!(next_frame(20), 5);
+        assert_eq!(next_frame(LAST_WALKING_INDEX), 8);
+        assert_eq!(
+            next_frame(LAST_WALKING_INDEX - ATLAS_COLUMNS),
+            LAST_WALKING_INDEX
+        );
+
+        // A full cycle never lands on the standing row.
+        let mut index = 3;
+        for _ in 0..20 {
+            index = next_frame(index);
+            assert!((ATLAS_COLUMNS..=LAST_WALKING_INDEX).contains(&index));
+            assert_eq!(index % ATLAS_COLUMNS, 3);
+        }
+    }
+
+    #[test]
+    fn turn_frame_faces_each_direction() {
+        // Row 2, facing left.
+        let index = 12;
+        assert_eq!(turn_frame(index, RIGHT), 11);
+        assert_eq!(turn_frame(index, LEFT), 12);
+        assert_eq!(turn_frame(index, FORWARD), 13);
+        assert_eq!(turn_frame(index, BACKWARD), 10);
+    }
+
+    #[test]
+    fn turn_frame_diagonals_face_sideways() {
+        assert_eq!(turn_frame(5, FORWARD + RIGHT), 6);
+        assert_eq!(turn_frame(5, FORWARD + LEFT), 7);
+        assert_eq!(turn_frame(5, BACKWARD + RIGHT), 6);
+        assert_eq!(turn_frame(5, BACKWARD + LEFT), 7);
+    }
+
+    #[test]
+    fn turn_frame_keeps_the_index_without_horizontal_movement() {
+        assert_eq!(turn_frame(13, Vec3::ZERO), 13);
+        assert_eq!(turn_frame(13, Vec3::Y), 13);
+    }
+
+    #[test]
+    fn stopping_returns_to_the_standing_frame() {
+        for index in 0..=LAST_WALKING_INDEX {
+            let standing = standing_frame(index);
+            assert!(standing < ATLAS_COLUMNS);
+            assert_eq!(standing, index % ATLAS_COLUMNS);
+        }
+        // Walking then stopping ends up facing the same way as before.
+        let walked = next_frame(next_frame(turn_frame(0, LEFT)));
+        assert_eq!(standing_frame(walked), 2);
+    }
+
+    #[test]
+    fn footsteps_are_on_rows_2_and_4() {
+        for index in 0..=LAST_WALKING_INDEX {
+            let row = index / ATLAS_COLUMNS;
+            assert_eq!(is_footstep_frame(index), row == 2 || row == 4, "{index}");
+        }
+        assert!(!is_footstep_frame(9));
+        assert!(is_footstep_frame(10));
+        assert!(is_footstep_frame(14));
+        assert!(!is_footstep_frame(15));
+        assert!(is_footstep_frame(20));
+    }
+
+    #[test]
+    fn footsteps_play_once_per_row_reached() {
+        assert!(reached_footstep(5, 10));
+        assert!(reached_footstep(15, 20));
+        // Turning on a footstep row, or the same frame arriving twice, isn't another step.
+        assert!(!reached_footstep(12, 11));
+        assert!(!reached_footstep(10, 10));
+        // Leaving a footstep row isn't a step either.
+        assert!(!reached_footstep(20, 5));
+        // Frames from the network can skip straight past a row.
+        assert!(reached_footstep(5, 20));
+    }
+
+    #[test]
+    fn a_walk_cycle_has_two_footsteps() {
+        let mut index = next_frame(turn_frame(0, LEFT));
+        let mut footsteps = 0;
+        // Four rows of walking, so one full cycle.
+        for _ in 0..4 {
+            let previous = index;
+            index = next_frame(index);
+            if reached_footstep(previous, index) {
+                footsteps += 1;
+            }
+        }
+        assert_eq!(footsteps, 2);
+    }
 }
@@ -0,0 +1,243 @@
+use crate::plugins::overworld::multiplayer::{MultiplayerState, PlayerNames, ServerConnection};
+use bevy::input::keyboard::KeyboardInput;
+use bevy::input::ButtonState;
+use bevy::prelude::{
+    default, AssetServer, ChildOf, Children, Color, Commands, Component, DetectChanges, Entity,
+    Event, EventReader, FlexDirection, KeyCode, Local, Node, PositionType, Query, Res, ResMut,
+    Resource, Single, StateScoped, Text, TextColor, TextFont, Val, Visibility, With,
+};
+use bevy::text::FontSmoothing;
+use miniscop::networking::{Packet, MAX_CHAT_LENGTH};
+use tracing::error;
+
+// Constants
+/// How many messages the chat log shows before the oldest ones are removed.
+const MAX_CHAT_LINES: usize = 8;
+const CHAT_KEY: KeyCode = KeyCode::Enter;
+const CANCEL_CHAT_KEY: KeyCode = KeyCode::Escape;
+/// Switches the message being typed between nearby and global chat.
+const CHAT_SCOPE_KEY: KeyCode = KeyCode::ArrowUp;
+
+// Components
+/// The column of recent chat messages.
+#[derive(Component)]
+pub struct ChatLog;
+/// The line showing the message being typed.
+#[derive(Component)]
+pub struct ChatInputLine;
+
+// Resources
+/// The message the player is typing. This only exists while the chat box is open,
+/// and other controls ignore the keyboard while it does.
+///
+/// Nearby chat is only heard by players close enough to be seen, if the server limits that.
+/// Global chat is heard by everyone.
+#[derive(Resource, Default)]
+pub struct ChatInput {
+    message: String,
+    global: bool,
+}
+
+// Events
+/// Someone else sent a chat message.
+#[derive(Event)]
+pub struct ChatReceived {
+    pub id: u64,
+    pub message: String,
+    pub global: bool,
+}
+
+// Systems
+pub fn spawn_chat(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font = TextFont {
+        font: asset_server.load("global/fonts/PetscopWide.ttf"),
+        font_size: 24.0,
+        font_smoothing: FontSmoothing::None,
+        ..default()
+    };
+    commands.spawn((
+        StateScoped(MultiplayerState::Online),
+        ChatLog,
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(50.0),
+            left: Val::Px(10.0),
+            flex_direction: FlexDirection::Column,
+            ..default()
+        },
+    ));
+    commands.spawn((
+        StateScoped(MultiplayerState::Online),
+        ChatInputLine,
+        Text::default(),
+        TextColor(Color::BLACK),
+        font,
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        },
+        Visibility::Hidden,
+    ));
+}
+
+/// This system opens the chat box when the player presses Enter, and sends the message when they press it again.
+///
+/// Escape closes the chat box without sending anything, and the up arrow switches between nearby and global chat.
+/// The chat box opens with whichever was used last.
+pub fn type_chat_message(
+    mut commands: Commands,
+    mut keyboard_input: EventReader<KeyboardInput>,
+    connection: Res<ServerConnection>,
+    mut chat_input: Option<ResMut<ChatInput>>,
+    chat_log: Single<Entity, With<ChatLog>>,
+    font: Single<&TextFont, With<ChatInputLine>>,
+    mut last_global: Local<bool>,
+) {
+    for input in keyboard_input.read() {
+        if input.state != ButtonState::Pressed {
+            continue;
+        }
+        let Some(chat_input) = chat_input.as_mut() else {
+            if input.key_code == CHAT_KEY {
+                commands.insert_resource(ChatInput {
+                    global: *last_global,
+                    ..default()
+                });
+                // The resource is only inserted once commands are applied, so ignore the rest of this frame's typing.
+                return;
+            }
+            continue;
+        };
+
+        match input.key_code {
+            CHAT_KEY => {
+                let message = chat_input.message.trim().to_string();
+                let global = chat_input.global;
+                commands.remove_resource::<ChatInput>();
+                if message.is_empty() {
+                    return;
+                }
+                add_chat_line(
+                    &mut commands,
+                    *chat_log,
+                    format!("{}You: {message}", scope_tag(global)),
+                    (*font).clone(),
+                );
+                if let Err(e) = connection.to_client.try_send(Packet::Chat {
+                    id: None,
+                    message,
+                    global,
+                }) {
+                    error!("Failed to send chat message: {e}");
+                }
+                return;
+            }
+            CANCEL_CHAT_KEY => {
+                commands.remove_resource::<ChatInput>();
+                return;
+            }
+            CHAT_SCOPE_KEY => {
+                chat_input.global = !chat_input.global;
+                *last_global = chat_input.global;
+            }
+            KeyCode::Backspace => {
+                chat_input.message.pop();
+            }
+            _ => {
+                if let Some(text) = &input.text {
+                    for character in text.chars().filter(|character| !character.is_control()) {
+                        if chat_input.message.chars().count() < MAX_CHAT_LENGTH {
+                            chat_input.message.push(character);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Shows the message being typed, or hides the line when the chat box is closed.
+pub fn update_chat_input_line(
+    chat_input: Option<Res<ChatInput>>,
+    input_line: Single<(&mut Text, &mut Visibility), With<ChatInputLine>>,
+) {
+    let (mut text, mut visibility) = input_line.into_inner();
+    match chat_input {
+        Some(chat_input) => {
+            if chat_input.is_changed() {
+                let scope = if chat_input.global {
+                    "Global"
+                } else {
+                    "Nearby"
+                };
+                text.0 = format!("[{scope}] > {}_", chat_input.message);
+            }
+            *visibility = Visibility::Inherited;
+        }
+        None => *visibility = Visibility::Hidden,
+    }
+}
+
+pub fn on_chat_received(
+    mut commands: Commands,
+    mut chat_received: EventReader<ChatReceived>,
+    player_names: Res<PlayerNames>,
+    chat_log: Single<Entity, With<ChatLog>>,
+    font: Single<&TextFont, With<ChatInputLine>>,
+) {
+    for ChatReceived {
+        id,
+        message,
+        global,
+    } in chat_received.read()
+    {
+        add_chat_line(
+            &mut commands,
+            *chat_log,
+            format!(
+                "{}{}: {message}",
+                scope_tag(*global),
+                player_names.name(*id)
+            ),
+            (*font).clone(),
+        );
+    }
+}
+
+/// Global messages are marked in the chat log, so they can be told apart from nearby ones.
+fn scope_tag(global: bool) -> &'static str {
+    if global {
+        "[Global] "
+    } else {
+        ""
+    }
+}
+
+/// Adds a message to the bottom of the chat log. trim_chat_log removes the oldest ones later.
+fn add_chat_line(commands: &mut Commands, chat_log: Entity, message: String, font: TextFont) {
+    commands.spawn((
+        ChildOf(chat_log),
+        Text::new(message),
+        TextColor(Color::BLACK),
+        font,
+    ));
+}
+
+/// Removes the oldest messages once the chat log has more than MAX_CHAT_LINES.
+pub fn trim_chat_log(mut commands: Commands, chat_log: Query<&Children, With<ChatLog>>) {
+    for lines in chat_log.iter() {
+        for line in lines
+            .iter()
+            .take(lines.len().saturating_sub(MAX_CHAT_LINES))
+        {
+            commands.entity(*line).despawn();
+        }
+    }
+}
+
+/// The chat box can't stay open once its UI is gone.
+pub fn close_chat(mut commands: Commands) {
+    commands.remove_resource::<ChatInput>();
+}
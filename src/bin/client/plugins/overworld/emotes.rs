@@ -0,0 +1,279 @@
+use crate::plugins::overworld::chat::ChatInput;
+use crate::plugins::overworld::multiplayer::{
+    interpolate_networked_entities, MultiplayerState, OtherPlayer, ServerConnection,
+};
+use crate::plugins::overworld::name_tags::{place_over_head, NAME_TAG_FONT_SIZE};
+use crate::plugins::overworld::pause::PauseState;
+use crate::plugins::overworld::{CameraFollowSet, OverworldState, Player};
+use bevy::prelude::{
+    default, in_state, not, resource_exists, App, AssetServer, ButtonInput, Camera, Camera3d,
+    Color, Commands, Component, ComputedNode, Condition, Entity, Event, EventReader,
+    GlobalTransform, IntoScheduleConfigs, KeyCode, Node, Or, Plugin, PositionType, Query, Res,
+    Single, State, StateScoped, Text, TextColor, TextFont, Time, Timer, TimerMode, Transform,
+    Update, Visibility, With, Without,
+};
+use bevy::text::FontSmoothing;
+use miniscop::networking::{Packet, EMOTE_COUNT};
+use std::time::Duration;
+use tracing::warn;
+
+/// Lets players show an emote over their head by pressing 1 to 5, which everyone else sees too.
+pub struct EmotePlugin;
+impl Plugin for EmotePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<EmoteReceived>().add_systems(
+            Update,
+            (
+                send_emote
+                    .run_if(in_state(PauseState::Running).and(not(resource_exists::<ChatInput>))),
+                on_emote_received.run_if(in_state(MultiplayerState::Online)),
+                animate_emotes,
+                update_emote_bubbles
+                    .after(CameraFollowSet)
+                    .after(interpolate_networked_entities),
+            )
+                .chain()
+                .run_if(in_state(OverworldState::InGame)),
+        );
+    }
+}
+
+// Constants
+/// The frames of each emote, in the order Packet::Emote numbers them.
+/// Each frame shows for EMOTE_FRAME_DURATION, and the emote disappears after its last one.
+const EMOTES: [&[&str]; EMOTE_COUNT as usize] = [
+    &["!", "!!", "!", "!!", "!", "!", "!", "!"],
+    &["?", "?", "??", "??", "???", "???", "???", "???"],
+    &["<3", "<3", "<3 <3", "<3", "<3", "<3 <3", "<3", "<3"],
+    &[":)", ":)", ":D", ":D", ":)", ":)", ":)", ":)"],
+    &[":(", ":(", ":'(", ":'(", ":(", ":'(", ":'(", ":("],
+];
+const _: () = {
+    let mut emote = 0;
+    while emote < EMOTES.len() {
+        assert!(!EMOTES[emote].is_empty());
+        emote += 1;
+    }
+};
+/// The key for each emote.
+const EMOTE_KEYS: [KeyCode; EMOTE_COUNT as usize] = [
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+];
+/// How long each frame of an emote shows for.
+const EMOTE_FRAME_DURATION: Duration = Duration::from_millis(250);
+const EMOTE_FONT_SIZE: f32 = 30.0;
+/// How far above the top of a player's name tag their emote sits, in pixels.
+const EMOTE_MARGIN: f32 = 4.0;
+
+// Components
+/// An emote over a player's head.
+#[derive(Component)]
+struct EmoteBubble {
+    /// The Player or OtherPlayer it's over.
+    player: Entity,
+}
+/// Steps an emote's text through its frames, and despawns it after the last one.
+#[derive(Component)]
+struct EmoteAnimation {
+    frames: &'static [&'static str],
+    frame: usize,
+    timer: Timer,
+}
+impl EmoteAnimation {
+    fn new(frames: &'static [&'static str]) -> Self {
+        Self {
+            frames,
+            frame: 0,
+            timer: Timer::new(EMOTE_FRAME_DURATION, TimerMode::Repeating),
+        }
+    }
+
+    /// Moves the animation on by some time, and returns the frame it's on, or None once it's finished.
+    fn advance(&mut self, delta: Duration) -> Option<&'static str> {
+        self.timer.tick(delta);
+        self.frame += self.timer.times_finished_this_tick() as usize;
+        self.frames.get(self.frame).copied()
+    }
+}
+
+// Events
+/// Someone else showed an emote. The emote is always less than EMOTE_COUNT.
+#[derive(Event)]
+pub struct EmoteReceived {
+    pub id: u64,
+    pub emote: u8,
+}
+
+// Systems
+/// Shows an emote over the player when they press its key, and tells the server so everyone else sees it.
+fn send_emote(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    multiplayer_state: Res<State<MultiplayerState>>,
+    connection: Option<Res<ServerConnection>>,
+    player: Single<Entity, With<Player>>,
+    bubbles: Query<(Entity, &EmoteBubble)>,
+) {
+    let Some(emote) = EMOTE_KEYS
+        .iter()
+        .position(|key| keyboard.just_pressed(*key))
+    else {
+        return;
+    };
+    spawn_emote_bubble(&mut commands, &asset_server, &bubbles, *player, emote);
+
+    if *multiplayer_state.get() == MultiplayerState::Online
+        && let Some(connection) = connection
+        && let Err(e) = connection.to_client.try_send(Packet::Emote {
+            id: None,
+            emote: emote as u8,
+        })
+    {
+        warn!("Failed to send emote: {e}");
+    }
+}
+
+fn on_emote_received(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut emote_received: EventReader<EmoteReceived>,
+    other_players: Query<(Entity, &OtherPlayer)>,
+    bubbles: Query<(Entity, &EmoteBubble)>,
+) {
+    for EmoteReceived { id, emote } in emote_received.read() {
+        // Players who are out of range, or in another zone, aren't shown, so neither are their emotes.
+        if let Some((entity, _)) = other_players
+            .iter()
+            .find(|(_, other_player)| other_player.id() == *id)
+        {
+            spawn_emote_bubble(
+                &mut commands,
+                &asset_server,
+                &bubbles,
+                entity,
+                *emote as usize,
+            );
+        }
+    }
+}
+
+/// Replaces any emote already over a player's head with a new one.
+fn spawn_emote_bubble(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    bubbles: &Query<(Entity, &EmoteBubble)>,
+    player: Entity,
+    emote: usize,
+) {
+    for (entity, bubble) in bubbles.iter() {
+        if bubble.player == player {
+            commands.entity(entity).despawn();
+        }
+    }
+    commands.spawn((
+        StateScoped(OverworldState::InGame),
+        EmoteBubble { player },
+        EmoteAnimation::new(EMOTES[emote]),
+        Text::new(EMOTES[emote][0]),
+        TextColor(Color::BLACK),
+        TextFont {
+            font: asset_server.load("global/fonts/PetscopWide.ttf"),
+            font_size: EMOTE_FONT_SIZE,
+            font_smoothing: FontSmoothing::None,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            ..default()
+        },
+        // Hidden until it's been placed over the player.
+        Visibility::Hidden,
+    ));
+}
+
+/// Shows the current frame of each emote, and despawns emotes that have played their last frame.
+fn animate_emotes(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut emotes: Query<(Entity, &mut EmoteAnimation, &mut Text)>,
+) {
+    for (entity, mut animation, mut text) in emotes.iter_mut() {
+        match animation.advance(time.delta()) {
+            Some(frame) => {
+                if text.0 != frame {
+                    text.0 = frame.to_string();
+                }
+            }
+            None => commands.entity(entity).despawn(),
+        }
+    }
+}
+
+/// Moves each emote over its player's head, above their name tag, and despawns it once its player is gone.
+#[expect(
+    clippy::type_complexity,
+    reason = "The bubble query is only used here, so naming it wouldn't make it clearer."
+)]
+fn update_emote_bubbles(
+    mut commands: Commands,
+    camera: Single<(&Camera, &Transform), With<Camera3d>>,
+    players: Query<&Transform, (Or<(With<Player>, With<OtherPlayer>)>, Without<Camera3d>)>,
+    mut bubbles: Query<(
+        Entity,
+        &EmoteBubble,
+        &mut Node,
+        &mut Visibility,
+        &ComputedNode,
+    )>,
+) {
+    let (camera, camera_transform) = camera.into_inner();
+    // Like name tags, this runs before the camera's GlobalTransform catches up with it.
+    let camera_transform = GlobalTransform::from(*camera_transform);
+    for (entity, bubble, node, visibility, computed) in bubbles.iter_mut() {
+        let Ok(transform) = players.get(bubble.player) else {
+            commands.entity(entity).despawn();
+            continue;
+        };
+        place_over_head(
+            camera,
+            &camera_transform,
+            transform.translation,
+            NAME_TAG_FONT_SIZE + EMOTE_MARGIN,
+            computed,
+            node,
+            visibility,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emotes_play_every_frame_once() {
+        let mut animation = EmoteAnimation::new(EMOTES[1]);
+        let mut shown = vec![animation.advance(Duration::ZERO).unwrap()];
+        while let Some(frame) = animation.advance(EMOTE_FRAME_DURATION) {
+            shown.push(frame);
+        }
+        assert_eq!(shown, EMOTES[1]);
+    }
+
+    #[test]
+    fn slow_frames_skip_ahead() {
+        let mut animation = EmoteAnimation::new(EMOTES[0]);
+        assert_eq!(
+            animation.advance(EMOTE_FRAME_DURATION * 3),
+            Some(EMOTES[0][3])
+        );
+        // One long frame can finish the whole emote.
+        assert_eq!(animation.advance(EMOTE_FRAME_DURATION * 10), None);
+        assert_eq!(animation.advance(Duration::ZERO), None);
+    }
+}
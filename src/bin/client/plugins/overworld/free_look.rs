@@ -0,0 +1,122 @@
+use crate::plugins::overworld::{CameraFollowSet, OverworldState};
+use crate::AppState;
+use bevy::input::mouse::AccumulatedMouseMotion;
+use bevy::prelude::{
+    in_state, not, resource_exists, App, ButtonInput, Camera3d, Commands, EulerRot,
+    IntoScheduleConfigs, KeyCode, OnExit, Plugin, Quat, Res, ResMut, Resource, Single, Time,
+    Transform, Update, Vec3, With,
+};
+use tracing::info;
+
+/// A free-flying camera for inspecting the level and remote players.
+///
+/// Only exists with the debug feature enabled. Press F4 to toggle it, then fly with WASD, Space, Shift, and the mouse.
+pub struct FreeLookPlugin;
+impl Plugin for FreeLookPlugin {
+    fn build(&self, app: &mut App) {
+        app.configure_sets(
+            Update,
+            CameraFollowSet.run_if(not(resource_exists::<FreeLook>)),
+        )
+        .add_systems(
+            Update,
+            (
+                toggle_free_look,
+                fly_camera.run_if(resource_exists::<FreeLook>),
+            )
+                .chain()
+                .run_if(in_state(OverworldState::InGame)),
+        )
+        .add_systems(OnExit(AppState::Overworld), stop_free_look);
+    }
+}
+
+// Constants
+const TOGGLE_FREE_LOOK_KEY: KeyCode = KeyCode::F4;
+/// Meters per second.
+const FLY_SPEED: f32 = 8.0;
+/// Radians per pixel of mouse movement.
+const MOUSE_SENSITIVITY: f32 = 0.003;
+
+// Resources
+/// Exists while the free-look camera is active.
+#[derive(Resource)]
+struct FreeLook {
+    /// Where the follow camera was, so it can be put back when free-look is turned off.
+    follow_transform: Transform,
+    yaw: f32,
+    pitch: f32,
+}
+
+// Systems
+/// Detaches the camera from the player, or puts it back where it was.
+fn toggle_free_look(
+    mut commands: Commands,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    free_look: Option<Res<FreeLook>>,
+    mut camera_transform: Single<&mut Transform, With<Camera3d>>,
+) {
+    if !keyboard.just_pressed(TOGGLE_FREE_LOOK_KEY) {
+        return;
+    }
+
+    match free_look {
+        Some(free_look) => {
+            **camera_transform = free_look.follow_transform;
+            commands.remove_resource::<FreeLook>();
+            info!("Free-look camera disabled.");
+        }
+        None => {
+            let (yaw, pitch, _) = camera_transform.rotation.to_euler(EulerRot::YXZ);
+            commands.insert_resource(FreeLook {
+                follow_transform: **camera_transform,
+                yaw,
+                pitch,
+            });
+            info!("Free-look camera enabled.");
+        }
+    }
+}
+
+/// Moves the camera relative to where it's looking, and turns it with the mouse.
+fn fly_camera(
+    time: Res<Time>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mouse_motion: Res<AccumulatedMouseMotion>,
+    mut free_look: ResMut<FreeLook>,
+    mut camera_transform: Single<&mut Transform, With<Camera3d>>,
+) {
+    free_look.yaw -= mouse_motion.delta.x * MOUSE_SENSITIVITY;
+    free_look.pitch = (free_look.pitch - mouse_motion.delta.y * MOUSE_SENSITIVITY).clamp(
+        -std::f32::consts::FRAC_PI_2 + 0.01,
+        std::f32::consts::FRAC_PI_2 - 0.01,
+    );
+    camera_transform.rotation =
+        Quat::from_euler(EulerRot::YXZ, free_look.yaw, free_look.pitch, 0.0);
+
+    let mut direction = Vec3::ZERO;
+    if keyboard.pressed(KeyCode::KeyW) {
+        direction += *camera_transform.forward();
+    }
+    if keyboard.pressed(KeyCode::KeyS) {
+        direction -= *camera_transform.forward();
+    }
+    if keyboard.pressed(KeyCode::KeyD) {
+        direction += *camera_transform.right();
+    }
+    if keyboard.pressed(KeyCode::KeyA) {
+        direction -= *camera_transform.right();
+    }
+    if keyboard.pressed(KeyCode::Space) {
+        direction += Vec3::Y;
+    }
+    if keyboard.pressed(KeyCode::ShiftLeft) {
+        direction -= Vec3::Y;
+    }
+    camera_transform.translation += direction.normalize_or_zero() * FLY_SPEED * time.delta_secs();
+}
+
+/// The camera is despawned when leaving the overworld, so there is nothing to put back.
+fn stop_free_look(mut commands: Commands) {
+    commands.remove_resource::<FreeLook>();
+}
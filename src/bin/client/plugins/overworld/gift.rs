@@ -0,0 +1,125 @@
+use crate::plugins::overworld::multiplayer::ServerConnection;
+use crate::plugins::overworld::{OverworldAssetCollection, Player};
+use crate::plugins::settings::{AudioSettings, InputAction, InputBindings};
+use crate::AppState;
+use bevy::audio::{AudioPlayer, PlaybackMode, PlaybackSettings};
+use bevy::prelude::{
+    default, ButtonInput, Commands, Component, Event, EventReader, KeyCode, Query, Res, Single,
+    StateScoped, Transform, Vec3, Visibility, With, Without,
+};
+use bevy_sprite3d::{Sprite3dBuilder, Sprite3dParams};
+use miniscop::networking::Packet;
+use tracing::{error, info};
+
+// Constants
+const GIFT_TRANSLATION: Vec3 = Vec3::new(2.0, 0.4, -2.0);
+/// The gift image is 88 pixels wide, which makes it 0.8 meters wide.
+const GIFT_PIXELS_PER_METER: f32 = 110.0;
+/// How close the player has to be to open the gift, in meters.
+const INTERACT_RADIUS: f32 = 1.5;
+
+// Components
+/// The gift in the overworld. Only one player can open it, and then it comes back after a while.
+#[derive(Component)]
+pub struct Gift {
+    opened: bool,
+}
+
+// Events
+/// A player opened the gift. This includes this client's own player.
+#[derive(Event)]
+pub struct GiftOpened(pub u64);
+/// The server said whether the gift is open, without anyone opening it just now.
+#[derive(Event)]
+pub struct GiftStateChanged(pub bool);
+
+// Systems
+/// This runs every time a level finishes loading, but the gift lasts until the player leaves the overworld,
+/// so it's only spawned the first time.
+pub fn spawn_gift(
+    mut commands: Commands,
+    assets: Res<OverworldAssetCollection>,
+    mut sprite3d_params: Sprite3dParams,
+    gifts: Query<(), With<Gift>>,
+) {
+    if !gifts.is_empty() {
+        return;
+    }
+    commands.spawn((
+        StateScoped(AppState::Overworld),
+        Gift { opened: false },
+        Sprite3dBuilder {
+            image: assets.sprites.gift_image.clone(),
+            pixels_per_metre: GIFT_PIXELS_PER_METER,
+            double_sided: false,
+            unlit: true,
+            ..default()
+        }
+        .bundle(&mut sprite3d_params),
+        Transform::from_translation(GIFT_TRANSLATION),
+    ));
+}
+
+/// This system asks the server to open the gift when the player is next to it and presses the interact key.
+///
+/// The gift only opens once the server agrees, so two players can't both open it.
+pub fn open_gift_on_interact(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    bindings: Res<InputBindings>,
+    connection: Res<ServerConnection>,
+    player: Single<&Transform, With<Player>>,
+    gift: Single<(&Gift, &Transform), Without<Player>>,
+) {
+    let (gift, gift_transform) = gift.into_inner();
+    if bindings.just_pressed(&keyboard, InputAction::Interact)
+        && !gift.opened
+        && player.translation.distance(gift_transform.translation) <= INTERACT_RADIUS
+        && let Err(e) = connection.to_client.try_send(Packet::Interact { id: None })
+    {
+        error!("Failed to send interaction: {e}");
+    }
+}
+
+pub fn on_gift_opened(
+    mut commands: Commands,
+    assets: Res<OverworldAssetCollection>,
+    audio_settings: Res<AudioSettings>,
+    mut gift_opened: EventReader<GiftOpened>,
+    gift: Single<(&mut Gift, &mut Visibility)>,
+) {
+    let Some(GiftOpened(id)) = gift_opened.read().last() else {
+        return;
+    };
+    info!("Player {id} opened the gift.");
+
+    let (mut gift, mut visibility) = gift.into_inner();
+    gift.opened = true;
+    *visibility = Visibility::Hidden;
+    commands.spawn((
+        StateScoped(AppState::Overworld),
+        AudioPlayer::new(assets.sound_effects.walking[0].clone()),
+        PlaybackSettings {
+            mode: PlaybackMode::Despawn,
+            volume: audio_settings.sfx_volume(),
+            speed: 0.5,
+            ..default()
+        },
+    ));
+}
+
+pub fn on_gift_state_changed(
+    mut gift_state_changed: EventReader<GiftStateChanged>,
+    gift: Single<(&mut Gift, &mut Visibility)>,
+) {
+    let Some(GiftStateChanged(opened)) = gift_state_changed.read().last() else {
+        return;
+    };
+
+    let (mut gift, mut visibility) = gift.into_inner();
+    gift.opened = *opened;
+    *visibility = if *opened {
+        Visibility::Hidden
+    } else {
+        Visibility::Inherited
+    };
+}
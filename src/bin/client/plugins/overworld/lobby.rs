@@ -0,0 +1,332 @@
+use crate::plugins::overworld::multiplayer::{
+    read_lobby_packets, MultiplayerState, NetworkMode, ServerConnection,
+};
+use crate::plugins::overworld::{
+    CurrentLevel, LevelId, OverworldAssetCollection, OverworldState, SpawnPoint,
+};
+use crate::AppState;
+use bevy::prelude::{
+    default, in_state, resource_equals, resource_exists, AlignItems, App, AssetServer, ButtonInput,
+    Camera, Camera2d, ClearColorConfig, Color, Commands, Component, Condition, DetectChangesMut,
+    Event, EventReader, FlexDirection, IntoScheduleConfigs, JustifyContent, KeyCode, NextState,
+    Node, OnEnter, Plugin, PositionType, Query, Res, ResMut, Resource, Single, State, StateScoped,
+    Text, TextColor, TextFont, Update, Val, With, Without,
+};
+use bevy::text::FontSmoothing;
+use miniscop::networking::Packet;
+use tracing::{info, warn};
+
+/// Online, the overworld starts in a lobby that lists every level's room and how many players are in it.
+/// The player picks one, and the room's level loads once the server lets them in.
+///
+/// Offline, the lobby is skipped and the first level loads straight away.
+pub struct LobbyPlugin;
+impl Plugin for LobbyPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RoomListReceived>()
+            .add_systems(
+                OnEnter(OverworldState::Lobby),
+                (
+                    skip_lobby.run_if(resource_equals(NetworkMode::Offline)),
+                    spawn_lobby.run_if(resource_equals(NetworkMode::Online)),
+                ),
+            )
+            .add_systems(
+                Update,
+                (
+                    read_lobby_packets.run_if(
+                        in_state(MultiplayerState::Connecting)
+                            .and(resource_exists::<ServerConnection>),
+                    ),
+                    on_room_list_received,
+                    navigate_lobby,
+                    update_lobby,
+                )
+                    .chain()
+                    .run_if(in_state(OverworldState::Lobby).and(resource_exists::<LobbyMenu>)),
+            );
+    }
+}
+
+// Constants
+const SELECT_KEY: KeyCode = KeyCode::KeyZ;
+const BACK_KEY: KeyCode = KeyCode::Escape;
+const SELECTED_COLOR: Color = Color::BLACK;
+const UNSELECTED_COLOR: Color = Color::srgb(0.5, 0.5, 0.5);
+/// Full rooms can't be picked, so they're fainter than the rest.
+const FULL_COLOR: Color = Color::srgb(0.8, 0.8, 0.8);
+
+// Components
+/// One room in the lobby, in the order of LevelId::ALL.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+struct RoomOption(LevelId);
+/// The line under the rooms saying what the lobby is waiting for.
+#[derive(Component)]
+struct LobbyStatus;
+
+// Resources
+/// What the lobby knows about the rooms, and which one is selected.
+/// This only exists while the lobby is open online.
+#[derive(Resource, Default)]
+struct LobbyMenu {
+    selected: usize,
+    /// The latest Packet::RoomList. None until the server sends one.
+    rooms: Option<RoomList>,
+    /// The room the player picked, while the server decides whether to let them in.
+    joining: Option<LevelId>,
+}
+
+struct RoomList {
+    rooms: Vec<(u16, u32)>,
+    capacity: u32,
+}
+
+// Events
+/// The server sent how many players are in each room.
+#[derive(Event)]
+pub struct RoomListReceived {
+    pub rooms: Vec<(u16, u32)>,
+    pub capacity: u32,
+}
+
+/// Returns how many players are in a zone's room, from a Packet::RoomList.
+pub fn occupancy(rooms: &[(u16, u32)], zone: u16) -> u32 {
+    rooms
+        .iter()
+        .find(|&&(room, _)| room == zone)
+        .map_or(0, |&(_, players)| players)
+}
+
+/// Returns whether a zone's room has no space left, from a Packet::RoomList.
+pub fn room_is_full(rooms: &[(u16, u32)], capacity: u32, zone: u16) -> bool {
+    occupancy(rooms, zone) >= capacity
+}
+
+// Systems
+/// Offline, there are no rooms to pick, so the first level loads straight away.
+fn skip_lobby(mut next_state: ResMut<NextState<OverworldState>>) {
+    next_state.set(OverworldState::LoadingScreen);
+}
+
+fn spawn_lobby(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(LobbyMenu::default());
+    // Messages about the connection are black, so they can be read over the lobby like over a level.
+    commands.spawn((
+        StateScoped(OverworldState::Lobby),
+        Camera2d,
+        Camera {
+            clear_color: ClearColorConfig::Custom(Color::WHITE),
+            ..default()
+        },
+    ));
+
+    let font = TextFont {
+        font: asset_server.load("global/fonts/PetscopWide.ttf"),
+        font_size: 50.0,
+        font_smoothing: FontSmoothing::None,
+        ..default()
+    };
+    let small_font = TextFont {
+        font_size: 30.0,
+        ..font.clone()
+    };
+    commands
+        .spawn((
+            StateScoped(OverworldState::Lobby),
+            Node {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                row_gap: Val::Px(20.0),
+                ..default()
+            },
+        ))
+        .with_children(|lobby| {
+            lobby.spawn((
+                Text::new("Pick a Room"),
+                TextColor(SELECTED_COLOR),
+                font.clone(),
+            ));
+            for level in LevelId::ALL {
+                lobby.spawn((
+                    RoomOption(level),
+                    Text::new(level.name()),
+                    TextColor(UNSELECTED_COLOR),
+                    font.clone(),
+                ));
+            }
+            lobby.spawn((
+                LobbyStatus,
+                Text::default(),
+                TextColor(SELECTED_COLOR),
+                small_font.clone(),
+            ));
+            lobby.spawn((
+                Text::new("Press Escape to go Back to Menu"),
+                TextColor(SELECTED_COLOR),
+                small_font,
+            ));
+        });
+}
+
+/// Keeps the latest room list. A list arriving while joining means the room filled up first.
+fn on_room_list_received(
+    mut room_list_received: EventReader<RoomListReceived>,
+    mut menu: ResMut<LobbyMenu>,
+) {
+    let Some(room_list) = room_list_received.read().last() else {
+        return;
+    };
+    if let Some(level) = menu.joining.take() {
+        info!(
+            "{} filled up before this client could join it.",
+            level.name()
+        );
+    }
+    menu.rooms = Some(RoomList {
+        rooms: room_list.rooms.clone(),
+        capacity: room_list.capacity,
+    });
+}
+
+/// The arrow keys move the selection, and Z joins the selected room if it isn't full.
+/// Once offline, Z plays the selected room's level alone instead.
+#[expect(
+    clippy::too_many_arguments,
+    reason = "Bevy systems take one parameter per resource."
+)]
+fn navigate_lobby(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut menu: ResMut<LobbyMenu>,
+    connection: Option<Res<ServerConnection>>,
+    multiplayer_state: Res<State<MultiplayerState>>,
+    asset_server: Res<AssetServer>,
+    mut assets: ResMut<OverworldAssetCollection>,
+    mut current_level: ResMut<CurrentLevel>,
+    mut spawn_point: ResMut<SpawnPoint>,
+    mut next_overworld_state: ResMut<NextState<OverworldState>>,
+    mut next_app_state: ResMut<NextState<AppState>>,
+) {
+    if keyboard.just_pressed(BACK_KEY) {
+        // Leaving the overworld disconnects from the server with disconnect_on_exit.
+        next_app_state.set(AppState::MainMenu);
+        return;
+    }
+    if menu.joining.is_some() {
+        return;
+    }
+    let count = LevelId::ALL.len();
+    if keyboard.just_pressed(KeyCode::ArrowDown) {
+        menu.selected = (menu.selected + 1) % count;
+    }
+    if keyboard.just_pressed(KeyCode::ArrowUp) {
+        menu.selected = (menu.selected + count - 1) % count;
+    }
+    if !keyboard.just_pressed(SELECT_KEY) {
+        return;
+    }
+
+    let level = LevelId::ALL[menu.selected];
+    let offline = *multiplayer_state.get() == MultiplayerState::Offline;
+    if !offline {
+        let (Some(rooms), Some(connection)) = (&menu.rooms, connection) else {
+            return;
+        };
+        if room_is_full(&rooms.rooms, rooms.capacity, level.zone()) {
+            return;
+        }
+        if let Err(e) = connection
+            .to_client
+            .try_send(Packet::JoinRoom { zone: level.zone() })
+        {
+            warn!("Failed to join {}: {e}", level.name());
+            return;
+        }
+        info!("Joining {}.", level.name());
+        menu.joining = Some(level);
+    }
+
+    // The level starts loading while the server answers, so it's ready sooner.
+    if current_level.0 != level {
+        current_level.0 = level;
+        assets.switch_level(level, &asset_server);
+        if !spawn_point.assigned {
+            spawn_point.translation = level.config().spawn;
+        }
+    }
+    if offline {
+        info!("Playing {} offline.", level.name());
+        next_overworld_state.set(OverworldState::LoadingScreen);
+    }
+}
+
+/// Shows each room's occupancy, greys out full rooms, and says what the lobby is waiting for.
+fn update_lobby(
+    menu: Res<LobbyMenu>,
+    multiplayer_state: Res<State<MultiplayerState>>,
+    mut options: Query<(&RoomOption, &mut Text, &mut TextColor)>,
+    mut status: Single<&mut Text, (With<LobbyStatus>, Without<RoomOption>)>,
+) {
+    let selected = LevelId::ALL[menu.selected];
+    for (option, mut text, mut color) in options.iter_mut() {
+        let level = option.0;
+        let (label, full) = match &menu.rooms {
+            Some(rooms) if room_is_full(&rooms.rooms, rooms.capacity, level.zone()) => {
+                (format!("{} (Full)", level.name()), true)
+            }
+            Some(rooms) => (
+                format!(
+                    "{} ({}/{})",
+                    level.name(),
+                    occupancy(&rooms.rooms, level.zone()),
+                    rooms.capacity
+                ),
+                false,
+            ),
+            None => (level.name().to_string(), false),
+        };
+        text.set_if_neq(Text(label));
+        let new_color = if full {
+            FULL_COLOR
+        } else if level == selected {
+            SELECTED_COLOR
+        } else {
+            UNSELECTED_COLOR
+        };
+        color.set_if_neq(TextColor(new_color));
+    }
+
+    let message = match (multiplayer_state.get(), &menu.rooms, menu.joining) {
+        (MultiplayerState::Offline, ..) => "Not connected. Press Z to Play Offline".to_string(),
+        (_, _, Some(level)) => format!("Joining {}...", level.name()),
+        (_, None, None) => "Connecting...".to_string(),
+        (_, Some(_), None) => "Press Z to Join".to_string(),
+    };
+    status.set_if_neq(Text(message));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rooms_missing_from_the_list_are_empty() {
+        let rooms = [(0, 3), (2, 5)];
+        assert_eq!(occupancy(&rooms, 0), 3);
+        assert_eq!(occupancy(&rooms, 1), 0);
+        assert_eq!(occupancy(&rooms, 2), 5);
+    }
+
+    #[test]
+    fn rooms_at_capacity_are_full() {
+        let rooms = [(0, 4), (1, 3)];
+        assert!(room_is_full(&rooms, 4, 0));
+        assert!(!room_is_full(&rooms, 4, 1));
+        assert!(!room_is_full(&rooms, 4, 2));
+        // A server that lowered its capacity can have rooms over it.
+        assert!(room_is_full(&rooms, 2, 1));
+    }
+}
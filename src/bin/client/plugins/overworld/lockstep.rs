@@ -0,0 +1,237 @@
+use crate::plugins::overworld::animation::{
+    next_frame, standing_frame, turn_frame, AnimationDirection,
+};
+use crate::plugins::overworld::chat::ChatInput;
+use crate::plugins::overworld::multiplayer::ServerConnection;
+use crate::plugins::overworld::Player;
+use crate::plugins::settings::{InputAction, InputBindings};
+use avian3d::prelude::RigidBodyDisabled;
+use bevy::prelude::{
+    ButtonInput, Commands, Entity, Has, KeyCode, Res, ResMut, Resource, Single, Transform, Vec3,
+    With,
+};
+use miniscop::lockstep::{
+    direction, speed_per_tick, LockstepPlayer, MOVE_DOWN, MOVE_LEFT, MOVE_RIGHT, MOVE_UP,
+};
+use miniscop::networking::Packet;
+use std::collections::HashMap;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::warn;
+
+/// How many ticks each frame of another player's walk cycle lasts, about as long as this player's AnimationTimer.
+const WALK_FRAME_TICKS: u32 = 10;
+
+/// Everyone's lockstep movement, while the server is in lockstep mode.
+///
+/// Outside lockstep mode, this stays at its default.
+#[derive(Resource, Default)]
+pub struct Lockstep {
+    /// How far a player walks each tick. Set by Packet::LevelConfig, and None outside lockstep mode.
+    speed: Option<i32>,
+    /// This player, from the latest Packet::SetSpawn.
+    local: Option<LockstepPlayer>,
+    /// Other players in this zone, from Packet::PlayerRoster.
+    others: HashMap<u64, OtherLockstepPlayer>,
+}
+impl Lockstep {
+    /// Turns lockstep mode on or off for the level's move speed, as Packet::LevelConfig says.
+    pub fn configure(&mut self, lockstep: bool, move_speed: f32) {
+        self.speed = lockstep.then(|| speed_per_tick(move_speed));
+    }
+
+    /// Starts stepping this player from their spawn point, which restarts the ticks.
+    pub fn start_local(&mut self, spawn: Vec3) {
+        if self.speed.is_some() {
+            self.local = Some(LockstepPlayer::at(spawn.to_array()));
+        }
+    }
+
+    /// Starts stepping another player from where the roster says they are.
+    pub fn start_other(&mut self, id: u64, translation: Vec3, animation_frame: usize) {
+        if self.speed.is_some() {
+            self.others.insert(
+                id,
+                OtherLockstepPlayer {
+                    player: LockstepPlayer::at(translation.to_array()),
+                    animation_frame,
+                    walking_ticks: 0,
+                },
+            );
+        }
+    }
+
+    pub fn forget_other(&mut self, id: u64) {
+        self.others.remove(&id);
+    }
+
+    /// Steps another player by their input, and returns where they are and which frame they show.
+    ///
+    /// Returns None for players who aren't being stepped, which are the ones in other zones.
+    pub fn step_other(&mut self, id: u64, tick: u32, input: u8) -> Option<(Vec3, usize)> {
+        let speed = self.speed?;
+        let other = self.others.get_mut(&id)?;
+        if !other.player.step(tick, input, speed) {
+            // The server kicks players who skip ticks, so this only happens if it's broken.
+            warn!(
+                "Player {id} sent the input for tick {tick}, but tick {} was next.",
+                other.player.next_tick()
+            );
+            return None;
+        }
+        other.animate(input);
+        Some((
+            Vec3::from_array(other.player.position()),
+            other.animation_frame,
+        ))
+    }
+}
+
+/// Another player being stepped by their inputs, and their walk cycle, which is counted in ticks.
+struct OtherLockstepPlayer {
+    player: LockstepPlayer,
+    animation_frame: usize,
+    walking_ticks: u32,
+}
+impl OtherLockstepPlayer {
+    /// Animates one tick the same way animate_sprites does, starting on the first walking frame.
+    fn animate(&mut self, input: u8) {
+        let [x, z] = direction(input);
+        if x == 0 && z == 0 {
+            self.walking_ticks = 0;
+            self.animation_frame = standing_frame(self.animation_frame);
+            return;
+        }
+        self.animation_frame = turn_frame(self.animation_frame, Vec3::new(x as f32, 0.0, z as f32));
+        if self.walking_ticks.is_multiple_of(WALK_FRAME_TICKS) {
+            self.animation_frame = next_frame(self.animation_frame);
+        }
+        self.walking_ticks = self.walking_ticks.wrapping_add(1);
+    }
+}
+
+// Conditions
+/// Whether the server is in lockstep mode, where this client sends inputs instead of positions.
+pub fn lockstep_mode(lockstep: Res<Lockstep>) -> bool {
+    lockstep.speed.is_some()
+}
+
+// Systems
+/// This system sends the held directions as this tick's input, and steps the player by it.
+/// It replaces apply_controls and physics in lockstep mode.
+///
+/// Inputs aren't turned towards the camera, because every client has to step them the same way.
+pub fn apply_lockstep_controls(
+    mut commands: Commands,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    bindings: Res<InputBindings>,
+    chat_input: Option<Res<ChatInput>>,
+    connection: Res<ServerConnection>,
+    mut lockstep: ResMut<Lockstep>,
+    player: Single<
+        (
+            Entity,
+            &mut Transform,
+            &mut AnimationDirection,
+            Has<RigidBodyDisabled>,
+        ),
+        With<Player>,
+    >,
+) {
+    let Lockstep {
+        speed: Some(speed),
+        local: Some(local),
+        ..
+    } = &mut *lockstep
+    else {
+        // Waiting for Packet::SetSpawn.
+        return;
+    };
+    let (entity, mut transform, mut animation_direction, physics_disabled) = player.into_inner();
+    if !physics_disabled {
+        // Physics would move the player somewhere the other clients don't know about.
+        commands.entity(entity).insert(RigidBodyDisabled);
+    }
+
+    // Typing a chat message shouldn't walk the player around.
+    let typing = chat_input.is_some();
+    let pressed = |action: InputAction| !typing && bindings.pressed(&keyboard, action);
+    let input = [
+        (InputAction::MoveUp, MOVE_UP),
+        (InputAction::MoveDown, MOVE_DOWN),
+        (InputAction::MoveLeft, MOVE_LEFT),
+        (InputAction::MoveRight, MOVE_RIGHT),
+    ]
+    .into_iter()
+    .filter(|&(action, _)| pressed(action))
+    .fold(0, |input, (_, bit)| input | bit);
+
+    let tick = local.next_tick();
+    match connection.to_client.try_send(Packet::Input {
+        id: None,
+        tick,
+        input,
+    }) {
+        Ok(()) => {}
+        // The same tick is sent again next time, so the player just stands still for a tick.
+        Err(TrySendError::Full(_)) => return,
+        // read_packets notices the connection is gone.
+        Err(TrySendError::Closed(_)) => return,
+    }
+    local.step(tick, input, *speed);
+
+    let [x, z] = direction(input);
+    animation_direction.0 = Vec3::new(x as f32, 0.0, z as f32);
+    transform.translation = Vec3::from_array(local.position());
+}
+
+/// Leaves lockstep mode once offline, and gives the player back to physics.
+/// The server says whether it's in lockstep mode again after reconnecting.
+pub fn leave_lockstep(
+    mut commands: Commands,
+    mut lockstep: ResMut<Lockstep>,
+    player: Option<Single<Entity, With<Player>>>,
+) {
+    if lockstep.local.is_some()
+        && let Some(player) = player
+    {
+        commands.entity(*player).remove::<RigidBodyDisabled>();
+    }
+    *lockstep = Lockstep::default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_players_walk_and_stand_like_this_one() {
+        let mut lockstep = Lockstep::default();
+        lockstep.configure(true, 4.0);
+        lockstep.start_other(2, Vec3::ZERO, 0);
+
+        let (_, first) = lockstep.step_other(2, 0, MOVE_RIGHT).unwrap();
+        assert_eq!(first, next_frame(turn_frame(0, Vec3::X)));
+        for tick in 1..WALK_FRAME_TICKS {
+            assert_eq!(lockstep.step_other(2, tick, MOVE_RIGHT).unwrap().1, first);
+        }
+        let (_, second) = lockstep
+            .step_other(2, WALK_FRAME_TICKS, MOVE_RIGHT)
+            .unwrap();
+        assert_eq!(second, next_frame(first));
+
+        let (translation, standing) = lockstep.step_other(2, WALK_FRAME_TICKS + 1, 0).unwrap();
+        assert_eq!(standing, standing_frame(second));
+        // 11 ticks of 63 millimeters each.
+        assert_eq!(translation, Vec3::new(0.693, 0.0, 0.0));
+    }
+
+    #[test]
+    fn nobody_is_stepped_outside_lockstep_mode() {
+        let mut lockstep = Lockstep::default();
+        lockstep.configure(false, 4.0);
+        lockstep.start_local(Vec3::ZERO);
+        lockstep.start_other(2, Vec3::ZERO, 0);
+        assert!(lockstep.local.is_none());
+        assert_eq!(lockstep.step_other(2, 0, MOVE_RIGHT), None);
+    }
+}
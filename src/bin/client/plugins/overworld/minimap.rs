@@ -0,0 +1,181 @@
+use crate::plugins::overworld::chat::ChatInput;
+use crate::plugins::overworld::multiplayer::OtherPlayer;
+use crate::plugins::overworld::{OverworldState, Player};
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::{
+    default, in_state, not, resource_exists, App, BackgroundColor, BorderRadius, ButtonInput,
+    ChildOf, Color, Commands, Component, DetectChangesMut, Entity, IntoScheduleConfigs, KeyCode,
+    Node, OnEnter, Plugin, PositionType, Query, Res, ResMut, Resource, Single, StateScoped,
+    Transform, Update, Val, Vec2, Visibility, With,
+};
+use std::collections::HashSet;
+
+/// A top-down map in the corner of the screen, with the player in the middle and other players around them.
+///
+/// North on the map is -Z, the way the fixed camera looks. Press M to show or hide it.
+pub struct MinimapPlugin;
+impl Plugin for MinimapPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Minimap>()
+            .add_systems(OnEnter(OverworldState::InGame), spawn_minimap)
+            .add_systems(
+                Update,
+                (
+                    toggle_minimap.run_if(not(resource_exists::<ChatInput>)),
+                    update_minimap.run_if(minimap_visible),
+                )
+                    .chain()
+                    .run_if(in_state(OverworldState::InGame)),
+            );
+    }
+}
+
+// Constants
+const TOGGLE_MINIMAP_KEY: KeyCode = KeyCode::KeyM;
+/// How wide the minimap is on screen, in pixels.
+const MINIMAP_SIZE: f32 = 160.0;
+/// How wide each player's dot is, in pixels.
+const DOT_SIZE: f32 = 8.0;
+const BACKGROUND_COLOR: Color = Color::srgba(0.0, 0.0, 0.0, 0.25);
+const PLAYER_DOT_COLOR: Color = Color::WHITE;
+const OTHER_PLAYER_DOT_COLOR: Color = Color::srgb(0.9, 0.2, 0.2);
+
+// Components
+/// The circle the minimap is drawn in.
+#[derive(Component)]
+struct MinimapRoot;
+/// The dot for an OtherPlayer entity.
+#[derive(Component)]
+struct MinimapDot(Entity);
+
+// Resources
+/// How the minimap is shown. This lives for the whole session, so it's kept from one level to the next.
+#[derive(Resource)]
+pub struct Minimap {
+    pub visible: bool,
+    /// How many meters from the player the edge of the minimap is. Lower zooms in.
+    /// Players further away than this aren't shown.
+    pub range: f32,
+}
+impl Default for Minimap {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            range: 20.0,
+        }
+    }
+}
+
+// Systems
+fn minimap_visible(minimap: Res<Minimap>) -> bool {
+    minimap.visible
+}
+
+fn spawn_minimap(mut commands: Commands, minimap: Res<Minimap>) {
+    let root = commands
+        .spawn((
+            StateScoped(OverworldState::InGame),
+            MinimapRoot,
+            // Below where the server shutdown message goes, in the top right corner.
+            Node {
+                position_type: PositionType::Absolute,
+                top: Val::Px(50.0),
+                right: Val::Px(10.0),
+                width: Val::Px(MINIMAP_SIZE),
+                height: Val::Px(MINIMAP_SIZE),
+                ..default()
+            },
+            BackgroundColor(BACKGROUND_COLOR),
+            BorderRadius::MAX,
+            if minimap.visible {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            },
+        ))
+        .id();
+    commands.spawn((
+        ChildOf(root),
+        dot_node(Vec2::ZERO),
+        BackgroundColor(PLAYER_DOT_COLOR),
+        BorderRadius::MAX,
+    ));
+}
+
+/// Shows or hides the minimap. Dots stop updating while it's hidden.
+fn toggle_minimap(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut minimap: ResMut<Minimap>,
+    mut root: Single<&mut Visibility, With<MinimapRoot>>,
+) {
+    if !keyboard.just_pressed(TOGGLE_MINIMAP_KEY) {
+        return;
+    }
+    minimap.visible = !minimap.visible;
+    root.set_if_neq(if minimap.visible {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    });
+}
+
+/// Moves each other player's dot to where they are relative to the player, and hides the ones out of range.
+///
+/// Players get a dot the first time they're seen, and lose it once they despawn.
+fn update_minimap(
+    mut commands: Commands,
+    minimap: Res<Minimap>,
+    root: Single<Entity, With<MinimapRoot>>,
+    player: Single<&Transform, With<Player>>,
+    other_players: Query<(Entity, &Transform), With<OtherPlayer>>,
+    mut dots: Query<(Entity, &MinimapDot, &mut Node, &mut Visibility)>,
+) {
+    let center = player.translation.xz();
+    let mut has_dot = HashSet::new();
+    for (dot, MinimapDot(other_player), mut node, mut visibility) in dots.iter_mut() {
+        let Ok((_, transform)) = other_players.get(*other_player) else {
+            commands.entity(dot).despawn();
+            continue;
+        };
+        has_dot.insert(*other_player);
+        let offset = transform.translation.xz() - center;
+        if offset.length() > minimap.range {
+            visibility.set_if_neq(Visibility::Hidden);
+            continue;
+        }
+        visibility.set_if_neq(Visibility::Inherited);
+        node.set_if_neq(dot_node(offset / minimap.range));
+    }
+
+    for (other_player, transform) in other_players.iter() {
+        if has_dot.contains(&other_player) {
+            continue;
+        }
+        let offset = transform.translation.xz() - center;
+        commands.spawn((
+            ChildOf(*root),
+            MinimapDot(other_player),
+            dot_node(offset / minimap.range),
+            BackgroundColor(OTHER_PLAYER_DOT_COLOR),
+            BorderRadius::MAX,
+            if offset.length() > minimap.range {
+                Visibility::Hidden
+            } else {
+                Visibility::Inherited
+            },
+        ));
+    }
+}
+
+/// A dot at a point on the minimap, where (0, 0) is the middle and a length of 1 is the edge.
+fn dot_node(position: Vec2) -> Node {
+    let radius = MINIMAP_SIZE / 2.0;
+    Node {
+        position_type: PositionType::Absolute,
+        left: Val::Px(radius + position.x * radius - DOT_SIZE / 2.0),
+        top: Val::Px(radius + position.y * radius - DOT_SIZE / 2.0),
+        width: Val::Px(DOT_SIZE),
+        height: Val::Px(DOT_SIZE),
+        ..default()
+    }
+}
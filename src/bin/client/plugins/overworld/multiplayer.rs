@@ -1,22 +1,52 @@
 mod netcode;
 
-use crate::plugins::overworld::{OverworldAssetCollection, SPRITE_PIXELS_PER_METER};
+use crate::plugins::continue_prompt::{Continue, ContinuePrompt};
+use crate::plugins::overworld::animation::{
+    footstep_sound, reached_footstep, Billboard, FootstepSound, MAX_FOOTSTEP_SOUNDS,
+};
+use crate::plugins::overworld::chat::ChatReceived;
+use crate::plugins::overworld::emotes::EmoteReceived;
+use crate::plugins::overworld::gift::{GiftOpened, GiftStateChanged};
+use crate::plugins::overworld::lobby::{room_is_full, RoomListReceived};
+use crate::plugins::overworld::lockstep::Lockstep;
+use crate::plugins::overworld::physics::MovementTuning;
+use crate::plugins::overworld::profiling::{READ_PACKETS_TIME, SEND_POSITION_TIME};
+use crate::plugins::overworld::{
+    clamp_animation_frame, CurrentLevel, OverworldAssetCollection, OverworldState, SpawnPoint,
+    Teleported, SPRITE_PIXELS_PER_METER,
+};
+use crate::plugins::settings::{AudioSettings, GraphicsSettings};
+use crate::AppState;
+use bevy::diagnostic::Diagnostics;
+use bevy::ecs::system::SystemParam;
+use bevy::math::Vec3Swizzles;
+use bevy::platform::time::Instant;
 use bevy::prelude::{
-    default, Commands, Component, Entity, Event, EventReader, EventWriter, NextState, Query, Res,
-    ResMut, Resource, Single, StateScoped, States, TextureAtlas, Transform, Vec3,
+    default, Alpha, AlphaMode, AssetServer, Assets, ChildOf, Color, Commands, Component, Deref,
+    DerefMut, DetectChangesMut, Entity, Event, EventReader, EventWriter, Fixed, Has, KeyCode,
+    Local, MeshMaterial3d, NextState, Node, PositionType, Query, Real, Res, ResMut, Resource,
+    Single, StandardMaterial, StateScoped, States, Text, TextColor, TextFont, TextureAtlas, Time,
+    Timer, TimerMode, Transform, Trigger, Val, Vec3, Visibility, With, Without, World,
 };
+use bevy::text::FontSmoothing;
 use bevy::window::WindowCloseRequested;
 use bevy_sprite3d::{Sprite3d, Sprite3dBuilder, Sprite3dParams};
 use bevy_tnua::prelude::{TnuaBuiltinWalk, TnuaController};
-use miniscop::networking::Packet;
-use netcode::connect_to_server;
-use quinn::{Connection, Endpoint};
+use miniscop::networking::{
+    origin_tag, quantize_position, NetworkError, Packet, EMOTE_COUNT, HEARTBEAT_INTERVAL,
+    PING_INTERVAL, PROTOCOL_VERSION, QUANTIZED_MOVEMENTS_PER_RESYNC,
+};
+use netcode::{connect_to_server, ConnectToServerOutput};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::{Builder, Runtime};
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 // States
 #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, States)]
@@ -28,26 +58,369 @@ pub enum MultiplayerState {
     Online,
 }
 
+// Constants
+const DEFAULT_DISCONNECT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+/// How long other players take to fade in when they appear.
+const SPAWN_FADE_DURATION: Duration = Duration::from_millis(400);
+/// How big other players start when they appear, compared to their full size.
+const SPAWN_FADE_START_SCALE: f32 = 0.8;
+/// How many fixed ticks of walking a single movement packet may cover before it's treated as a corrupt teleport.
+/// Senders drop packets when their channel is full, so this leaves room for a few missing packets.
+const MAX_TICKS_PER_MOVEMENT: f32 = 10.0;
+/// After this many rejected movements in a row, the player is assumed to really be there, and snaps to it.
+const MAX_REJECTED_MOVEMENTS: u8 = 5;
+/// The least time between two footsteps of the same other player.
+/// The walk cycle steps about every 0.3 seconds, so this only cuts footsteps from movements arriving in bursts.
+const MIN_FOOTSTEP_INTERVAL: Duration = Duration::from_millis(200);
+/// Pings that haven't been answered after this long are forgotten, so a lost one can't wait forever.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for Packet::LevelConfig before giving up and playing with the client's defaults.
+const LEVEL_CONFIG_TIMEOUT: Duration = Duration::from_secs(5);
+/// The server players connect to unless they type a different one in the main menu.
+pub const DEFAULT_SERVER_HOST: &str = "miniscop.twilightparadox.com";
+pub const DEFAULT_SERVER_PORT: u16 = 4433;
+/// One fixed tick at Bevy's default rate of 64 Hz.
+const DEFAULT_INTERPOLATION_DELAY: Duration = Duration::from_micros(15_625);
+/// Positions that arrive further apart than this are snapped to instead of interpolated,
+/// because the player stood still or was teleported in between.
+const MAX_INTERPOLATION_GAP: Duration = Duration::from_millis(250);
+const DEFAULT_MAX_RECONNECT_ATTEMPTS: u32 = 5;
+const DEFAULT_FIRST_RECONNECT_DELAY: Duration = Duration::from_secs(2);
+/// Reconnect delays double after every failed attempt, but never grow past this.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How many recent movement sends SendThrottle remembers. It can never remember more than 32.
+const SEND_HISTORY_LENGTH: u32 = 32;
+/// If more than this many recent sends found the channel full, the client sends less often.
+const MAX_FULL_SENDS: u32 = 8;
+/// The most ticks the client waits between movement sends when throttled.
+const MAX_SEND_INTERVAL: u32 = 8;
+const RETRY_CONNECT_KEY: KeyCode = KeyCode::KeyR;
+const BACK_TO_MENU_KEY: KeyCode = KeyCode::KeyB;
+
 // Resources
+/// How long a disconnected player fades out before being despawned.
+/// If the same player moves again during this time, they stay.
+#[derive(Resource)]
+pub struct DisconnectGracePeriod(pub Duration);
+impl Default for DisconnectGracePeriod {
+    fn default() -> Self {
+        Self(DEFAULT_DISCONNECT_GRACE_PERIOD)
+    }
+}
+
 /// This resource keeps the async server connection alive.
 ///
 /// This is guaranteed to exist when MultiplayerState is Connecting or Online.
+/// It stays in MultiplayerState::Offline after losing the connection, so that it can reconnect.
 #[derive(Resource)]
 pub(crate) struct ServerConnection {
     runtime: Runtime,
-    pub connection_handle:
-        JoinHandle<anyhow::Result<(Endpoint, Connection, JoinHandle<()>, JoinHandle<()>)>>,
+    pub connection_handle: JoinHandle<Result<ConnectToServerOutput, NetworkError>>,
     pub to_client: Sender<Packet>,
     pub from_server: Receiver<Packet>,
+    host: String,
+    port: u16,
+    session_token: u64,
+    player_token: u64,
+    insecure: bool,
+    /// Set by the connecting task if connect_to_server returned an error.
+    connect_failed: Arc<AtomicBool>,
+    /// Whether the server ever sent Packet::ClientConnect. Reconnects keep this,
+    /// so only a first connection that never worked asks the player what to do.
+    was_online: bool,
+}
+
+/// How many times to try reconnecting after losing the connection, and how long to wait before the first try.
+#[derive(Resource)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub first_delay: Duration,
+}
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_RECONNECT_ATTEMPTS,
+            first_delay: DEFAULT_FIRST_RECONNECT_DELAY,
+        }
+    }
+}
+
+/// Counts reconnect attempts since the client was last online, and times the next one.
+#[derive(Resource, Default)]
+pub struct ReconnectAttempts {
+    attempts: u32,
+    /// None until the connection is lost, so the first delay can be read from ReconnectPolicy.
+    timer: Option<Timer>,
+}
+/// The shortest time other players take to move to each position the server sends.
+///
+/// Players usually take as long as the time since the position before, so they keep moving until the next one arrives.
+/// This stops positions that arrive in a burst from being jumped between.
+/// It should be about one fixed tick, which is as often as movement is sent.
+#[derive(Resource)]
+pub struct InterpolationDelay(pub Duration);
+impl Default for InterpolationDelay {
+    fn default() -> Self {
+        Self(DEFAULT_INTERPOLATION_DELAY)
+    }
+}
+
+/// Which server to connect to when entering the overworld.
+#[derive(Resource, Debug, Clone)]
+pub struct ServerAddress {
+    pub host: String,
+    pub port: u16,
+}
+impl Default for ServerAddress {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_SERVER_HOST.to_string(),
+            port: DEFAULT_SERVER_PORT,
+        }
+    }
 }
-// Todo: Add reconnecting support
+
+/// What other players see above this player's head, and next to their chat messages.
+/// If it's empty, they see "Player {id}" instead.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct PlayerName(pub String);
+
+/// The names other players sent with Packet::SetName, keyed by their ID.
+#[derive(Resource, Default)]
+pub struct PlayerNames(HashMap<u64, String>);
+impl PlayerNames {
+    /// Returns a player's name, or "Player {id}" if they haven't sent one yet.
+    pub fn name(&self, id: u64) -> String {
+        match self.0.get(&id) {
+            Some(name) => name.clone(),
+            None => format!("Player {id}"),
+        }
+    }
+}
+
+/// A random token that identifies this client to the server for as long as the game is open.
+/// If the client reconnects, the server uses it to give the client its old ID back.
+#[derive(Resource)]
+pub struct SessionToken(u64);
+impl Default for SessionToken {
+    fn default() -> Self {
+        Self(rand::random())
+    }
+}
+
+/// A random token that identifies this player to servers, which is saved in the settings so it lasts between launches.
+/// Servers use it to put the player back where they were when they last left.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct PlayerToken(pub u64);
+impl Default for PlayerToken {
+    fn default() -> Self {
+        Self(rand::random())
+    }
+}
+
+/// Whether to skip checking the server's certificate, which the client's --insecure flag turns on.
+///
+/// This is only for testing against a server on your own computer with a self-signed certificate.
+/// It must never be used with a public server, since anyone between you and it could pretend to be it.
+#[derive(Resource, Default)]
+pub struct InsecureMode(pub bool);
+
+/// Whether entering the overworld connects to a server, which the main menu chooses.
+///
+/// Offline, there's no ServerConnection or Tokio runtime at all, and MultiplayerState stays Offline,
+/// so none of the packet systems run.
+#[derive(Resource, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NetworkMode {
+    #[default]
+    Online,
+    Offline,
+}
+
+/// Slows down movement sends while the channel to the server keeps filling up, and speeds them back up once it drains.
+#[derive(Resource)]
+pub struct SendThrottle {
+    /// One bit per recent send, newest in the lowest bit. A bit is set if the channel was full.
+    history: u32,
+    /// How many sends the history holds so far, up to SEND_HISTORY_LENGTH.
+    recorded: u32,
+    /// How many ticks to wait between sends. 1 means every tick.
+    interval: u32,
+    ticks_since_send: u32,
+}
+impl Default for SendThrottle {
+    fn default() -> Self {
+        Self {
+            history: 0,
+            recorded: 0,
+            interval: 1,
+            ticks_since_send: 0,
+        }
+    }
+}
+impl SendThrottle {
+    pub fn is_throttled(&self) -> bool {
+        self.interval > 1
+    }
+
+    /// Returns whether enough ticks have passed to send again.
+    fn tick(&mut self) -> bool {
+        self.ticks_since_send = self.ticks_since_send.saturating_add(1);
+        self.ticks_since_send >= self.interval
+    }
+
+    /// Remembers whether a send found the channel full, and adjusts the send rate once the history is full.
+    fn record(&mut self, full: bool) {
+        self.ticks_since_send = 0;
+        self.history = (self.history << 1) | full as u32;
+        self.recorded = (self.recorded + 1).min(SEND_HISTORY_LENGTH);
+        if self.recorded < SEND_HISTORY_LENGTH {
+            return;
+        }
+
+        let full_sends = self.history.count_ones();
+        if full_sends > MAX_FULL_SENDS && self.interval < MAX_SEND_INTERVAL {
+            self.interval *= 2;
+            warn!(
+                "Packet channel keeps filling up, sending movement every {} ticks.",
+                self.interval
+            );
+        } else if full_sends == 0 && self.interval > 1 {
+            self.interval /= 2;
+            info!(
+                "Packet channel drained, sending movement every {} ticks.",
+                self.interval
+            );
+        } else {
+            return;
+        }
+        // Judge the new rate on its own sends.
+        self.history = 0;
+        self.recorded = 0;
+    }
+}
+
+/// Times Packet::Heartbeat, which tells the server the game hasn't frozen.
+#[derive(Resource, Deref, DerefMut)]
+pub struct HeartbeatTimer(Timer);
+impl Default for HeartbeatTimer {
+    fn default() -> Self {
+        Self(Timer::new(HEARTBEAT_INTERVAL, TimerMode::Repeating))
+    }
+}
+
+/// The last absolute position sent to the server, which quantized movements are sent relative to.
+#[derive(Resource, Default)]
+pub struct MovementOrigin {
+    /// None until the first movement is sent on this connection.
+    position: Option<[f32; 3]>,
+    /// How many Packet::QuantizedMovement have been sent since the position was.
+    quantized_since: u32,
+}
+
+/// The latest measurements of the connection, shown under the FPS counter.
+#[derive(Resource, Default)]
+pub struct NetworkStats {
+    /// How long the latest Packet::Ping took to be answered. None until the first answer arrives.
+    pub rtt: Option<Duration>,
+}
+
+/// Times Packet::Ping, and remembers when each unanswered ping was sent.
+#[derive(Resource)]
+pub struct PendingPings {
+    timer: Timer,
+    next_nonce: u64,
+    sent: HashMap<u64, Instant>,
+}
+impl Default for PendingPings {
+    fn default() -> Self {
+        Self {
+            timer: Timer::new(PING_INTERVAL, TimerMode::Repeating),
+            next_nonce: 0,
+            sent: HashMap::new(),
+        }
+    }
+}
+
+/// This resource exists until the server sends Packet::LevelConfig, or until the timer runs out.
+#[derive(Resource, Deref, DerefMut)]
+pub struct LevelConfigTimeout(Timer);
+
 impl ServerConnection {
+    /// Starts connecting to the server on a new runtime. Check connection_handle to see if it worked.
+    fn connect(
+        host: String,
+        port: u16,
+        session_token: u64,
+        player_token: u64,
+        insecure: bool,
+    ) -> Self {
+        let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+        let (to_client, from_bevy) = mpsc::channel::<Packet>(128);
+        let (to_bevy, from_server) = mpsc::channel::<Packet>(128);
+        let connection_host = host.clone();
+        let connect_failed = Arc::new(AtomicBool::new(false));
+        let task_connect_failed = connect_failed.clone();
+        let connection_handle = runtime.spawn(async move {
+            match connect_to_server(
+                connection_host,
+                port,
+                from_bevy,
+                to_bevy,
+                session_token,
+                player_token,
+                insecure,
+            )
+            .await
+            {
+                Ok(output) => Ok(output),
+                Err(e) => {
+                    // Report the error immediately, rather than waiting for the join handle to read it
+                    error!("Unable to connect to server: {e:#?}");
+                    task_connect_failed.store(true, Ordering::Relaxed);
+                    Err(e)
+                }
+            }
+        });
+
+        Self {
+            runtime,
+            connection_handle,
+            to_client,
+            from_server,
+            host,
+            port,
+            session_token,
+            player_token,
+            insecure,
+            connect_failed,
+            was_online: false,
+        }
+    }
+
+    /// Throws away the old connection and its tasks, and starts connecting to the same server again.
+    ///
+    /// This doesn't tell the server anything, since this is only called once the old connection is already lost.
+    pub(crate) fn reconnect(&mut self) {
+        let mut new_connection = Self::connect(
+            self.host.clone(),
+            self.port,
+            self.session_token,
+            self.player_token,
+            self.insecure,
+        );
+        new_connection.was_online = self.was_online;
+        let old_connection = std::mem::replace(self, new_connection);
+        // Blocking on the old tasks could freeze the game, so they are dropped without waiting.
+        old_connection.runtime.shutdown_background();
+    }
+
     /// Try to gracefully disconnect from the server.
     ///
     /// You can force a disconnection by removing the ServerConnection resource.
     #[tracing::instrument(skip(self))]
     pub(crate) fn try_disconnect(&mut self) -> anyhow::Result<()> {
-        self.to_client.try_send(Packet::ClientDisconnect(None))?;
+        self.to_client
+            .try_send(Packet::ClientDisconnect(None, None))?;
 
         let connect_to_server_output = self.runtime.block_on(&mut self.connection_handle)?;
         match connect_to_server_output {
@@ -70,169 +443,863 @@ impl ServerConnection {
 #[derive(Component)]
 pub struct OtherPlayer {
     id: u64,
+    rejected_movements: u8,
+    /// When this player's last footstep played, so movements arriving close together don't repeat it.
+    last_footstep: Option<Instant>,
+}
+impl OtherPlayer {
+    /// The ID the server gave this player.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+/// Added to an OtherPlayer when they disconnect. They are despawned when the timer finishes.
+#[derive(Component, Deref, DerefMut)]
+pub struct DisconnectGrace(pub Timer);
+/// Added to an OtherPlayer when they appear. They fade and grow in until the timer finishes.
+#[derive(Component)]
+pub struct SpawnFade {
+    timer: Timer,
+    /// The alpha mode to go back to once the fade is over, since blending is only needed while fading.
+    alpha_mode: Option<AlphaMode>,
+}
+
+/// Smooths a networked entity's translation between the positions the server sends.
+///
+/// Movement only arrives once per fixed tick at best, so without this, other players would visibly jump from spot to spot.
+/// Each new position is moved to from wherever the entity was shown when it arrived, over the time since the position
+/// before it, so players never jump however far apart positions arrive.
+/// The latest position is kept here rather than in the Transform, so the rendered translation never gets ahead of it.
+#[derive(Component)]
+pub struct InterpolationBuffer {
+    /// The two latest positions from the server and when they arrived, oldest first.
+    samples: [(Instant, Vec3); 2],
+    /// Where the entity was shown when the latest position arrived.
+    start: Vec3,
+}
+impl InterpolationBuffer {
+    /// Starts at a translation without moving.
+    fn at(translation: Vec3) -> Self {
+        let now = Instant::now();
+        Self {
+            samples: [(now, translation), (now, translation)],
+            start: translation,
+        }
+    }
+
+    /// The latest position from the server.
+    fn target(&self) -> Vec3 {
+        self.samples[1].1
+    }
+
+    /// Adds the latest position from the server, which the entity starts moving to from where it's shown now.
+    fn push(&mut self, received_at: Instant, translation: Vec3, min_duration: Duration) {
+        self.start = self.translation_at(received_at, min_duration);
+        self.samples = [self.samples[1], (received_at, translation)];
+    }
+
+    /// Returns where the entity is shown at a moment, on its way from where it was to the latest position.
+    ///
+    /// The move takes as long as the two latest positions arrived apart, but at least min_duration.
+    /// If they arrived more than MAX_INTERPOLATION_GAP apart, the player stood still or teleported in between,
+    /// so this snaps to the latest one instead.
+    fn translation_at(&self, moment: Instant, min_duration: Duration) -> Vec3 {
+        let [(previous_time, _), (latest_time, latest)] = self.samples;
+        let spacing = latest_time.saturating_duration_since(previous_time);
+        if spacing.is_zero() || spacing > MAX_INTERPOLATION_GAP {
+            return latest;
+        }
+        let t = moment.saturating_duration_since(latest_time).as_secs_f32()
+            / spacing.max(min_duration).as_secs_f32();
+        self.start.lerp(latest, t.min(1.0))
+    }
 }
 
+/// Text under the FPS counter showing NetworkStats' round trip time.
+#[derive(Component)]
+pub struct PingDisplay;
+/// Text telling the player that the server shut down or can't be played on,
+/// which stays up until they leave the overworld.
+#[derive(Component)]
+pub struct ServerShutdownMessage;
+/// Part of the prompt asking the player whether to try connecting again, after their first try failed.
+/// This is on the text, and on the entities waiting for each answer.
+#[derive(Component)]
+pub struct ConnectFailedPrompt;
+/// Text telling the player that their movement is being sent less often than usual.
+#[derive(Component)]
+pub struct CongestionIndicator;
+
 // Events
+/// Fired when the server says it is shutting down.
+#[derive(Event)]
+pub struct ServerShutDown;
+/// Fired when the server turns this client away for being on a different protocol version.
+#[derive(Event)]
+pub struct IncompatibleServer {
+    server_version: u32,
+}
 #[derive(Event)]
 pub struct OtherPlayerMoved {
     id: u64,
     translation: Vec3,
     animation_frame: usize,
+    teleported: bool,
 }
 #[derive(Event)]
 pub struct OtherPlayerDisconnected(u64);
+/// The server told every client to reset the level.
+#[derive(Event)]
+pub struct LevelReloaded;
+/// Every event read_packets can fire, since systems can't take more than 16 parameters.
+#[derive(SystemParam)]
+pub struct PacketEvents<'w> {
+    player_moved: EventWriter<'w, OtherPlayerMoved>,
+    player_disconnected: EventWriter<'w, OtherPlayerDisconnected>,
+    gift_opened: EventWriter<'w, GiftOpened>,
+    gift_state_changed: EventWriter<'w, GiftStateChanged>,
+    level_reloaded: EventWriter<'w, LevelReloaded>,
+    chat_received: EventWriter<'w, ChatReceived>,
+    server_shut_down: EventWriter<'w, ServerShutDown>,
+    incompatible_server: EventWriter<'w, IncompatibleServer>,
+    emote_received: EventWriter<'w, EmoteReceived>,
+}
 
 // Systems
 /// This system is not responsible for setting MultiplayerState to Online.
 /// Whichever system reads the packets should set MultiplayerState::Online when it receives Packet::ClientConnect.
 pub(crate) fn setup_client_runtime(
     mut commands: Commands,
+    session_token: Res<SessionToken>,
+    player_token: Res<PlayerToken>,
+    insecure_mode: Res<InsecureMode>,
+    server_address: Res<ServerAddress>,
     mut next_state: ResMut<NextState<MultiplayerState>>,
 ) {
     next_state.set(MultiplayerState::Connecting);
 
-    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
-    let (to_client, from_bevy) = mpsc::channel::<Packet>(128);
-    let (to_bevy, from_server) = mpsc::channel::<Packet>(128);
-    // Connect to server
-    let connection_handle = runtime.spawn(async move {
-        match connect_to_server(from_bevy, to_bevy).await {
-            Ok(output) => Ok(output),
-            Err(e) => {
-                // Report the error immediately, rather than waiting for the join handle to read it
-                error!("Unable to connect to server: {e:#?}");
-                Err(e)
-            }
-        }
-    });
+    let host = server_address.host.trim();
+    let host = if host.is_empty() {
+        warn!("No server host was given, so connecting to {DEFAULT_SERVER_HOST} instead.");
+        DEFAULT_SERVER_HOST.to_string()
+    } else {
+        host.to_string()
+    };
+    let port = server_address.port;
 
-    commands.insert_resource(ServerConnection {
-        runtime,
-        connection_handle,
-        to_client,
-        from_server,
-    });
+    commands.insert_resource(ServerConnection::connect(
+        host,
+        port,
+        session_token.0,
+        player_token.0,
+        insecure_mode.0,
+    ));
+    commands.insert_resource(ReconnectAttempts::default());
+    commands.insert_resource(LevelConfigTimeout(Timer::new(
+        LEVEL_CONFIG_TIMEOUT,
+        TimerMode::Once,
+    )));
 }
 
 /// This system reads incoming packets, and fires a matching event for each one.
 /// This system is responsible for setting MultiplayerState to Online whenever the server says it is connected.
-#[tracing::instrument(skip(connection, next_state, player_moved, player_disconnected))]
+#[expect(
+    clippy::too_many_arguments,
+    reason = "Every kind of packet updates its own resource or fires its own event."
+)]
+#[tracing::instrument(skip(
+    commands,
+    connection,
+    next_state,
+    tuning,
+    spawn_point,
+    player_names,
+    events,
+    current_level,
+    pending_pings,
+    network_stats,
+    diagnostics,
+    lockstep
+))]
 pub fn read_packets(
+    mut commands: Commands,
     mut connection: ResMut<ServerConnection>,
     mut next_state: ResMut<NextState<MultiplayerState>>,
-    mut player_moved: EventWriter<OtherPlayerMoved>,
-    mut player_disconnected: EventWriter<OtherPlayerDisconnected>,
+    mut tuning: ResMut<MovementTuning>,
+    mut spawn_point: ResMut<SpawnPoint>,
+    mut player_names: ResMut<PlayerNames>,
+    mut events: PacketEvents,
+    current_level: Res<CurrentLevel>,
+    mut pending_pings: ResMut<PendingPings>,
+    mut network_stats: ResMut<NetworkStats>,
+    mut diagnostics: Diagnostics,
+    mut lockstep: ResMut<Lockstep>,
 ) {
-    // let time = Instant::now();
-    while let Ok(packet) = connection.from_server.try_recv() {
+    let started = Instant::now();
+    let zone = current_level.0.zone();
+    loop {
+        let packet = match connection.from_server.try_recv() {
+            Ok(packet) => packet,
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => {
+                // The connection failed, or its tasks stopped without saying goodbye.
+                warn!("Lost the connection to the server.");
+                next_state.set(MultiplayerState::Offline);
+                break;
+            }
+        };
         match packet {
-            Packet::ClientConnect => next_state.set(MultiplayerState::Online),
-            Packet::ClientDisconnect(id) => match id {
+            Packet::Hello { .. }
+            | Packet::Heartbeat
+            | Packet::Ping { .. }
+            | Packet::QuantizedMovement { .. }
+            | Packet::JoinRoom { .. } => {
+                error!("Server sent {packet:?}. Please report this to the dev.");
+            }
+            // After reconnecting, the server asks which room to join again, and the player stays in the one they're in.
+            Packet::RoomList { rooms, capacity } => {
+                if room_is_full(&rooms, capacity, zone) {
+                    warn!(
+                        "{} filled up while this client was reconnecting, so it's playing offline.",
+                        current_level.0.name()
+                    );
+                    next_state.set(MultiplayerState::Offline);
+                } else if let Err(e) = connection.to_client.try_send(Packet::JoinRoom { zone }) {
+                    warn!("Failed to rejoin the room: {e}");
+                }
+            }
+            Packet::Pong { nonce } => match pending_pings.sent.remove(&nonce) {
+                Some(sent_at) => network_stats.rtt = Some(sent_at.elapsed()),
+                // It already timed out, or the server answered a ping this client never sent.
+                None => warn!("Received a pong for unknown ping {nonce}."),
+            },
+            Packet::ClientConnect { id } => {
+                info!("The server gave this client ID {id}.");
+                connection.was_online = true;
+                next_state.set(MultiplayerState::Online);
+            }
+            Packet::ClientDisconnect(id, reason) => match id {
                 None => next_state.set(MultiplayerState::Offline),
                 Some(id) => {
-                    player_disconnected.write(OtherPlayerDisconnected(id));
+                    match reason {
+                        Some(reason) => info!("Player {id} {reason}."),
+                        None => info!("Player {id} disconnected."),
+                    }
+                    player_names.0.remove(&id);
+                    lockstep.forget_other(id);
+                    events
+                        .player_disconnected
+                        .write(OtherPlayerDisconnected(id));
                 }
             },
+            // Players out of range fade out like they disconnected.
+            // Their next movement brings them back, the same way a quick reconnect does.
+            Packet::PlayerOutOfRange(id) => {
+                events
+                    .player_disconnected
+                    .write(OtherPlayerDisconnected(id));
+            }
             Packet::PlayerMovement {
                 id,
                 x,
                 y,
                 z,
                 animation_frame,
+                teleported,
+                zone: player_zone,
             } => {
-                player_moved.write(OtherPlayerMoved {
-                    id: id.expect("Server should send id of movement. Please report to dev."),
+                let id = id.expect("Server should send id of movement. Please report to dev.");
+                // Players in another zone fade out like they went out of range.
+                if player_zone != zone {
+                    events
+                        .player_disconnected
+                        .write(OtherPlayerDisconnected(id));
+                    continue;
+                }
+                let animation_frame = received_animation_frame(animation_frame);
+                events.player_moved.write(OtherPlayerMoved {
+                    id,
                     translation: Vec3::new(x, y, z),
-                    animation_frame: animation_frame as usize,
+                    animation_frame,
+                    teleported,
                 });
             }
+            Packet::ServerShutdown => {
+                info!("The server is shutting down.");
+                events.server_shut_down.write(ServerShutDown);
+                next_state.set(MultiplayerState::Offline);
+            }
+            Packet::VersionMismatch { server_version } => {
+                error!("The server is on protocol version {server_version}, but this client is on {PROTOCOL_VERSION}.");
+                events
+                    .incompatible_server
+                    .write(IncompatibleServer { server_version });
+                next_state.set(MultiplayerState::Offline);
+            }
+            Packet::MovementBatch(movements) => {
+                for (id, x, y, z, animation_frame, player_zone) in movements {
+                    if player_zone != zone {
+                        events
+                            .player_disconnected
+                            .write(OtherPlayerDisconnected(id));
+                        continue;
+                    }
+                    events.player_moved.write(OtherPlayerMoved {
+                        id,
+                        translation: Vec3::new(x, y, z),
+                        animation_frame: received_animation_frame(animation_frame),
+                        teleported: false,
+                    });
+                }
+            }
+            Packet::LevelConfig {
+                move_speed,
+                spawn,
+                lockstep: lockstep_mode,
+            } => {
+                info!("Received level config from server.");
+                // A broken server could send anything, and walking at NaN or a negative speed would break physics.
+                if move_speed.is_finite() && move_speed > 0.0 {
+                    tuning.max_velocity = move_speed;
+                } else {
+                    warn!(
+                        "Server sent a move speed of {move_speed}, which nobody could walk at. Keeping {}.",
+                        tuning.max_velocity
+                    );
+                }
+                if lockstep_mode {
+                    info!("The server is in lockstep mode, so this client sends its inputs.");
+                }
+                lockstep.configure(lockstep_mode, tuning.max_velocity);
+                // Packets arrive on separate streams, so SetSpawn might have arrived first.
+                if !spawn_point.assigned {
+                    spawn_point.translation = Vec3::from_array(spawn);
+                }
+                commands.remove_resource::<LevelConfigTimeout>();
+            }
+            Packet::SetSpawn { x, y, z } => {
+                info!("Received spawn point from server.");
+                spawn_point.translation = Vec3::new(x, y, z);
+                spawn_point.assigned = true;
+                lockstep.start_local(spawn_point.translation);
+            }
+            Packet::Interact { id } => {
+                events.gift_opened.write(GiftOpened(
+                    id.expect("Server should send id of interaction. Please report to dev."),
+                ));
+            }
+            Packet::GiftState { opened } => {
+                events.gift_state_changed.write(GiftStateChanged(opened));
+            }
+            Packet::ReloadLevel => {
+                events.level_reloaded.write(LevelReloaded);
+            }
+            Packet::PlayerRoster(players) => {
+                info!("Received {} other players from server.", players.len());
+                for (id, x, y, z, animation_frame, player_zone) in players {
+                    if player_zone != zone {
+                        continue;
+                    }
+                    let animation_frame = received_animation_frame(animation_frame);
+                    lockstep.start_other(id, Vec3::new(x, y, z), animation_frame);
+                    // Nobody has seen these players yet, so they appear in place rather than walking there.
+                    events.player_moved.write(OtherPlayerMoved {
+                        id,
+                        translation: Vec3::new(x, y, z),
+                        animation_frame,
+                        teleported: true,
+                    });
+                }
+            }
+            Packet::Chat {
+                id,
+                message,
+                global,
+            } => {
+                events.chat_received.write(ChatReceived {
+                    id: id.expect("Server should send id of chat. Please report to dev."),
+                    message,
+                    global,
+                });
+            }
+            Packet::SetName { id, name } => {
+                let id = id.expect("Server should send id of name. Please report to dev.");
+                info!("Player {id} is called {name:?}.");
+                player_names.0.insert(id, name);
+            }
+            Packet::Emote { id, emote } => {
+                let id = id.expect("Server should send id of emote. Please report to dev.");
+                if emote < EMOTE_COUNT {
+                    events.emote_received.write(EmoteReceived { id, emote });
+                } else {
+                    // It's from a newer build, which has emotes this one doesn't.
+                    warn!("Player {id} sent emote {emote}, which doesn't exist.");
+                }
+            }
+            Packet::Input { id, tick, input } => {
+                let id = id.expect("Server should send id of input. Please report to dev.");
+                // Players in other zones aren't stepped, so their inputs are skipped.
+                if let Some((translation, animation_frame)) = lockstep.step_other(id, tick, input) {
+                    events.player_moved.write(OtherPlayerMoved {
+                        id,
+                        translation,
+                        animation_frame,
+                        teleported: false,
+                    });
+                }
+            }
         }
     }
-    // info!("Took {:?}", time.elapsed());
+    diagnostics.add_measurement(&READ_PACKETS_TIME, || {
+        started.elapsed().as_secs_f64() * 1000.0
+    });
+}
+
+/// This system reads packets while the player is in the lobby, until the server lets them into a room.
+///
+/// The server only sends Packet::RoomList until then, and Packet::ClientConnect once the player has joined.
+/// Everything after ClientConnect is left in the channel for read_packets, which starts once the room's level loads.
+pub fn read_lobby_packets(
+    mut connection: ResMut<ServerConnection>,
+    mut next_multiplayer_state: ResMut<NextState<MultiplayerState>>,
+    mut next_overworld_state: ResMut<NextState<OverworldState>>,
+    mut room_list_received: EventWriter<RoomListReceived>,
+    mut server_shut_down: EventWriter<ServerShutDown>,
+    mut incompatible_server: EventWriter<IncompatibleServer>,
+) {
+    loop {
+        let packet = match connection.from_server.try_recv() {
+            Ok(packet) => packet,
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => {
+                warn!("Lost the connection to the server.");
+                next_multiplayer_state.set(MultiplayerState::Offline);
+                break;
+            }
+        };
+        match packet {
+            Packet::RoomList { rooms, capacity } => {
+                room_list_received.write(RoomListReceived { rooms, capacity });
+            }
+            Packet::ClientConnect { id } => {
+                info!("The server gave this client ID {id}.");
+                connection.was_online = true;
+                next_multiplayer_state.set(MultiplayerState::Online);
+                next_overworld_state.set(OverworldState::LoadingScreen);
+                return;
+            }
+            Packet::ClientDisconnect(None, _) => {
+                next_multiplayer_state.set(MultiplayerState::Offline);
+            }
+            Packet::ServerShutdown => {
+                info!("The server is shutting down.");
+                server_shut_down.write(ServerShutDown);
+                next_multiplayer_state.set(MultiplayerState::Offline);
+            }
+            Packet::VersionMismatch { server_version } => {
+                error!("The server is on protocol version {server_version}, but this client is on {PROTOCOL_VERSION}.");
+                incompatible_server.write(IncompatibleServer { server_version });
+                next_multiplayer_state.set(MultiplayerState::Offline);
+            }
+            packet => {
+                error!("Server sent {packet:?} before this client joined a room. Please report this to the dev.");
+            }
+        }
+    }
+}
+
+/// Clamps an animation frame from the server, so a buggy or malicious client can't crash the renderer.
+fn received_animation_frame(animation_frame: u8) -> usize {
+    let index = animation_frame as usize;
+    let frame = clamp_animation_frame(index);
+    if frame != index {
+        warn!("Server sent animation frame {index}, which doesn't exist. Showing {frame} instead.");
+    }
+    frame
+}
+
+/// This system resets the level when the server asks for it.
+///
+/// Other players are despawned, and reappear from the roster the server sends right after. The player goes back to their spawn point.
+/// The gift isn't touched, because the server sends its state right after too.
+pub fn on_level_reloaded(
+    mut commands: Commands,
+    mut level_reloaded: EventReader<LevelReloaded>,
+    mut spawn_point: ResMut<SpawnPoint>,
+    other_players: Query<Entity, With<OtherPlayer>>,
+) {
+    if level_reloaded.read().last().is_none() {
+        return;
+    }
+    info!("The server reloaded the level.");
+
+    for entity in other_players.iter() {
+        commands.entity(entity).despawn();
+    }
+    // Moves the player back to the spawn point
+    spawn_point.set_changed();
+}
+
+/// This system despawns every other player when this player goes to another level.
+///
+/// Players in the new level appear as soon as they move, like after the level reloads.
+pub fn despawn_other_players(
+    mut commands: Commands,
+    other_players: Query<Entity, With<OtherPlayer>>,
+) {
+    for entity in other_players.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
+/// This system gives up on receiving Packet::LevelConfig if the server takes too long to send it.
+pub fn wait_for_level_config(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut timeout: ResMut<LevelConfigTimeout>,
+) {
+    if timeout.tick(time.delta()).finished() {
+        warn!("Server did not send a level config in time. Using the client's defaults instead.");
+        commands.remove_resource::<LevelConfigTimeout>();
+    }
 }
 
 /// This system updates the transforms of other players, and spawns the player if they don't exist yet.
+///
+/// Movements that cover more ground than the player could have walked are rejected, unless they were flagged as teleports.
+/// If the player was disconnecting, they stop fading out. Their footsteps play from their sprite as their animation steps.
+/// A player who moves more than once before they're spawned, like from a roster and then their inputs, appears where they last moved to.
+#[expect(
+    clippy::too_many_arguments,
+    reason = "Moving, spawning and playing footsteps for other players each need their own resources."
+)]
 pub fn on_other_player_moved(
     mut commands: Commands,
     assets: Res<OverworldAssetCollection>,
+    tuning: Res<MovementTuning>,
+    graphics_settings: Res<GraphicsSettings>,
+    fixed_time: Res<Time<Fixed>>,
     mut sprite3d_params: Sprite3dParams,
+    audio_settings: Res<AudioSettings>,
+    mut last_walking_sound: Local<Option<usize>>,
+    footstep_sounds: Query<(), With<FootstepSound>>,
     mut player_moved: EventReader<OtherPlayerMoved>,
-    mut query: Query<(&OtherPlayer, &mut Transform, &mut Sprite3d)>,
+    delay: Res<InterpolationDelay>,
+    mut query: Query<(
+        Entity,
+        &mut OtherPlayer,
+        &mut InterpolationBuffer,
+        &mut Transform,
+        &mut Sprite3d,
+    )>,
+    disconnecting: Query<&MeshMaterial3d<StandardMaterial>, With<DisconnectGrace>>,
 ) {
+    // Vertical movement isn't limited by walking speed, so only horizontal distance is checked.
+    let max_distance =
+        tuning.max_velocity * fixed_time.timestep().as_secs_f32() * MAX_TICKS_PER_MOVEMENT;
+    let mut playing_footsteps = footstep_sounds.iter().count();
+    // Spawns only happen after this system, so spawning as soon as a new player moves would spawn them once per movement.
+    let mut new_players: Vec<&OtherPlayerMoved> = Vec::new();
+
     for movement in player_moved.read() {
         let mut found_player = false;
-        for (other_player, mut transform, mut sprite_3d) in query.iter_mut() {
+        for (entity, mut other_player, mut interpolated, mut transform, mut sprite_3d) in
+            query.iter_mut()
+        {
             if other_player.id == movement.id {
-                transform.translation = movement.translation;
-                sprite_3d.texture_atlas.as_mut().unwrap().index = movement.animation_frame;
                 found_player = true;
+
+                let distance = interpolated
+                    .target()
+                    .xz()
+                    .distance(movement.translation.xz());
+                if !movement.teleported
+                    && distance > max_distance
+                    && other_player.rejected_movements < MAX_REJECTED_MOVEMENTS
+                {
+                    other_player.rejected_movements += 1;
+                    warn!(
+                        "Rejected movement of player {} covering {distance:.2}m, more than the {max_distance:.2}m limit.",
+                        movement.id
+                    );
+                    continue;
+                }
+                other_player.rejected_movements = 0;
+
+                if movement.teleported || distance > max_distance {
+                    // Gliding across the level would look worse than snapping
+                    *interpolated = InterpolationBuffer::at(movement.translation);
+                    transform.translation = movement.translation;
+                } else {
+                    interpolated.push(Instant::now(), movement.translation, delay.0);
+                }
+                let atlas = sprite_3d.texture_atlas.as_mut().unwrap();
+                let stepped =
+                    !movement.teleported && reached_footstep(atlas.index, movement.animation_frame);
+                atlas.index = movement.animation_frame;
+                if stepped
+                    && playing_footsteps < MAX_FOOTSTEP_SOUNDS
+                    && other_player
+                        .last_footstep
+                        .is_none_or(|played| played.elapsed() >= MIN_FOOTSTEP_INTERVAL)
+                {
+                    other_player.last_footstep = Some(Instant::now());
+                    playing_footsteps += 1;
+                    // Parented to the sprite, so the footstep comes from wherever the player is shown.
+                    commands.spawn((
+                        footstep_sound(
+                            &assets.sound_effects.walking,
+                            &audio_settings,
+                            &mut last_walking_sound,
+                            true,
+                        ),
+                        Transform::default(),
+                        ChildOf(entity),
+                    ));
+                }
+
+                if let Ok(material) = disconnecting.get(entity) {
+                    commands.entity(entity).remove::<DisconnectGrace>();
+                    if let Some(material) = sprite3d_params.materials.get_mut(material.id()) {
+                        material.base_color.set_alpha(1.0);
+                    }
+                }
             }
         }
         if !found_player {
-            commands.spawn((
-                StateScoped(MultiplayerState::Online),
-                OtherPlayer { id: movement.id },
-                Sprite3dBuilder {
-                    image: assets.sprites.other_player_image.clone(),
-                    pixels_per_metre: SPRITE_PIXELS_PER_METER,
-                    double_sided: false,
-                    unlit: true,
-                    ..default()
-                }
-                .bundle_with_atlas(
-                    &mut sprite3d_params,
-                    TextureAtlas {
-                        layout: assets.sprites.sprite_layout.clone(),
-                        index: movement.animation_frame,
-                    },
-                ),
-                Transform::from_translation(movement.translation),
-            ));
+            match new_players.iter_mut().find(|spawn| spawn.id == movement.id) {
+                Some(spawn) => *spawn = movement,
+                None => new_players.push(movement),
+            }
+        }
+    }
+    for movement in new_players {
+        let mut entity = commands.spawn((
+            StateScoped(MultiplayerState::Online),
+            OtherPlayer {
+                id: movement.id,
+                rejected_movements: 0,
+                last_footstep: None,
+            },
+            InterpolationBuffer::at(movement.translation),
+            Sprite3dBuilder {
+                image: assets.sprites.other_player_image.clone(),
+                pixels_per_metre: SPRITE_PIXELS_PER_METER,
+                double_sided: graphics_settings.double_sided_sprites,
+                unlit: !graphics_settings.lit_sprites,
+                ..default()
+            }
+            .bundle_with_atlas(
+                &mut sprite3d_params,
+                TextureAtlas {
+                    layout: assets.sprites.sprite_layout.clone(),
+                    index: movement.animation_frame,
+                },
+            ),
+            Transform::from_translation(movement.translation),
+            Billboard,
+        ));
+        if graphics_settings.spawn_animations {
+            entity.insert(SpawnFade {
+                timer: Timer::new(SPAWN_FADE_DURATION, TimerMode::Once),
+                alpha_mode: None,
+            });
         }
     }
 }
 
+/// This system moves every InterpolationBuffer entity along towards its latest position.
+pub fn interpolate_networked_entities(
+    delay: Res<InterpolationDelay>,
+    mut query: Query<(&InterpolationBuffer, &mut Transform)>,
+) {
+    let now = Instant::now();
+    for (interpolated, mut transform) in query.iter_mut() {
+        transform.translation = interpolated.translation_at(now, delay.0);
+    }
+}
+
+/// This system starts fading out disconnected players.
+///
+/// Sprite3d materials are shared between sprites, so each fading player gets its own copy to fade.
 pub fn on_other_player_disconnected(
     mut commands: Commands,
+    grace_period: Res<DisconnectGracePeriod>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
     mut players_disconnected: EventReader<OtherPlayerDisconnected>,
-    query: Query<(&OtherPlayer, Entity)>,
+    query: Query<
+        (&OtherPlayer, Entity, &MeshMaterial3d<StandardMaterial>),
+        Without<DisconnectGrace>,
+    >,
 ) {
     for player_disconnected in players_disconnected.read() {
-        for (other_player, entity) in query.iter() {
-            if other_player.id == player_disconnected.0 {
-                if let Ok(mut entity) = commands.get_entity(entity) {
+        for (other_player, entity, material) in query.iter() {
+            if other_player.id == player_disconnected.0
+                && let Ok(mut entity) = commands.get_entity(entity)
+            {
+                let Some(mut fading_material) = materials.get(material.id()).cloned() else {
                     entity.despawn();
-                }
+                    continue;
+                };
+                fading_material.alpha_mode = AlphaMode::Blend;
+                entity.remove::<SpawnFade>().insert((
+                    MeshMaterial3d(materials.add(fading_material)),
+                    DisconnectGrace(Timer::new(grace_period.0, TimerMode::Once)),
+                ));
+            }
+        }
+    }
+}
+
+/// This system fades out disconnected players, and despawns them once their grace period is over.
+pub fn fade_disconnected_players(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut query: Query<(
+        Entity,
+        &mut DisconnectGrace,
+        &MeshMaterial3d<StandardMaterial>,
+    )>,
+) {
+    for (entity, mut grace, material) in query.iter_mut() {
+        grace.tick(time.delta());
+        if grace.finished() {
+            commands.entity(entity).despawn();
+        } else if let Some(material) = materials.get_mut(material.id()) {
+            material.base_color = Color::WHITE.with_alpha(grace.fraction_remaining());
+        }
+    }
+}
+
+/// This system fades and grows in players who just appeared.
+///
+/// Like fading out, each fading player gets its own copy of their shared Sprite3d material.
+pub fn fade_in_spawned_players(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut query: Query<(
+        Entity,
+        &mut SpawnFade,
+        &mut MeshMaterial3d<StandardMaterial>,
+        &mut Transform,
+    )>,
+) {
+    for (entity, mut fade, mut material, mut transform) in query.iter_mut() {
+        if fade.alpha_mode.is_none() {
+            let Some(mut fading_material) = materials.get(material.id()).cloned() else {
+                commands.entity(entity).remove::<SpawnFade>();
+                continue;
+            };
+            fade.alpha_mode = Some(fading_material.alpha_mode);
+            fading_material.alpha_mode = AlphaMode::Blend;
+            material.0 = materials.add(fading_material);
+        }
+
+        fade.timer.tick(time.delta());
+        let progress = fade.timer.fraction();
+        transform.scale =
+            Vec3::splat(SPAWN_FADE_START_SCALE + (1.0 - SPAWN_FADE_START_SCALE) * progress);
+        if let Some(material) = materials.get_mut(material.id()) {
+            material.base_color = Color::WHITE.with_alpha(progress);
+            if fade.timer.finished() {
+                material.alpha_mode = fade.alpha_mode.unwrap();
             }
         }
+        if fade.timer.finished() {
+            commands.entity(entity).remove::<SpawnFade>();
+        }
     }
 }
 
 /// This system should be scheduled to run after the physics simulation.
+///
+/// It must only run in MultiplayerState::Online, which is set once the server sends Packet::ClientConnect.
+/// Netcode also drops any movement sent before then.
+#[expect(
+    clippy::too_many_arguments,
+    reason = "The throttle, origin and level are separate resources, since other systems reset them."
+)]
 pub fn send_current_position(
+    mut commands: Commands,
     connection: Res<ServerConnection>,
+    mut throttle: ResMut<SendThrottle>,
     mut next_state: ResMut<NextState<MultiplayerState>>,
-    position: Single<(&TnuaController, &Transform, &Sprite3d)>,
+    mut warned_about_frame: Local<bool>,
+    mut origin: ResMut<MovementOrigin>,
+    current_level: Res<CurrentLevel>,
+    position: Single<(
+        Entity,
+        &TnuaController,
+        &Transform,
+        &Sprite3d,
+        Has<Teleported>,
+    )>,
+    mut diagnostics: Diagnostics,
 ) {
-    let (controller, transform, sprite_3d) = position.into_inner();
+    let started = Instant::now();
+    let (entity, controller, transform, sprite_3d, teleported) = position.into_inner();
     let (_, walk_state) = controller
         .concrete_basis::<TnuaBuiltinWalk>()
         .expect("The player should have a walk state.");
     let velocity = walk_state.running_velocity;
 
-    if velocity.length() > 0.001 {
-        let packet = Packet::PlayerMovement {
-            id: None,
-            x: transform.translation.x,
-            y: transform.translation.y,
-            z: transform.translation.z,
-            animation_frame: u8::try_from(sprite_3d.texture_atlas.as_ref().unwrap().index)
-                .expect("Sprite atlas index should fit within 0 and 255"),
+    // Teleports skip the throttle, because other clients would reject the jump without them.
+    let ready = throttle.tick();
+    if (velocity.length() > 0.001 && ready) || teleported {
+        let index = sprite_3d.texture_atlas.as_ref().unwrap().index;
+        let frame = clamp_animation_frame(index);
+        if frame != index && !*warned_about_frame {
+            warn!("Sprite atlas index {index} doesn't exist, sending {frame} instead.");
+            *warned_about_frame = true;
+        }
+        // SPRITE_ATLAS_FRAMES is checked to fit in a u8, so this can't truncate.
+        let animation_frame = frame as u8;
+        let translation = transform.translation.to_array();
+        // Every few movements, and whenever an offset wouldn't work, the full position is sent as a new origin.
+        let offset = origin
+            .position
+            .filter(|_| !teleported && origin.quantized_since < QUANTIZED_MOVEMENTS_PER_RESYNC)
+            .and_then(|position| {
+                quantize_position(translation, position).map(|offset| (position, offset))
+            });
+        let packet = match offset {
+            Some((position, [x, y, z])) => Packet::QuantizedMovement {
+                origin: origin_tag(position),
+                x,
+                y,
+                z,
+                animation_frame,
+            },
+            None => Packet::PlayerMovement {
+                id: None,
+                x: translation[0],
+                y: translation[1],
+                z: translation[2],
+                animation_frame,
+                teleported,
+                zone: current_level.0.zone(),
+            },
         };
         match connection.to_client.try_send(packet) {
-            Ok(_) => {}
+            Ok(_) => {
+                throttle.record(false);
+                if offset.is_some() {
+                    origin.quantized_since += 1;
+                } else {
+                    *origin = MovementOrigin {
+                        position: Some(translation),
+                        quantized_since: 0,
+                    };
+                }
+                if teleported {
+                    commands.entity(entity).remove::<Teleported>();
+                }
+            }
             Err(TrySendError::Full(_)) => {
                 info!("Packet channel is full, packet not sent.");
+                throttle.record(true);
             }
             Err(TrySendError::Closed(_)) => {
                 error!("Packet channel is closed, no longer sending packets.");
@@ -240,6 +1307,371 @@ pub fn send_current_position(
             }
         }
     }
+    diagnostics.add_measurement(&SEND_POSITION_TIME, || {
+        started.elapsed().as_secs_f64() * 1000.0
+    });
+}
+
+/// This system tries to reconnect after losing the connection, waiting twice as long after every failed attempt.
+///
+/// It gives up after ReconnectPolicy's max attempts, and the player keeps playing offline.
+/// Other players are scoped to MultiplayerState::Online, so they were already despawned and won't be duplicated.
+pub fn retry_connection(
+    mut commands: Commands,
+    time: Res<Time>,
+    policy: Res<ReconnectPolicy>,
+    mut reconnect: ResMut<ReconnectAttempts>,
+    mut connection: ResMut<ServerConnection>,
+    mut next_state: ResMut<NextState<MultiplayerState>>,
+) {
+    if reconnect.attempts >= policy.max_attempts {
+        return;
+    }
+    let attempts = reconnect.attempts;
+    let timer = reconnect.timer.get_or_insert_with(|| {
+        let delay = policy
+            .first_delay
+            .saturating_mul(2u32.saturating_pow(attempts))
+            .min(MAX_RECONNECT_DELAY);
+        info!("Reconnecting in {} seconds.", delay.as_secs_f32());
+        Timer::new(delay, TimerMode::Once)
+    });
+    if !timer.tick(time.delta()).finished() {
+        return;
+    }
+
+    reconnect.attempts += 1;
+    reconnect.timer = None;
+    info!(
+        "Reconnecting to the server, attempt {} of {}.",
+        reconnect.attempts, policy.max_attempts
+    );
+    if reconnect.attempts == policy.max_attempts {
+        info!("This is the last attempt. If it fails, the game stays offline.");
+    }
+    connection.reconnect();
+    next_state.set(MultiplayerState::Connecting);
+    commands.insert_resource(LevelConfigTimeout(Timer::new(
+        LEVEL_CONFIG_TIMEOUT,
+        TimerMode::Once,
+    )));
+}
+
+/// Tells the player the server shut down, and stops trying to reconnect, since there's nothing to reconnect to.
+pub fn on_server_shut_down(
+    commands: Commands,
+    asset_server: Res<AssetServer>,
+    policy: Res<ReconnectPolicy>,
+    mut reconnect: ResMut<ReconnectAttempts>,
+    mut server_shut_down: EventReader<ServerShutDown>,
+) {
+    if server_shut_down.read().count() == 0 {
+        return;
+    }
+    reconnect.attempts = policy.max_attempts;
+    spawn_server_shutdown_message(
+        commands,
+        &asset_server,
+        "The server shut down. You are playing offline.".to_string(),
+    );
+}
+
+/// Tells the player their game is too old or too new for the server, and stops trying to reconnect,
+/// since every attempt would be turned away the same way.
+pub fn on_incompatible_server(
+    commands: Commands,
+    asset_server: Res<AssetServer>,
+    policy: Res<ReconnectPolicy>,
+    mut reconnect: ResMut<ReconnectAttempts>,
+    mut incompatible_server: EventReader<IncompatibleServer>,
+) {
+    let Some(incompatible) = incompatible_server.read().last() else {
+        return;
+    };
+    reconnect.attempts = policy.max_attempts;
+    let update = if incompatible.server_version > PROTOCOL_VERSION {
+        "Update your game"
+    } else {
+        "The server has to be updated"
+    };
+    spawn_server_shutdown_message(
+        commands,
+        &asset_server,
+        format!("{update} to play online. You are playing offline."),
+    );
+}
+
+fn spawn_server_shutdown_message(
+    mut commands: Commands,
+    asset_server: &AssetServer,
+    message: String,
+) {
+    commands.spawn((
+        StateScoped(AppState::Overworld),
+        ServerShutdownMessage,
+        Text::new(message),
+        TextColor(Color::BLACK),
+        TextFont {
+            font: asset_server.load("global/fonts/PetscopWide.ttf"),
+            font_size: 30.0,
+            font_smoothing: FontSmoothing::None,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            right: Val::Px(10.0),
+            ..default()
+        },
+    ));
+}
+
+/// Notices when the first connection to a server fails, and asks the player whether to retry or go back to the menu.
+///
+/// Reconnecting to a server the client was already on doesn't ask, since retry_connection handles that on its own.
+pub fn detect_connect_failure(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    connection: Res<ServerConnection>,
+    policy: Res<ReconnectPolicy>,
+    mut reconnect: ResMut<ReconnectAttempts>,
+    mut next_state: ResMut<NextState<MultiplayerState>>,
+    prompts: Query<(), With<ConnectFailedPrompt>>,
+) {
+    if connection.was_online
+        || !prompts.is_empty()
+        || !connection.connection_handle.is_finished()
+        || !connection.connect_failed.load(Ordering::Relaxed)
+    {
+        return;
+    }
+    // Whatever the player answers, retry_connection shouldn't also try on its own.
+    reconnect.attempts = policy.max_attempts;
+    next_state.set(MultiplayerState::Offline);
+
+    commands.spawn((
+        StateScoped(AppState::Overworld),
+        ConnectFailedPrompt,
+        Text::new(format!(
+            "Could not connect to {}.\nPress R to Retry, or B to go Back to Menu.",
+            connection.host
+        )),
+        TextColor(Color::BLACK),
+        TextFont {
+            font: asset_server.load("global/fonts/PetscopWide.ttf"),
+            font_size: 30.0,
+            font_smoothing: FontSmoothing::None,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            right: Val::Px(10.0),
+            ..default()
+        },
+    ));
+    commands
+        .spawn((
+            StateScoped(AppState::Overworld),
+            ConnectFailedPrompt,
+            ContinuePrompt::key(RETRY_CONNECT_KEY),
+        ))
+        .observe(retry_after_connect_failure);
+    commands
+        .spawn((
+            StateScoped(AppState::Overworld),
+            ConnectFailedPrompt,
+            ContinuePrompt::key(BACK_TO_MENU_KEY),
+        ))
+        .observe(back_to_menu_after_connect_failure);
+}
+
+/// Throws away the failed connection and connects again from scratch, the same way entering the overworld does.
+fn retry_after_connect_failure(
+    _: Trigger<Continue>,
+    mut commands: Commands,
+    prompts: Query<Entity, With<ConnectFailedPrompt>>,
+) {
+    for entity in prompts.iter() {
+        commands.entity(entity).despawn();
+    }
+    commands.queue(|world: &mut World| {
+        if let Some(old_connection) = world.remove_resource::<ServerConnection>() {
+            // Blocking on the old tasks could freeze the game, so they are dropped without waiting.
+            old_connection.runtime.shutdown_background();
+        }
+    });
+    commands.run_system_cached(setup_client_runtime);
+}
+
+/// The prompt is scoped to the overworld, so leaving despawns it. disconnect_on_exit cleans up the connection.
+fn back_to_menu_after_connect_failure(
+    _: Trigger<Continue>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    next_state.set(AppState::MainMenu);
+}
+
+/// Once the client is back online, the next lost connection gets every reconnect attempt again.
+pub fn reset_reconnect_attempts(mut reconnect: ResMut<ReconnectAttempts>) {
+    *reconnect = ReconnectAttempts::default();
+}
+
+/// Tells the server what this player is called, unless they left their name empty.
+///
+/// This runs every time the client comes online, since the server forgets names when their connection ends.
+pub fn send_name(connection: Res<ServerConnection>, player_name: Res<PlayerName>) {
+    if player_name.0.trim().is_empty() {
+        return;
+    }
+    if let Err(e) = connection.to_client.try_send(Packet::SetName {
+        id: None,
+        name: player_name.0.clone(),
+    }) {
+        warn!("Failed to send name: {e}");
+    }
+}
+
+/// Forgets other players' names once offline. The server sends them again after reconnecting.
+pub fn forget_player_names(mut player_names: ResMut<PlayerNames>) {
+    player_names.0.clear();
+}
+
+/// A new connection has no origin yet, so the first movement sent on it is absolute.
+pub fn reset_movement_origin(mut origin: ResMut<MovementOrigin>) {
+    *origin = MovementOrigin::default();
+}
+
+/// This system sends Packet::Heartbeat every HEARTBEAT_INTERVAL.
+///
+/// It uses real time, so heartbeats keep going even if the game's clock is paused.
+pub fn send_heartbeat(
+    time: Res<Time<Real>>,
+    mut timer: ResMut<HeartbeatTimer>,
+    connection: Res<ServerConnection>,
+) {
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    if let Err(e) = connection.to_client.try_send(Packet::Heartbeat) {
+        warn!("Failed to send heartbeat: {e}");
+    }
+}
+
+/// This system sends Packet::Ping every PING_INTERVAL, and forgets pings that were never answered.
+///
+/// Like send_heartbeat, it uses real time, so pausing the game doesn't stop it.
+pub fn send_ping(
+    time: Res<Time<Real>>,
+    mut pending_pings: ResMut<PendingPings>,
+    connection: Res<ServerConnection>,
+) {
+    if !pending_pings.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    pending_pings.sent.retain(|nonce, sent_at| {
+        let answered_in_time = sent_at.elapsed() < PING_TIMEOUT;
+        if !answered_in_time {
+            warn!("Ping {nonce} was never answered.");
+        }
+        answered_in_time
+    });
+
+    let nonce = pending_pings.next_nonce;
+    pending_pings.next_nonce += 1;
+    match connection.to_client.try_send(Packet::Ping { nonce }) {
+        Ok(()) => {
+            pending_pings.sent.insert(nonce, Instant::now());
+        }
+        Err(e) => warn!("Failed to send ping: {e}"),
+    }
+}
+
+/// Starts measuring the connection from scratch, and shows the round trip time under the FPS counter.
+pub fn spawn_ping_display(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(PendingPings::default());
+    commands.insert_resource(NetworkStats::default());
+    commands.spawn((
+        StateScoped(MultiplayerState::Online),
+        PingDisplay,
+        Text::new("Ping: ..."),
+        TextColor(Color::BLACK),
+        TextFont {
+            font: asset_server.load("global/fonts/PetscopWide.ttf"),
+            font_size: 30.0,
+            font_smoothing: FontSmoothing::None,
+            ..default()
+        },
+        // The FPS overlay sits in the top left corner, with the same font size.
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(35.0),
+            left: Val::Px(0.0),
+            ..default()
+        },
+    ));
+}
+
+pub fn update_ping_display(
+    network_stats: Res<NetworkStats>,
+    mut display: Single<&mut Text, With<PingDisplay>>,
+) {
+    let text = match network_stats.rtt {
+        Some(rtt) => format!("Ping: {}ms", rtt.as_millis()),
+        None => "Ping: ...".to_string(),
+    };
+    display.set_if_neq(Text(text));
+}
+
+pub fn spawn_congestion_indicator(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(SendThrottle::default());
+    commands.spawn((
+        StateScoped(MultiplayerState::Online),
+        CongestionIndicator,
+        Text::new("Connection is slow"),
+        TextColor(Color::BLACK),
+        TextFont {
+            font: asset_server.load("global/fonts/PetscopWide.ttf"),
+            font_size: 30.0,
+            font_smoothing: FontSmoothing::None,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.0),
+            right: Val::Px(10.0),
+            ..default()
+        },
+        Visibility::Hidden,
+    ));
+}
+
+pub fn update_congestion_indicator(
+    throttle: Res<SendThrottle>,
+    mut indicator: Single<&mut Visibility, With<CongestionIndicator>>,
+) {
+    **indicator = if throttle.is_throttled() {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+}
+
+/// Disconnects from the server when the player leaves the overworld, so other players don't see them frozen in place.
+///
+/// Waiting for the server to confirm would freeze the game, so it happens on another thread.
+/// The connection is gone from the overworld's point of view as soon as this runs.
+pub(crate) fn disconnect_on_exit(world: &mut World) {
+    world
+        .resource_mut::<NextState<MultiplayerState>>()
+        .set(MultiplayerState::Offline);
+    let Some(mut server_connection) = world.remove_resource::<ServerConnection>() else {
+        return;
+    };
+    std::thread::spawn(move || match server_connection.try_disconnect() {
+        Ok(()) => info!("Successfully disconnected from server."),
+        Err(e) => error!("Unable to disconnect from server: {e:#?}"),
+    });
 }
 
 /// A system that tries to disconnect from the server when the window is closed.
@@ -263,3 +1695,401 @@ pub(crate) fn stop_client_runtime_on_window_close(
         next_state.set(MultiplayerState::Offline);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::continue_prompt::ContinuePromptPlugin;
+    use crate::plugins::overworld::{
+        LevelId, OverworldSongs, OverworldSoundEffects, OverworldSprites, SPRITE_ATLAS_COLUMNS,
+        SPRITE_ATLAS_ROWS, SPRITE_FRAME_PIXELS,
+    };
+    use bevy::diagnostic::DiagnosticsStore;
+    use bevy::prelude::{
+        in_state, not, resource_exists, App, AppExtStates, AssetApp, AssetPlugin, ButtonInput,
+        Condition, Font, Handle, Image, IntoScheduleConfigs, Mesh, MinimalPlugins, OnExit, State,
+        TextureAtlasLayout, UVec2, Update,
+    };
+    use bevy::state::app::StatesPlugin;
+    use bevy_sprite3d::Sprite3dCaches;
+    use miniscop::lockstep::{MOVE_RIGHT, MOVE_UP};
+
+    const TICK: Duration = DEFAULT_INTERPOLATION_DELAY;
+
+    /// An overworld that has just started connecting to a host that can't be looked up, so it fails straight away.
+    fn failing_connection_app() -> App {
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            AssetPlugin::default(),
+            ContinuePromptPlugin,
+        ))
+        .init_asset::<Font>()
+        .init_resource::<ButtonInput<KeyCode>>()
+        .init_state::<AppState>()
+        .init_state::<MultiplayerState>()
+        // Host names can't have a null byte in them, so looking this up fails without touching the network.
+        .insert_resource(ServerAddress {
+            host: "unresolvable\0host".to_string(),
+            port: DEFAULT_SERVER_PORT,
+        })
+        .init_resource::<SessionToken>()
+        .init_resource::<PlayerToken>()
+        .init_resource::<InsecureMode>()
+        .init_resource::<ReconnectPolicy>()
+        .init_resource::<ReconnectAttempts>()
+        .add_systems(OnExit(AppState::Overworld), disconnect_on_exit)
+        .add_systems(
+            Update,
+            detect_connect_failure.run_if(
+                not(in_state(MultiplayerState::Online)).and(resource_exists::<ServerConnection>),
+            ),
+        );
+        app.world_mut()
+            .resource_mut::<NextState<AppState>>()
+            .set(AppState::Overworld);
+        app.update();
+        app.world_mut()
+            .run_system_cached(setup_client_runtime)
+            .unwrap();
+        app
+    }
+
+    fn prompts(app: &mut App) -> usize {
+        app.world_mut()
+            .query_filtered::<(), With<ConnectFailedPrompt>>()
+            .iter(app.world())
+            .count()
+    }
+
+    /// Updates the app until the failed connection is noticed and the prompt is up.
+    fn wait_for_prompt(app: &mut App) {
+        let started = Instant::now();
+        while prompts(app) == 0 {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "The connection never failed."
+            );
+            std::thread::sleep(Duration::from_millis(10));
+            app.update();
+        }
+        // States change at the start of the next update.
+        app.update();
+        assert_eq!(
+            *app.world().resource::<State<MultiplayerState>>().get(),
+            MultiplayerState::Offline
+        );
+    }
+
+    fn press(app: &mut App, key: KeyCode) {
+        app.world_mut()
+            .resource_mut::<ButtonInput<KeyCode>>()
+            .press(key);
+        app.update();
+        // Nothing clears the input without InputPlugin, so the key would stay just pressed.
+        app.world_mut()
+            .resource_mut::<ButtonInput<KeyCode>>()
+            .clear();
+    }
+
+    #[test]
+    fn retrying_after_a_failed_connection_connects_again() {
+        let mut app = failing_connection_app();
+        wait_for_prompt(&mut app);
+        // The text and one prompt for each answer.
+        assert_eq!(prompts(&mut app), 3);
+
+        press(&mut app, RETRY_CONNECT_KEY);
+        assert_eq!(prompts(&mut app), 0);
+        // The failed connection set every attempt as used up, and the new connection starts over.
+        assert_eq!(app.world().resource::<ReconnectAttempts>().attempts, 0);
+        assert!(app.world().contains_resource::<ServerConnection>());
+
+        // The new connection fails the same way, so the player is asked again.
+        wait_for_prompt(&mut app);
+        assert_eq!(prompts(&mut app), 3);
+    }
+
+    #[test]
+    fn going_back_after_a_failed_connection_returns_to_the_menu() {
+        let mut app = failing_connection_app();
+        wait_for_prompt(&mut app);
+
+        press(&mut app, BACK_TO_MENU_KEY);
+        app.update();
+        assert_eq!(
+            *app.world().resource::<State<AppState>>().get(),
+            AppState::MainMenu
+        );
+        assert_eq!(prompts(&mut app), 0);
+        assert!(!app.world().contains_resource::<ServerConnection>());
+    }
+
+    /// An overworld in the default level, connected to a server that is really the returned sender.
+    fn connected_app() -> (App, Sender<Packet>) {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, StatesPlugin, AssetPlugin::default()))
+            .init_asset::<Image>()
+            .init_asset::<TextureAtlasLayout>()
+            .init_asset::<Mesh>()
+            .init_asset::<StandardMaterial>()
+            .init_resource::<Sprite3dCaches>()
+            .init_resource::<DiagnosticsStore>()
+            .init_state::<MultiplayerState>()
+            .init_resource::<MovementTuning>()
+            .init_resource::<SpawnPoint>()
+            .init_resource::<PlayerNames>()
+            .init_resource::<CurrentLevel>()
+            .init_resource::<PendingPings>()
+            .init_resource::<NetworkStats>()
+            .init_resource::<GraphicsSettings>()
+            .init_resource::<AudioSettings>()
+            .init_resource::<InterpolationDelay>()
+            .init_resource::<Lockstep>()
+            .add_event::<OtherPlayerMoved>()
+            .add_event::<OtherPlayerDisconnected>()
+            .add_event::<GiftOpened>()
+            .add_event::<GiftStateChanged>()
+            .add_event::<LevelReloaded>()
+            .add_event::<ChatReceived>()
+            .add_event::<ServerShutDown>()
+            .add_event::<IncompatibleServer>()
+            .add_event::<EmoteReceived>()
+            .add_systems(Update, (read_packets, on_other_player_moved).chain());
+
+        let world = app.world_mut();
+        let other_player_image = world.resource_mut::<Assets<Image>>().add(Image::default());
+        let sprite_layout =
+            world
+                .resource_mut::<Assets<TextureAtlasLayout>>()
+                .add(TextureAtlasLayout::from_grid(
+                    UVec2::splat(SPRITE_FRAME_PIXELS),
+                    SPRITE_ATLAS_COLUMNS,
+                    SPRITE_ATLAS_ROWS,
+                    None,
+                    None,
+                ));
+        let level_id = LevelId::default();
+        world.insert_resource(OverworldAssetCollection {
+            level: Handle::default(),
+            level_id,
+            level_config: level_id.config(),
+            sprites: OverworldSprites {
+                guardian_image: Handle::default(),
+                other_player_image,
+                gift_image: Handle::default(),
+                sprite_layout,
+            },
+            sound_effects: OverworldSoundEffects {
+                walking: Vec::new(),
+            },
+            songs: OverworldSongs {
+                level: Handle::default(),
+            },
+        });
+
+        // Nothing is really connected, so the connecting task never finishes.
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let connection_handle = runtime.spawn(std::future::pending());
+        let (to_client, _from_bevy) = mpsc::channel::<Packet>(128);
+        let (to_bevy, from_server) = mpsc::channel::<Packet>(128);
+        world.insert_resource(ServerConnection {
+            runtime,
+            connection_handle,
+            to_client,
+            from_server,
+            host: String::new(),
+            port: DEFAULT_SERVER_PORT,
+            session_token: 0,
+            player_token: 0,
+            insecure: false,
+            connect_failed: Arc::new(AtomicBool::new(false)),
+            was_online: true,
+        });
+        (app, to_bevy)
+    }
+
+    fn other_players(app: &mut App) -> Vec<u64> {
+        let mut ids: Vec<u64> = app
+            .world_mut()
+            .query::<&OtherPlayer>()
+            .iter(app.world())
+            .map(|other_player| other_player.id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn movement(id: u64, zone: u16) -> Packet {
+        Packet::PlayerMovement {
+            id: Some(id),
+            x: 1.0,
+            y: 0.0,
+            z: 1.0,
+            animation_frame: 0,
+            teleported: false,
+            zone,
+        }
+    }
+
+    #[test]
+    fn movements_in_another_zone_spawn_nobody() {
+        let (mut app, to_bevy) = connected_app();
+        let other_zone = LevelId::default().zone() + 1;
+        to_bevy.try_send(movement(1, other_zone)).unwrap();
+        to_bevy
+            .try_send(Packet::MovementBatch(vec![(
+                2, 1.0, 0.0, 1.0, 0, other_zone,
+            )]))
+            .unwrap();
+        app.update();
+        assert!(other_players(&mut app).is_empty());
+    }
+
+    #[test]
+    fn movements_in_the_same_zone_spawn_the_player() {
+        let (mut app, to_bevy) = connected_app();
+        let zone = LevelId::default().zone();
+        to_bevy.try_send(movement(1, zone)).unwrap();
+        to_bevy
+            .try_send(Packet::MovementBatch(vec![(2, 1.0, 0.0, 1.0, 0, zone)]))
+            .unwrap();
+        app.update();
+        assert_eq!(other_players(&mut app), [1, 2]);
+    }
+
+    #[test]
+    fn lockstep_inputs_step_players_from_the_roster() {
+        let (mut app, to_bevy) = connected_app();
+        let zone = LevelId::default().zone();
+        for packet in [
+            Packet::LevelConfig {
+                move_speed: 4.0,
+                spawn: [0.0; 3],
+                lockstep: true,
+            },
+            Packet::PlayerRoster(vec![(2, 1.0, 0.5, 1.0, 0, zone)]),
+            Packet::Input {
+                id: Some(2),
+                tick: 0,
+                input: MOVE_RIGHT,
+            },
+            Packet::Input {
+                id: Some(2),
+                tick: 1,
+                input: MOVE_RIGHT | MOVE_UP,
+            },
+            // Nobody knows where a player outside the roster started, so their inputs move nobody.
+            Packet::Input {
+                id: Some(3),
+                tick: 0,
+                input: MOVE_RIGHT,
+            },
+        ] {
+            to_bevy.try_send(packet).unwrap();
+        }
+        app.update();
+
+        assert_eq!(other_players(&mut app), [2]);
+        let target = app
+            .world_mut()
+            .query::<&InterpolationBuffer>()
+            .single(app.world())
+            .unwrap()
+            .target();
+        assert_eq!(target, Vec3::new(1.126, 0.5, 0.937));
+    }
+
+    #[test]
+    fn positions_further_apart_than_the_delay_are_moved_to_without_jumping() {
+        let mut buffer = InterpolationBuffer::at(Vec3::ZERO);
+        let start = buffer.samples[1].0;
+        let spacing = Duration::from_millis(50);
+
+        buffer.push(start + spacing, Vec3::X, TICK);
+        // Still where it was when the position arrived, then moving the whole time until the next one.
+        assert_eq!(buffer.translation_at(start + spacing, TICK), Vec3::ZERO);
+        assert!(buffer
+            .translation_at(start + spacing * 3 / 2, TICK)
+            .abs_diff_eq(Vec3::X * 0.5, 0.001));
+
+        // Arriving a little early picks up from partway along, instead of jumping to the end.
+        let early = start + spacing * 9 / 5;
+        let shown = buffer.translation_at(early, TICK);
+        buffer.push(early, Vec3::X * 2.0, TICK);
+        assert_eq!(buffer.translation_at(early, TICK), shown);
+        assert!(buffer
+            .translation_at(early + spacing, TICK)
+            .abs_diff_eq(Vec3::X * 2.0, 0.001));
+    }
+
+    #[test]
+    fn a_sequence_of_targets_moves_smoothly_in_order() {
+        let mut buffer = InterpolationBuffer::at(Vec3::ZERO);
+        let start = buffer.samples[1].0;
+        let frame = TICK / 4;
+        let mut shown = Vec::new();
+        // A player walking 0.1m every tick, drawn four times a tick.
+        for tick in 1..=10u32 {
+            let received_at = start + TICK * tick;
+            buffer.push(received_at, Vec3::X * 0.1 * tick as f32, TICK);
+            for step in 0..4 {
+                shown.push(buffer.translation_at(received_at + frame * step, TICK).x);
+            }
+        }
+
+        for pair in shown.windows(2) {
+            // Never going backwards, and never jumping more than a frame's worth of walking.
+            assert!(pair[1] >= pair[0], "{shown:?}");
+            assert!(pair[1] - pair[0] <= 0.1 / 4.0 + 0.001, "{shown:?}");
+        }
+        // Each position is shown between the two targets around it, not past the latest one.
+        assert!(shown.iter().all(|x| *x <= 1.0));
+        assert!(buffer
+            .translation_at(start + TICK * 11, TICK)
+            .abs_diff_eq(Vec3::X, 0.001));
+    }
+
+    #[test]
+    fn positions_in_a_burst_still_take_the_delay() {
+        let mut buffer = InterpolationBuffer::at(Vec3::ZERO);
+        let start = buffer.samples[1].0;
+        buffer.push(start + TICK, Vec3::X, TICK);
+        // The next one arrives almost straight after, like after a stall.
+        let burst = start + TICK + Duration::from_millis(1);
+        buffer.push(burst, Vec3::X * 2.0, TICK);
+        let halfway = buffer.translation_at(burst + TICK / 2, TICK);
+        assert!(halfway.x > 0.0 && halfway.x < 2.0, "{halfway}");
+        assert!(buffer
+            .translation_at(burst + TICK, TICK)
+            .abs_diff_eq(Vec3::X * 2.0, 0.001));
+    }
+
+    #[test]
+    fn positions_after_a_long_gap_are_snapped_to() {
+        let mut buffer = InterpolationBuffer::at(Vec3::ZERO);
+        let start = buffer.samples[1].0;
+        let late = start + MAX_INTERPOLATION_GAP + TICK;
+        buffer.push(late, Vec3::X * 5.0, TICK);
+        assert_eq!(buffer.translation_at(late, TICK), Vec3::X * 5.0);
+        assert_eq!(buffer.target(), Vec3::X * 5.0);
+    }
+
+    #[test]
+    fn a_new_buffer_stays_where_it_starts() {
+        let buffer = InterpolationBuffer::at(Vec3::ONE);
+        let start = buffer.samples[1].0;
+        assert_eq!(buffer.translation_at(start, TICK), Vec3::ONE);
+        assert_eq!(buffer.translation_at(start + TICK * 100, TICK), Vec3::ONE);
+    }
+
+    #[test]
+    fn received_animation_frames_are_kept_up_to_the_last_frame() {
+        // The atlases are 5 by 5, so 24 is the last frame, and anything a u8 can hold past it is clamped.
+        assert_eq!(received_animation_frame(0), 0);
+        assert_eq!(received_animation_frame(24), 24);
+        assert_eq!(received_animation_frame(25), 24);
+        assert_eq!(received_animation_frame(u8::MAX), 24);
+    }
+}
@@ -1,75 +1,218 @@
-use miniscop::networking::{receive_packet, send_packet, Packet};
-use quinn::{rustls, ClientConfig, Connection, Endpoint};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use miniscop::networking::{
+    read_framed, receive_packet_datagram, send_packet_datagram, write_framed, NetworkError, Packet,
+    PROTOCOL_VERSION,
+};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use quinn::rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use quinn::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use quinn::rustls::{DigitallySignedStruct, SignatureScheme};
+use quinn::{rustls, ClientConfig, Connection, Endpoint, SendStream, TransportConfig};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::lookup_host;
 use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-#[tracing::instrument(skip(from_bevy, to_bevy))]
+/// How often to ping the server when no packets are being sent, such as while standing still or minimized.
+/// This must be shorter than the server's idle timeout, which is 30 seconds by default.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The endpoint and connection to the server, plus the tasks sending and receiving packets.
+pub(crate) type ConnectToServerOutput = (Endpoint, Connection, JoinHandle<()>, JoinHandle<()>);
+
+/// The host is also the name checked against the server's certificate, so it should be a domain name.
+///
+/// If insecure is true, the server's certificate isn't checked at all. See SkipServerVerification.
+#[tracing::instrument(skip(from_bevy, to_bevy, session_token, player_token))]
 pub(crate) async fn connect_to_server(
+    host: String,
+    port: u16,
     from_bevy: Receiver<Packet>,
     to_bevy: Sender<Packet>,
-) -> anyhow::Result<(Endpoint, Connection, JoinHandle<()>, JoinHandle<()>)> {
-    let endpoint = Endpoint::client(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)))?;
-
-    // Todo: Let player choose server to connect to
-    const URL: &str = "miniscop.twilightparadox.com";
-    let server_address = lookup_host((URL, 4433))
+    session_token: u64,
+    player_token: u64,
+    insecure: bool,
+) -> Result<ConnectToServerOutput, NetworkError> {
+    let server_address = lookup_host((host.as_str(), port))
         .await?
         .next()
-        .ok_or_else(|| anyhow::anyhow!("Could not resolve the server's IP address"))?;
-    info!("Connecting to {URL}");
+        .ok_or(NetworkError::UnresolvedAddress)?;
+    info!("Connecting to {host}:{port}");
+
+    // The client's socket has to be the same IP version as the server's address.
+    let bind_address = match server_address {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let endpoint = Endpoint::client(bind_address)?;
 
     // Rustls needs to get the computer's crypto provider first, or else Quinn will panic.
     // https://github.com/quinn-rs/quinn/issues/2275
-    rustls::client::ClientConfig::builder();
+    let builder = rustls::client::ClientConfig::builder();
+
+    let mut transport_config = TransportConfig::default();
+    transport_config.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    let mut client_config = if insecure {
+        warn!(
+            "NOT CHECKING {host}'s CERTIFICATE. Anyone on the network can pretend to be it. This is only for testing."
+        );
+        let provider = builder.crypto_provider().clone();
+        let crypto = builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipServerVerification(provider)))
+            .with_no_client_auth();
+        ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?))
+    } else {
+        ClientConfig::with_platform_verifier()
+    };
+    client_config.transport_config(Arc::new(transport_config));
 
     let connection = endpoint
-        .connect_with(ClientConfig::with_platform_verifier(), server_address, URL)
-        .map_err(|e| anyhow::anyhow!("Connection configuration error: {e:?}"))?
+        .connect_with(client_config, server_address, &host)?
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to connect to server: {e:?}"))?;
+        .map_err(NetworkError::from_connection_error)?;
     info!("Connected to {server_address}");
 
+    // The server won't send anything until it knows who we are.
+    // This must be the first stream opened, because the server expects the first stream to be the reliable one.
+    let mut reliable = connection.open_uni().await?;
+    write_framed(
+        &mut reliable,
+        Packet::Hello {
+            version: PROTOCOL_VERSION,
+            session_token,
+            player_token,
+        },
+    )
+    .await?;
+
+    // Set once the server sends Packet::ClientConnect, which means it knows this client's ID.
+    let has_id = Arc::new(AtomicBool::new(false));
+
     let connection_handle = connection.clone();
+    let has_id_clone = has_id.clone();
     let bevy_task = tokio::spawn(async move {
-        if let Err(e) = await_bevy_packets(connection_handle, from_bevy).await {
-            error!("Packet sending error: {e:#?}. No longer sending packets.");
+        match await_bevy_packets(connection_handle, reliable, from_bevy, has_id_clone).await {
+            Ok(()) => {}
+            Err(e) if e.is_server_shutdown() => {
+                info!("Server shut down. No longer sending packets.")
+            }
+            Err(e) if e.is_version_mismatch() => {
+                warn!("Server is on a different protocol version. No longer sending packets.")
+            }
+            Err(e) => error!("Packet sending error: {e:#?}. No longer sending packets."),
         }
     });
 
     let connection_handle = connection.clone();
     let server_task = tokio::spawn(async move {
-        if let Err(e) = await_server_packets(connection_handle, to_bevy.clone()).await {
-            error!("Packet receiving error: {e:#?}. No longer receiving packets.");
+        match await_server_packets(connection_handle, to_bevy.clone(), has_id).await {
+            Ok(()) => {}
+            Err(e) if e.is_server_shutdown() => {
+                info!("Server shut down. No longer receiving packets.")
+            }
+            Err(e) if e.is_version_mismatch() => {
+                warn!("Server is on a different protocol version. No longer receiving packets.")
+            }
+            Err(e) => error!("Packet receiving error: {e:#?}. No longer receiving packets."),
         }
-        let _ = to_bevy.send(Packet::ClientDisconnect(None)).await;
+        let _ = to_bevy.send(Packet::ClientDisconnect(None, None)).await;
     });
 
     Ok((endpoint, connection, bevy_task, server_task))
 }
 
+/// Accepts any certificate the server presents, for the client's --insecure flag.
+///
+/// Signatures are still checked, so the connection is encrypted, but nothing proves the server is who it says it is.
+/// That's fine for a server on the same computer with a self-signed certificate, and never fine for a public one.
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
 /// Awaits packets from Bevy to send to the server.
-#[tracing::instrument(skip(connection_handle, from_bevy))]
+///
+/// Reliable packets are written to the reliable stream one after another, so they arrive in the order Bevy sent them.
+/// Movements are sent as datagrams, and dropped until the server has sent Packet::ClientConnect,
+/// because the server doesn't expect them yet.
+#[tracing::instrument(skip(connection_handle, reliable, from_bevy, has_id))]
 pub(crate) async fn await_bevy_packets(
     connection_handle: Connection,
+    mut reliable: SendStream,
     mut from_bevy: Receiver<Packet>,
-) -> anyhow::Result<()> {
+    has_id: Arc<AtomicBool>,
+) -> Result<(), NetworkError> {
     // This loop ends when the channel is closed.
     while let Some(packet) = from_bevy.recv().await {
-        // Could not find a way to move the open_uni() future into send_packet(), so we await here.
-        // Since streams are "instantaneous to open", this shouldn't fill up the channel.
-        let send = connection_handle.open_uni().await?;
-        tokio::spawn(async move {
-            if let Err(e) = send_packet(send, packet).await {
-                error!("Failed to send packet to server: {e:#?}");
+        if packet.is_reliable() {
+            let disconnecting = packet == Packet::ClientDisconnect(None, None);
+            write_framed(&mut reliable, packet).await?;
+            if disconnecting {
+                reliable.finish()?;
+                return Ok(());
             }
-        });
+            continue;
+        }
 
-        if packet == Packet::ClientDisconnect(None) {
-            return Ok(());
+        if !has_id.load(Ordering::Acquire) {
+            warn!("Dropping movement sent before the server sent Packet::ClientConnect.");
+            continue;
+        }
+
+        if let Err(e) = send_packet_datagram(&connection_handle, &packet) {
+            error!("Failed to send packet to server: {e:#?}");
         }
     }
 
@@ -77,30 +220,151 @@ pub(crate) async fn await_bevy_packets(
 }
 
 /// Awaits packets from the server to send to Bevy.
-#[tracing::instrument(skip(connection_handle, to_bevy))]
+///
+/// Movements arrive as datagrams. Everything else arrives in order on the first stream the server opens,
+/// and is passed on in that order.
+/// If Bevy falls behind and the channel fills up, movements are coalesced so only the latest one per player is kept.
+/// Every other packet waits for space in the channel, so none of them are lost.
+#[tracing::instrument(skip(connection_handle, to_bevy, has_id))]
 pub(crate) async fn await_server_packets(
     connection_handle: Connection,
     to_bevy: Sender<Packet>,
-) -> anyhow::Result<()> {
-    while !to_bevy.is_closed() {
-        let recv = connection_handle.accept_uni().await?;
-        let to_bevy_clone = to_bevy.clone();
-
-        tokio::spawn(async move {
-            match receive_packet(recv).await {
-                Ok(packet) => {
-                    if let Err(TrySendError::Full(_)) = to_bevy_clone.try_send(packet) {
-                        error!(
-                            "Failed to send packet to Bevy because channel is full.\nIf you see this, please report this error so the dev can consider increasing channel size.\nAwaiting space in the channel..."
-                        );
-                        if let Err(_) = to_bevy_clone.send(packet).await {
-                            info!("Channel to Bevy closed, async loop will close next iteration");
-                        }
-                    }
+    has_id: Arc<AtomicBool>,
+) -> Result<(), NetworkError> {
+    let pending_movements = Arc::new(PendingMovements::default());
+    let flush_task = tokio::spawn(flush_pending_movements(
+        pending_movements.clone(),
+        to_bevy.clone(),
+    ));
+    let datagram_task = tokio::spawn(receive_movement_datagrams(
+        connection_handle.clone(),
+        pending_movements.clone(),
+        to_bevy.clone(),
+    ));
+
+    let result = async {
+        let mut reliable = connection_handle.accept_uni().await?;
+        while !to_bevy.is_closed() {
+            // The server finishes the stream when it's done with this client.
+            let Some(packet) = read_framed(&mut reliable).await? else {
+                return Ok(());
+            };
+            if matches!(packet, Packet::ClientConnect { .. }) {
+                has_id.store(true, Ordering::Release);
+            }
+            if let Packet::PlayerMovement { id: Some(id), .. } = packet {
+                pending_movements.send_or_coalesce(&to_bevy, id, packet);
+            } else if let Err(TrySendError::Full(packet)) = to_bevy.try_send(packet) {
+                error!(
+                    "Failed to send packet to Bevy because channel is full.\nIf you see this, please report this error so the dev can consider increasing channel size.\nAwaiting space in the channel..."
+                );
+                // Waiting here rather than in another task keeps the packets after this one in order.
+                if to_bevy.send(packet).await.is_err() {
+                    info!("Channel to Bevy closed, async loop will close next iteration");
                 }
-                Err(e) => error!("Failed to receive packet from server: {e:?}"),
             }
-        });
+        }
+        Ok(())
+    }
+    .await;
+
+    flush_task.abort();
+    datagram_task.abort();
+    result
+}
+
+/// Reads movement datagrams until the connection closes, passing them on like await_server_packets does.
+async fn receive_movement_datagrams(
+    connection_handle: Connection,
+    pending_movements: Arc<PendingMovements>,
+    to_bevy: Sender<Packet>,
+) {
+    while !to_bevy.is_closed() {
+        match receive_packet_datagram(&connection_handle).await {
+            Ok(packet @ Packet::PlayerMovement { id: Some(id), .. }) => {
+                pending_movements.send_or_coalesce(&to_bevy, id, packet);
+            }
+            Ok(Packet::MovementBatch(movements)) => {
+                pending_movements.send_batch_or_coalesce(&to_bevy, movements);
+            }
+            Ok(packet) => {
+                error!("Server sent {packet:?} as a datagram. Please report this to the dev.")
+            }
+            Err(NetworkError::Connection(_)) => return,
+            Err(e) => error!("Failed to receive datagram from server: {e:?}"),
+        }
+    }
+}
+
+/// Movements that couldn't fit in the channel to Bevy, keyed by player ID.
+#[derive(Default)]
+struct PendingMovements {
+    movements: Mutex<HashMap<u64, Packet>>,
+    notify: Notify,
+}
+impl PendingMovements {
+    /// Sends a movement to Bevy right away if there's room and nothing is already waiting.
+    /// Otherwise, it replaces whatever movement of the same player was waiting.
+    fn send_or_coalesce(&self, to_bevy: &Sender<Packet>, id: u64, packet: Packet) {
+        let mut movements = self.movements.lock().unwrap();
+        // Sending straight away while other movements wait would let this one skip ahead of them,
+        // so once anything is waiting, everything waits.
+        let packet = if movements.is_empty() {
+            match to_bevy.try_send(packet) {
+                Ok(()) => return,
+                Err(e) => e.into_inner(),
+            }
+        } else {
+            packet
+        };
+        if movements.insert(id, packet).is_some() {
+            warn!("Bevy is falling behind, dropped a stale movement of player {id}.");
+        }
+        self.notify.notify_one();
+    }
+
+    /// Sends a whole batch of movements to Bevy if there's room, like send_or_coalesce.
+    /// Otherwise, the batch is split up so each of its movements can be coalesced.
+    fn send_batch_or_coalesce(
+        &self,
+        to_bevy: &Sender<Packet>,
+        movements: Vec<(u64, f32, f32, f32, u8, u16)>,
+    ) {
+        if self.movements.lock().unwrap().is_empty()
+            && let Ok(permit) = to_bevy.try_reserve()
+        {
+            permit.send(Packet::MovementBatch(movements));
+            return;
+        }
+        for (id, x, y, z, animation_frame, zone) in movements {
+            let packet = Packet::PlayerMovement {
+                id: Some(id),
+                x,
+                y,
+                z,
+                animation_frame,
+                teleported: false,
+                zone,
+            };
+            self.send_or_coalesce(to_bevy, id, packet);
+        }
+    }
+}
+
+/// Sends waiting movements to Bevy as space in the channel frees up.
+async fn flush_pending_movements(pending: Arc<PendingMovements>, to_bevy: Sender<Packet>) {
+    loop {
+        pending.notify.notified().await;
+        loop {
+            let Ok(permit) = to_bevy.reserve().await else {
+                return;
+            };
+            let mut movements = pending.movements.lock().unwrap();
+            let Some(&id) = movements.keys().next() else {
+                break;
+            };
+            let packet = movements.remove(&id).unwrap();
+            permit.send(packet);
+        }
     }
-    Ok(())
 }
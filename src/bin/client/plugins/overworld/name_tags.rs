@@ -0,0 +1,144 @@
+use crate::plugins::overworld::multiplayer::{
+    interpolate_networked_entities, DisconnectGrace, MultiplayerState, OtherPlayer, PlayerNames,
+};
+use crate::plugins::overworld::{CameraFollowSet, OverworldState, PLAYER_HEAD_HEIGHT};
+use bevy::prelude::{
+    default, in_state, Added, App, AssetServer, Camera, Camera3d, Color, Commands, Component,
+    ComputedNode, Condition, DetectChanges, DetectChangesMut, Entity, GlobalTransform, Has,
+    IntoScheduleConfigs, Mut, Node, Plugin, PositionType, Query, Res, Single, StateScoped, Text,
+    TextColor, TextFont, Transform, Update, Val, Vec3, Visibility, With, Without,
+};
+use bevy::text::FontSmoothing;
+
+/// Shows each other player's name above their head.
+///
+/// Tags are UI text moved to wherever the player's head is on screen, so they always face the camera
+/// and stay the same size however far away the player is.
+pub struct NameTagPlugin;
+impl Plugin for NameTagPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (spawn_name_tags, update_name_tags)
+                .chain()
+                .after(CameraFollowSet)
+                .after(interpolate_networked_entities)
+                .run_if(in_state(MultiplayerState::Online).and(in_state(OverworldState::InGame))),
+        );
+    }
+}
+
+// Constants
+/// How far above the top of a player's head their name tag goes, in meters.
+const NAME_TAG_MARGIN: f32 = 0.2;
+pub const NAME_TAG_FONT_SIZE: f32 = 20.0;
+
+// Components
+/// The name tag of an OtherPlayer entity.
+#[derive(Component)]
+struct NameTag(Entity);
+
+// Systems
+fn spawn_name_tags(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    player_names: Res<PlayerNames>,
+    other_players: Query<(Entity, &OtherPlayer), Added<OtherPlayer>>,
+) {
+    for (entity, other_player) in other_players.iter() {
+        commands.spawn((
+            StateScoped(MultiplayerState::Online),
+            NameTag(entity),
+            Text::new(player_names.name(other_player.id())),
+            TextColor(Color::BLACK),
+            TextFont {
+                font: asset_server.load("global/fonts/PetscopWide.ttf"),
+                font_size: NAME_TAG_FONT_SIZE,
+                font_smoothing: FontSmoothing::None,
+                ..default()
+            },
+            Node {
+                position_type: PositionType::Absolute,
+                ..default()
+            },
+            // Hidden until it's been placed over the player.
+            Visibility::Hidden,
+        ));
+    }
+}
+
+/// Moves each name tag above its player's head, and keeps its text matching the player's name.
+///
+/// Tags are hidden while their player is fading out or off screen, and despawn once their player does.
+fn update_name_tags(
+    mut commands: Commands,
+    player_names: Res<PlayerNames>,
+    camera: Single<(&Camera, &Transform), With<Camera3d>>,
+    other_players: Query<(&OtherPlayer, &Transform, Has<DisconnectGrace>), Without<Camera3d>>,
+    mut name_tags: Query<(
+        Entity,
+        &NameTag,
+        &mut Text,
+        &mut Node,
+        &mut Visibility,
+        &ComputedNode,
+    )>,
+) {
+    let (camera, camera_transform) = camera.into_inner();
+    // The camera was just moved in Update, so its GlobalTransform hasn't caught up yet.
+    // It has no parent, so its Transform is where it really is.
+    let camera_transform = GlobalTransform::from(*camera_transform);
+    for (entity, NameTag(other_player), mut text, node, mut visibility, computed) in
+        name_tags.iter_mut()
+    {
+        let Ok((other_player, transform, disconnecting)) = other_players.get(*other_player) else {
+            commands.entity(entity).despawn();
+            continue;
+        };
+        if player_names.is_changed() {
+            text.set_if_neq(Text(player_names.name(other_player.id())));
+        }
+
+        if disconnecting {
+            visibility.set_if_neq(Visibility::Hidden);
+            continue;
+        }
+        place_over_head(
+            camera,
+            &camera_transform,
+            transform.translation,
+            0.0,
+            computed,
+            node,
+            visibility,
+        );
+    }
+}
+
+/// Centers a UI node over a player's head on screen, raise_pixels higher than a name tag sits.
+/// If the head is off screen, the node is hidden instead.
+///
+/// The node's size is from the last layout, which is only wrong for the frame its content changes.
+pub fn place_over_head(
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+    player_translation: Vec3,
+    raise_pixels: f32,
+    computed: &ComputedNode,
+    mut node: Mut<Node>,
+    mut visibility: Mut<Visibility>,
+) {
+    let head = player_translation + Vec3::Y * (PLAYER_HEAD_HEIGHT + NAME_TAG_MARGIN);
+    let Ok(position) = camera.world_to_viewport(camera_transform, head) else {
+        visibility.set_if_neq(Visibility::Hidden);
+        return;
+    };
+    let size = computed.size() * computed.inverse_scale_factor();
+    let left = Val::Px(position.x - size.x / 2.0);
+    let top = Val::Px(position.y - raise_pixels - size.y);
+    if node.left != left || node.top != top {
+        node.left = left;
+        node.top = top;
+    }
+    visibility.set_if_neq(Visibility::Inherited);
+}
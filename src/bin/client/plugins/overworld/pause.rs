@@ -0,0 +1,188 @@
+use crate::plugins::overworld::chat::{self, ChatInput};
+use crate::plugins::overworld::physics::JumpBuffer;
+use crate::plugins::overworld::OverworldState;
+use crate::AppState;
+use avian3d::prelude::{Physics, PhysicsTime};
+use bevy::prelude::{
+    default, in_state, not, resource_exists, AlignItems, App, AppExtStates, AssetServer,
+    BackgroundColor, ButtonInput, Color, Commands, Component, DetectChangesMut, FlexDirection,
+    IntoScheduleConfigs, JustifyContent, KeyCode, NextState, Node, OnEnter, OnExit, Plugin,
+    PositionType, Query, Res, ResMut, Single, State, StateScoped, StateSet, SubStates, Text,
+    TextColor, TextFont, Time, Update, Val,
+};
+use bevy::text::FontSmoothing;
+use tracing::info;
+
+/// Escape pauses the overworld and opens a menu to resume or quit to the main menu.
+///
+/// Pausing freezes the player, physics, and animations, but the scene keeps rendering and packets keep being read,
+/// so the connection stays alive and other players are still up to date when the game resumes.
+pub struct PausePlugin;
+impl Plugin for PausePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_sub_state::<PauseState>()
+            .add_systems(
+                OnEnter(PauseState::Paused),
+                (spawn_pause_menu, pause_physics),
+            )
+            .add_systems(OnExit(PauseState::Paused), resume_physics)
+            .add_systems(
+                Update,
+                (
+                    // Escape also closes the chat box, so this has to see it open before it closes.
+                    toggle_pause
+                        .before(chat::type_chat_message)
+                        .run_if(not(resource_exists::<ChatInput>)),
+                    (navigate_pause_menu, update_pause_menu)
+                        .chain()
+                        .run_if(in_state(PauseState::Paused)),
+                )
+                    .chain()
+                    .run_if(in_state(OverworldState::InGame)),
+            );
+    }
+}
+
+// Constants
+const PAUSE_KEY: KeyCode = KeyCode::Escape;
+const SELECT_KEY: KeyCode = KeyCode::KeyZ;
+const UNSELECTED_COLOR: Color = Color::srgb(0.5, 0.5, 0.5);
+
+// Sub-States
+/// Whether gameplay is frozen behind the pause menu. Gameplay systems should only run while this is Running.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, SubStates)]
+#[source(OverworldState = OverworldState::InGame)]
+#[states(scoped_entities)]
+pub enum PauseState {
+    #[default]
+    Running,
+    Paused,
+}
+
+// Components
+/// One line of the pause menu. The lines are listed in the order they appear.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+enum PauseMenuOption {
+    Resume,
+    QuitToMainMenu,
+}
+impl PauseMenuOption {
+    const ALL: [PauseMenuOption; 2] = [PauseMenuOption::Resume, PauseMenuOption::QuitToMainMenu];
+
+    fn label(self) -> &'static str {
+        match self {
+            PauseMenuOption::Resume => "Resume",
+            PauseMenuOption::QuitToMainMenu => "Quit to Main Menu",
+        }
+    }
+}
+/// The menu's column of options, which remembers which one is selected.
+#[derive(Component, Default)]
+struct PauseMenu {
+    selected: usize,
+}
+
+// Systems
+/// Escape pauses the game, and pressing it again resumes.
+fn toggle_pause(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    pause_state: Res<State<PauseState>>,
+    mut next_state: ResMut<NextState<PauseState>>,
+) {
+    if !keyboard.just_pressed(PAUSE_KEY) {
+        return;
+    }
+    match pause_state.get() {
+        PauseState::Running => {
+            info!("Paused.");
+            next_state.set(PauseState::Paused);
+        }
+        PauseState::Paused => next_state.set(PauseState::Running),
+    }
+}
+
+fn spawn_pause_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font = TextFont {
+        font: asset_server.load("global/fonts/PetscopWide.ttf"),
+        font_size: 50.0,
+        font_smoothing: FontSmoothing::None,
+        ..default()
+    };
+    commands
+        .spawn((
+            StateScoped(PauseState::Paused),
+            PauseMenu::default(),
+            Node {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                row_gap: Val::Px(20.0),
+                ..default()
+            },
+            // Dims the scene, which keeps rendering behind the menu.
+            BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.5)),
+        ))
+        .with_children(|menu| {
+            for option in PauseMenuOption::ALL {
+                menu.spawn((
+                    option,
+                    Text::new(option.label()),
+                    TextColor(UNSELECTED_COLOR),
+                    font.clone(),
+                ));
+            }
+        });
+}
+
+/// The arrow keys move the selection, and Z picks the selected option.
+fn navigate_pause_menu(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut menu: Single<&mut PauseMenu>,
+    mut next_pause_state: ResMut<NextState<PauseState>>,
+    mut next_app_state: ResMut<NextState<AppState>>,
+) {
+    let count = PauseMenuOption::ALL.len();
+    if keyboard.just_pressed(KeyCode::ArrowDown) {
+        menu.selected = (menu.selected + 1) % count;
+    }
+    if keyboard.just_pressed(KeyCode::ArrowUp) {
+        menu.selected = (menu.selected + count - 1) % count;
+    }
+    if keyboard.just_pressed(SELECT_KEY) {
+        match PauseMenuOption::ALL[menu.selected] {
+            PauseMenuOption::Resume => next_pause_state.set(PauseState::Running),
+            // Leaving the overworld disconnects from the server with disconnect_on_exit.
+            PauseMenuOption::QuitToMainMenu => next_app_state.set(AppState::MainMenu),
+        }
+    }
+}
+
+/// Highlights the selected option.
+fn update_pause_menu(
+    menu: Single<&PauseMenu>,
+    mut options: Query<(&PauseMenuOption, &mut TextColor)>,
+) {
+    let selected = PauseMenuOption::ALL[menu.selected];
+    for (option, mut color) in options.iter_mut() {
+        let new_color = if *option == selected {
+            Color::WHITE
+        } else {
+            UNSELECTED_COLOR
+        };
+        color.set_if_neq(TextColor(new_color));
+    }
+}
+
+/// Physics time stops advancing while paused, so resuming doesn't simulate the paused time all at once.
+fn pause_physics(mut physics_time: ResMut<Time<Physics>>) {
+    physics_time.pause();
+}
+
+/// Jumps pressed before pausing are forgotten, so resuming doesn't start with a burst of input.
+fn resume_physics(mut physics_time: ResMut<Time<Physics>>, mut jump_buffer: ResMut<JumpBuffer>) {
+    physics_time.unpause();
+    *jump_buffer = JumpBuffer::default();
+}
@@ -1,55 +1,160 @@
 use crate::plugins::overworld::animation::AnimationDirection;
-use bevy::prelude::{default, ButtonInput, KeyCode, Res, Single, Vec3};
+use crate::plugins::overworld::chat::ChatInput;
+use crate::plugins::overworld::{CameraOrbit, PLAYER_FEET_DEPTH};
+use crate::plugins::settings::{CameraMode, GraphicsSettings, InputAction, InputBindings};
+use avian3d::prelude::Gravity;
+use bevy::prelude::{default, ButtonInput, KeyCode, Res, ResMut, Resource, Single, Time, Vec3};
 use bevy_tnua::math::Float;
 use bevy_tnua::prelude::{TnuaBuiltinJump, TnuaBuiltinWalk, TnuaController};
+use bevy_tnua::TnuaAction;
 
 // Physics Constants
 const MAX_VELOCITY: Float = 4.0;
-const FLOAT_HEIGHT: Float = 0.95;
+/// Floating exactly as high as the feet in the player's sprite are below its middle keeps them on the ground.
+/// It scales with the sprite, so a bigger player doesn't sink into the ground.
+const FLOAT_HEIGHT: Float = PLAYER_FEET_DEPTH;
 const CLING_DISTANCE: Float = 0.1;
 const SPRING_DAMPENING: Float = 1.0;
 const ACCELERATION: Float = 25.0;
 const AIR_ACCELERATION: Float = ACCELERATION;
 const COYOTE_TIME: Float = 0.0;
+const JUMP_HEIGHT: Float = 1.0;
+/// How long a jump press is remembered, in seconds, so a quick tap between fixed ticks still jumps.
+const JUMP_BUFFER_TIME: Float = 0.1;
+/// Multiplies Avian's default gravity.
+const GRAVITY_SCALE: Float = 1.0;
+/// Avian's default gravity, in meters per second squared.
+const BASE_GRAVITY: Float = 9.81;
+/// Tnua's default spring strength, which is tuned for Avian's default gravity.
+const BASE_SPRING_STRENGTH: Float = 400.0;
+
+// Resources
+/// The constants used to move the player.
+///
+/// These start as the client's defaults, but the server can override some of them with Packet::LevelConfig.
+#[derive(Resource)]
+pub struct MovementTuning {
+    pub max_velocity: Float,
+    pub float_height: Float,
+    pub cling_distance: Float,
+    pub spring_dampening: Float,
+    pub acceleration: Float,
+    pub air_acceleration: Float,
+    pub coyote_time: Float,
+    pub jump_height: Float,
+    pub jump_buffer_time: Float,
+    /// Below 1.0, levels feel floaty. Above 1.0, they feel heavy.
+    pub gravity_scale: Float,
+}
+impl Default for MovementTuning {
+    fn default() -> Self {
+        Self {
+            max_velocity: MAX_VELOCITY,
+            float_height: FLOAT_HEIGHT,
+            cling_distance: CLING_DISTANCE,
+            spring_dampening: SPRING_DAMPENING,
+            acceleration: ACCELERATION,
+            air_acceleration: AIR_ACCELERATION,
+            coyote_time: COYOTE_TIME,
+            jump_height: JUMP_HEIGHT,
+            jump_buffer_time: JUMP_BUFFER_TIME,
+            gravity_scale: GRAVITY_SCALE,
+        }
+    }
+}
+
+/// How many seconds are left before a buffered jump press is forgotten.
+#[derive(Resource, Default)]
+pub struct JumpBuffer(Float);
 
 // Systems
+/// This system remembers jump presses every frame, so apply_controls can't miss them between fixed ticks.
+///
+/// It should run before the fixed main loop.
+pub fn buffer_jump(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    bindings: Res<InputBindings>,
+    tuning: Res<MovementTuning>,
+    mut jump_buffer: ResMut<JumpBuffer>,
+) {
+    if bindings.just_pressed(&keyboard, InputAction::Jump) {
+        jump_buffer.0 = tuning.jump_buffer_time;
+    }
+}
+
+/// Holding the keys for opposite directions cancels them out.
+///
+/// Directions are relative to the level, except with the orbit camera, where up means away from the camera.
+#[expect(
+    clippy::too_many_arguments,
+    reason = "Controls depend on the bindings, the camera, the movement tuning and chat."
+)]
 pub fn apply_controls(
     keyboard: Res<ButtonInput<KeyCode>>,
+    bindings: Res<InputBindings>,
+    graphics_settings: Res<GraphicsSettings>,
+    orbit: Res<CameraOrbit>,
+    time: Res<Time>,
+    tuning: Res<MovementTuning>,
+    mut jump_buffer: ResMut<JumpBuffer>,
     query: Single<(&mut TnuaController, &mut AnimationDirection)>,
+    chat_input: Option<Res<ChatInput>>,
 ) {
     let (mut controller, mut animation_direction) = query.into_inner();
+    // Typing a chat message shouldn't walk the player around.
+    let typing = chat_input.is_some();
+    let pressed = |action: InputAction| !typing && bindings.pressed(&keyboard, action);
 
     let mut direction = Vec3::ZERO;
-    if keyboard.pressed(KeyCode::ArrowUp) {
+    if pressed(InputAction::MoveUp) {
         direction -= Vec3::Z;
     }
-    if keyboard.pressed(KeyCode::ArrowDown) {
+    if pressed(InputAction::MoveDown) {
         direction += Vec3::Z;
     }
-    if keyboard.pressed(KeyCode::ArrowLeft) {
+    if pressed(InputAction::MoveLeft) {
         direction -= Vec3::X;
     }
-    if keyboard.pressed(KeyCode::ArrowRight) {
+    if pressed(InputAction::MoveRight) {
         direction += Vec3::X;
     }
     direction = direction.clamp(Vec3::NEG_ONE, Vec3::ONE);
+    if graphics_settings.camera_mode == CameraMode::Orbit {
+        direction = orbit.relative_to_camera(direction);
+    }
     animation_direction.0 = direction;
 
     controller.basis(TnuaBuiltinWalk {
-        desired_velocity: direction * MAX_VELOCITY,
-        float_height: FLOAT_HEIGHT,
-        cling_distance: CLING_DISTANCE,
-        spring_dampening: SPRING_DAMPENING,
-        acceleration: ACCELERATION,
-        air_acceleration: AIR_ACCELERATION,
-        coyote_time: COYOTE_TIME,
+        desired_velocity: direction * tuning.max_velocity,
+        float_height: tuning.float_height,
+        cling_distance: tuning.cling_distance,
+        spring_dampening: tuning.spring_dampening,
+        acceleration: tuning.acceleration,
+        air_acceleration: tuning.air_acceleration,
+        coyote_time: tuning.coyote_time,
+        // The spring has to hold the player up against gravity, so it scales with it.
+        // Otherwise, heavy levels would make the player sag into the ground, and floaty levels would make them bounce.
+        spring_strength: BASE_SPRING_STRENGTH * tuning.gravity_scale,
         ..default()
     });
 
-    if keyboard.pressed(KeyCode::Space) {
+    let jump_buffered = jump_buffer.0 > 0.0;
+    if controller.action_name() == Some(TnuaBuiltinJump::NAME) {
+        // The jump started, so the press has been used up.
+        jump_buffer.0 = 0.0;
+    } else {
+        jump_buffer.0 = (jump_buffer.0 - time.delta_secs()).max(0.0);
+    }
+
+    if pressed(InputAction::Jump) || jump_buffered {
         controller.action(TnuaBuiltinJump {
-            height: 1.0,
+            height: tuning.jump_height,
             ..default()
         });
     }
 }
+
+/// This system keeps Avian's gravity in sync with the tuning's gravity scale.
+pub fn apply_gravity(tuning: Res<MovementTuning>, mut gravity: ResMut<Gravity>) {
+    gravity.0 = Vec3::NEG_Y * BASE_GRAVITY * tuning.gravity_scale;
+}
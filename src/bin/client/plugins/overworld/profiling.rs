@@ -0,0 +1,36 @@
+#[cfg(feature = "debug")]
+use bevy::diagnostic::LogDiagnosticsPlugin;
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, RegisterDiagnostic};
+use bevy::prelude::{App, Plugin};
+
+/// Measures how long the overworld's busiest systems take, in milliseconds.
+///
+/// The measurements are only taken with the debug feature enabled, which also logs them every second.
+/// Without it, measuring costs a single check per system.
+pub struct ProfilingPlugin;
+impl Plugin for ProfilingPlugin {
+    fn build(&self, app: &mut App) {
+        for path in [READ_PACKETS_TIME, SEND_POSITION_TIME, ANIMATE_SPRITES_TIME] {
+            let mut diagnostic = Diagnostic::new(path).with_suffix("ms");
+            diagnostic.is_enabled = cfg!(feature = "debug");
+            app.register_diagnostic(diagnostic);
+        }
+
+        #[cfg(feature = "debug")]
+        app.add_plugins(LogDiagnosticsPlugin {
+            filter: Some(vec![
+                READ_PACKETS_TIME,
+                SEND_POSITION_TIME,
+                ANIMATE_SPRITES_TIME,
+            ]),
+            ..Default::default()
+        });
+    }
+}
+
+// Diagnostics
+pub const READ_PACKETS_TIME: DiagnosticPath = DiagnosticPath::const_new("overworld/read_packets");
+pub const SEND_POSITION_TIME: DiagnosticPath =
+    DiagnosticPath::const_new("overworld/send_current_position");
+pub const ANIMATE_SPRITES_TIME: DiagnosticPath =
+    DiagnosticPath::const_new("overworld/animate_sprites");
@@ -0,0 +1,49 @@
+use bevy::prelude::*;
+use bevy::window::{PrimaryWindow, WindowOccluded};
+use bevy::winit::{UpdateMode, WinitSettings};
+use std::time::Duration;
+
+/// Lowers the update rate while the window is minimized or hidden, to save battery and keep laptops cool.
+///
+/// The server connection runs on its own runtime and sends keep-alives, so it stays open at any update rate.
+pub struct PowerSavingPlugin;
+impl Plugin for PowerSavingPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(WinitSettings {
+            focused_mode: UpdateMode::Continuous,
+            unfocused_mode: UpdateMode::reactive_low_power(UNFOCUSED_FRAME_TIME),
+        })
+        .add_systems(Update, throttle_while_occluded);
+    }
+}
+
+// Constants
+/// How long to wait between updates while the window is visible but unfocused, such as on a second monitor.
+const UNFOCUSED_FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+/// How long to wait between updates while the window can't be seen at all.
+///
+/// Physics falls behind at this rate rather than catching up, but the player can't see it anyway.
+/// Packets from the server still get read every update, so nothing is lost.
+const OCCLUDED_FRAME_TIME: Duration = Duration::from_millis(500);
+
+// Systems
+/// Switches the unfocused update rate whenever the primary window is minimized or restored.
+fn throttle_while_occluded(
+    mut occluded_events: EventReader<WindowOccluded>,
+    primary_window: Single<Entity, With<PrimaryWindow>>,
+    mut winit_settings: ResMut<WinitSettings>,
+) {
+    for event in occluded_events.read() {
+        if event.window != *primary_window {
+            continue;
+        }
+        let frame_time = if event.occluded {
+            info!("Window is hidden, lowering update rate.");
+            OCCLUDED_FRAME_TIME
+        } else {
+            info!("Window is visible, restoring update rate.");
+            UNFOCUSED_FRAME_TIME
+        };
+        winit_settings.unfocused_mode = UpdateMode::reactive_low_power(frame_time);
+    }
+}
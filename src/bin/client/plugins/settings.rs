@@ -0,0 +1,350 @@
+use bevy::audio::Volume;
+use bevy::prelude::*;
+use bevy::window::{Monitor, MonitorSelection, PrimaryMonitor, PrimaryWindow, WindowMode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub struct SettingsPlugin;
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GraphicsSettings>()
+            .init_resource::<AudioSettings>()
+            .init_resource::<InputBindings>()
+            .add_systems(
+                Update,
+                (
+                    (cycle_msaa, apply_msaa).chain(),
+                    (cycle_resolution, apply_resolution).chain(),
+                    apply_window_mode.run_if(resource_changed::<GraphicsSettings>),
+                    cycle_camera_mode,
+                    toggle_physics_debug,
+                ),
+            );
+    }
+}
+
+// Constants
+const CYCLE_MSAA_KEY: KeyCode = KeyCode::F2;
+const CYCLE_RESOLUTION_KEY: KeyCode = KeyCode::F3;
+// F5 is left to the browser, since prevent_default_event_handling is off.
+const CYCLE_CAMERA_MODE_KEY: KeyCode = KeyCode::F6;
+const TOGGLE_PHYSICS_DEBUG_KEY: KeyCode = KeyCode::F7;
+
+// Resources
+/// Graphics options that can be changed while the game is running.
+#[derive(Resource)]
+pub struct GraphicsSettings {
+    /// Multisample anti-aliasing smooths the edges of 3D geometry.
+    /// It never blurs sprites, because ImagePlugin::default_nearest() is what keeps their pixels crisp.
+    ///
+    /// Every camera uses the same sample count, since cameras drawing to the same window must agree.
+    /// WebGL2 only supports Msaa::Off and Msaa::Sample4.
+    pub msaa: Msaa,
+    /// The size of the window when it isn't fullscreen.
+    pub resolution: Resolution,
+    /// Whether the window covers the whole monitor it's on, without borders.
+    pub fullscreen: bool,
+    /// Whether player sprites can be seen from behind. Only applies to sprites spawned after it changes.
+    pub double_sided_sprites: bool,
+    /// Whether player sprites are shaded by the level's lights. Only applies to sprites spawned after it changes.
+    pub lit_sprites: bool,
+    /// Whether other players fade in when they appear, instead of popping in.
+    pub spawn_animations: bool,
+    /// How the overworld camera follows the player.
+    pub camera_mode: CameraMode,
+    /// How quickly the behind-the-player camera swings around to a new facing direction.
+    /// Higher is snappier. Lower keeps the camera from whipping around on quick turns.
+    pub camera_turn_speed: f32,
+    /// Whether colliders are drawn over the level. Starts on in debug builds and off in release builds.
+    pub physics_debug: bool,
+}
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        Self {
+            msaa: Msaa::Sample4,
+            resolution: Resolution::Hd,
+            fullscreen: false,
+            double_sided_sprites: false,
+            lit_sprites: false,
+            spawn_animations: true,
+            camera_mode: CameraMode::Fixed,
+            camera_turn_speed: 3.0,
+            physics_debug: cfg!(debug_assertions),
+        }
+    }
+}
+
+/// How loud each kind of sound is, from 0.0 to 1.0. Every sound multiplies its category's volume by the master volume.
+///
+/// This is the only place volume should come from. Sounds read it when they're spawned,
+/// and MusicPlugin turns music that is already playing up or down whenever it changes.
+#[derive(Resource)]
+pub struct AudioSettings {
+    pub master: f32,
+    pub music: f32,
+    pub sfx: f32,
+    /// How many seconds one song takes to fade into the next. 0.0 cuts straight to the next song.
+    pub crossfade: f32,
+}
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            master: 1.0,
+            music: 1.0,
+            sfx: 1.0,
+            crossfade: 1.0,
+        }
+    }
+}
+impl AudioSettings {
+    /// The volume for a song that plays at `volume` when every setting is at full.
+    pub fn music_volume(&self, volume: f32) -> Volume {
+        Volume::Linear(volume * self.music.clamp(0.0, 1.0) * self.master.clamp(0.0, 1.0))
+    }
+
+    /// The volume for a sound effect.
+    pub fn sfx_volume(&self) -> Volume {
+        Volume::Linear(self.sfx.clamp(0.0, 1.0) * self.master.clamp(0.0, 1.0))
+    }
+}
+
+/// Which keys do what in the overworld. The controls screen rebinds them.
+///
+/// An action can have more than one key, like the arrow keys and WASD both moving the player,
+/// but a key can only be bound to one action.
+#[derive(Resource)]
+pub struct InputBindings(HashMap<InputAction, Vec<KeyCode>>);
+impl Default for InputBindings {
+    fn default() -> Self {
+        Self(HashMap::from([
+            (InputAction::MoveUp, vec![KeyCode::ArrowUp, KeyCode::KeyW]),
+            (
+                InputAction::MoveDown,
+                vec![KeyCode::ArrowDown, KeyCode::KeyS],
+            ),
+            (
+                InputAction::MoveLeft,
+                vec![KeyCode::ArrowLeft, KeyCode::KeyA],
+            ),
+            (
+                InputAction::MoveRight,
+                vec![KeyCode::ArrowRight, KeyCode::KeyD],
+            ),
+            (InputAction::Jump, vec![KeyCode::Space]),
+            (InputAction::Interact, vec![KeyCode::KeyX]),
+        ]))
+    }
+}
+impl InputBindings {
+    /// Makes bindings from a list of keys for each action. Actions that aren't listed keep their default keys.
+    ///
+    /// If a key ends up bound to more than one action, that key is returned instead.
+    pub fn from_keys(keys: HashMap<InputAction, Vec<KeyCode>>) -> Result<Self, KeyCode> {
+        let mut bindings = Self::default();
+        bindings.0.extend(keys);
+        let mut seen = Vec::new();
+        for key in bindings.0.values().flatten() {
+            if seen.contains(key) {
+                return Err(*key);
+            }
+            seen.push(*key);
+        }
+        Ok(bindings)
+    }
+
+    pub fn keys(&self, action: InputAction) -> &[KeyCode] {
+        self.0.get(&action).map_or(&[], Vec::as_slice)
+    }
+
+    /// Holding more than one of an action's keys still only counts once.
+    pub fn pressed(&self, keyboard: &ButtonInput<KeyCode>, action: InputAction) -> bool {
+        keyboard.any_pressed(self.keys(action).iter().copied())
+    }
+
+    pub fn just_pressed(&self, keyboard: &ButtonInput<KeyCode>, action: InputAction) -> bool {
+        keyboard.any_just_pressed(self.keys(action).iter().copied())
+    }
+
+    /// Makes `key` the only key for `action`.
+    ///
+    /// If another action already uses `key`, nothing changes and that action is returned instead.
+    pub fn bind(&mut self, action: InputAction, key: KeyCode) -> Result<(), InputAction> {
+        if let Some(other) = InputAction::ALL
+            .into_iter()
+            .find(|other| *other != action && self.keys(*other).contains(&key))
+        {
+            return Err(other);
+        }
+        self.0.insert(action, vec![key]);
+        Ok(())
+    }
+}
+
+/// Everything the player can do with a key in the overworld.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum InputAction {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    Jump,
+    Interact,
+}
+impl InputAction {
+    pub const ALL: [InputAction; 6] = [
+        InputAction::MoveUp,
+        InputAction::MoveDown,
+        InputAction::MoveLeft,
+        InputAction::MoveRight,
+        InputAction::Jump,
+        InputAction::Interact,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InputAction::MoveUp => "Move Up",
+            InputAction::MoveDown => "Move Down",
+            InputAction::MoveLeft => "Move Left",
+            InputAction::MoveRight => "Move Right",
+            InputAction::Jump => "Jump",
+            InputAction::Interact => "Interact",
+        }
+    }
+
+    /// The action's name in the config file.
+    pub fn config_key(self) -> &'static str {
+        match self {
+            InputAction::MoveUp => "move_up",
+            InputAction::MoveDown => "move_down",
+            InputAction::MoveLeft => "move_left",
+            InputAction::MoveRight => "move_right",
+            InputAction::Jump => "jump",
+            InputAction::Interact => "interact",
+        }
+    }
+}
+
+/// Window sizes in physical pixels.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// 1280×720, the size the Garalina screen is designed around.
+    #[default]
+    Hd,
+    /// 1920×1080
+    FullHd,
+    /// The size of the primary monitor.
+    Native,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum CameraMode {
+    /// Looks at the level from a fixed angle, and only slides sideways to keep the player in view.
+    #[default]
+    Fixed,
+    /// Swings around to stay behind the direction the player is facing.
+    ///
+    /// Controls stay relative to the level, not the camera, so up doesn't always mean away from the camera.
+    BehindPlayer,
+    /// Circles the player and looks at them. Holding the right mouse button and moving the mouse turns it.
+    ///
+    /// Controls follow the camera, so up always means away from it.
+    Orbit,
+}
+
+// Systems
+fn cycle_msaa(keyboard: Res<ButtonInput<KeyCode>>, mut settings: ResMut<GraphicsSettings>) {
+    if keyboard.just_pressed(CYCLE_MSAA_KEY) {
+        settings.msaa = match settings.msaa {
+            Msaa::Off => Msaa::Sample2,
+            Msaa::Sample2 => Msaa::Sample4,
+            Msaa::Sample4 => Msaa::Sample8,
+            Msaa::Sample8 => Msaa::Off,
+        };
+        info!("MSAA set to {:?}", settings.msaa);
+    }
+}
+
+fn cycle_resolution(keyboard: Res<ButtonInput<KeyCode>>, mut settings: ResMut<GraphicsSettings>) {
+    if keyboard.just_pressed(CYCLE_RESOLUTION_KEY) {
+        settings.resolution = match settings.resolution {
+            Resolution::Hd => Resolution::FullHd,
+            Resolution::FullHd => Resolution::Native,
+            Resolution::Native => Resolution::Hd,
+        };
+        info!("Resolution set to {:?}", settings.resolution);
+    }
+}
+
+fn cycle_camera_mode(keyboard: Res<ButtonInput<KeyCode>>, mut settings: ResMut<GraphicsSettings>) {
+    if keyboard.just_pressed(CYCLE_CAMERA_MODE_KEY) {
+        settings.camera_mode = match settings.camera_mode {
+            CameraMode::Fixed => CameraMode::BehindPlayer,
+            CameraMode::BehindPlayer => CameraMode::Orbit,
+            CameraMode::Orbit => CameraMode::Fixed,
+        };
+        info!("Camera mode set to {:?}", settings.camera_mode);
+    }
+}
+
+fn toggle_physics_debug(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut settings: ResMut<GraphicsSettings>,
+) {
+    if keyboard.just_pressed(TOGGLE_PHYSICS_DEBUG_KEY) {
+        settings.physics_debug = !settings.physics_debug;
+        info!("Physics debug rendering set to {}", settings.physics_debug);
+    }
+}
+
+/// Resizes the primary window whenever the resolution setting changes, including once at startup.
+///
+/// Resizing sends WindowResized, which the Garalina screen already uses to keep its logo fitted to the window.
+fn apply_resolution(
+    settings: Res<GraphicsSettings>,
+    mut applied_resolution: Local<Option<Resolution>>,
+    mut window: Single<&mut Window, With<PrimaryWindow>>,
+    monitor: Option<Single<&Monitor, With<PrimaryMonitor>>>,
+) {
+    if *applied_resolution == Some(settings.resolution) {
+        return;
+    }
+
+    let (width, height) = match settings.resolution {
+        Resolution::Hd => (1280, 720),
+        Resolution::FullHd => (1920, 1080),
+        Resolution::Native => {
+            let Some(monitor) = monitor else {
+                // Monitors might not be found on the first frame, so try again next frame.
+                return;
+            };
+            (monitor.physical_width, monitor.physical_height)
+        }
+    };
+    window.resolution.set_physical_resolution(width, height);
+    *applied_resolution = Some(settings.resolution);
+}
+
+/// Switches the primary window in and out of fullscreen to match GraphicsSettings.
+fn apply_window_mode(
+    settings: Res<GraphicsSettings>,
+    mut window: Single<&mut Window, With<PrimaryWindow>>,
+) {
+    let mode = if settings.fullscreen {
+        WindowMode::BorderlessFullscreen(MonitorSelection::Current)
+    } else {
+        WindowMode::Windowed
+    };
+    if window.mode != mode {
+        window.mode = mode;
+    }
+}
+
+/// Keeps every camera's MSAA in sync with GraphicsSettings, including cameras spawned by later states.
+fn apply_msaa(settings: Res<GraphicsSettings>, mut cameras: Query<(Ref<Camera>, &mut Msaa)>) {
+    for (camera, mut msaa) in cameras.iter_mut() {
+        if (settings.is_changed() || camera.is_added()) && *msaa != settings.msaa {
+            *msaa = settings.msaa;
+        }
+    }
+}
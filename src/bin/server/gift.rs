@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Whether the gift is open, shared by every connection and the console.
+///
+/// The lowest bit is whether it's open, and the rest count how many times it has opened or closed.
+/// A respawn timer remembers the count from when the gift opened, so it does nothing if the level
+/// reloaded and the gift changed in the meantime.
+#[derive(Default)]
+pub struct Gift(AtomicU64);
+
+impl Gift {
+    pub fn is_open(&self) -> bool {
+        self.0.load(Ordering::Acquire) & 1 == 1
+    }
+
+    /// Opens the gift if it's closed, and returns what respawn needs to close it again.
+    ///
+    /// If two clients open the gift at the same time, only the first one gets it.
+    pub fn open(&self) -> Option<u64> {
+        let closed = self.0.load(Ordering::Acquire);
+        if closed & 1 == 1 {
+            return None;
+        }
+        self.0
+            .compare_exchange(closed, closed + 1, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| closed + 1)
+    }
+
+    /// Closes the gift after it respawns, unless it was closed some other way since it opened.
+    ///
+    /// Returns whether it closed.
+    pub fn respawn(&self, opened: u64) -> bool {
+        self.0
+            .compare_exchange(opened, opened + 1, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Closes the gift when the level reloads, which cancels any respawn that was waiting.
+    pub fn reset(&self) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |state| {
+                Some((state | 1) + 1)
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_first_opener_gets_the_gift() {
+        let gift = Gift::default();
+        assert!(!gift.is_open());
+        assert!(gift.open().is_some());
+        assert!(gift.is_open());
+        assert!(gift.open().is_none());
+    }
+
+    #[test]
+    fn the_gift_respawns_closed() {
+        let gift = Gift::default();
+        let opened = gift.open().unwrap();
+        assert!(gift.respawn(opened));
+        assert!(!gift.is_open());
+        assert!(gift.open().is_some());
+    }
+
+    #[test]
+    fn reloading_cancels_the_respawn() {
+        let gift = Gift::default();
+        let opened = gift.open().unwrap();
+        gift.reset();
+        assert!(!gift.is_open());
+
+        // Someone opens it again after the reload, and the old timer must not close it early.
+        let reopened = gift.open().unwrap();
+        assert!(!gift.respawn(opened));
+        assert!(gift.is_open());
+        assert!(gift.respawn(reopened));
+        assert!(!gift.is_open());
+    }
+
+    #[test]
+    fn reloading_a_closed_gift_keeps_it_closed() {
+        let gift = Gift::default();
+        gift.reset();
+        assert!(!gift.is_open());
+        assert!(gift.open().is_some());
+    }
+}
@@ -0,0 +1,275 @@
+use miniscop::networking::Packet;
+use std::collections::{HashMap, HashSet};
+
+/// Keeps track of which other players one client is close enough to be sent, so movement far away isn't sent at all.
+/// Players in another zone are never in range.
+///
+/// Each connection's broadcast receiver has its own, and feeds it every movement on the broadcast channel,
+/// including the client's own. That way it never has to lock the ConnectionRegistry.
+pub struct InterestArea {
+    client_id: u64,
+    radius: f32,
+    /// The position and zone are None until the client first moves, and until then, every player is in range.
+    position: Option<[f32; 3]>,
+    zone: Option<u16>,
+    /// The latest movement of every other player, so they can be shown again when they come back in range.
+    others: HashMap<u64, ([f32; 3], u8, u16)>,
+    /// The players this client has been sent, and hasn't been told are out of range.
+    visible: HashSet<u64>,
+}
+
+/// What a client should be sent after a movement, to keep its view of nearby players up to date.
+pub enum InterestUpdate {
+    /// A movement of a player that is in range. If they just came into range, it's reliable and teleports them there.
+    Show { packet: Packet, entered: bool },
+    /// A player that just went out of range, which is sent as Packet::PlayerOutOfRange.
+    Hide(u64),
+}
+
+impl InterestArea {
+    /// The roster is what the client was sent when it joined, so it already sees everyone on it.
+    pub fn new(client_id: u64, radius: f32, roster: &[(u64, f32, f32, f32, u8, u16)]) -> Self {
+        Self {
+            client_id,
+            radius,
+            position: None,
+            zone: None,
+            others: roster
+                .iter()
+                .map(|&(id, x, y, z, animation_frame, zone)| {
+                    (id, ([x, y, z], animation_frame, zone))
+                })
+                .collect(),
+            visible: roster.iter().map(|&(id, ..)| id).collect(),
+        }
+    }
+
+    /// Records a movement from the broadcast channel, and returns what this client should be sent because of it.
+    ///
+    /// When another player moves, only they can come into or go out of range.
+    /// When this client moves, every other player is checked again.
+    pub fn on_movement(
+        &mut self,
+        id: u64,
+        position: [f32; 3],
+        animation_frame: u8,
+        zone: u16,
+        packet: Packet,
+    ) -> Vec<InterestUpdate> {
+        if id == self.client_id {
+            self.position = Some(position);
+            self.zone = Some(zone);
+            let mut updates = Vec::new();
+            for (&other_id, &(other_position, other_frame, other_zone)) in &self.others {
+                let in_range = self.in_range(other_position, other_zone);
+                if in_range && self.visible.insert(other_id) {
+                    let [x, y, z] = other_position;
+                    updates.push(InterestUpdate::Show {
+                        packet: Packet::PlayerMovement {
+                            id: Some(other_id),
+                            x,
+                            y,
+                            z,
+                            animation_frame: other_frame,
+                            teleported: true,
+                            zone: other_zone,
+                        },
+                        entered: true,
+                    });
+                } else if !in_range && self.visible.remove(&other_id) {
+                    updates.push(InterestUpdate::Hide(other_id));
+                }
+            }
+            return updates;
+        }
+
+        self.others.insert(id, (position, animation_frame, zone));
+        if !self.in_range(position, zone) {
+            return if self.visible.remove(&id) {
+                vec![InterestUpdate::Hide(id)]
+            } else {
+                Vec::new()
+            };
+        }
+        let entered = self.visible.insert(id);
+        let packet = match packet {
+            // A player coming back into range appears where they are, rather than walking from where they left.
+            Packet::PlayerMovement {
+                id,
+                x,
+                y,
+                z,
+                animation_frame,
+                zone,
+                ..
+            } if entered => Packet::PlayerMovement {
+                id,
+                x,
+                y,
+                z,
+                animation_frame,
+                teleported: true,
+                zone,
+            },
+            packet => packet,
+        };
+        vec![InterestUpdate::Show { packet, entered }]
+    }
+
+    /// Whether this client can see another player, and so hears their nearby chat.
+    pub fn is_visible(&self, id: u64) -> bool {
+        self.visible.contains(&id)
+    }
+
+    /// Forgets a player who disconnected.
+    pub fn forget(&mut self, id: u64) {
+        self.others.remove(&id);
+        self.visible.remove(&id);
+    }
+
+    fn in_range(&self, other: [f32; 3], other_zone: u16) -> bool {
+        if self.zone.is_some_and(|zone| zone != other_zone) {
+            return false;
+        }
+        self.position.is_none_or(|position| {
+            let distance_squared: f32 = (0..3).map(|i| (other[i] - position[i]).powi(2)).sum();
+            distance_squared <= self.radius * self.radius
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn movement(id: u64, x: f32, zone: u16) -> Packet {
+        Packet::PlayerMovement {
+            id: Some(id),
+            x,
+            y: 0.0,
+            z: 0.0,
+            animation_frame: 0,
+            teleported: false,
+            zone,
+        }
+    }
+
+    fn on_movement(area: &mut InterestArea, id: u64, x: f32, zone: u16) -> Vec<InterestUpdate> {
+        area.on_movement(id, [x, 0.0, 0.0], 0, zone, movement(id, x, zone))
+    }
+
+    #[test]
+    fn nearby_chat_is_only_heard_in_range() {
+        let mut area = InterestArea::new(1, 10.0, &[(2, 50.0, 0.0, 0.0, 0, 0)]);
+        // Until the client moves, it sees everyone on its roster.
+        assert!(area.is_visible(2));
+        on_movement(&mut area, 1, 0.0, 0);
+        assert!(!area.is_visible(2));
+        on_movement(&mut area, 2, 5.0, 0);
+        assert!(area.is_visible(2));
+        assert!(!area.is_visible(3));
+    }
+
+    /// The IDs of players a list of updates shows, and whether each just came into range and teleports there.
+    fn shown(updates: &[InterestUpdate]) -> Vec<(u64, bool, bool)> {
+        updates
+            .iter()
+            .filter_map(|update| match update {
+                InterestUpdate::Show {
+                    packet: Packet::PlayerMovement { id, teleported, .. },
+                    entered,
+                } => Some((id.unwrap(), *entered, *teleported)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn hidden(updates: &[InterestUpdate]) -> Vec<u64> {
+        updates
+            .iter()
+            .filter_map(|update| match update {
+                InterestUpdate::Hide(id) => Some(*id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn players_moving_in_range_are_shown() {
+        let mut area = InterestArea::new(1, 10.0, &[]);
+        on_movement(&mut area, 1, 0.0, 0);
+        // First seen, so it teleports in.
+        assert_eq!(shown(&on_movement(&mut area, 2, 5.0, 0)), [(2, true, true)]);
+        // Already shown, so it just walks.
+        assert_eq!(
+            shown(&on_movement(&mut area, 2, 6.0, 0)),
+            [(2, false, false)]
+        );
+        // Exactly on the edge of the radius still counts.
+        assert_eq!(
+            shown(&on_movement(&mut area, 2, 10.0, 0)),
+            [(2, false, false)]
+        );
+    }
+
+    #[test]
+    fn players_leaving_range_are_hidden_once() {
+        let mut area = InterestArea::new(1, 10.0, &[]);
+        on_movement(&mut area, 1, 0.0, 0);
+        on_movement(&mut area, 2, 5.0, 0);
+        assert_eq!(hidden(&on_movement(&mut area, 2, 11.0, 0)), [2]);
+        // Moving around out of range sends nothing more.
+        assert!(on_movement(&mut area, 2, 20.0, 0).is_empty());
+    }
+
+    #[test]
+    fn players_coming_back_teleport_in() {
+        let mut area = InterestArea::new(1, 10.0, &[]);
+        on_movement(&mut area, 1, 0.0, 0);
+        on_movement(&mut area, 2, 5.0, 0);
+        on_movement(&mut area, 2, 50.0, 0);
+        // Their movement said they walked, but they have to appear where they are.
+        assert_eq!(shown(&on_movement(&mut area, 2, 9.0, 0)), [(2, true, true)]);
+    }
+
+    #[test]
+    fn moving_away_hides_others_and_moving_back_shows_them() {
+        let mut area = InterestArea::new(1, 10.0, &[]);
+        on_movement(&mut area, 1, 0.0, 0);
+        on_movement(&mut area, 2, 5.0, 0);
+        on_movement(&mut area, 3, -5.0, 0);
+
+        let mut hidden_ids = hidden(&on_movement(&mut area, 1, 30.0, 0));
+        hidden_ids.sort();
+        assert_eq!(hidden_ids, [2, 3]);
+
+        let mut shown_ids = shown(&on_movement(&mut area, 1, 0.0, 0));
+        shown_ids.sort();
+        assert_eq!(shown_ids, [(2, true, true), (3, true, true)]);
+    }
+
+    #[test]
+    fn other_zones_are_never_in_range() {
+        let mut area = InterestArea::new(1, 10.0, &[]);
+        on_movement(&mut area, 1, 0.0, 0);
+        on_movement(&mut area, 2, 1.0, 0);
+        // The same spot in another level is still out of range.
+        assert_eq!(hidden(&on_movement(&mut area, 2, 1.0, 1)), [2]);
+        assert_eq!(shown(&on_movement(&mut area, 2, 1.0, 0)), [(2, true, true)]);
+
+        // The client changing zones hides everyone it left behind, and shows who's in the new one.
+        on_movement(&mut area, 3, 2.0, 1);
+        let updates = on_movement(&mut area, 1, 0.0, 1);
+        assert_eq!(hidden(&updates), [2]);
+        assert_eq!(shown(&updates), [(3, true, true)]);
+    }
+
+    #[test]
+    fn forgotten_players_come_back_as_new() {
+        let mut area = InterestArea::new(1, 10.0, &[(2, 5.0, 0.0, 0.0, 0, 0)]);
+        on_movement(&mut area, 1, 0.0, 0);
+        area.forget(2);
+        assert!(!area.is_visible(2));
+        assert_eq!(shown(&on_movement(&mut area, 2, 5.0, 0)), [(2, true, true)]);
+    }
+}
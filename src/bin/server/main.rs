@@ -1,21 +1,73 @@
-use anyhow;
+mod gift;
+mod interest;
+mod metrics;
+mod rate_limit;
+mod registry;
+mod saved_positions;
+#[cfg(test)]
+mod testing;
+
+use anyhow::Context;
 use clap::Parser;
-use miniscop::networking::{receive_packet, send_packet, Packet};
-use quinn::{Connection, Endpoint, ServerConfig};
+use gift::Gift;
+use interest::{InterestArea, InterestUpdate};
+use metrics::{serve_metrics, Metrics};
+use miniscop::lockstep::{self, LockstepPlayer};
+use miniscop::networking::{
+    dequantize_position, movement_batches, origin_tag, read_framed, receive_packet,
+    receive_packet_datagram, sanitize_name, sanitize_text, send_packet_datagram, write_framed,
+    DisconnectReason, NetworkError, Packet, ReliableSender, EMOTE_COUNT, HEARTBEAT_INTERVAL,
+    MAX_CHAT_LENGTH, MAX_PACKET_SIZE, PROTOCOL_VERSION, SERVER_SHUTDOWN_CODE,
+    VERSION_MISMATCH_CODE,
+};
+use quinn::{
+    Connection, ConnectionError, Endpoint, EndpointConfig, RecvStream, ServerConfig, TokioRuntime,
+    VarInt,
+};
+use rate_limit::{RateLimit, RateLimiter};
+use registry::{ConnectionRegistry, SessionStats};
 use rustls_pki_types::pem::PemObject;
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use tokio::sync::broadcast;
+use saved_positions::{SavedPosition, SavedPositions};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
-use tracing::{error, info};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn, Level};
+use tracing_subscriber::EnvFilter;
+
+/// How long the gift stays open before it comes back for someone else to open.
+const GIFT_RESPAWN_TIME: Duration = Duration::from_secs(30);
+/// How soon after a level reload another one is allowed, so a held-down command can't flood every client.
+const RELOAD_COOLDOWN: Duration = Duration::from_secs(5);
+/// How long to wait after telling every client the server is shutting down, before closing their connections.
+/// This gives Packet::ServerShutdown time to arrive, since closing a connection drops whatever hasn't been sent.
+/// Clients turned away by Packet::VersionMismatch get as long, unless they hang up first.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+/// How long movement is collected before being sent to a client as Packet::MovementBatch.
+/// This is one of the client's fixed ticks, so most batches have at most one movement from each player.
+const MOVEMENT_BATCH_INTERVAL: Duration = Duration::from_micros(15_625);
+/// How often a connection that keeps falling behind the broadcast channel logs how far behind it was.
+const LAG_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// How often positions saved by players leaving are written to --saved-positions.
+/// Players who leave less than this long before the server crashes start at their old position next time.
+const SAVED_POSITIONS_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// The file path of your TLS certificate in PEM format.
     /// This can come from a .pem, .cert, or .crt file.
+    ///
+    /// The certificate and key are read again when the server receives SIGHUP, or when "reload-certificate" is typed
+    /// into its terminal, so a renewed certificate can be used without kicking anyone.
     #[clap(short, long, value_name = "PATH")]
     certificate: PathBuf,
     /// The file path of your TLS private key in PEM format.
@@ -24,36 +76,251 @@ struct Args {
     key: PathBuf,
     /// An optional IP address and port to use when hosting your server.
     /// This defaults to your computer's IP on port 4433.
+    ///
+    /// To accept players on both IPv4 and IPv6 networks, use [::]:4433.
+    /// IPv6 addresses always accept IPv4 connections too.
     #[clap(short, long, default_value = "127.0.0.1:4433")]
     address: SocketAddr,
-    // Todo: Add optional file path to .txt file with banned client IPs
+    /// An optional file path to a .txt file of banned IP addresses, with one address per line.
+    /// Blank lines and lines starting with # are skipped.
+    #[clap(long, value_name = "PATH")]
+    banned_ips: Option<PathBuf>,
     /// Maximum number of allowed players.
     /// If you increase this past 100, you accept the of risk overwhelming your players with packets and/or running out of memory on your computer.
     #[clap(short, long, default_value = "100")]
     max_players: usize,
+    /// How many players can be in each room at once. Each level is its own room, which players pick in the lobby.
+    /// This defaults to --max-players, so only the whole server can be full.
+    #[clap(long)]
+    room_capacity: Option<u32>,
+    /// How many seconds a client can go without sending anything before it is disconnected.
+    /// Clients send a heartbeat every few seconds, so this catches clients that froze without closing their connection.
+    #[clap(long, value_name = "SECONDS", default_value = "15")]
+    timeout: u64,
+    /// The walking speed every player uses, in meters per second.
+    #[clap(long, default_value = "4.0", value_parser = parse_move_speed)]
+    move_speed: f32,
+    /// Where players spawn in the level, as x,y,z.
+    #[clap(long, value_delimiter = ',', num_args = 3, default_values_t = [0.0, 0.5, 0.0])]
+    spawn: Vec<f32>,
+    /// How close another player has to be, in meters, for a client to be sent their movement.
+    /// Without this, every client is sent everyone's movement.
+    ///
+    /// Players who go out of range are sent as Packet::PlayerOutOfRange, and come back as a teleport when they return,
+    /// so clients see them pop in and out at the edge of the radius.
+    /// Nearby chat only reaches players within the radius too, and global chat still reaches everyone.
+    #[clap(long, value_name = "METERS")]
+    interest_radius: Option<f32>,
+    /// The most packets per second each client can send. Packets past that are dropped,
+    /// and clients that keep sending more than double it are kicked.
+    ///
+    /// Clients send at most 64 movement packets per second, plus a few others, so this should stay well above that.
+    #[clap(long, value_name = "PACKETS_PER_SECOND", default_value = "200")]
+    rate_limit: u32,
+    /// An optional IP address and port to serve metrics on over HTTP, in Prometheus' text format.
+    /// Without this, no metrics are served.
+    ///
+    /// The metrics are miniscop_connections, miniscop_packets_received_total, miniscop_packets_forwarded_total,
+    /// miniscop_broadcast_lags_total, and miniscop_broadcast_packets_skipped_total.
+    /// Anyone who can reach the address can read them, so 127.0.0.1:9100 is safest.
+    #[clap(long, value_name = "ADDRESS")]
+    metrics_address: Option<SocketAddr>,
+    /// How many broadcast packets are kept for each player, for connections that fall behind.
+    ///
+    /// Each player sends up to 64 movement packets per second, so the channel fills at up to 64 times the
+    /// player count per second. With the default of 4, a connection that stalls for more than 1/16 of a second
+    /// while everyone moves skips packets, which is logged as the broadcast channel lagging.
+    /// Raise this if that warning shows up often. It costs about 100 bytes per packet kept.
+    #[clap(long, default_value = "4")]
+    channel_multiplier: usize,
+    /// How much the server logs: error, warn, info, debug, or trace.
+    ///
+    /// This takes precedence over the RUST_LOG environment variable, which is used when this isn't given.
+    /// If neither is set, the server logs at info.
+    #[clap(long, value_name = "LEVEL")]
+    log_level: Option<Level>,
+    /// An optional file path to a .json file to keep where each player was when they last left.
+    /// Players who come back spawn there instead of at the spawn point. The file is created if it doesn't exist.
+    ///
+    /// Without this, players still spawn where they left off, but only until the server restarts.
+    #[clap(long, value_name = "PATH")]
+    saved_positions: Option<PathBuf>,
+    /// Runs the server in lockstep mode, where clients send their inputs instead of their positions,
+    /// and every client steps every player the same way. See miniscop::lockstep.
+    ///
+    /// Everyone sees everyone in exactly the same place, but other players move later and more unevenly,
+    /// and players can't jump or use level exits. It's meant for small co-op sessions.
+    /// It can't be used with --interest-radius, and "reload" does nothing in lockstep mode.
+    #[clap(long)]
+    lockstep: bool,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    tracing::subscriber::set_global_default(tracing_subscriber::FmtSubscriber::new())?;
+    let filter = match args.log_level {
+        Some(level) => EnvFilter::new(level.to_string()),
+        None => EnvFilter::builder()
+            .with_default_directive(Level::INFO.into())
+            .from_env_lossy(),
+    };
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::FmtSubscriber::builder()
+            .with_env_filter(filter)
+            .finish(),
+    )?;
 
-    let certificate_chain = CertificateDer::pem_file_iter(args.certificate)?
-        .map(|cert| cert.unwrap())
-        .collect();
-    let key = PrivateKeyDer::from_pem_file(args.key)?;
-    let server_config = ServerConfig::with_single_cert(certificate_chain, key)?;
-    let endpoint = Endpoint::server(server_config, args.address)?;
+    let timeout = Duration::from_secs(args.timeout);
+    if let Some(radius) = args.interest_radius
+        && (radius.is_nan() || radius <= 0.0)
+    {
+        return Err(anyhow::anyhow!(
+            "--interest-radius must be more than 0, or nobody would see anyone."
+        ));
+    }
+    let interest_radius = args.interest_radius;
+    if args.lockstep && interest_radius.is_some() {
+        return Err(anyhow::anyhow!(
+            "--lockstep can't be used with --interest-radius, since clients have to see every input to step everyone."
+        ));
+    }
+    let lockstep_speed = args
+        .lockstep
+        .then(|| lockstep::speed_per_tick(args.move_speed));
+    let room_capacity = args
+        .room_capacity
+        .unwrap_or(u32::try_from(args.max_players).unwrap_or(u32::MAX));
+    if room_capacity == 0 {
+        return Err(anyhow::anyhow!(
+            "--room-capacity must be more than 0, or nobody could join a room."
+        ));
+    }
+    let rate_limit = args.rate_limit;
+    if args.channel_multiplier == 0 {
+        return Err(anyhow::anyhow!(
+            "--channel-multiplier must be more than 0, or nothing could be broadcast."
+        ));
+    }
+    if rate_limit == 0 {
+        return Err(anyhow::anyhow!(
+            "--rate-limit must be more than 0, or every client would be kicked."
+        ));
+    }
+    if timeout <= HEARTBEAT_INTERVAL {
+        return Err(anyhow::anyhow!(
+            "--timeout must be longer than the {} second heartbeat interval, or every client would time out.",
+            HEARTBEAT_INTERVAL.as_secs()
+        ));
+    }
+
+    let banned_ips = match &args.banned_ips {
+        Some(path) => load_banned_ips(path)?,
+        None => HashSet::new(),
+    };
+    if !banned_ips.is_empty() {
+        info!("Loaded {} banned IP addresses.", banned_ips.len());
+    }
+
+    let server_config = load_server_config(&args.certificate, &args.key)?;
+    let endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        Some(server_config),
+        bind_socket(args.address)?,
+        Arc::new(TokioRuntime),
+    )?;
+    info!("Listening on {}", args.address);
+
+    let level_spawn = [args.spawn[0], args.spawn[1], args.spawn[2]];
+    let level_config = Packet::LevelConfig {
+        move_speed: args.move_speed,
+        spawn: level_spawn,
+        lockstep: args.lockstep,
+    };
+    if args.lockstep {
+        info!("Running in lockstep mode.");
+    }
 
     // Create packet broadcaster.
-    // Capacity is enough to handle all connections sending up to 4 packets at the exact same time.
-    let (to_all_connections, _) = broadcast::channel::<Packet>(args.max_players * 4);
+    // Capacity is enough to handle all connections sending up to channel_multiplier packets at the exact same time.
+    let (to_all_connections, _) =
+        broadcast::channel::<Packet>(args.max_players * args.channel_multiplier);
+    let registry = Arc::new(Mutex::new(ConnectionRegistry::default()));
+    let saved_positions = SavedPositions::load(args.saved_positions.clone())?;
+    if args.saved_positions.is_some() {
+        info!("Loaded {} saved positions.", saved_positions.len());
+    }
+    let saved_positions = Arc::new(Mutex::new(saved_positions));
+    tokio::spawn(flush_saved_positions(saved_positions.clone()));
+    let gift = Arc::new(Gift::default());
+    let metrics = Arc::new(Metrics::default());
+
+    if let Some(address) = args.metrics_address {
+        let endpoint = endpoint.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(address, endpoint, metrics).await {
+                error!("Metrics server error: {e:#?}");
+            }
+        });
+    }
 
-    info!("Waiting for connections...");
-    while let Some(incoming) = endpoint.accept().await {
-        let address = incoming.remote_address();
-        if endpoint.open_connections() > args.max_players {
+    let console_broadcaster = to_all_connections.clone();
+    let console_gift = gift.clone();
+    let console_registry = registry.clone();
+    let console_endpoint = endpoint.clone();
+    let console_certificate = args.certificate.clone();
+    let console_key = args.key.clone();
+    let console_lockstep = args.lockstep;
+    tokio::spawn(async move {
+        if let Err(e) = run_console(
+            console_broadcaster,
+            console_gift,
+            console_registry,
+            console_endpoint,
+            console_certificate,
+            console_key,
+            console_lockstep,
+        )
+        .await
+        {
+            error!("Console error: {e:#?}");
+        }
+    });
+
+    #[cfg(unix)]
+    {
+        let endpoint = endpoint.clone();
+        let certificate = args.certificate.clone();
+        let key = args.key.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reload_certificate_on_hangup(endpoint, certificate, key).await {
+                error!("SIGHUP handler error: {e:#?}");
+            }
+        });
+    }
+
+    info!("Waiting for connections... Press Ctrl-C to shut down.");
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            signal = tokio::signal::ctrl_c() => {
+                signal?;
+                shut_down(&endpoint, &to_all_connections).await;
+                if let Err(e) = saved_positions.lock().await.flush() {
+                    error!("Could not save positions: {e:#}");
+                }
+                break;
+            }
+        };
+        let address = canonical_address(incoming.remote_address());
+        if banned_ips.contains(&address.ip()) {
+            info!("Refusing {address}. Its IP address is banned.");
+            incoming.refuse();
+        } else if endpoint.open_connections() > args.max_players {
             info!("Refusing {address}. Max player-count was reached.");
             incoming.refuse();
         } else if !incoming.remote_address_validated() {
@@ -63,18 +330,84 @@ async fn main() -> anyhow::Result<()> {
             info!("Accepting connection from {address}...");
             match incoming.await {
                 Ok(connection) => {
-                    let client_id = connection.stable_id() as u64;
-                    info!("Established connection. Client ID is {client_id}.");
+                    info!("Established connection with {address}.");
 
                     let to_all_connections_clone = to_all_connections.clone();
+                    let registry = registry.clone();
+                    let saved_positions = saved_positions.clone();
+                    let gift = gift.clone();
+                    let level_config = level_config.clone();
+                    let metrics = metrics.clone();
                     tokio::spawn(async move {
-                        if let Err(e) =
-                            handle_connection(connection, to_all_connections_clone.clone()).await
+                        let (session_token, player_token, reliable) =
+                            match receive_hello(&connection).await {
+                                Ok(hello) => hello,
+                                Err(e) => {
+                                    error!("Handshake error from {address}: {e:#?}");
+                                    return;
+                                }
+                            };
+                        let (client_id, spawn_slot) = registry
+                            .lock()
+                            .await
+                            .begin_session(session_token, &connection);
+                        info!("Client ID of {address} is {client_id}.");
+                        let saved_position = saved_positions.lock().await.get(player_token);
+                        let stats = SessionStats::new();
+
+                        let reason = match handle_connection(
+                            connection.clone(),
+                            reliable,
+                            client_id,
+                            to_all_connections_clone.clone(),
+                            level_config,
+                            saved_position,
+                            level_spawn,
+                            spawn_slot,
+                            gift,
+                            registry.clone(),
+                            timeout,
+                            interest_radius,
+                            rate_limit,
+                            room_capacity,
+                            lockstep_speed,
+                            &stats,
+                            metrics,
+                        )
+                        .await
                         {
-                            error!("Connection error from {address}: {e:#?}")
+                            Ok(reason) => reason,
+                            Err(e) => {
+                                let reason = disconnect_reason(&e);
+                                match reason {
+                                    DisconnectReason::ProtocolViolation => {
+                                        warn!("Kicking {address}: {e}")
+                                    }
+                                    DisconnectReason::Error => {
+                                        error!("Connection error from {address}: {e:#?}")
+                                    }
+                                    DisconnectReason::Graceful | DisconnectReason::Timeout => {
+                                        info!("Connection from {address} ended: {e}")
+                                    }
+                                }
+                                reason
+                            }
+                        };
+                        let mut registry = registry.lock().await;
+                        let zone = registry.room_of(client_id);
+                        if registry.end_session(session_token, &connection, address, &stats, reason)
+                        {
+                            if let (Some(zone), Some(position)) =
+                                (zone, registry.position_at(client_id, Instant::now()))
+                            {
+                                saved_positions
+                                    .lock()
+                                    .await
+                                    .insert(player_token, zone, position);
+                            }
+                            let _ = to_all_connections_clone
+                                .send(Packet::ClientDisconnect(Some(client_id), Some(reason)));
                         }
-                        let _ = to_all_connections_clone
-                            .send(Packet::ClientDisconnect(Some(client_id)));
                     });
                 }
                 Err(connection_error) => {
@@ -87,48 +420,564 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Parses --move-speed, which has to be a finite number of meters per second above 0.
+fn parse_move_speed(value: &str) -> Result<f32, String> {
+    let speed = value.parse::<f32>().map_err(|e| e.to_string())?;
+    if speed.is_finite() && speed > 0.0 {
+        Ok(speed)
+    } else {
+        Err("it has to be a finite number more than 0, or nobody could walk".to_string())
+    }
+}
+
+/// Tells every client the server is shutting down, waits for that to be sent, and then closes every connection.
+async fn shut_down(endpoint: &Endpoint, to_all_connections: &Sender<Packet>) {
+    let connections = endpoint.open_connections();
+    info!("Shutting down...");
+    // Nobody being connected is the only reason this could fail.
+    let _ = to_all_connections.send(Packet::ServerShutdown);
+    tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+    endpoint.close(SERVER_SHUTDOWN_CODE, b"Server shut down.");
+    endpoint.wait_idle().await;
+    info!("Closed {connections} connections. Goodbye!");
+}
+
+/// Reads a ban list, which has one IP address per line.
+///
+/// Blank lines and lines starting with # are skipped. Any other line that isn't an IP address is an error,
+/// so a typo can't silently leave someone unbanned.
+fn load_banned_ips(path: &Path) -> anyhow::Result<HashSet<IpAddr>> {
+    let file = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Could not read the ban list at {}: {e}", path.display()))?;
+    let mut banned_ips = HashSet::new();
+    for (line_number, line) in file.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let ip: IpAddr = line.parse().map_err(|e| {
+            anyhow::anyhow!(
+                "Line {} of the ban list at {} is not an IP address ({line:?}): {e}",
+                line_number + 1,
+                path.display()
+            )
+        })?;
+        // Players connecting over IPv4 to a dual-stack socket are compared by their IPv4 address.
+        banned_ips.insert(ip.to_canonical());
+    }
+    Ok(banned_ips)
+}
+
+/// Reads the TLS certificate chain and private key, and makes the server config that presents them.
+fn load_server_config(certificate: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let certificate_chain = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Could not read the certificate at {}: {e}",
+                certificate.display()
+            )
+        })?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| anyhow::anyhow!("Could not read the key at {}: {e}", key.display()))?;
+    Ok(ServerConfig::with_single_cert(certificate_chain, key)?)
+}
+
+/// Reads the TLS certificate and key again, and gives them to the endpoint.
+/// New connections are made with them, and connections that are already open carry on with the old ones.
+///
+/// If either can't be read, or they don't match, the endpoint keeps the old ones.
+fn reload_certificate(endpoint: &Endpoint, certificate: &Path, key: &Path) {
+    match load_server_config(certificate, key) {
+        Ok(server_config) => {
+            endpoint.set_server_config(Some(server_config));
+            info!("Reloaded the TLS certificate.");
+        }
+        Err(e) => {
+            error!("Could not reload the TLS certificate, so the old one is still used: {e:#}")
+        }
+    }
+}
+
+/// Reloads the TLS certificate every time the server receives SIGHUP, like after a certificate is renewed.
+#[cfg(unix)]
+async fn reload_certificate_on_hangup(
+    endpoint: Endpoint,
+    certificate: PathBuf,
+    key: PathBuf,
+) -> anyhow::Result<()> {
+    let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, so reloading the TLS certificate.");
+        reload_certificate(&endpoint, &certificate, &key);
+    }
+    Ok(())
+}
+
+/// Binds the server's UDP socket.
+///
+/// IPv6 sockets are made dual-stack, because some operating systems (like Windows) only accept IPv6 on them by default.
+fn bind_socket(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+/// IPv4 players connecting to a dual-stack socket show up as IPv4-mapped IPv6 addresses, like [::ffff:1.2.3.4].
+/// This turns them back into IPv4 addresses, so each player has only one address.
+fn canonical_address(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+/// Awaits the client's Packet::Hello, and returns its session token, player token, and reliable stream.
+///
+/// The client opens its reliable stream before any other stream, and sends Hello as its first frame.
+/// Streams are accepted in the order they were opened, so the first stream is always the reliable one.
+/// Movement is sent as datagrams, which aren't read until handle_connection, so movement sent early just waits.
+///
+/// If the client's protocol version is different, it's sent Packet::VersionMismatch and disconnected.
+async fn receive_hello(connection: &Connection) -> anyhow::Result<(u64, u64, RecvStream)> {
+    let mut reliable = connection.accept_uni().await?;
+    let hello = read_framed(&mut reliable).await.with_context(|| {
+        format!("Could not read Packet::Hello. The client may not be on protocol version {PROTOCOL_VERSION}.")
+    })?;
+    match hello {
+        Some(Packet::Hello {
+            version,
+            session_token,
+            player_token,
+        }) if version == PROTOCOL_VERSION => Ok((session_token, player_token, reliable)),
+        Some(Packet::Hello { version, .. }) => {
+            reject_version(connection).await;
+            Err(anyhow::anyhow!(
+                "Client is on protocol version {version}, but the server is on {PROTOCOL_VERSION}."
+            ))
+        }
+        Some(packet) => Err(anyhow::anyhow!(
+            "Client sent {packet:?} instead of Packet::Hello."
+        )),
+        None => Err(anyhow::anyhow!(
+            "Client closed its reliable stream before sending Packet::Hello."
+        )),
+    }
+}
+
+/// Tells a client with the wrong protocol version which one the server is on, and disconnects it.
+///
+/// The client is given SHUTDOWN_GRACE_PERIOD to receive the packet and hang up itself,
+/// since closing the connection first would drop the packet.
+async fn reject_version(connection: &Connection) {
+    let sent = async {
+        // The client reads everything the server sends from the first stream the server opens.
+        let mut send = connection.open_uni().await?;
+        write_framed(
+            &mut send,
+            Packet::VersionMismatch {
+                server_version: PROTOCOL_VERSION,
+            },
+        )
+        .await?;
+        send.finish()?;
+        anyhow::Ok(())
+    };
+    if let Err(e) = sent.await {
+        error!("Error sending packet: {e:#?}");
+    }
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, connection.closed()).await;
+    connection.close(VERSION_MISMATCH_CODE, b"Protocol version mismatch.");
+}
+
+/// Sends the client the lobby's Packet::RoomList, and waits for it to pick a room that isn't full with Packet::JoinRoom.
+///
+/// If the room filled up before the client picked it, the client is sent the list again so it can pick another.
+/// Returns the zone of the room it joined, or None if it left from the lobby.
+async fn choose_room(
+    reliable: &mut RecvStream,
+    reliable_send: &ReliableSender,
+    client_id: u64,
+    registry: &Mutex<ConnectionRegistry>,
+    room_capacity: u32,
+) -> anyhow::Result<Option<u16>> {
+    loop {
+        let rooms = registry.lock().await.room_list();
+        reliable_send.send(Packet::RoomList {
+            rooms,
+            capacity: room_capacity,
+        })?;
+        match read_framed(reliable).await? {
+            Some(Packet::JoinRoom { zone }) => {
+                if registry
+                    .lock()
+                    .await
+                    .join_room(client_id, zone, room_capacity)
+                {
+                    return Ok(Some(zone));
+                }
+                info!("Client tried to join zone {zone}, which is full.");
+            }
+            Some(Packet::ClientDisconnect(..)) | None => return Ok(None),
+            Some(packet) => {
+                return Err(ProtocolViolation(format!(
+                    "Client sent {packet:?} before joining a room."
+                ))
+                .into());
+            }
+        }
+    }
+}
+
+/// Returned by handle_connection when the client breaks the protocol, so it can be told apart from other errors.
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct ProtocolViolation(String);
+
+/// Works out why handle_connection ended with an error.
+///
+/// The client closing its side, or QUIC giving up on a silent client, aren't really errors.
+fn disconnect_reason(error: &anyhow::Error) -> DisconnectReason {
+    if error.is::<ProtocolViolation>() {
+        return DisconnectReason::ProtocolViolation;
+    }
+    let connection_error = match error.downcast_ref::<NetworkError>() {
+        Some(NetworkError::Connection(connection_error)) => Some(connection_error),
+        _ => error.downcast_ref::<ConnectionError>(),
+    };
+    match connection_error {
+        Some(ConnectionError::ApplicationClosed(_)) => DisconnectReason::Graceful,
+        Some(ConnectionError::TimedOut) => DisconnectReason::Timeout,
+        _ => DisconnectReason::Error,
+    }
+}
+
 /// This function is essentially the first half of a connection.
 ///
 /// It receives packets from the connection, and broadcasts the packets to every other connection.
 ///
-/// 1. Spawn a task to handle the second half of the connection.
-/// 2. Tell the client its ID
-/// 3. Await packets from the client in a loop
-#[tracing::instrument(skip(connection, to_all_connections), fields(address = %connection.remote_address()
+/// 1. Wait for the client to pick a room in the lobby. See choose_room.
+/// 2. Tell the client its ID, and where every other player is and what they're called
+/// 3. Send the client the level config, its spawn point, and whether the gift is open.
+///    Returning clients spawn where they left off, if they left from the room they joined.
+/// 4. Spawn a task to handle the second half of the connection.
+/// 5. Await packets from the client's reliable stream, other streams, and movement datagrams in a loop
+///
+/// With lockstep_speed, the server is in lockstep mode, and steps the client's position from its inputs
+/// by that many millimeters a tick. See miniscop::lockstep.
+///
+/// It returns why the client left, if it left normally or timed out.
+/// Kicked clients end it with a ProtocolViolation error.
+#[expect(
+    clippy::too_many_arguments,
+    reason = "The server's settings are passed to each connection one by one, as they're parsed from Args."
+)]
+#[tracing::instrument(skip(connection, reliable, to_all_connections, gift, registry, stats, metrics), fields(address = %connection.remote_address()
 ))]
 async fn handle_connection(
     connection: Connection,
+    mut reliable: RecvStream,
+    client_id: u64,
     to_all_connections: Sender<Packet>,
-) -> anyhow::Result<()> {
-    // Start a broadcast receiver
+    level_config: Packet,
+    saved_position: Option<SavedPosition>,
+    level_spawn: [f32; 3],
+    spawn_slot: usize,
+    gift: Arc<Gift>,
+    registry: Arc<Mutex<ConnectionRegistry>>,
+    timeout: Duration,
+    interest_radius: Option<f32>,
+    rate_limit: u32,
+    room_capacity: u32,
+    lockstep_speed: Option<i32>,
+    stats: &SessionStats,
+    metrics: Arc<Metrics>,
+) -> anyhow::Result<DisconnectReason> {
+    // Everything reliable goes to the client over this one stream, so it all arrives in the order it was sent.
+    let reliable_send = ReliableSender::spawn(connection.open_uni().await?);
+
+    let Some(room) = choose_room(
+        &mut reliable,
+        &reliable_send,
+        client_id,
+        &registry,
+        room_capacity,
+    )
+    .await?
+    else {
+        return Ok(DisconnectReason::Graceful);
+    };
+    info!("Client joined zone {room}.");
+
+    // Start a broadcast receiver.
+    // Subscribing before reading the roster means no movement can happen in between without being seen.
+    // Inputs are stepped and broadcast under the registry's lock, so subscribing under it too means
+    // each input is either already in the roster's positions or still to come from the receiver, but never both.
     let connection_handle = connection.clone();
-    let from_all_connections = to_all_connections.subscribe();
+    let (from_all_connections, roster, names) = {
+        let registry = registry.lock().await;
+        (
+            to_all_connections.subscribe(),
+            registry.roster_for(client_id),
+            registry.names_for(client_id),
+        )
+    };
+    let interest_area = interest_radius.map(|radius| InterestArea::new(client_id, radius, &roster));
+
+    // Tell the client its ID
+    reliable_send.send(Packet::ClientConnect { id: client_id })?;
+
+    // Show the client everyone who is already here
+    reliable_send.send(Packet::PlayerRoster(roster))?;
+    for (id, name) in names {
+        reliable_send.send(Packet::SetName { id: Some(id), name })?;
+    }
+
+    // Tell the client how to play the level
+    reliable_send.send(level_config)?;
+    if saved_position.is_some_and(|saved| saved.zone == room) {
+        info!("Client is back, so it spawns where it left off.");
+    }
+    let [x, y, z] = saved_positions::spawn_point(saved_position, room, level_spawn, spawn_slot);
+    reliable_send.send(Packet::SetSpawn { x, y, z })?;
+    // In lockstep mode, everyone else starts stepping this client from its spawn point, which they're sent as a roster.
+    let mut lockstep_player = lockstep_speed.map(|_| LockstepPlayer::at([x, y, z]));
+    if let Some(player) = &lockstep_player {
+        let [x, y, z] = player.position();
+        let mut registry = registry.lock().await;
+        registry.record_movement(client_id, [x, y, z], 0, room);
+        to_all_connections.send(Packet::PlayerRoster(vec![(client_id, x, y, z, 0, room)]))?;
+    }
+    let opened = gift.is_open();
+    reliable_send.send(Packet::GiftState { opened })?;
+
+    // Broadcasts are only passed on once the packets above are queued, so the client always gets its ID first.
+    // Anything broadcast in the meantime waits in the receiver.
+    let broadcast_metrics = metrics.clone();
+    let broadcast_send = reliable_send.clone();
     tokio::spawn(async move {
-        if let Err(e) = receive_broadcasts(connection_handle, from_all_connections).await {
+        if let Err(e) = receive_broadcasts(
+            connection_handle,
+            broadcast_send,
+            client_id,
+            from_all_connections,
+            interest_area,
+            broadcast_metrics,
+        )
+        .await
+        {
             error!("Broadcast receiver error: {e:#?}");
         }
     });
 
-    // Tell the client its ID
-    let client_id = connection.stable_id() as u64;
-    let send = connection.open_uni().await?;
-    let packet = Packet::ClientConnect;
-    send_packet(send, packet).await?;
+    // Reading a frame can't be cancelled halfway through, so the reliable stream is read in its own task.
+    let (to_handler, mut from_reliable) = mpsc::channel::<Packet>(16);
+    tokio::spawn(async move {
+        loop {
+            match read_framed(&mut reliable).await {
+                Ok(Some(packet)) => {
+                    if to_handler.send(packet).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    error!("Failed to read reliable stream: {e:#?}");
+                    return;
+                }
+            }
+        }
+    });
 
     // Start awaiting packets.
-    // This loop ends when an error occurs.
+    // This loop ends when an error occurs, or when the client goes silent for longer than the timeout.
+    let mut last_received = Instant::now();
+    // The last absolute position the client sent, which its quantized movements are relative to.
+    let mut movement_origin: Option<[f32; 3]> = None;
+    // The zone the client was last in. Quantized movement doesn't say, and can't change it.
+    let mut movement_zone = room;
+    let mut rate_limiter = RateLimiter::new(rate_limit, last_received);
     loop {
-        let recv = connection.accept_uni().await?;
-        let packet = receive_packet(recv).await?;
+        let packet = tokio::select! {
+            _ = tokio::time::sleep(timeout.saturating_sub(last_received.elapsed())) => {
+                connection.close(VarInt::from_u32(0), b"Timed out.");
+                info!("Client sent nothing for {timeout:?}.");
+                return Ok(DisconnectReason::Timeout);
+            }
+            packet = from_reliable.recv() => match packet {
+                Some(packet) => packet,
+                None => return Err(anyhow::anyhow!("Client's reliable stream closed.")),
+            },
+            recv = connection.accept_uni() => receive_packet(recv?).await?,
+            packet = receive_packet_datagram(&connection) => packet?,
+        };
+        last_received = Instant::now();
+        stats.record_packet();
+        metrics.record_received();
+        // Leaving is always allowed, so a throttled client can still say goodbye.
+        if !matches!(packet, Packet::ClientDisconnect(..)) {
+            match rate_limiter.check(last_received) {
+                RateLimit::Allow => {}
+                RateLimit::Drop { first_in_window } => {
+                    if first_in_window {
+                        warn!("Client sent more than {rate_limit} packets per second. Dropping packets.");
+                    }
+                    continue;
+                }
+                RateLimit::Kick => {
+                    connection.close(VarInt::from_u32(0), b"Sent too many packets.");
+                    return Err(ProtocolViolation(format!(
+                        "Client kept sending more than double the limit of {rate_limit} packets per second."
+                    ))
+                    .into());
+                }
+            }
+        }
         match packet {
-            Packet::ClientConnect => {
-                return Err(anyhow::anyhow!(
-                    "Client tried to send Packet::ClientConnect."
-                ));
+            // Receiving it was the point, so there is nothing else to do.
+            Packet::Heartbeat => {}
+            // Answered straight away, so the round trip doesn't include waiting for other packets.
+            Packet::Ping { nonce } => {
+                reliable_send.send(Packet::Pong { nonce })?;
+            }
+            Packet::Hello { .. } => {
+                return Err(
+                    ProtocolViolation("Client sent Packet::Hello twice.".to_string()).into(),
+                );
+            }
+            Packet::JoinRoom { .. } => {
+                return Err(ProtocolViolation(
+                    "Client sent Packet::JoinRoom after joining a room.".to_string(),
+                )
+                .into());
             }
-            Packet::ClientDisconnect(_) => {
+            Packet::ClientConnect { .. }
+            | Packet::LevelConfig { .. }
+            | Packet::SetSpawn { .. }
+            | Packet::GiftState { .. }
+            | Packet::ReloadLevel
+            | Packet::PlayerRoster(_)
+            | Packet::Pong { .. }
+            | Packet::PlayerOutOfRange(_)
+            | Packet::MovementBatch(_)
+            | Packet::ServerShutdown
+            | Packet::VersionMismatch { .. }
+            | Packet::RoomList { .. } => {
+                return Err(ProtocolViolation(format!("Client tried to send {packet:?}.")).into());
+            }
+            Packet::Interact { id } => {
+                if id.is_some() {
+                    return Err(
+                        ProtocolViolation("Client sent Interact with an ID.".to_string()).into(),
+                    );
+                }
+                if let Some(opened) = gift.open() {
+                    // The client pressed the button about half a round trip ago, so that's where it was.
+                    let pressed_at = Instant::now() - connection.rtt() / 2;
+                    match registry.lock().await.position_at(client_id, pressed_at) {
+                        Some(position) => info!("Client opened the gift at {position:?}."),
+                        None => info!("Client opened the gift."),
+                    }
+                    to_all_connections.send(Packet::Interact {
+                        id: Some(client_id),
+                    })?;
+                    tokio::spawn(respawn_gift(
+                        gift.clone(),
+                        opened,
+                        to_all_connections.clone(),
+                    ));
+                }
+            }
+            Packet::Chat {
+                id,
+                message,
+                global,
+            } => {
+                if id.is_some() {
+                    return Err(
+                        ProtocolViolation("Client sent Chat with an ID.".to_string()).into(),
+                    );
+                }
+                let message = sanitize_text(&message, MAX_CHAT_LENGTH);
+                if !message.is_empty() {
+                    to_all_connections.send(Packet::Chat {
+                        id: Some(client_id),
+                        message,
+                        global,
+                    })?;
+                }
+            }
+            Packet::SetName { id, name } => {
+                if id.is_some() {
+                    return Err(
+                        ProtocolViolation("Client sent SetName with an ID.".to_string()).into(),
+                    );
+                }
+                let name = sanitize_name(&name);
+                if !name.is_empty() {
+                    info!("Client is called {name:?}.");
+                    registry.lock().await.set_name(client_id, name.clone());
+                    to_all_connections.send(Packet::SetName {
+                        id: Some(client_id),
+                        name,
+                    })?;
+                }
+            }
+            Packet::Emote { id, emote } => {
+                if id.is_some() {
+                    return Err(
+                        ProtocolViolation("Client sent Emote with an ID.".to_string()).into(),
+                    );
+                }
+                if emote < EMOTE_COUNT {
+                    to_all_connections.send(Packet::Emote {
+                        id: Some(client_id),
+                        emote,
+                    })?;
+                } else {
+                    debug!("Client sent emote {emote}, which doesn't exist.");
+                }
+            }
+            Packet::ClientDisconnect(..) => {
                 info!("Client is disconnecting.");
-                return Ok(());
+                return Ok(DisconnectReason::Graceful);
+            }
+            Packet::Input { id, tick, input } => {
+                if id.is_some() {
+                    return Err(
+                        ProtocolViolation("Client sent Input with an ID.".to_string()).into(),
+                    );
+                }
+                let (Some(speed), Some(player)) = (lockstep_speed, &mut lockstep_player) else {
+                    return Err(ProtocolViolation(
+                        "Client sent Input, but the server isn't in lockstep mode.".to_string(),
+                    )
+                    .into());
+                };
+                if !player.step(tick, input, speed) {
+                    return Err(ProtocolViolation(format!(
+                        "Client sent input for tick {tick}, but its next tick is {}.",
+                        player.next_tick()
+                    ))
+                    .into());
+                }
+                let mut registry = registry.lock().await;
+                registry.record_movement(client_id, player.position(), 0, movement_zone);
+                to_all_connections.send(Packet::Input {
+                    id: Some(client_id),
+                    tick,
+                    input,
+                })?;
+            }
+            // The client can move before Packet::LevelConfig tells it the server is in lockstep mode.
+            Packet::PlayerMovement { .. } | Packet::QuantizedMovement { .. }
+                if lockstep_speed.is_some() =>
+            {
+                debug!("Ignoring movement sent in lockstep mode.");
             }
             Packet::PlayerMovement {
                 id,
@@ -136,16 +985,59 @@ async fn handle_connection(
                 y,
                 z,
                 animation_frame,
+                teleported,
+                zone,
             } => {
                 if id.is_some() {
-                    return Err(anyhow::anyhow!("Client sent PlayerMovement with an ID."));
+                    return Err(ProtocolViolation(
+                        "Client sent PlayerMovement with an ID.".to_string(),
+                    )
+                    .into());
                 }
+                movement_origin = Some([x, y, z]);
+                movement_zone = zone;
+                registry
+                    .lock()
+                    .await
+                    .record_movement(client_id, [x, y, z], animation_frame, zone);
                 to_all_connections.send(Packet::PlayerMovement {
                     id: Some(client_id),
                     x,
                     y,
                     z,
                     animation_frame,
+                    teleported,
+                    zone,
+                })?;
+            }
+            // Other clients are sent the absolute position, so only this connection needs to know the origin.
+            Packet::QuantizedMovement {
+                origin: tag,
+                x,
+                y,
+                z,
+                animation_frame,
+            } => {
+                let Some(origin) = movement_origin.filter(|origin| origin_tag(*origin) == tag)
+                else {
+                    // The PlayerMovement this is relative to was lost, so wait for the next one.
+                    continue;
+                };
+                let [x, y, z] = dequantize_position([x, y, z], origin);
+                registry.lock().await.record_movement(
+                    client_id,
+                    [x, y, z],
+                    animation_frame,
+                    movement_zone,
+                );
+                to_all_connections.send(Packet::PlayerMovement {
+                    id: Some(client_id),
+                    x,
+                    y,
+                    z,
+                    animation_frame,
+                    teleported: false,
+                    zone: movement_zone,
                 })?;
             }
         }
@@ -155,53 +1047,641 @@ async fn handle_connection(
 /// This function is essentially the second half of a connection.
 ///
 /// It receives packets from every other connection, and sends the relevant ones to this connection.
-#[tracing::instrument(skip(connection, from_all_connections), fields(address = %connection.remote_address()
+/// With an interest area, movement is only sent for players within its radius.
+#[tracing::instrument(skip(connection, reliable_send, from_all_connections, interest_area, metrics), fields(address = %connection.remote_address()
 ))]
 async fn receive_broadcasts(
     connection: Connection,
+    reliable_send: ReliableSender,
+    client_id: u64,
     mut from_all_connections: Receiver<Packet>,
+    mut interest_area: Option<InterestArea>,
+    metrics: Arc<Metrics>,
 ) -> anyhow::Result<()> {
-    let client_id = connection.stable_id() as u64;
+    // The latest movement of every player that moved since the last batch was sent.
+    let mut batch = HashMap::new();
+    let mut batch_timer = tokio::time::interval(MOVEMENT_BATCH_INTERVAL);
+    batch_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Lags since the last report, which are added up so a struggling connection doesn't log every time.
+    let mut last_lag_report: Option<Instant> = None;
+    let mut lags_since_report = 0u64;
+    let mut skipped_since_report = 0u64;
 
     // Start awaiting packets.
-    // This loop must run extremely fast, so if any packets need to be sent, they should be sent in a separate task.
+    // This loop must run extremely fast. Queueing onto the reliable stream and sending datagrams never wait.
     loop {
-        match from_all_connections.recv().await {
+        let received = tokio::select! {
+            _ = batch_timer.tick() => {
+                send_movement_batch(&connection, &mut batch);
+                continue;
+            }
+            received = from_all_connections.recv() => received,
+        };
+        if received.is_ok() {
+            metrics.record_forwarded();
+        }
+        match received {
             Ok(packet) => match packet {
-                Packet::ClientConnect => {
+                Packet::Hello { .. }
+                | Packet::ClientConnect { .. }
+                | Packet::LevelConfig { .. }
+                | Packet::SetSpawn { .. }
+                | Packet::Heartbeat
+                | Packet::Ping { .. }
+                | Packet::Pong { .. }
+                | Packet::PlayerOutOfRange(_)
+                | Packet::MovementBatch(_)
+                | Packet::QuantizedMovement { .. }
+                | Packet::VersionMismatch { .. }
+                | Packet::RoomList { .. }
+                | Packet::JoinRoom { .. } => {
                     panic!(
-                        "Server broadcasted a client connect. This should never happen. Please report this to the dev."
+                        "Server broadcasted {packet:?}. This should never happen. Please report this to the dev."
                     )
                 }
-                Packet::ClientDisconnect(id) => {
-                    if id.expect("Server broadcasted Packet::ClientDisconnect with no id. This should never happen. Please report this to the dev.") == client_id {
+                Packet::ClientDisconnect(id, _) => {
+                    let id = id.expect("Server broadcasted Packet::ClientDisconnect with no id. This should never happen. Please report this to the dev.");
+                    if id == client_id {
                         return Ok(());
                     } else {
-                        let send = connection.open_uni().await?;
-                        tokio::spawn(async move {
-                            if let Err(e) = send_packet(send, packet).await {
-                                error!("Error sending packet: {e:#?}");
+                        batch.remove(&id);
+                        if let Some(interest_area) = &mut interest_area {
+                            interest_area.forget(id);
+                        }
+                        reliable_send.send(packet)?;
+                    }
+                }
+                // After a reload, the client is only sent the players it can see, and never itself.
+                Packet::PlayerRoster(roster) => {
+                    let roster = roster
+                        .into_iter()
+                        .filter(|&(id, ..)| {
+                            id != client_id
+                                && interest_area
+                                    .as_ref()
+                                    .is_none_or(|interest_area| interest_area.is_visible(id))
+                        })
+                        .collect();
+                    reliable_send.send(Packet::PlayerRoster(roster))?;
+                }
+                Packet::Interact { .. }
+                | Packet::GiftState { .. }
+                | Packet::ReloadLevel
+                | Packet::ServerShutdown => {
+                    reliable_send.send(packet)?;
+                }
+                // The sender already shows its own movement, chat, name, emotes and inputs, so they aren't sent back to it.
+                // Movement is batched into datagrams, which don't need a task because they never wait.
+                Packet::PlayerMovement {
+                    id: Some(id),
+                    x,
+                    y,
+                    z,
+                    animation_frame,
+                    zone,
+                    ..
+                } => {
+                    let Some(interest_area) = &mut interest_area else {
+                        if id != client_id {
+                            queue_movement(&connection, &mut batch, packet);
+                        }
+                        continue;
+                    };
+                    for update in
+                        interest_area.on_movement(id, [x, y, z], animation_frame, zone, packet)
+                    {
+                        match update {
+                            InterestUpdate::Show {
+                                packet,
+                                entered: false,
+                            } => queue_movement(&connection, &mut batch, packet),
+                            // Coming into and going out of range are sent reliably, so a lost datagram
+                            // can't leave a player stuck shown or hidden until they next move.
+                            InterestUpdate::Show {
+                                packet,
+                                entered: true,
+                            } => {
+                                if let Packet::PlayerMovement { id: Some(id), .. } = packet {
+                                    batch.remove(&id);
+                                }
+                                reliable_send.send(packet)?;
+                            }
+                            InterestUpdate::Hide(id) => {
+                                batch.remove(&id);
+                                reliable_send.send(Packet::PlayerOutOfRange(id))?;
                             }
-                        });
+                        }
                     }
                 }
-                Packet::PlayerMovement { id, .. } => {
+                Packet::PlayerMovement { id: None, .. } => {
+                    panic!(
+                        "Server broadcasted {packet:?} with no id. This should never happen. Please report this to the dev."
+                    )
+                }
+                // Nearby chat from a player this client can't see doesn't reach it.
+                Packet::Chat {
+                    id: Some(id),
+                    global: false,
+                    ..
+                } if interest_area
+                    .as_ref()
+                    .is_some_and(|interest_area| !interest_area.is_visible(id)) => {}
+                Packet::Chat { id, .. }
+                | Packet::SetName { id, .. }
+                | Packet::Emote { id, .. }
+                | Packet::Input { id, .. } => {
                     if id.is_some_and(|id| id != client_id) {
-                        let send = connection.open_uni().await?;
-                        tokio::spawn(async move {
-                            if let Err(e) = send_packet(send, packet).await {
-                                error!("Error sending packet: {e:#?}");
-                            }
-                        });
+                        reliable_send.send(packet)?;
                     }
                 }
             },
             Err(RecvError::Closed) => return Err(anyhow::anyhow!("All broadcasters closed")),
             Err(RecvError::Lagged(skipped_messages)) => {
-                error!(
-                    "Server is behind by {skipped_messages} messages! Please report this error to the dev so they can consider increasing channel capacity."
-                );
+                metrics.record_lag(skipped_messages);
+                lags_since_report += 1;
+                skipped_since_report += skipped_messages;
+                if last_lag_report.is_none_or(|reported| reported.elapsed() >= LAG_REPORT_INTERVAL)
+                {
+                    warn!(
+                        "Fell behind the broadcast channel {lags_since_report} times and skipped {skipped_since_report} packets. If this keeps happening, raise --channel-multiplier."
+                    );
+                    last_lag_report = Some(Instant::now());
+                    lags_since_report = 0;
+                    skipped_since_report = 0;
+                }
+            }
+        }
+    }
+}
+
+/// Adds a movement to the next batch, replacing any older movement of the same player.
+///
+/// Teleports are sent straight away on their own instead, since batches don't say whether a movement was a teleport.
+fn queue_movement(
+    connection: &Connection,
+    batch: &mut HashMap<u64, (f32, f32, f32, u8, u16)>,
+    packet: Packet,
+) {
+    match packet {
+        Packet::PlayerMovement {
+            id: Some(id),
+            teleported: true,
+            ..
+        } => {
+            batch.remove(&id);
+            if let Err(e) = send_packet_datagram(connection, &packet) {
+                error!("Error sending packet: {e:#?}");
+            }
+        }
+        Packet::PlayerMovement {
+            id: Some(id),
+            x,
+            y,
+            z,
+            animation_frame,
+            teleported: false,
+            zone,
+        } => {
+            batch.insert(id, (x, y, z, animation_frame, zone));
+        }
+        packet => error!("Tried to batch {packet:?}. Please report this to the dev."),
+    }
+}
+
+/// Sends every movement in the batch, split into as few datagrams as the connection allows, and empties it.
+fn send_movement_batch(
+    connection: &Connection,
+    batch: &mut HashMap<u64, (f32, f32, f32, u8, u16)>,
+) {
+    if batch.is_empty() {
+        return;
+    }
+    let movements = batch
+        .drain()
+        .map(|(id, (x, y, z, animation_frame, zone))| (id, x, y, z, animation_frame, zone))
+        .collect();
+    // Without datagram support, sending fails with a clearer error than working out a size would.
+    let max_size = connection
+        .max_datagram_size()
+        .unwrap_or(MAX_PACKET_SIZE)
+        .min(MAX_PACKET_SIZE);
+    for packet in movement_batches(movements, max_size) {
+        if let Err(e) = send_packet_datagram(connection, &packet) {
+            error!("Error sending packet: {e:#?}");
+        }
+    }
+}
+
+/// Reads commands typed into the server's terminal, until it closes.
+///
+/// "reload" makes every client reset the level, and then resends the level's state to everyone.
+/// That closes the gift, cancelling its respawn, and sends the roster again so clients see everyone who stood still.
+/// Position histories are cleared too, since they're from before the reset.
+/// Reloading more than once within RELOAD_COOLDOWN does nothing, and so does reloading in lockstep mode,
+/// since every client would have to move everyone back to their spawn point on the same tick.
+///
+/// "reload-certificate" reads the TLS certificate and key again, the same way SIGHUP does.
+async fn run_console(
+    to_all_connections: Sender<Packet>,
+    gift: Arc<Gift>,
+    registry: Arc<Mutex<ConnectionRegistry>>,
+    endpoint: Endpoint,
+    certificate: PathBuf,
+    key: PathBuf,
+    lockstep: bool,
+) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut last_reload: Option<Instant> = None;
+    while let Some(line) = lines.next_line().await? {
+        match line.trim() {
+            "" => {}
+            "reload" => {
+                if lockstep {
+                    info!("The level can't be reloaded in lockstep mode.");
+                    continue;
+                }
+                if last_reload.is_some_and(|last_reload| last_reload.elapsed() < RELOAD_COOLDOWN) {
+                    info!("The level was reloaded too recently. Try again in a few seconds.");
+                    continue;
+                }
+                last_reload = Some(Instant::now());
+
+                info!("Reloading the level for every client.");
+                gift.reset();
+                let roster = {
+                    let mut registry = registry.lock().await;
+                    registry.clear_position_histories();
+                    registry.full_roster()
+                };
+                // Nobody might be connected, and that's fine.
+                let _ = to_all_connections.send(Packet::ReloadLevel);
+                let _ = to_all_connections.send(Packet::GiftState { opened: false });
+                let _ = to_all_connections.send(Packet::PlayerRoster(roster));
             }
+            "reload-certificate" => reload_certificate(&endpoint, &certificate, &key),
+            command => info!(
+                "Unknown command {command:?}. The commands are \"reload\" and \"reload-certificate\"."
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Writes saved positions to their file every SAVED_POSITIONS_FLUSH_INTERVAL, for as long as the server runs.
+///
+/// The file is small, so it's written straight from this task instead of a blocking thread.
+async fn flush_saved_positions(saved_positions: Arc<Mutex<SavedPositions>>) {
+    let mut timer = tokio::time::interval(SAVED_POSITIONS_FLUSH_INTERVAL);
+    loop {
+        timer.tick().await;
+        if let Err(e) = saved_positions.lock().await.flush() {
+            error!("Could not save positions: {e:#}");
+        }
+    }
+}
+
+/// Closes the gift again after GIFT_RESPAWN_TIME, and tells every client.
+///
+/// Does nothing if the level reloaded in the meantime, because that already closed it.
+async fn respawn_gift(gift: Arc<Gift>, opened: u64, to_all_connections: Sender<Packet>) {
+    tokio::time::sleep(GIFT_RESPAWN_TIME).await;
+    if !gift.respawn(opened) {
+        return;
+    }
+    info!("The gift respawned.");
+    let _ = to_all_connections.send(Packet::GiftState { opened: false });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn move_speed_has_to_be_finite_and_positive() {
+        assert_eq!(parse_move_speed("4.0"), Ok(4.0));
+        assert_eq!(parse_move_speed("0.5"), Ok(0.5));
+        for speed in ["0", "-1", "NaN", "inf", "-inf", "fast"] {
+            assert!(parse_move_speed(speed).is_err(), "{speed} was accepted");
+        }
+    }
+
+    #[test]
+    fn args_reject_bad_move_speed() {
+        let args = |move_speed: &str| {
+            Args::try_parse_from([
+                "server",
+                "--certificate",
+                "certificate.pem",
+                "--key",
+                "key.pem",
+                &format!("--move-speed={move_speed}"),
+            ])
+        };
+        assert_eq!(args("6").unwrap().move_speed, 6.0);
+        assert!(args("0").is_err());
+        assert!(args("NaN").is_err());
+    }
+
+    #[tokio::test]
+    async fn movement_sent_before_hello_is_ignored() {
+        let loopback = testing::connect_loopback().await;
+        let movement = Packet::PlayerMovement {
+            id: None,
+            x: 1.0,
+            y: 0.5,
+            z: 2.0,
+            animation_frame: 5,
+            teleported: false,
+            zone: 0,
+        };
+        send_packet_datagram(&loopback.client, &movement).unwrap();
+        let mut reliable = loopback.client.open_uni().await.unwrap();
+        write_framed(
+            &mut reliable,
+            Packet::Hello {
+                version: PROTOCOL_VERSION,
+                session_token: 7,
+                player_token: 9,
+            },
+        )
+        .await
+        .unwrap();
+
+        let (session_token, player_token, _) = receive_hello(&loopback.server).await.unwrap();
+        assert_eq!((session_token, player_token), (7, 9));
+        // The connection is still up, and the movement is left for handle_connection.
+        assert_eq!(
+            receive_packet_datagram(&loopback.server).await.unwrap(),
+            movement
+        );
+    }
+
+    #[tokio::test]
+    async fn mismatched_versions_are_sent_version_mismatch() {
+        let loopback = testing::connect_loopback().await;
+        let mut reliable = loopback.client.open_uni().await.unwrap();
+        write_framed(
+            &mut reliable,
+            Packet::Hello {
+                version: PROTOCOL_VERSION + 1,
+                session_token: 7,
+                player_token: 9,
+            },
+        )
+        .await
+        .unwrap();
+
+        let (hello, mismatch) = tokio::join!(receive_hello(&loopback.server), async {
+            let mut server_stream = loopback.client.accept_uni().await.unwrap();
+            let packet = read_framed(&mut server_stream).await.unwrap();
+            let closed = NetworkError::from_connection_error(loopback.client.closed().await);
+            (packet, closed)
+        });
+        let error = hello.unwrap_err().to_string();
+        assert!(error.contains("protocol version"), "{error}");
+        let (packet, closed) = mismatch;
+        assert_eq!(
+            packet,
+            Some(Packet::VersionMismatch {
+                server_version: PROTOCOL_VERSION
+            })
+        );
+        assert!(closed.is_version_mismatch(), "{closed:?}");
+    }
+
+    #[tokio::test]
+    async fn joining_a_full_room_sends_the_room_list_again() {
+        let loopback = testing::connect_loopback().await;
+        let registry = Mutex::new(ConnectionRegistry::default());
+        assert!(registry.lock().await.join_room(1, 0, 1));
+        let mut client_send = loopback.client.open_uni().await.unwrap();
+        write_framed(&mut client_send, Packet::JoinRoom { zone: 0 })
+            .await
+            .unwrap();
+        write_framed(&mut client_send, Packet::JoinRoom { zone: 1 })
+            .await
+            .unwrap();
+
+        let mut reliable = loopback.server.accept_uni().await.unwrap();
+        let reliable_send = ReliableSender::spawn(loopback.server.open_uni().await.unwrap());
+        let room = choose_room(&mut reliable, &reliable_send, 2, &registry, 1).await;
+        assert_eq!(room.unwrap(), Some(1));
+
+        let mut client_recv = loopback.client.accept_uni().await.unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                read_framed(&mut client_recv).await.unwrap(),
+                Some(Packet::RoomList {
+                    rooms: vec![(0, 1)],
+                    capacity: 1
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_can_leave_from_the_lobby() {
+        let loopback = testing::connect_loopback().await;
+        let registry = Mutex::new(ConnectionRegistry::default());
+        let mut client_send = loopback.client.open_uni().await.unwrap();
+        write_framed(&mut client_send, Packet::ClientDisconnect(None, None))
+            .await
+            .unwrap();
+
+        let mut reliable = loopback.server.accept_uni().await.unwrap();
+        let reliable_send = ReliableSender::spawn(loopback.server.open_uni().await.unwrap());
+        let room = choose_room(&mut reliable, &reliable_send, 1, &registry, 1).await;
+        assert_eq!(room.unwrap(), None);
+        assert!(registry.lock().await.room_list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lockstep_inputs_step_the_client_until_it_skips_a_tick() {
+        let loopback = testing::connect_loopback().await;
+        let mut client_send = loopback.client.open_uni().await.unwrap();
+        for packet in [
+            Packet::JoinRoom { zone: 0 },
+            Packet::Input {
+                id: None,
+                tick: 0,
+                input: lockstep::MOVE_RIGHT,
+            },
+            Packet::Input {
+                id: None,
+                tick: 2,
+                input: lockstep::MOVE_RIGHT,
+            },
+        ] {
+            write_framed(&mut client_send, packet).await.unwrap();
+        }
+
+        let reliable = loopback.server.accept_uni().await.unwrap();
+        let registry = Arc::new(Mutex::new(ConnectionRegistry::default()));
+        let (to_all_connections, mut from_all_connections) = broadcast::channel(16);
+        let spawn = [0.0, 0.5, 0.0];
+        let result = handle_connection(
+            loopback.server.clone(),
+            reliable,
+            1,
+            to_all_connections,
+            Packet::LevelConfig {
+                move_speed: 4.0,
+                spawn,
+                lockstep: true,
+            },
+            None,
+            spawn,
+            0,
+            Arc::new(Gift::default()),
+            registry.clone(),
+            Duration::from_secs(5),
+            None,
+            200,
+            10,
+            Some(lockstep::speed_per_tick(4.0)),
+            &SessionStats::new(),
+            Arc::new(Metrics::default()),
+        )
+        .await;
+        let reason = disconnect_reason(&result.unwrap_err());
+        assert_eq!(reason, DisconnectReason::ProtocolViolation);
+
+        // Everyone else is sent where the client starts, and then its first input, but not the one that skipped a tick.
+        assert_eq!(
+            from_all_connections.recv().await.unwrap(),
+            Packet::PlayerRoster(vec![(1, 0.0, 0.5, 0.0, 0, 0)])
+        );
+        assert_eq!(
+            from_all_connections.recv().await.unwrap(),
+            Packet::Input {
+                id: Some(1),
+                tick: 0,
+                input: lockstep::MOVE_RIGHT
+            }
+        );
+        assert!(from_all_connections.try_recv().is_err());
+        assert_eq!(
+            registry.lock().await.position_at(1, Instant::now()),
+            Some([0.063, 0.5, 0.0])
+        );
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_become_ipv4() {
+        let mapped: SocketAddr = "[::ffff:192.0.2.7]:4433".parse().unwrap();
+        assert_eq!(canonical_address(mapped), "192.0.2.7:4433".parse().unwrap());
+        let ipv6: SocketAddr = "[2001:db8::7]:4433".parse().unwrap();
+        assert_eq!(canonical_address(ipv6), ipv6);
+        let ipv4: SocketAddr = "192.0.2.7:4433".parse().unwrap();
+        assert_eq!(canonical_address(ipv4), ipv4);
+    }
+
+    #[test]
+    fn ban_list_reads_ipv4_and_ipv6() {
+        let path = std::env::temp_dir().join(format!("miniscop-bans-{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Test ban list\n192.0.2.7\n\n  2001:db8::7  \n::ffff:198.51.100.1\n",
+        )
+        .unwrap();
+        let banned_ips = load_banned_ips(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let banned_ips = banned_ips.unwrap();
+        assert_eq!(banned_ips.len(), 3);
+        assert!(banned_ips.contains(&"192.0.2.7".parse().unwrap()));
+        assert!(banned_ips.contains(&"2001:db8::7".parse().unwrap()));
+        // Mapped addresses are banned by their IPv4 address, the same way connections are checked.
+        assert!(banned_ips.contains(&"198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ban_list_rejects_typos() {
+        let path =
+            std::env::temp_dir().join(format!("miniscop-bad-bans-{}.txt", std::process::id()));
+        std::fs::write(&path, "192.0.2.7\n2001:db8::g\n").unwrap();
+        let banned_ips = load_banned_ips(&path);
+        std::fs::remove_file(&path).unwrap();
+        let error = banned_ips.unwrap_err().to_string();
+        assert!(error.contains("Line 2"), "{error}");
+    }
+
+    #[test]
+    fn ipv6_sockets_are_dual_stack() {
+        let socket = bind_socket("[::]:0".parse().unwrap()).unwrap();
+        let address = socket.local_addr().unwrap();
+        assert!(address.is_ipv6());
+        // An IPv4 packet reaches the IPv6 socket, and comes from an IPv4-mapped address.
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender
+            .send_to(
+                b"hi",
+                SocketAddr::new("127.0.0.1".parse().unwrap(), address.port()),
+            )
+            .unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0; 2];
+        let (_, from) = socket.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hi");
+        assert_eq!(canonical_address(from), sender.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn clients_connect_over_ipv6_loopback() {
+        let loopback =
+            testing::connect("[::]:0".parse().unwrap(), "[::1]:0".parse().unwrap()).await;
+        let address = canonical_address(loopback.server.remote_address());
+        assert_eq!(address, loopback.client_endpoint.local_addr().unwrap());
+        assert!(address.is_ipv6());
+
+        let mut reliable = loopback.client.open_uni().await.unwrap();
+        write_framed(
+            &mut reliable,
+            Packet::Hello {
+                version: PROTOCOL_VERSION,
+                session_token: 1,
+                player_token: 2,
+            },
+        )
+        .await
+        .unwrap();
+        let (session_token, player_token, _) = receive_hello(&loopback.server).await.unwrap();
+        assert_eq!((session_token, player_token), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn ipv4_clients_connect_to_ipv6_servers() {
+        let loopback =
+            testing::connect("[::]:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()).await;
+        let address = canonical_address(loopback.server.remote_address());
+        assert_eq!(address, loopback.client_endpoint.local_addr().unwrap());
+        assert!(address.is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn reliable_packets_reach_the_client_in_order() {
+        let loopback = testing::connect_loopback().await;
+        let reliable_send = ReliableSender::spawn(loopback.server.open_uni().await.unwrap());
+        let chat = |i: u32| Packet::Chat {
+            id: Some(1),
+            message: format!("Message {i}"),
+            global: true,
+        };
+        // Names and chat from other tasks go through clones of the same sender.
+        let broadcast_send = reliable_send.clone();
+        for i in 0..200 {
+            if i % 3 == 0 {
+                broadcast_send.send(chat(i)).unwrap();
+            } else {
+                reliable_send.send(chat(i)).unwrap();
+            }
+        }
+
+        let mut reliable = loopback.client.accept_uni().await.unwrap();
+        for i in 0..200 {
+            assert_eq!(read_framed(&mut reliable).await.unwrap(), Some(chat(i)));
         }
     }
 }
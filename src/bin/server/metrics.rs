@@ -0,0 +1,127 @@
+use quinn::Endpoint;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info};
+
+/// How much of a request is read before answering. Every request gets the same answer, so it's only read at all
+/// so the client doesn't see its connection reset.
+const MAX_REQUEST_SIZE: usize = 1024;
+
+/// Counters for the whole server, served by serve_metrics with --metrics-address.
+///
+/// They are only ever added to, and start over when the server restarts.
+#[derive(Default)]
+pub struct Metrics {
+    /// Every packet received from every client.
+    packets_received: AtomicU64,
+    /// Every broadcast packet that reached a connection's broadcast receiver.
+    /// A packet broadcast to 10 connections counts 10 times, whether or not it was sent on to the client.
+    packets_forwarded: AtomicU64,
+    /// How many times a broadcast receiver fell behind the broadcast channel, and how many packets it skipped.
+    broadcast_lags: AtomicU64,
+    broadcast_packets_skipped: AtomicU64,
+}
+impl Metrics {
+    pub fn record_received(&self) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_forwarded(&self) {
+        self.packets_forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_lag(&self, skipped_packets: u64) {
+        self.broadcast_lags.fetch_add(1, Ordering::Relaxed);
+        self.broadcast_packets_skipped
+            .fetch_add(skipped_packets, Ordering::Relaxed);
+    }
+
+    /// Writes every metric in Prometheus' text format.
+    fn render(&self, endpoint: &Endpoint) -> String {
+        let metrics = [
+            (
+                "miniscop_connections",
+                "gauge",
+                "Clients connected right now.",
+                endpoint.open_connections() as u64,
+            ),
+            (
+                "miniscop_packets_received_total",
+                "counter",
+                "Packets received from every client.",
+                self.packets_received.load(Ordering::Relaxed),
+            ),
+            (
+                "miniscop_packets_forwarded_total",
+                "counter",
+                "Broadcast packets handed to each connection.",
+                self.packets_forwarded.load(Ordering::Relaxed),
+            ),
+            (
+                "miniscop_broadcast_lags_total",
+                "counter",
+                "Times a connection fell behind the broadcast channel.",
+                self.broadcast_lags.load(Ordering::Relaxed),
+            ),
+            (
+                "miniscop_broadcast_packets_skipped_total",
+                "counter",
+                "Broadcast packets skipped by connections that fell behind.",
+                self.broadcast_packets_skipped.load(Ordering::Relaxed),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            // Writing to a String can't fail.
+            let _ = writeln!(
+                text,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+            );
+        }
+        text
+    }
+}
+
+/// Answers every HTTP request on the address with the current metrics, until the server shuts down.
+pub async fn serve_metrics(
+    address: SocketAddr,
+    endpoint: Endpoint,
+    metrics: Arc<Metrics>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Serving metrics on http://{address}/metrics");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let endpoint = endpoint.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer_request(stream, &endpoint, &metrics).await {
+                error!("Metrics request error: {e:#?}");
+            }
+        });
+    }
+}
+
+async fn answer_request(
+    mut stream: TcpStream,
+    endpoint: &Endpoint,
+    metrics: &Metrics,
+) -> anyhow::Result<()> {
+    let mut request = [0; MAX_REQUEST_SIZE];
+    if stream.read(&mut request).await? == 0 {
+        // The client hung up without asking for anything.
+        return Ok(());
+    }
+    let body = metrics.render(endpoint);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
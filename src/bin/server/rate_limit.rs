@@ -0,0 +1,179 @@
+use std::time::{Duration, Instant};
+
+/// How long the packets a client's rate limit drops are counted for, before the count starts over.
+const DROP_WINDOW: Duration = Duration::from_secs(1);
+
+/// Limits how many packets one client can send per second.
+///
+/// It starts with a second's worth of tokens and gets them back at the rate limit, so a client can burst
+/// for a moment but not keep sending faster than the limit.
+pub struct RateLimiter {
+    packets_per_second: f32,
+    tokens: f32,
+    last_refill: Instant,
+    /// When the current DROP_WINDOW started, and how many packets were dropped in it.
+    window_started: Instant,
+    dropped_in_window: u32,
+}
+
+/// What to do with a packet from a rate limited client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RateLimit {
+    Allow,
+    /// The first packet dropped in each DROP_WINDOW is marked, so throttling is logged once per window.
+    Drop {
+        first_in_window: bool,
+    },
+    /// The client sent more than double its limit for a whole DROP_WINDOW, which no real client does.
+    Kick,
+}
+
+impl RateLimiter {
+    pub fn new(packets_per_second: u32, now: Instant) -> Self {
+        let packets_per_second = packets_per_second as f32;
+        Self {
+            packets_per_second,
+            tokens: packets_per_second,
+            last_refill: now,
+            window_started: now,
+            dropped_in_window: 0,
+        }
+    }
+
+    /// Takes a token for a packet that arrived at the given time, if there is one.
+    pub fn check(&mut self, now: Instant) -> RateLimit {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f32();
+        self.tokens =
+            (self.tokens + elapsed * self.packets_per_second).min(self.packets_per_second);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return RateLimit::Allow;
+        }
+
+        if now.saturating_duration_since(self.window_started) >= DROP_WINDOW {
+            self.window_started = now;
+            self.dropped_in_window = 0;
+        }
+        self.dropped_in_window += 1;
+        if self.dropped_in_window as f32 > self.packets_per_second {
+            RateLimit::Kick
+        } else {
+            RateLimit::Drop {
+                first_in_window: self.dropped_in_window == 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: u32 = 10;
+
+    /// A limiter whose burst has already been used up at start.
+    fn exhausted(start: Instant) -> RateLimiter {
+        let mut limiter = RateLimiter::new(LIMIT, start);
+        for _ in 0..LIMIT {
+            assert_eq!(limiter.check(start), RateLimit::Allow);
+        }
+        limiter
+    }
+
+    /// Sends packets until one isn't allowed, and returns what happened to it.
+    fn first_refused(limiter: &mut RateLimiter, now: Instant) -> RateLimit {
+        loop {
+            match limiter.check(now) {
+                RateLimit::Allow => {}
+                refused => return refused,
+            }
+        }
+    }
+
+    #[test]
+    fn a_second_of_packets_can_be_sent_at_once() {
+        let start = Instant::now();
+        let mut limiter = exhausted(start);
+        assert_eq!(
+            limiter.check(start),
+            RateLimit::Drop {
+                first_in_window: true
+            }
+        );
+    }
+
+    #[test]
+    fn tokens_refill_at_the_limit_up_to_a_second_of_them() {
+        let start = Instant::now();
+        let mut limiter = exhausted(start);
+        // A tenth of a second at 10 per second is one packet.
+        let later = start + Duration::from_millis(100);
+        assert_eq!(limiter.check(later), RateLimit::Allow);
+        assert!(matches!(limiter.check(later), RateLimit::Drop { .. }));
+
+        // Waiting longer doesn't save up more than the burst.
+        let much_later = later + Duration::from_secs(10);
+        for _ in 0..LIMIT {
+            assert_eq!(limiter.check(much_later), RateLimit::Allow);
+        }
+        assert!(matches!(limiter.check(much_later), RateLimit::Drop { .. }));
+    }
+
+    #[test]
+    fn only_the_first_drop_in_each_window_is_marked() {
+        let start = Instant::now();
+        let mut limiter = exhausted(start);
+        assert_eq!(
+            limiter.check(start),
+            RateLimit::Drop {
+                first_in_window: true
+            }
+        );
+        assert_eq!(
+            limiter.check(start),
+            RateLimit::Drop {
+                first_in_window: false
+            }
+        );
+
+        // The next window's first drop is marked again, once the refilled tokens are used up.
+        assert_eq!(
+            first_refused(&mut limiter, start + DROP_WINDOW),
+            RateLimit::Drop {
+                first_in_window: true
+            }
+        );
+    }
+
+    #[test]
+    fn more_than_double_the_limit_in_a_window_is_kicked() {
+        let start = Instant::now();
+        let mut limiter = exhausted(start);
+        // Exactly double the limit is only dropped.
+        for _ in 0..LIMIT {
+            assert!(matches!(limiter.check(start), RateLimit::Drop { .. }));
+        }
+        assert_eq!(limiter.check(start), RateLimit::Kick);
+    }
+
+    #[test]
+    fn drops_spread_over_windows_are_not_kicked() {
+        let start = Instant::now();
+        let mut limiter = exhausted(start);
+        for _ in 0..LIMIT {
+            assert!(matches!(limiter.check(start), RateLimit::Drop { .. }));
+        }
+        // The count starts over in the next window, after the refilled tokens are used up.
+        let next_window = start + DROP_WINDOW;
+        assert!(matches!(
+            first_refused(&mut limiter, next_window),
+            RateLimit::Drop { .. }
+        ));
+        for _ in 1..LIMIT {
+            assert!(matches!(limiter.check(next_window), RateLimit::Drop { .. }));
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use miniscop::networking::DisconnectReason;
+use quinn::{Connection, VarInt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::f32::consts::TAU;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How long a disconnected client's session token can still be used to reclaim its ID.
+const SESSION_EXPIRY: Duration = Duration::from_secs(60);
+/// Spawn slots after the first are laid out in rings around the level's spawn point.
+const SPAWN_SLOTS_PER_RING: usize = 8;
+/// The distance between rings of spawn slots, in meters.
+const SPAWN_RING_SPACING: f32 = 1.0;
+/// How many recent positions are remembered for each client.
+/// Clients send at most 64 movement packets per second, so this covers at least one second.
+///
+/// Each position takes 32 bytes with its timestamp, so this is about 2 KiB per client.
+const POSITION_HISTORY_LENGTH: usize = 64;
+
+/// Keeps track of every client's session, so that a client who reconnects keeps the same ID.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    /// Sessions keyed by the client-generated session token.
+    sessions: HashMap<u64, Session>,
+    /// Recent positions keyed by client ID, oldest first. See position_at.
+    position_histories: HashMap<u64, VecDeque<(Instant, [f32; 3])>>,
+    /// The latest position, animation frame and zone of every connected client, keyed by client ID.
+    /// New clients are sent this, so they can see everyone before they move.
+    roster: HashMap<u64, ([f32; 3], u8, u16)>,
+    /// The name every connected client gave itself with Packet::SetName, keyed by client ID.
+    names: HashMap<u64, String>,
+    /// The zone of the room every connected client is in, keyed by client ID.
+    /// It's set when the client joins a room, and follows the client through level exits.
+    rooms: HashMap<u64, u16>,
+    /// The ID the next new session gets. IDs are never reused, even after their session expires,
+    /// so a packet about an old client can't be mistaken for one about a new client.
+    next_client_id: u64,
+}
+
+struct Session {
+    client_id: u64,
+    /// Which spot near the level's spawn point belongs to this client. See spawn_position.
+    spawn_slot: usize,
+    /// The connection currently using this session.
+    connection: Connection,
+    /// None while the session's connection is still alive.
+    expires_at: Option<Instant>,
+}
+
+/// Counters for a single connection, which are logged once it ends.
+pub struct SessionStats {
+    connected_at: Instant,
+    packets_received: AtomicU64,
+}
+impl SessionStats {
+    pub fn new() -> Self {
+        Self {
+            connected_at: Instant::now(),
+            packets_received: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_packet(&self) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionRegistry {
+    /// Starts a session for a connection and returns the client's ID and spawn slot.
+    ///
+    /// If the session token belongs to an existing session, the client reclaims its old ID and spawn slot.
+    /// If the old connection is still open (e.g. it hasn't timed out yet), it is closed so it can't linger as a ghost.
+    pub fn begin_session(&mut self, session_token: u64, connection: &Connection) -> (u64, usize) {
+        let now = Instant::now();
+        self.sessions
+            .retain(|_, session| session.expires_at.is_none_or(|expires_at| expires_at > now));
+        let client_ids: HashSet<u64> = self
+            .sessions
+            .values()
+            .map(|session| session.client_id)
+            .collect();
+        self.position_histories
+            .retain(|client_id, _| client_ids.contains(client_id));
+
+        if let Some(session) = self.sessions.get_mut(&session_token) {
+            if session.expires_at.is_none() {
+                session
+                    .connection
+                    .close(VarInt::from_u32(0), b"Client reconnected.");
+            }
+            info!("Client reclaimed ID {}.", session.client_id);
+            session.connection = connection.clone();
+            session.expires_at = None;
+            (session.client_id, session.spawn_slot)
+        } else {
+            let client_id = self.next_client_id;
+            self.next_client_id += 1;
+            let taken_slots: HashSet<usize> = self
+                .sessions
+                .values()
+                .map(|session| session.spawn_slot)
+                .collect();
+            let spawn_slot = (0..)
+                .find(|slot| !taken_slots.contains(slot))
+                .expect("There are more spawn slots than sessions.");
+            self.sessions.insert(
+                session_token,
+                Session {
+                    client_id,
+                    spawn_slot,
+                    connection: connection.clone(),
+                    expires_at: None,
+                },
+            );
+            (client_id, spawn_slot)
+        }
+    }
+
+    /// Ends a connection's session, keeping its token around for a while in case the client reconnects.
+    /// A summary of the connection is logged either way.
+    ///
+    /// Returns false if another connection has already reclaimed the session,
+    /// in which case the client didn't really leave and nobody should be told it disconnected.
+    pub fn end_session(
+        &mut self,
+        session_token: u64,
+        connection: &Connection,
+        address: SocketAddr,
+        stats: &SessionStats,
+        reason: DisconnectReason,
+    ) -> bool {
+        let connection_stats = connection.stats();
+        match self.sessions.get(&session_token) {
+            Some(session) => info!(
+                "Client {} ({address}) {reason} after {:.1?}. Received {} packets and {} bytes, sent {} bytes.",
+                session.client_id,
+                stats.connected_at.elapsed(),
+                stats.packets_received.load(Ordering::Relaxed),
+                connection_stats.udp_rx.bytes,
+                connection_stats.udp_tx.bytes,
+            ),
+            None => info!("Unknown client ({address}) {reason}."),
+        }
+
+        match self.sessions.get_mut(&session_token) {
+            Some(session) if session.connection.stable_id() == connection.stable_id() => {
+                session.expires_at = Some(Instant::now() + SESSION_EXPIRY);
+                self.roster.remove(&session.client_id);
+                self.names.remove(&session.client_id);
+                self.rooms.remove(&session.client_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remembers where a client is now, forgetting its oldest position if the history is full.
+    pub fn record_movement(
+        &mut self,
+        client_id: u64,
+        position: [f32; 3],
+        animation_frame: u8,
+        zone: u16,
+    ) {
+        self.roster
+            .insert(client_id, (position, animation_frame, zone));
+        self.rooms.insert(client_id, zone);
+        let history = self.position_histories.entry(client_id).or_default();
+        if history.len() == POSITION_HISTORY_LENGTH {
+            history.pop_front();
+        }
+        history.push_back((Instant::now(), position));
+    }
+
+    /// Returns the latest movement of every client except one, as sent in Packet::PlayerRoster.
+    pub fn roster_for(&self, client_id: u64) -> Vec<(u64, f32, f32, f32, u8, u16)> {
+        let mut roster = self.full_roster();
+        roster.retain(|&(id, ..)| id != client_id);
+        roster
+    }
+
+    /// Returns the latest movement of every client, for the roster broadcast after the level reloads.
+    pub fn full_roster(&self) -> Vec<(u64, f32, f32, f32, u8, u16)> {
+        self.roster
+            .iter()
+            .map(|(id, ([x, y, z], animation_frame, zone))| {
+                (*id, *x, *y, *z, *animation_frame, *zone)
+            })
+            .collect()
+    }
+
+    /// Forgets where everyone has been, so nothing from before the level reloaded is looked up afterwards.
+    pub fn clear_position_histories(&mut self) {
+        self.position_histories.clear();
+    }
+
+    /// Returns how many clients are in each room with anyone in it, by zone, as sent in Packet::RoomList.
+    pub fn room_list(&self) -> Vec<(u16, u32)> {
+        let mut rooms = HashMap::new();
+        for zone in self.rooms.values() {
+            *rooms.entry(*zone).or_insert(0) += 1;
+        }
+        let mut rooms: Vec<(u16, u32)> = rooms.into_iter().collect();
+        rooms.sort_unstable();
+        rooms
+    }
+
+    /// Puts a client in a room, unless the room already has capacity other clients in it.
+    ///
+    /// A client reclaiming its session still has its seat, so it can always get back into the same room.
+    /// Walking through a level exit doesn't check the capacity, so a room can end up with more than it.
+    /// Returns whether the client joined.
+    pub fn join_room(&mut self, client_id: u64, zone: u16, capacity: u32) -> bool {
+        let others = self
+            .rooms
+            .iter()
+            .filter(|&(&id, &room)| id != client_id && room == zone)
+            .count();
+        if others >= capacity as usize {
+            return false;
+        }
+        self.rooms.insert(client_id, zone);
+        true
+    }
+
+    /// Returns the zone of the room a client is in, or None if it hasn't joined one.
+    pub fn room_of(&self, client_id: u64) -> Option<u16> {
+        self.rooms.get(&client_id).copied()
+    }
+
+    /// Remembers a client's name, so clients who join later can be sent it.
+    pub fn set_name(&mut self, client_id: u64, name: String) {
+        self.names.insert(client_id, name);
+    }
+
+    /// Returns the name of every client except one, as sent in Packet::SetName after Packet::PlayerRoster.
+    pub fn names_for(&self, client_id: u64) -> Vec<(u64, String)> {
+        self.names
+            .iter()
+            .filter(|(id, _)| **id != client_id)
+            .map(|(id, name)| (*id, name.clone()))
+            .collect()
+    }
+
+    /// Returns where a client was at a moment in the past, so interactions can be checked against what the client saw.
+    ///
+    /// Positions between two recorded ones are interpolated.
+    /// Moments older than the history return the oldest position, and moments newer than it return the newest.
+    /// Returns None if the client hasn't moved yet.
+    pub fn position_at(&self, client_id: u64, timestamp: Instant) -> Option<[f32; 3]> {
+        let history = self.position_histories.get(&client_id)?;
+        let after = history.partition_point(|(recorded_at, _)| *recorded_at <= timestamp);
+        match (after.checked_sub(1).map(|i| history[i]), history.get(after)) {
+            (Some((before_time, before)), Some(&(after_time, after))) => {
+                let t = (timestamp - before_time).as_secs_f32()
+                    / (after_time - before_time).as_secs_f32();
+                Some(std::array::from_fn(|i| {
+                    before[i] + (after[i] - before[i]) * t
+                }))
+            }
+            (Some((_, position)), None) | (None, Some(&(_, position))) => Some(position),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Returns where a spawn slot is in the level.
+///
+/// Slot 0 is the level's spawn point, and every slot after it is placed on rings around it,
+/// so players who join at the same time don't stack on top of each other.
+pub fn spawn_position(level_spawn: [f32; 3], spawn_slot: usize) -> [f32; 3] {
+    let [x, y, z] = level_spawn;
+    if spawn_slot == 0 {
+        return level_spawn;
+    }
+
+    let ring = (spawn_slot - 1) / SPAWN_SLOTS_PER_RING + 1;
+    let angle =
+        ((spawn_slot - 1) % SPAWN_SLOTS_PER_RING) as f32 * TAU / SPAWN_SLOTS_PER_RING as f32;
+    let radius = ring as f32 * SPAWN_RING_SPACING;
+    [x + radius * angle.cos(), y, z + radius * angle.sin()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn the_full_roster_includes_everyone() {
+        let mut registry = ConnectionRegistry::default();
+        registry.record_movement(1, [1.0, 0.0, 0.0], 3, 0);
+        registry.record_movement(2, [2.0, 0.0, 0.0], 4, 1);
+
+        let mut roster = registry.full_roster();
+        roster.sort_by_key(|&(id, ..)| id);
+        assert_eq!(roster, [(1, 1.0, 0.0, 0.0, 3, 0), (2, 2.0, 0.0, 0.0, 4, 1)]);
+        assert_eq!(registry.roster_for(1), [(2, 2.0, 0.0, 0.0, 4, 1)]);
+    }
+
+    #[test]
+    fn rooms_fill_up_to_their_capacity() {
+        let mut registry = ConnectionRegistry::default();
+        assert!(registry.join_room(1, 0, 2));
+        assert!(registry.join_room(2, 0, 2));
+        assert!(!registry.join_room(3, 0, 2));
+        assert!(registry.join_room(3, 1, 2));
+        assert_eq!(registry.room_list(), [(0, 2), (1, 1)]);
+    }
+
+    #[test]
+    fn clients_keep_their_seat_in_a_full_room() {
+        let mut registry = ConnectionRegistry::default();
+        assert!(registry.join_room(1, 0, 1));
+        assert!(registry.join_room(1, 0, 1));
+        assert_eq!(registry.room_list(), [(0, 1)]);
+    }
+
+    #[test]
+    fn moving_to_another_zone_changes_room() {
+        let mut registry = ConnectionRegistry::default();
+        assert!(registry.join_room(1, 0, 1));
+        registry.record_movement(1, [0.0; 3], 0, 1);
+        assert_eq!(registry.room_list(), [(1, 1)]);
+        assert!(registry.join_room(2, 0, 1));
+    }
+
+    #[test]
+    fn clearing_position_histories_keeps_the_roster() {
+        let mut registry = ConnectionRegistry::default();
+        registry.record_movement(1, [1.0, 0.0, 0.0], 0, 0);
+        assert_eq!(
+            registry.position_at(1, Instant::now()),
+            Some([1.0, 0.0, 0.0])
+        );
+
+        registry.clear_position_histories();
+        assert_eq!(registry.position_at(1, Instant::now()), None);
+        assert_eq!(registry.full_roster().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sequential_connections_never_share_an_id() {
+        let first = testing::connect_loopback().await;
+        let second = testing::connect_loopback().await;
+        let third = testing::connect_loopback().await;
+        let address = first.client_endpoint.local_addr().unwrap();
+        let mut registry = ConnectionRegistry::default();
+
+        let (first_id, _) = registry.begin_session(1, &first.server);
+        assert!(registry.end_session(
+            1,
+            &first.server,
+            address,
+            &SessionStats::new(),
+            DisconnectReason::Graceful
+        ));
+        let (second_id, _) = registry.begin_session(2, &second.server);
+        assert_ne!(first_id, second_id);
+
+        // Even once the first session has expired and its token is forgotten, its ID isn't given out again.
+        registry.sessions.get_mut(&1).unwrap().expires_at = Some(Instant::now());
+        let (third_id, _) = registry.begin_session(3, &third.server);
+        assert!(!registry.sessions.contains_key(&1));
+        assert_ne!(third_id, first_id);
+        assert_ne!(third_id, second_id);
+    }
+
+    #[tokio::test]
+    async fn reconnecting_with_the_same_token_keeps_the_id() {
+        let first = testing::connect_loopback().await;
+        let second = testing::connect_loopback().await;
+        let mut registry = ConnectionRegistry::default();
+
+        let (id, slot) = registry.begin_session(1, &first.server);
+        assert_eq!(registry.begin_session(1, &second.server), (id, slot));
+        // The old connection is closed, so it can't linger as a ghost.
+        assert!(first.server.close_reason().is_some());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::registry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Where a player was when they last left, and which zone that was in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct SavedPosition {
+    pub zone: u16,
+    pub position: [f32; 3],
+}
+
+/// How a position is stored in the file.
+/// Files written before zones were saved only have the position, which was always in zone 0.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredPosition {
+    Zoned(SavedPosition),
+    Unzoned([f32; 3]),
+}
+impl From<StoredPosition> for SavedPosition {
+    fn from(stored: StoredPosition) -> Self {
+        match stored {
+            StoredPosition::Zoned(saved) => saved,
+            StoredPosition::Unzoned(position) => Self { zone: 0, position },
+        }
+    }
+}
+
+/// Where each player was when they last left, keyed by the player token from their Packet::Hello.
+///
+/// With a path, positions are read from a JSON file at startup and written back by flush,
+/// so players pick up where they left off even after the server restarts.
+/// Without one, positions are only remembered until the server shuts down.
+pub struct SavedPositions {
+    path: Option<PathBuf>,
+    positions: HashMap<u64, SavedPosition>,
+    /// Whether a position changed since the file was last written.
+    unsaved: bool,
+}
+impl SavedPositions {
+    /// Reads the saved positions at a path. A missing file is normal the first time the server runs,
+    /// and is created by the first flush.
+    pub fn load(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let positions = match &path {
+            Some(path) => read_positions(path)?,
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            path,
+            positions,
+            unsaved: false,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// Returns where a player was when they last left, or None if they've never played here.
+    pub fn get(&self, player_token: u64) -> Option<SavedPosition> {
+        self.positions.get(&player_token).copied()
+    }
+
+    /// Remembers where a player was when they left. It isn't written to the file until the next flush.
+    ///
+    /// Positions that aren't finite are ignored, since JSON can't hold them and they aren't anywhere to spawn.
+    pub fn insert(&mut self, player_token: u64, zone: u16, position: [f32; 3]) {
+        if position.iter().all(|axis| axis.is_finite()) {
+            self.positions
+                .insert(player_token, SavedPosition { zone, position });
+            self.unsaved = true;
+        }
+    }
+
+    /// Writes every position to the file, if any changed since the last time.
+    ///
+    /// The file is replaced all at once, so a crash halfway through can't leave it half written.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.unsaved {
+            return Ok(());
+        }
+        let json = serde_json::to_string(&self.positions)?;
+        let temporary_path = path.with_extension("json.tmp");
+        std::fs::write(&temporary_path, json)?;
+        std::fs::rename(&temporary_path, path)?;
+        self.unsaved = false;
+        Ok(())
+    }
+}
+
+/// Returns where a player spawns after joining a zone.
+///
+/// A returning player spawns where they left off if they left from the same zone.
+/// Otherwise that position is somewhere in another level, so they spawn in their slot near the level's spawn point.
+pub fn spawn_point(
+    saved: Option<SavedPosition>,
+    zone: u16,
+    level_spawn: [f32; 3],
+    spawn_slot: usize,
+) -> [f32; 3] {
+    match saved {
+        Some(saved) if saved.zone == zone => saved.position,
+        _ => registry::spawn_position(level_spawn, spawn_slot),
+    }
+}
+
+fn read_positions(path: &Path) -> anyhow::Result<HashMap<u64, SavedPosition>> {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "No saved positions at {}, so every player starts at the spawn point.",
+                path.display()
+            );
+            return Ok(HashMap::new());
+        }
+        Err(e) => {
+            return Err(anyhow::anyhow!(
+                "Could not read saved positions at {}: {e}",
+                path.display()
+            ));
+        }
+    };
+    let positions: HashMap<u64, StoredPosition> = serde_json::from_str(&json).map_err(|e| {
+        anyhow::anyhow!(
+            "Saved positions at {} aren't valid JSON: {e}",
+            path.display()
+        )
+    })?;
+    Ok(positions
+        .into_iter()
+        .map(|(player_token, stored)| (player_token, stored.into()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL_SPAWN: [f32; 3] = [1.0, 2.0, 3.0];
+
+    #[test]
+    fn players_spawn_where_they_left_off_in_the_same_zone() {
+        let saved = SavedPosition {
+            zone: 1,
+            position: [10.0, 0.0, -4.0],
+        };
+        assert_eq!(
+            spawn_point(Some(saved), 1, LEVEL_SPAWN, 3),
+            [10.0, 0.0, -4.0]
+        );
+    }
+
+    #[test]
+    fn players_joining_another_zone_spawn_at_their_slot() {
+        let saved = SavedPosition {
+            zone: 1,
+            position: [10.0, 0.0, -4.0],
+        };
+        assert_eq!(
+            spawn_point(Some(saved), 0, LEVEL_SPAWN, 3),
+            registry::spawn_position(LEVEL_SPAWN, 3)
+        );
+        assert_eq!(
+            spawn_point(None, 0, LEVEL_SPAWN, 3),
+            registry::spawn_position(LEVEL_SPAWN, 3)
+        );
+    }
+
+    #[test]
+    fn positions_keep_their_zone_through_the_file() {
+        let path = std::env::temp_dir().join(format!(
+            "miniscop-saved-positions-{}.json",
+            std::process::id()
+        ));
+        let mut saved_positions = SavedPositions::load(Some(path.clone())).unwrap();
+        saved_positions.insert(7, 2, [1.0, 2.0, 3.0]);
+        saved_positions.flush().unwrap();
+
+        let loaded = SavedPositions::load(Some(path.clone())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            loaded.get(7),
+            Some(SavedPosition {
+                zone: 2,
+                position: [1.0, 2.0, 3.0]
+            })
+        );
+    }
+
+    #[test]
+    fn positions_saved_without_a_zone_are_in_zone_0() {
+        let path = std::env::temp_dir().join(format!(
+            "miniscop-unzoned-positions-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, r#"{"7":[1.0,2.0,3.0]}"#).unwrap();
+
+        let loaded = SavedPositions::load(Some(path.clone())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            loaded.get(7),
+            Some(SavedPosition {
+                zone: 0,
+                position: [1.0, 2.0, 3.0]
+            })
+        );
+    }
+}
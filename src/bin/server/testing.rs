@@ -0,0 +1,86 @@
+// Loopback QUIC connections for tests, using the self-signed certificate for localhost in testing/.
+// It's valid for localhost, 127.0.0.1 and ::1, and only ever used by tests.
+use crate::{bind_socket, load_server_config};
+use quinn::rustls::pki_types::pem::PemObject;
+use quinn::rustls::pki_types::CertificateDer;
+use quinn::rustls::RootCertStore;
+use quinn::{ClientConfig, Connection, Endpoint, EndpointConfig, TokioRuntime};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn testing_file(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("src/bin/server/testing")
+        .join(name)
+}
+
+/// A server endpoint bound to a free port on an address, the same way main binds one.
+pub fn server_endpoint(address: SocketAddr) -> Endpoint {
+    let server_config =
+        load_server_config(&testing_file("certificate.pem"), &testing_file("key.pem")).unwrap();
+    Endpoint::new(
+        EndpointConfig::default(),
+        Some(server_config),
+        bind_socket(address).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap()
+}
+
+/// A client endpoint on a free port of an address, which trusts the test certificate.
+pub fn client_endpoint(address: SocketAddr) -> Endpoint {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(testing_file("certificate.pem")).unwrap())
+        .unwrap();
+    let mut endpoint = Endpoint::client(address).unwrap();
+    endpoint
+        .set_default_client_config(ClientConfig::with_root_certificates(Arc::new(roots)).unwrap());
+    endpoint
+}
+
+/// Both sides of a connection, and the endpoints it was made with, which have to outlive it.
+pub struct Loopback {
+    #[expect(dead_code, reason = "It's only kept so the connection outlives it.")]
+    pub server_endpoint: Endpoint,
+    pub client_endpoint: Endpoint,
+    pub server: Connection,
+    pub client: Connection,
+}
+
+/// Connects a client on one address to a server on another.
+/// The server is reached at the client's IP address, so both should be loopback addresses of the same kind.
+pub async fn connect(server_address: SocketAddr, client_address: SocketAddr) -> Loopback {
+    let server_endpoint = server_endpoint(server_address);
+    let client_endpoint = client_endpoint(client_address);
+    let address = SocketAddr::new(
+        client_address.ip(),
+        server_endpoint.local_addr().unwrap().port(),
+    );
+    let (server, client) = tokio::join!(
+        async { server_endpoint.accept().await.unwrap().await.unwrap() },
+        async {
+            client_endpoint
+                .connect(address, "localhost")
+                .unwrap()
+                .await
+                .unwrap()
+        },
+    );
+    Loopback {
+        server_endpoint,
+        client_endpoint,
+        server,
+        client,
+    }
+}
+
+/// Connects a client to a server, both on IPv4 loopback.
+pub async fn connect_loopback() -> Loopback {
+    connect(
+        "127.0.0.1:0".parse().unwrap(),
+        "127.0.0.1:0".parse().unwrap(),
+    )
+    .await
+}
@@ -1 +1,2 @@
+pub mod lockstep;
 pub mod networking;
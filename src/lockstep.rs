@@ -0,0 +1,143 @@
+//! Movement for the server's optional lockstep mode, where clients send their inputs instead of their positions.
+//!
+//! The server and every client step each player from the same inputs with the same integer math,
+//! so everyone sees every player in exactly the same place on every tick.
+//! Floats could round differently on different machines, so positions are kept in whole millimeters,
+//! and only turned into meters to be shown.
+//!
+//! That consistency costs latency. Inputs are sent reliably, so a lost packet holds up every input after it,
+//! and nobody sees a player move until that player's input for the next tick arrives.
+//! It's best for small co-op sessions, where everyone agreeing on where everyone is matters more than smoothness.
+//!
+//! Lockstep movement is much simpler than the physics it replaces. Players walk at a constant speed on flat ground,
+//! and can't jump, collide with anything, or use level exits. Players can't touch each other either,
+//! so each player only depends on their own inputs, and a player who falls behind doesn't hold up anyone else.
+
+/// How many ticks a second players are stepped at. This is Bevy's default fixed timestep, which the client ticks at.
+pub const TICKS_PER_SECOND: u32 = 64;
+/// Positions are kept in millimeters.
+const UNITS_PER_METER: f32 = 1000.0;
+
+/// The bits of an input, which are set while their direction is held.
+pub const MOVE_UP: u8 = 1 << 0;
+pub const MOVE_DOWN: u8 = 1 << 1;
+pub const MOVE_LEFT: u8 = 1 << 2;
+pub const MOVE_RIGHT: u8 = 1 << 3;
+
+/// Turns a move speed in meters per second into the millimeters a player walks each tick.
+///
+/// Every machine rounds the same move speed from Packet::LevelConfig the same way, so they all get the same number.
+pub fn speed_per_tick(move_speed: f32) -> i32 {
+    (move_speed * UNITS_PER_METER / TICKS_PER_SECOND as f32).round() as i32
+}
+
+/// Returns which way an input walks along x and z, from -1 to 1 each.
+///
+/// Up is towards -z, like the controls outside lockstep mode, and holding opposite directions cancels them out.
+/// Bits that aren't a direction are ignored.
+pub fn direction(input: u8) -> [i32; 2] {
+    let axis = |negative: u8, positive: u8| {
+        i32::from(input & positive != 0) - i32::from(input & negative != 0)
+    };
+    [axis(MOVE_LEFT, MOVE_RIGHT), axis(MOVE_UP, MOVE_DOWN)]
+}
+
+/// Where one player is in lockstep mode, and which tick's input they need next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockstepPlayer {
+    position: [i32; 3],
+    next_tick: u32,
+}
+impl LockstepPlayer {
+    /// Starts a player at a position, rounded to the nearest millimeter, waiting for the input for tick 0.
+    pub fn at(position: [f32; 3]) -> Self {
+        Self {
+            position: position.map(|axis| (axis * UNITS_PER_METER).round() as i32),
+            next_tick: 0,
+        }
+    }
+
+    /// Where the player is, in meters. Within 5 kilometers of the origin, starting a player there gives back the same
+    /// position, so clients can be sent it as an f32 without drifting from the server.
+    pub fn position(&self) -> [f32; 3] {
+        self.position.map(|axis| axis as f32 / UNITS_PER_METER)
+    }
+
+    pub fn next_tick(&self) -> u32 {
+        self.next_tick
+    }
+
+    /// Moves the player by one tick's input.
+    ///
+    /// Inputs have to come one tick after another. Returns false without moving if this one isn't for the next tick,
+    /// which only happens if whoever sent it is broken or malicious.
+    pub fn step(&mut self, tick: u32, input: u8, speed_per_tick: i32) -> bool {
+        if tick != self.next_tick {
+            return false;
+        }
+        let [x, z] = direction(input);
+        self.position[0] = self.position[0].saturating_add(x * speed_per_tick);
+        self.position[2] = self.position[2].saturating_add(z * speed_per_tick);
+        self.next_tick = self.next_tick.wrapping_add(1);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_inputs_end_in_the_same_place() {
+        let speed = speed_per_tick(4.0);
+        let mut first = LockstepPlayer::at([1.25, 0.5, -3.0]);
+        let mut second = LockstepPlayer::at(first.position());
+        for tick in 0..1000 {
+            let input = (tick * 7 % 16) as u8;
+            assert!(first.step(tick, input, speed));
+            assert!(second.step(tick, input, speed));
+        }
+        assert_eq!(first, second);
+        assert_eq!(first.next_tick(), 1000);
+    }
+
+    #[test]
+    fn positions_come_back_the_same_after_being_sent() {
+        for millimeters in (-5_000_000..5_000_000).step_by(997) {
+            let player = LockstepPlayer {
+                position: [millimeters, 0, -millimeters],
+                next_tick: 0,
+            };
+            assert_eq!(LockstepPlayer::at(player.position()), player);
+        }
+    }
+
+    #[test]
+    fn inputs_walk_at_the_move_speed() {
+        // 4 meters per second is 62.5 millimeters per tick, which rounds away from zero.
+        let speed = speed_per_tick(4.0);
+        assert_eq!(speed, 63);
+        let mut player = LockstepPlayer::at([0.0; 3]);
+        assert!(player.step(0, MOVE_UP | MOVE_RIGHT, speed));
+        assert!(player.step(1, MOVE_UP, speed));
+        assert!(player.step(2, MOVE_LEFT | MOVE_RIGHT, speed));
+        assert_eq!(player.position(), [0.063, 0.0, -0.126]);
+    }
+
+    #[test]
+    fn inputs_for_any_other_tick_are_refused() {
+        let speed = speed_per_tick(4.0);
+        let mut player = LockstepPlayer::at([0.0; 3]);
+        assert!(!player.step(1, MOVE_DOWN, speed));
+        assert!(player.step(0, MOVE_DOWN, speed));
+        assert!(!player.step(0, MOVE_DOWN, speed));
+        assert_eq!(player.next_tick(), 1);
+        assert_eq!(player.position(), [0.0, 0.0, 0.063]);
+    }
+
+    #[test]
+    fn unknown_bits_are_ignored() {
+        assert_eq!(direction(0xf0), [0, 0]);
+        assert_eq!(direction(0xf0 | MOVE_DOWN), [0, 1]);
+    }
+}
@@ -1,39 +1,952 @@
 use bincode::config::Configuration;
+use bincode::error::{DecodeError, EncodeError};
 use bincode::{config, decode_from_slice, Decode};
 use bincode::{encode_to_vec, Encode};
-use quinn::{RecvStream, SendStream};
+use bytes::Bytes;
+use quinn::crypto::rustls::NoInitialCipherSuite;
+use quinn::{
+    ClosedStream, ConnectError, Connection, ConnectionError, ReadError, ReadToEndError,
+    SendDatagramError, TransportErrorCode, VarInt, WriteError,
+};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::error;
 
 pub const PACKET_CONFIG: Configuration = config::standard();
-#[derive(Encode, Decode, Debug, Copy, Clone, PartialEq)]
+/// The largest encoded packet either side accepts, in bytes.
+///
+/// receive_packet rejects streams longer than this, and read_framed rejects frames longer than this,
+/// so a corrupt length prefix can't make it allocate a huge buffer.
+pub const MAX_PACKET_SIZE: usize = 4096;
+/// The most characters a chat message can have.
+/// Even if every character takes 4 bytes in UTF-8, a chat packet stays well under MAX_PACKET_SIZE.
+pub const MAX_CHAT_LENGTH: usize = 200;
+/// The most characters a player's name can have. See sanitize_name.
+pub const MAX_NAME_LENGTH: usize = 20;
+/// How many emotes there are. Packet::Emote numbers them from 0 to EMOTE_COUNT - 1.
+pub const EMOTE_COUNT: u8 = 5;
+/// How often the client sends Packet::Heartbeat while online.
+/// The server's timeout has to be longer than this, or every client would be dropped.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Quantized positions are whole multiples of this many meters away from their origin.
+pub const QUANTIZATION_STEP: f32 = 1.0 / 128.0;
+/// The furthest a quantized position can be from the real one on each axis, in meters.
+/// Player sprites are drawn at 33 pixels per meter, so this is about an eighth of a pixel.
+pub const MAX_QUANTIZATION_ERROR: f32 = QUANTIZATION_STEP / 2.0;
+const _: () = assert!(MAX_QUANTIZATION_ERROR < 0.01);
+/// How many Packet::QuantizedMovement the client sends after each absolute Packet::PlayerMovement.
+///
+/// Offsets stay smaller with fewer, and bincode writes small offsets in fewer bytes.
+/// At walking speed, 8 ticks is half a meter, which takes 1 byte per axis.
+/// It also limits how long the server has to wait for the next origin if one is lost.
+pub const QUANTIZED_MOVEMENTS_PER_RESYNC: u32 = 7;
+/// How often the client sends Packet::Ping while online, to measure its round trip time.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The code the server closes every connection with when it shuts down, after sending Packet::ServerShutdown.
+pub const SERVER_SHUTDOWN_CODE: VarInt = VarInt::from_u32(1);
+/// The code the server closes a connection with after sending Packet::VersionMismatch.
+pub const VERSION_MISMATCH_CODE: VarInt = VarInt::from_u32(2);
+/// Which version of Packet this build speaks, sent in Packet::Hello.
+/// Bump this whenever a change to Packet would make it encode differently, so old clients are turned away
+/// instead of misreading packets.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// Everything the client and server send each other.
+///
+/// Bincode encodes variants by their position and fields in order, so adding, removing or reordering either
+/// changes how packets encode. Any change like that needs a PROTOCOL_VERSION bump, except that Hello has to stay
+/// the first variant with version as its first field. The golden bytes in this file's tests catch a forgotten bump.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
 pub enum Packet {
+    /// The first packet a client sends.
+    /// The version is the client's PROTOCOL_VERSION. It has to stay the first field of the first variant,
+    /// so servers can read it from clients of any version.
+    /// The session token is generated by the client, and lets it keep its ID if it reconnects.
+    /// The player token is saved in the client's settings, so it stays the same between launches.
+    /// The server uses it to put players back where they were when they last left.
+    Hello {
+        version: u32,
+        session_token: u64,
+        player_token: u64,
+    },
     /// Client will be kicked if it sends this.
-    /// Its current purpose is to signal to the client that it can start sending packets.
-    ClientConnect,
+    /// It tells the client its ID, and that it can start sending packets.
+    ClientConnect { id: u64 },
     /// Client will be disconnected if they send this regardless of the ID inside, so they might as well send None.
-    ClientDisconnect(Option<u64>),
-    /// Client should send None for id because it doesn't know its own id.
+    /// The server sends it with why the client left, and clients should send None for that too.
+    ClientDisconnect(Option<u64>, Option<DisconnectReason>),
+    /// Client should send None for id, and the server fills in the ID it gave the client.
     PlayerMovement {
         id: Option<u64>,
         x: f32,
         y: f32,
         z: f32,
         animation_frame: u8,
+        /// True if the player moved instantly (e.g. respawned), so other clients shouldn't reject the jump.
+        teleported: bool,
+        /// Which level the player is in. Clients only show players in the same level as them.
+        zone: u16,
     },
+    /// Client will be kicked if it sends this.
+    /// The server sends this after ClientConnect so that every client plays the level with the same constants.
+    /// With lockstep set, the server is in lockstep mode, and clients send Input instead of their movement.
+    LevelConfig {
+        move_speed: f32,
+        spawn: [f32; 3],
+        lockstep: bool,
+    },
+    /// Client will be kicked if it sends this.
+    /// The server sends this after LevelConfig to give each client its own spot to spawn at.
+    /// That's where the player was when they last left, or a spot near the level's spawn point if they're new.
+    SetSpawn { x: f32, y: f32, z: f32 },
+    /// Client should send None for id when it opens the gift.
+    /// The server sends this to every client, including the one who opened it, with that client's id.
+    Interact { id: Option<u64> },
+    /// Client will be kicked if it sends this.
+    /// The server sends this when a client joins, and when the gift comes back after being opened.
+    GiftState { opened: bool },
+    /// Client will be kicked if it sends this.
+    /// The server operator sends this to make every client reset the level, and then the server resends its state.
+    ReloadLevel,
+    /// Client should send None for id, and the server fills in the ID it gave the client.
+    /// The server cleans the message up with sanitize_text, cutting it off at MAX_CHAT_LENGTH characters,
+    /// and drops it if it's empty.
+    ///
+    /// Chat that isn't global is only passed on to players within the server's --interest-radius of the sender.
+    /// Without --interest-radius, everyone hears everything.
+    Chat {
+        id: Option<u64>,
+        message: String,
+        global: bool,
+    },
+    /// Client will be kicked if it sends this.
+    /// The server sends this right after ClientConnect, with the ID, position, animation frame and zone of every other
+    /// player, so they show up before they next move.
+    PlayerRoster(Vec<(u64, f32, f32, f32, u8, u16)>),
+    /// The client sends this every HEARTBEAT_INTERVAL, so the server can tell a frozen client from a quiet one.
+    /// The server never sends this, or passes it on to other clients.
+    Heartbeat,
+    /// The client sends this every PING_INTERVAL, with a number it hasn't used for a ping before.
+    /// The server answers straight away with Packet::Pong, and doesn't pass it on to other clients.
+    Ping { nonce: u64 },
+    /// Client will be kicked if it sends this.
+    /// The server's answer to Packet::Ping, with the same nonce, which the client uses to time the round trip.
+    Pong { nonce: u64 },
+    /// Client will be kicked if it sends this.
+    /// With the server's --interest-radius set, this is sent when another player goes too far away to be sent their
+    /// movement. Clients should hide them until their next PlayerMovement, which teleports them back into view.
+    /// Players can pop in and out like this any number of times without disconnecting.
+    PlayerOutOfRange(u64),
+    /// Client will be kicked if it sends this.
+    /// The server collects other players' movement and sends it to each client in batches, with the ID, position,
+    /// animation frame and zone of each player that moved. Only their latest movement is kept.
+    /// Batches are split with movement_batches so each fits in a datagram.
+    /// Teleports are still sent as PlayerMovement, since batches have no room for that.
+    MovementBatch(Vec<(u64, f32, f32, f32, u8, u16)>),
+    /// The server will never send this.
+    /// A movement the client sends instead of PlayerMovement, as an offset from the last PlayerMovement it sent,
+    /// made with quantize_position. Teleports, and so changing zones, are always sent as PlayerMovement.
+    ///
+    /// The origin is that PlayerMovement's origin_tag. If it doesn't match what the server received last,
+    /// the PlayerMovement was lost, and the server skips movements until the next one.
+    QuantizedMovement {
+        origin: u8,
+        x: i16,
+        y: i16,
+        z: i16,
+        animation_frame: u8,
+    },
+    /// Client will be kicked if it sends this.
+    /// The server sends this to every client when it is shutting down, shortly before closing their connections
+    /// with SERVER_SHUTDOWN_CODE.
+    ServerShutdown,
+    /// Client will be kicked if it sends this.
+    /// The server sends this instead of ClientConnect when the client's Hello has a different PROTOCOL_VERSION,
+    /// shortly before closing the connection with VERSION_MISMATCH_CODE.
+    VersionMismatch { server_version: u32 },
+    /// Client should send None for id, once it has been sent ClientConnect, and the server fills in the ID it gave
+    /// the client before passing it on. The server cleans the name up with sanitize_name, and drops it if it's empty.
+    ///
+    /// New clients are sent one of these for every named player right after PlayerRoster, rather than inside it,
+    /// so a full server's names can't push the roster past MAX_PACKET_SIZE.
+    SetName { id: Option<u64>, name: String },
+    /// Client should send None for id, and the server fills in the ID it gave the client.
+    /// The server drops emotes from EMOTE_COUNT up instead of passing them on, and clients ignore them too,
+    /// so new emotes can be added without breaking older builds.
+    Emote { id: Option<u64>, emote: u8 },
+    /// Client will be kicked if it sends this.
+    /// The server sends this right after the client's Hello, with how many players are in each zone that has anyone
+    /// in it, and how many fit in one. Each level is a room, and zones missing from the list are empty.
+    /// It's sent again if the client asks to join a room that's full.
+    RoomList {
+        rooms: Vec<(u16, u32)>,
+        capacity: u32,
+    },
+    /// The server will never send this.
+    /// The client sends this once it has been sent RoomList, with the zone of the room it wants to play in.
+    /// The server sends nothing else until it does, and then carries on with ClientConnect.
+    JoinRoom { zone: u16 },
+    /// Client should send None for id, and the server fills in the ID it gave the client.
+    /// Only sent when Packet::LevelConfig turns lockstep on, in place of PlayerMovement and QuantizedMovement.
+    ///
+    /// The client sends one every tick, with which of the miniscop::lockstep input bits are held.
+    /// Ticks count up from 0 each time the server sends SetSpawn, and the server kicks clients that skip one.
+    /// Clients step each other player with their inputs, starting from where PlayerRoster said they were.
+    Input {
+        id: Option<u64>,
+        tick: u32,
+        input: u8,
+    },
+}
+
+/// Why a client's connection ended, as the server sends it in Packet::ClientDisconnect.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client said it was leaving, or closed its connection.
+    Graceful,
+    /// The client went quiet for longer than the server's timeout.
+    Timeout,
+    /// The client was kicked for sending something it shouldn't have.
+    ProtocolViolation,
+    /// Anything else went wrong with the connection.
+    Error,
+}
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DisconnectReason::Graceful => "left",
+            DisconnectReason::Timeout => "timed out",
+            DisconnectReason::ProtocolViolation => "was kicked",
+            DisconnectReason::Error => "lost connection",
+        })
+    }
 }
 
+impl Packet {
+    /// Reliable packets have to arrive in the order they were sent, so each side sends them over one long-lived stream
+    /// with write_framed.
+    ///
+    /// Movement isn't reliable. Each movement is sent as a datagram with send_packet_datagram,
+    /// so a lost one can't hold up the ones after it, and sending one doesn't open a stream.
+    pub fn is_reliable(&self) -> bool {
+        !matches!(
+            self,
+            Packet::PlayerMovement { .. }
+                | Packet::MovementBatch(_)
+                | Packet::QuantizedMovement { .. }
+        )
+    }
+}
+
+/// Removes control characters and surrounding whitespace from text a client sent,
+/// and cuts it off at max_length characters.
+///
+/// Control characters could move the cursor or clear the screen of anyone reading the server's logs.
+pub fn sanitize_text(text: &str, max_length: usize) -> String {
+    let text: String = text
+        .chars()
+        .filter(|character| !character.is_control())
+        .collect();
+    text.trim().chars().take(max_length).collect()
+}
+
+/// Cleans up a player's name with sanitize_text, cutting it off at MAX_NAME_LENGTH characters.
+pub fn sanitize_name(name: &str) -> String {
+    sanitize_text(name, MAX_NAME_LENGTH)
+}
+
+/// Turns a position into its offset from an origin, in QUANTIZATION_STEPs.
+/// Rounding keeps each axis within MAX_QUANTIZATION_ERROR of the real position.
+///
+/// Returns None if the position is too far from the origin to fit, in which case it has to be sent absolutely.
+pub fn quantize_position(position: [f32; 3], origin: [f32; 3]) -> Option<[i16; 3]> {
+    let mut offset = [0; 3];
+    for ((axis_offset, position), origin) in offset.iter_mut().zip(position).zip(origin) {
+        let steps = ((position - origin) / QUANTIZATION_STEP).round();
+        // NaN isn't in the range either, so it can't sneak through as 0.
+        if !(i16::MIN as f32..=i16::MAX as f32).contains(&steps) {
+            return None;
+        }
+        *axis_offset = steps as i16;
+    }
+    Some(offset)
+}
+
+/// Turns an offset from quantize_position back into a position.
+pub fn dequantize_position(offset: [i16; 3], origin: [f32; 3]) -> [f32; 3] {
+    std::array::from_fn(|axis| origin[axis] + offset[axis] as f32 * QUANTIZATION_STEP)
+}
+
+/// A short fingerprint of an origin, sent with each Packet::QuantizedMovement.
+///
+/// Different origins can share a tag, but that takes a lost packet and a 1 in 256 chance together.
+pub fn origin_tag(origin: [f32; 3]) -> u8 {
+    let hash = origin.iter().fold(0u32, |hash, axis| {
+        (hash ^ axis.to_bits()).wrapping_mul(0x9E37_79B1)
+    });
+    (hash >> 24) as u8
+}
+
+/// The most bytes one movement in Packet::MovementBatch can take.
+/// Bincode writes the ID in up to 9 bytes, then 4 bytes for each coordinate, 1 for the animation frame,
+/// and up to 3 for the zone.
+pub const MAX_BATCH_ENTRY_SIZE: usize = 9 + 3 * 4 + 1 + 3;
+
+/// Splits movements into as few Packet::MovementBatch as possible, without any of them taking more than max_size bytes.
+///
+/// Each batch holds at least one movement, even if max_size is too small for it.
+pub fn movement_batches(
+    movements: Vec<(u64, f32, f32, f32, u8, u16)>,
+    max_size: usize,
+) -> Vec<Packet> {
+    // The variant takes 1 byte, and the length of the batch takes up to 9 more.
+    let per_batch = (max_size.saturating_sub(10) / MAX_BATCH_ENTRY_SIZE).max(1);
+    movements
+        .chunks(per_batch)
+        .map(|chunk| Packet::MovementBatch(chunk.to_vec()))
+        .collect()
+}
+
+/// Everything that can go wrong while talking to the other side of a connection.
+#[derive(thiserror::Error, Debug)]
+pub enum NetworkError {
+    #[error("could not resolve the server's address")]
+    UnresolvedAddress,
+    #[error("the server refused the connection, most likely because it is full")]
+    Refused,
+    #[error("failed to start connecting: {0}")]
+    Connect(#[from] ConnectError),
+    #[error("the crypto provider can't be used for QUIC: {0}")]
+    Crypto(#[from] NoInitialCipherSuite),
+    #[error("connection lost: {0}")]
+    Connection(#[from] ConnectionError),
+    #[error("stream was already closed")]
+    ClosedStream(#[from] ClosedStream),
+    #[error("the reliable stream's writer stopped")]
+    ReliableClosed,
+    #[error("failed to write packet: {0}")]
+    Write(#[from] WriteError),
+    #[error("failed to read packet: {0}")]
+    Read(#[from] ReadToEndError),
+    #[error("frame of {0} bytes is larger than the {MAX_PACKET_SIZE} byte limit")]
+    FrameTooLarge(usize),
+    #[error("packet stream is longer than the {MAX_PACKET_SIZE} byte limit")]
+    PacketTooLarge,
+    #[error("failed to send datagram: {0}")]
+    SendDatagram(#[from] SendDatagramError),
+    #[error("datagram of {0} bytes is larger than the connection allows")]
+    DatagramTooLarge(usize),
+    #[error("failed to encode packet: {0}")]
+    Encode(#[from] EncodeError),
+    #[error("failed to decode packet with protocol version {PROTOCOL_VERSION}: {0}")]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+impl NetworkError {
+    /// Connection errors caused by the server refusing to accept a connection become NetworkError::Refused.
+    pub fn from_connection_error(error: ConnectionError) -> Self {
+        match error {
+            ConnectionError::ConnectionClosed(close)
+                if close.error_code == TransportErrorCode::CONNECTION_REFUSED =>
+            {
+                Self::Refused
+            }
+            error => Self::Connection(error),
+        }
+    }
+
+    /// Whether the server closed the connection because this client's PROTOCOL_VERSION is different from its own.
+    pub fn is_version_mismatch(&self) -> bool {
+        matches!(
+            self.connection_error(),
+            Some(ConnectionError::ApplicationClosed(close)) if close.error_code == VERSION_MISMATCH_CODE
+        )
+    }
+
+    /// Whether the connection was closed because the server shut down, which isn't really an error.
+    pub fn is_server_shutdown(&self) -> bool {
+        matches!(
+            self.connection_error(),
+            Some(ConnectionError::ApplicationClosed(close)) if close.error_code == SERVER_SHUTDOWN_CODE
+        )
+    }
+
+    /// The reason the connection closed, if that's what went wrong.
+    ///
+    /// read_framed and write_framed work on any stream, so quinn's errors reach them wrapped in std::io::Error.
+    fn connection_error(&self) -> Option<&ConnectionError> {
+        match self {
+            Self::Connection(error)
+            | Self::Write(WriteError::ConnectionLost(error))
+            | Self::Read(ReadToEndError::Read(ReadError::ConnectionLost(error))) => Some(error),
+            Self::Io(error) => {
+                let inner = error.get_ref()?;
+                match (
+                    inner.downcast_ref::<ReadError>(),
+                    inner.downcast_ref::<WriteError>(),
+                ) {
+                    (Some(ReadError::ConnectionLost(error)), _)
+                    | (_, Some(WriteError::ConnectionLost(error))) => Some(error),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Sends a packet on its own stream, and finishes the stream. Read it with receive_packet.
+///
 /// Note: This future finishes when the packet sent, not when it is received by the server.
-#[tracing::instrument]
-pub async fn send_packet(mut send: SendStream, packet: Packet) -> anyhow::Result<()> {
+/// Any stream works, but in practice this is a quinn::SendStream.
+#[tracing::instrument(skip(send))]
+pub async fn send_packet<W: AsyncWrite + Unpin>(
+    mut send: W,
+    packet: Packet,
+) -> Result<(), NetworkError> {
     let packet = encode_to_vec(packet, PACKET_CONFIG)?;
     send.write_all(packet.as_slice()).await?;
-    send.finish()?;
+    send.shutdown().await?;
 
     Ok(())
 }
 
-#[tracing::instrument]
-pub async fn receive_packet(mut recv: RecvStream) -> anyhow::Result<Packet> {
-    let packet = recv.read_to_end(64).await?;
+/// Reads the one packet on a stream sent by send_packet, once the stream is finished.
+///
+/// Streams longer than MAX_PACKET_SIZE are an error instead of being cut short.
+#[tracing::instrument(skip(recv))]
+pub async fn receive_packet<R: AsyncRead + Unpin>(recv: R) -> Result<Packet, NetworkError> {
+    let mut packet = Vec::new();
+    // One byte past the limit is enough to tell a stream that's too long from one that's exactly the limit.
+    recv.take(MAX_PACKET_SIZE as u64 + 1)
+        .read_to_end(&mut packet)
+        .await?;
+    if packet.len() > MAX_PACKET_SIZE {
+        return Err(NetworkError::PacketTooLarge);
+    }
     let (packet, _): (Packet, usize) = decode_from_slice(packet.as_slice(), PACKET_CONFIG)?;
     Ok(packet)
 }
+
+/// Sends a packet as a single datagram, which may be lost or arrive out of order.
+///
+/// Datagrams can't be split up, so the packet must fit in the connection's max datagram size.
+/// That is usually around 1200 bytes, which is much smaller than MAX_PACKET_SIZE.
+/// Packets bigger than either are rejected before sending. Read them with receive_packet_datagram.
+pub fn send_packet_datagram(connection: &Connection, packet: &Packet) -> Result<(), NetworkError> {
+    let packet = encode_to_vec(packet, PACKET_CONFIG)?;
+    let max_size = connection
+        .max_datagram_size()
+        .ok_or(SendDatagramError::UnsupportedByPeer)?
+        .min(MAX_PACKET_SIZE);
+    if packet.len() > max_size {
+        return Err(NetworkError::DatagramTooLarge(packet.len()));
+    }
+    connection.send_datagram(Bytes::from(packet))?;
+
+    Ok(())
+}
+
+/// Waits for the next datagram sent by send_packet_datagram.
+#[tracing::instrument]
+pub async fn receive_packet_datagram(connection: &Connection) -> Result<Packet, NetworkError> {
+    let packet = connection.read_datagram().await?;
+    if packet.len() > MAX_PACKET_SIZE {
+        return Err(NetworkError::PacketTooLarge);
+    }
+    let (packet, _): (Packet, usize) = decode_from_slice(&packet, PACKET_CONFIG)?;
+    Ok(packet)
+}
+
+/// Writes a packet to a long-lived stream, prefixed with its length as a big-endian u32.
+///
+/// Unlike send_packet, this doesn't finish the stream, so many packets can be written to the same stream in order.
+/// Read them with read_framed. Any stream works, but in practice this is a quinn::SendStream.
+#[tracing::instrument(skip(send))]
+pub async fn write_framed<W: AsyncWrite + Unpin>(
+    send: &mut W,
+    packet: Packet,
+) -> Result<(), NetworkError> {
+    let frame = encode_frame(&packet)?;
+    send.write_all(&frame).await?;
+
+    Ok(())
+}
+
+/// Sends reliable packets over one long-lived stream with write_framed, in the order they're queued.
+///
+/// Clones queue onto the same stream, so a connection can be sent packets from many tasks without them being reordered.
+/// Queueing never waits. Once every clone is dropped, the rest of the queue is written and the stream is finished.
+#[derive(Clone, Debug)]
+pub struct ReliableSender(mpsc::UnboundedSender<Packet>);
+impl ReliableSender {
+    /// Spawns the task that writes to the stream.
+    /// If writing fails, the error is logged, and queueing any more packets returns NetworkError::ReliableClosed.
+    pub fn spawn<W: AsyncWrite + Unpin + Send + 'static>(mut send: W) -> Self {
+        let (to_writer, mut from_senders) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let written = async {
+                while let Some(packet) = from_senders.recv().await {
+                    write_framed(&mut send, packet).await?;
+                }
+                send.shutdown().await?;
+                Ok::<(), NetworkError>(())
+            };
+            if let Err(e) = written.await {
+                error!("Failed to write reliable stream: {e:#?}");
+            }
+        });
+        Self(to_writer)
+    }
+
+    /// Queues a packet to be written after every packet queued before it.
+    pub fn send(&self, packet: Packet) -> Result<(), NetworkError> {
+        self.0
+            .send(packet)
+            .map_err(|_| NetworkError::ReliableClosed)
+    }
+}
+
+/// Encodes a packet into the frame that write_framed writes.
+pub fn encode_frame(packet: &Packet) -> Result<Vec<u8>, NetworkError> {
+    let packet = encode_to_vec(packet, PACKET_CONFIG)?;
+    if packet.len() > MAX_PACKET_SIZE {
+        return Err(NetworkError::FrameTooLarge(packet.len()));
+    }
+    let length = packet.len() as u32;
+
+    let mut frame = Vec::with_capacity(4 + packet.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(&packet);
+    Ok(frame)
+}
+
+/// Reads the next packet written by write_framed, waiting for the rest of the frame if only part of it has arrived.
+///
+/// Returns None once the stream is finished. A stream that finishes partway through a frame is an error.
+#[tracing::instrument(skip(recv))]
+pub async fn read_framed<R: AsyncRead + Unpin>(
+    recv: &mut R,
+) -> Result<Option<Packet>, NetworkError> {
+    let mut length = [0; 4];
+    // Reading whatever has arrived first tells a finished stream apart from one that stopped mid-frame.
+    let started = recv.read(&mut length).await?;
+    if started == 0 {
+        return Ok(None);
+    }
+    recv.read_exact(&mut length[started..]).await?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_PACKET_SIZE {
+        return Err(NetworkError::FrameTooLarge(length));
+    }
+
+    let mut packet = vec![0; length];
+    recv.read_exact(&mut packet).await?;
+    let (packet, _): (Packet, usize) = decode_from_slice(packet.as_slice(), PACKET_CONFIG)?;
+    Ok(Some(packet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn chat(message: &str) -> Packet {
+        Packet::Chat {
+            id: Some(3),
+            message: message.to_string(),
+            global: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn framed_packets_arrive_back_to_back() {
+        let (mut send, mut recv) = duplex(MAX_PACKET_SIZE);
+        // Both frames in one write, so they come out of the stream together.
+        let mut frames = encode_frame(&chat("first")).unwrap();
+        frames.extend(encode_frame(&Packet::Heartbeat).unwrap());
+        frames.extend(encode_frame(&chat("second")).unwrap());
+        send.write_all(&frames).await.unwrap();
+        drop(send);
+
+        assert_eq!(read_framed(&mut recv).await.unwrap(), Some(chat("first")));
+        assert_eq!(
+            read_framed(&mut recv).await.unwrap(),
+            Some(Packet::Heartbeat)
+        );
+        assert_eq!(read_framed(&mut recv).await.unwrap(), Some(chat("second")));
+        assert_eq!(read_framed(&mut recv).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn framed_packets_wait_for_the_rest_of_a_partial_frame() {
+        let (mut send, mut recv) = duplex(MAX_PACKET_SIZE);
+        let frame = encode_frame(&chat("split up")).unwrap();
+        let reading = tokio::spawn(async move { read_framed(&mut recv).await });
+
+        // Split the length prefix itself, then the body.
+        for piece in [&frame[..2], &frame[2..6], &frame[6..]] {
+            send.write_all(piece).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(reading.await.unwrap().unwrap(), Some(chat("split up")));
+    }
+
+    #[tokio::test]
+    async fn framed_packets_from_write_framed_round_trip() {
+        let (mut send, mut recv) = duplex(MAX_PACKET_SIZE);
+        write_framed(&mut send, chat("hello")).await.unwrap();
+        write_framed(&mut send, Packet::ReloadLevel).await.unwrap();
+        assert_eq!(read_framed(&mut recv).await.unwrap(), Some(chat("hello")));
+        assert_eq!(
+            read_framed(&mut recv).await.unwrap(),
+            Some(Packet::ReloadLevel)
+        );
+    }
+
+    #[tokio::test]
+    async fn reliable_packets_arrive_in_the_order_they_were_sent() {
+        let (send, mut recv) = duplex(64);
+        let reliable = ReliableSender::spawn(send);
+        let other_task = reliable.clone();
+        for i in 0..100 {
+            let sender = if i % 2 == 0 { &reliable } else { &other_task };
+            sender.send(chat(&i.to_string())).unwrap();
+        }
+        drop(reliable);
+        drop(other_task);
+
+        for i in 0..100 {
+            assert_eq!(
+                read_framed(&mut recv).await.unwrap(),
+                Some(chat(&i.to_string()))
+            );
+        }
+        // Dropping every sender finishes the stream.
+        assert_eq!(read_framed(&mut recv).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reliable_sender_reports_a_dead_stream() {
+        let (send, recv) = duplex(64);
+        drop(recv);
+        let reliable = ReliableSender::spawn(send);
+        reliable.send(chat("nobody is listening")).unwrap();
+        // The writer gives up once the write fails, and later packets have nowhere to go.
+        for _ in 0..100 {
+            if reliable.send(Packet::Heartbeat).is_err() {
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+        panic!("Queueing onto a dead stream kept working.");
+    }
+
+    #[tokio::test]
+    async fn stream_finishing_mid_frame_is_an_error() {
+        let frame = encode_frame(&chat("cut off")).unwrap();
+        for cut in [1, 4, frame.len() - 1] {
+            let (mut send, mut recv) = duplex(MAX_PACKET_SIZE);
+            send.write_all(&frame[..cut]).await.unwrap();
+            drop(send);
+            assert!(
+                matches!(read_framed(&mut recv).await, Err(NetworkError::Io(_))),
+                "cut at {cut}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_length_is_rejected() {
+        let (mut send, mut recv) = duplex(MAX_PACKET_SIZE);
+        let length = MAX_PACKET_SIZE as u32 + 1;
+        send.write_all(&length.to_be_bytes()).await.unwrap();
+        assert!(matches!(
+            read_framed(&mut recv).await,
+            Err(NetworkError::FrameTooLarge(size)) if size == MAX_PACKET_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn hello_starts_with_the_version() {
+        // Hello is variant 0, and small numbers encode as one byte, so any server can read the version
+        // before the rest of the packet, whatever else changed.
+        for version in [1, PROTOCOL_VERSION, PROTOCOL_VERSION + 1] {
+            let hello = Packet::Hello {
+                version,
+                session_token: 7,
+                player_token: 9,
+            };
+            let encoded = encode_to_vec(&hello, PACKET_CONFIG).unwrap();
+            assert_eq!(encoded[..2], [0, version as u8]);
+        }
+    }
+
+    #[tokio::test]
+    async fn decode_errors_say_which_protocol_version_was_expected() {
+        let (mut send, mut recv) = duplex(MAX_PACKET_SIZE);
+        // A variant this version doesn't have, like one from a newer client.
+        send.write_all(&[0, 0, 0, 1, 250]).await.unwrap();
+        let error = read_framed(&mut recv).await.unwrap_err();
+        assert!(matches!(error, NetworkError::Decode(_)));
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("protocol version {PROTOCOL_VERSION}")),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn packets_on_their_own_stream_round_trip() {
+        let (send, recv) = duplex(MAX_PACKET_SIZE);
+        let movement = Packet::PlayerMovement {
+            id: Some(3),
+            x: 1.5,
+            y: -2.0,
+            z: 40.25,
+            animation_frame: 12,
+            teleported: true,
+            zone: 0,
+        };
+        send_packet(send, movement.clone()).await.unwrap();
+        assert_eq!(receive_packet(recv).await.unwrap(), movement);
+    }
+
+    #[tokio::test]
+    async fn streams_longer_than_the_max_packet_size_are_rejected() {
+        let (mut send, recv) = duplex(MAX_PACKET_SIZE * 2);
+        send.write_all(&vec![0; MAX_PACKET_SIZE + 1]).await.unwrap();
+        drop(send);
+        assert!(matches!(
+            receive_packet(recv).await,
+            Err(NetworkError::PacketTooLarge)
+        ));
+    }
+
+    #[test]
+    fn quantized_positions_stay_within_the_max_error() {
+        let origin = [12.5, 0.75, -40.0];
+        for step in -200..=200 {
+            // Not a multiple of the step, so every position has to be rounded.
+            let distance = step as f32 * 0.137;
+            let position = [
+                origin[0] + distance,
+                origin[1] - distance / 2.0,
+                origin[2] + 3.0,
+            ];
+            let offset = quantize_position(position, origin).unwrap();
+            let dequantized = dequantize_position(offset, origin);
+            for axis in 0..3 {
+                let error = (dequantized[axis] - position[axis]).abs();
+                assert!(
+                    error <= MAX_QUANTIZATION_ERROR,
+                    "{position:?} came back as {dequantized:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn positions_too_far_to_quantize_are_sent_absolutely() {
+        let origin = [0.0; 3];
+        let too_far = (i16::MAX as f32 + 1.0) * QUANTIZATION_STEP;
+        assert!(quantize_position([too_far, 0.0, 0.0], origin).is_none());
+        assert!(quantize_position([0.0, 0.0, -too_far - QUANTIZATION_STEP], origin).is_none());
+        assert!(quantize_position([f32::NAN, 0.0, 0.0], origin).is_none());
+        assert_eq!(quantize_position(origin, origin), Some([0; 3]));
+    }
+
+    #[test]
+    fn names_lose_control_characters_and_surrounding_whitespace() {
+        assert_eq!(sanitize_name("  Pa\u{1b}[2Jul\n "), "Pa[2Jul");
+        assert_eq!(sanitize_name("\t\r\n"), "");
+        assert_eq!(
+            sanitize_name(&"é".repeat(MAX_NAME_LENGTH + 5)),
+            "é".repeat(MAX_NAME_LENGTH)
+        );
+    }
+
+    #[test]
+    fn chat_loses_control_characters_before_being_cut_off() {
+        assert_eq!(
+            sanitize_text(" hi\u{7}\u{8} there\r\n", MAX_CHAT_LENGTH),
+            "hi there"
+        );
+        // Control characters don't count towards the length, so they can't push the real message out.
+        let message = format!("{}{}!", "\0".repeat(10), "a".repeat(MAX_CHAT_LENGTH));
+        assert_eq!(
+            sanitize_text(&message, MAX_CHAT_LENGTH),
+            "a".repeat(MAX_CHAT_LENGTH)
+        );
+        assert_eq!(sanitize_text("\u{1b}\u{7f}", MAX_CHAT_LENGTH), "");
+    }
+
+    /// One of every variant, with each field set so its bytes are easy to tell apart.
+    fn every_variant() -> Vec<Packet> {
+        vec![
+            Packet::Hello {
+                version: 8,
+                session_token: 0x0102_0304_0506_0708,
+                player_token: 9,
+            },
+            Packet::ClientConnect { id: 3 },
+            Packet::ClientDisconnect(Some(3), Some(DisconnectReason::Timeout)),
+            Packet::PlayerMovement {
+                id: Some(3),
+                x: 1.0,
+                y: 0.5,
+                z: -2.0,
+                animation_frame: 12,
+                teleported: true,
+                zone: 1,
+            },
+            Packet::LevelConfig {
+                move_speed: 4.0,
+                spawn: [0.0, 0.5, 0.0],
+                lockstep: true,
+            },
+            Packet::SetSpawn {
+                x: 1.0,
+                y: 0.5,
+                z: 0.0,
+            },
+            Packet::Interact { id: None },
+            Packet::GiftState { opened: true },
+            Packet::ReloadLevel,
+            Packet::Chat {
+                id: Some(3),
+                message: "hi".to_string(),
+                global: true,
+            },
+            Packet::PlayerRoster(vec![(3, 1.0, 0.5, -2.0, 12, 1)]),
+            Packet::Heartbeat,
+            Packet::Ping { nonce: 300 },
+            Packet::Pong { nonce: 300 },
+            Packet::PlayerOutOfRange(3),
+            Packet::MovementBatch(vec![(3, 1.0, 0.5, -2.0, 12, 1)]),
+            Packet::QuantizedMovement {
+                origin: 7,
+                x: 1,
+                y: -1,
+                z: 300,
+                animation_frame: 12,
+            },
+            Packet::ServerShutdown,
+            Packet::VersionMismatch { server_version: 8 },
+            Packet::SetName {
+                id: Some(3),
+                name: "Me".to_string(),
+            },
+            Packet::Emote {
+                id: Some(3),
+                emote: 2,
+            },
+            Packet::RoomList {
+                rooms: vec![(0, 5)],
+                capacity: 100,
+            },
+            Packet::JoinRoom { zone: 1 },
+            Packet::Input {
+                id: Some(3),
+                tick: 300,
+                input: 5,
+            },
+        ]
+    }
+
+    /// How each of every_variant encodes. If one of these changes, so does PROTOCOL_VERSION.
+    const GOLDEN_BYTES: [&[u8]; 24] = [
+        &[
+            0x00, 0x08, 0xfd, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x09,
+        ],
+        &[0x01, 0x03],
+        &[0x02, 0x01, 0x03, 0x01, 0x01],
+        &[
+            0x03, 0x01, 0x03, 0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00,
+            0xc0, 0x0c, 0x01, 0x01,
+        ],
+        &[
+            0x04, 0x00, 0x00, 0x80, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0x00,
+            0x00, 0x00, 0x00, 0x01,
+        ],
+        &[
+            0x05, 0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, 0x00,
+        ],
+        &[0x06, 0x00],
+        &[0x07, 0x01],
+        &[0x08],
+        &[0x09, 0x01, 0x03, 0x02, 0x68, 0x69, 0x01],
+        &[
+            0x0a, 0x01, 0x03, 0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00,
+            0xc0, 0x0c, 0x01,
+        ],
+        &[0x0b],
+        &[0x0c, 0xfb, 0x2c, 0x01],
+        &[0x0d, 0xfb, 0x2c, 0x01],
+        &[0x0e, 0x03],
+        &[
+            0x0f, 0x01, 0x03, 0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00,
+            0xc0, 0x0c, 0x01,
+        ],
+        &[0x10, 0x07, 0x02, 0x01, 0xfb, 0x58, 0x02, 0x0c],
+        &[0x11],
+        &[0x12, 0x08],
+        &[0x13, 0x01, 0x03, 0x02, 0x4d, 0x65],
+        &[0x14, 0x01, 0x03, 0x02],
+        &[0x15, 0x01, 0x00, 0x05, 0x64],
+        &[0x16, 0x01],
+        &[0x17, 0x01, 0x03, 0xfb, 0x2c, 0x01, 0x05],
+    ];
+
+    /// Where a variant is in Packet. This has no wildcard, so a new variant doesn't compile until it's added here,
+    /// and to every_variant and GOLDEN_BYTES.
+    fn variant_index(packet: &Packet) -> usize {
+        match packet {
+            Packet::Hello { .. } => 0,
+            Packet::ClientConnect { .. } => 1,
+            Packet::ClientDisconnect(..) => 2,
+            Packet::PlayerMovement { .. } => 3,
+            Packet::LevelConfig { .. } => 4,
+            Packet::SetSpawn { .. } => 5,
+            Packet::Interact { .. } => 6,
+            Packet::GiftState { .. } => 7,
+            Packet::ReloadLevel => 8,
+            Packet::Chat { .. } => 9,
+            Packet::PlayerRoster(..) => 10,
+            Packet::Heartbeat => 11,
+            Packet::Ping { .. } => 12,
+            Packet::Pong { .. } => 13,
+            Packet::PlayerOutOfRange(..) => 14,
+            Packet::MovementBatch(..) => 15,
+            Packet::QuantizedMovement { .. } => 16,
+            Packet::ServerShutdown => 17,
+            Packet::VersionMismatch { .. } => 18,
+            Packet::SetName { .. } => 19,
+            Packet::Emote { .. } => 20,
+            Packet::RoomList { .. } => 21,
+            Packet::JoinRoom { .. } => 22,
+            Packet::Input { .. } => 23,
+        }
+    }
+
+    #[test]
+    fn packets_encode_to_their_golden_bytes() {
+        let packets = every_variant();
+        assert_eq!(packets.len(), GOLDEN_BYTES.len());
+        for (index, (packet, golden)) in packets.iter().zip(GOLDEN_BYTES).enumerate() {
+            assert_eq!(variant_index(packet), index);
+            assert_eq!(
+                encode_to_vec(packet, PACKET_CONFIG).unwrap(),
+                golden,
+                "{packet:?} encodes differently. Bump PROTOCOL_VERSION and update its golden bytes."
+            );
+            let (decoded, read): (Packet, usize) =
+                decode_from_slice(golden, PACKET_CONFIG).unwrap();
+            assert_eq!((&decoded, read), (packet, golden.len()));
+        }
+    }
+}